@@ -3,6 +3,8 @@ use parking_lot::RwLock;
 use std::sync::atomic::{AtomicBool, Ordering};
 use crate::models::{
     ApplicationProcess,
+    FlowStats,
+    PacketStats,
     ProcessNetworkUsage
 };
 use crate::log_info;
@@ -11,6 +13,7 @@ use crate::cache::traits::Cache;
 // thread-safe cache
 pub struct NetworkCache {
     processes: RwLock<HashMap<u32, ApplicationProcess>>,
+    flows: RwLock<HashMap<u32, Vec<FlowStats>>>,
     is_clearing: AtomicBool,
 }
 
@@ -22,6 +25,7 @@ impl Cache for NetworkCache {
     fn clear(&self) {
         self.is_clearing.store(true, Ordering::SeqCst);
         self.processes.write().clear();
+        self.flows.write().clear();
         self.is_clearing.store(false, Ordering::SeqCst);
         self.log_clear();
     }
@@ -31,6 +35,7 @@ impl NetworkCache {
     pub fn new() -> Self {
         Self {
             processes: RwLock::new(HashMap::new()),
+            flows: RwLock::new(HashMap::new()),
             is_clearing: AtomicBool::new(false),
         }
     }
@@ -41,6 +46,27 @@ impl NetworkCache {
         processes.get(&pid).map(|process| process.network_usage.clone())
     }
 
+    pub fn get_process(&self, pid: u32) -> Option<ApplicationProcess> {
+        self.processes.read().get(&pid).cloned()
+    }
+
+    pub fn get_packet_stats(&self, pid: u32) -> Option<PacketStats> {
+        let processes = self.processes.read();
+        processes.get(&pid).map(|process| process.packet_stats.clone())
+    }
+
+    pub fn get_flows(&self, pid: u32) -> Vec<FlowStats> {
+        self.flows.read().get(&pid).cloned().unwrap_or_default()
+    }
+
+    pub fn update_flows(&self, pid: u32, flows: Vec<FlowStats>) {
+        if self.is_clearing.load(Ordering::SeqCst) {
+            return;
+        }
+
+        self.flows.write().insert(pid, flows);
+    }
+
     pub fn update_process(&self, process: ApplicationProcess) {
         if self.is_clearing.load(Ordering::SeqCst) {
             return;
@@ -76,12 +102,13 @@ impl NetworkCache {
         
         processes.retain(|&pid, process| {
             let is_active = active_pids.contains(&pid);
-            let has_traffic = process.network_usage.download.value > 0.01 || 
-                            process.network_usage.upload.value > 0.01;
+            let has_traffic = process.network_usage.is_active();
             
             is_active || has_traffic
         });
         
+        self.flows.write().retain(|pid, _| processes.contains_key(pid));
+
         let processes_after = processes.len();
         if processes_before != processes_after {
             log_info!("Cleaned up inactive processes. Before: {}, After: {}", 
@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::Path;
+use chrono::Utc;
+use crate::SYSTEM_MONITOR;
+use crate::{log_info, log_error};
+use crate::models::{ConfigDocument, ConfigFormat, ConfigValidation, ImportMode};
+use crate::modules::config_file::{self, SCHEMA_VERSION};
+use crate::modules::{app_limits, process_tree, profiles, settings};
+
+fn read(path: &str) -> Result<config_file::LoadedConfig, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let format = config_file::detect_format(Path::new(path), &text);
+    Ok(config_file::load(&text, format))
+}
+
+// entries matching an existing one replace it, the rest are appended
+fn merge<T, F>(existing: &mut Vec<T>, incoming: Vec<T>, same: F)
+where
+    F: Fn(&T, &T) -> bool
+{
+    for item in incoming {
+        match existing.iter().position(|current| same(current, &item)) {
+            Some(index) => existing[index] = item,
+            None => existing.push(item),
+        }
+    }
+}
+
+fn assign_ids<T, F>(items: &mut [T], prefix: &str, id: F)
+where
+    F: Fn(&mut T) -> &mut String
+{
+    let millis = Utc::now().timestamp_millis();
+    for (index, item) in items.iter_mut().enumerate() {
+        let id = id(item);
+        if id.is_empty() {
+            *id = format!("{}-{}-{}", prefix, millis, index);
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn export_config(path: String, format: Option<ConfigFormat>) -> Result<(), String> {
+    let format = format.unwrap_or_else(|| config_file::detect_format(Path::new(&path), ""));
+    let document = ConfigDocument {
+        schema_version: SCHEMA_VERSION,
+        rules: settings::rules(),
+        limits: settings::app_limits(),
+        quotas: settings::quotas(),
+        profiles: settings::profiles(),
+    };
+
+    log_info!(
+        "Exporting {} rules, {} limits, {} quotas and {} profiles to {}",
+        document.rules.len(), document.limits.len(), document.quotas.len(), document.profiles.len(), path
+    );
+    let contents = config_file::serialize(&document, format)?;
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+#[tauri::command]
+pub async fn validate_config(path: String) -> Result<ConfigValidation, String> {
+    Ok(read(&path)?.validation)
+}
+
+// all or nothing: a file with any issue changes nothing
+#[tauri::command]
+pub async fn import_config(path: String, mode: Option<ImportMode>) -> Result<ConfigValidation, String> {
+    let config_file::LoadedConfig { mut document, validation } = read(&path)?;
+    if let Some(first) = validation.issues.first() {
+        return Err(format!(
+            "{} has {} problem(s), first at {}",
+            path,
+            validation.issues.len(),
+            config_file::describe(first)
+        ));
+    }
+
+    let mode = mode.unwrap_or_default();
+    log_info!("Importing {} ({:?}, schema {:?} -> {})", path, mode, validation.migrated_from, SCHEMA_VERSION);
+    assign_ids(&mut document.rules, "rule", |rule| &mut rule.id);
+    assign_ids(&mut document.quotas, "quota", |quota| &mut quota.id);
+    assign_ids(&mut document.profiles, "profile", |profile| &mut profile.id);
+    for limit in &mut document.limits {
+        limit.path = app_limits::canonical_path(&limit.path);
+    }
+
+    let previous_quotas: Vec<String> = settings::quotas().into_iter().map(|quota| quota.id).collect();
+    let ConfigDocument { rules: imported_rules, limits, quotas, profiles: imported_profiles, .. } = document;
+    let saved = settings::update(move |settings| {
+        match mode {
+            ImportMode::Merge => {
+                merge(&mut settings.rules, imported_rules, |a, b| a.id == b.id);
+                merge(&mut settings.app_limits, limits, |a, b| a.path.eq_ignore_ascii_case(&b.path));
+                merge(&mut settings.quotas, quotas, |a, b| a.id == b.id);
+                merge(&mut settings.profiles, imported_profiles, |a, b| a.id == b.id);
+            }
+            ImportMode::Replace => {
+                settings.rules = imported_rules;
+                settings.app_limits = limits;
+                settings.quotas = quotas;
+                settings.profiles = imported_profiles;
+            }
+        }
+
+        // an active profile that the import removed is switched off
+        let known = settings.active_profile.as_ref()
+            .is_none_or(|id| settings.profiles.iter().any(|profile| &profile.id == id));
+        if !known {
+            settings.active_profile = None;
+        }
+    })?;
+
+    let processes = SYSTEM_MONITOR.get_processes();
+    if let Err(e) = profiles::reapply(&processes) {
+        log_error!("{}", e);
+    }
+    app_limits::reconcile(&process_tree::processes());
+
+    let quota_monitor = SYSTEM_MONITOR.get_quota_monitor();
+    for id in previous_quotas.iter().chain(saved.quotas.iter().map(|quota| &quota.id)) {
+        quota_monitor.reset(id);
+    }
+    SYSTEM_MONITOR.evaluate_quotas();
+
+    Ok(validation)
+}
@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+use std::path::Path;
+use chrono::Utc;
+use crate::log_info;
+use crate::models::{EmulationProfile, HoldWindow, ReplayReport};
+use crate::modules::{emulator, settings};
+
+#[tauri::command]
+pub async fn get_emulation_profiles() -> Result<Vec<EmulationProfile>, String> {
+    Ok(settings::emulation_profiles())
+}
+
+// creates the profile when the id is empty or unknown, replaces it otherwise
+#[tauri::command]
+pub async fn set_emulation_profile(mut profile: EmulationProfile) -> Result<EmulationProfile, String> {
+    emulator::validate(&profile)?;
+    if profile.id.is_empty() {
+        profile.id = format!("emulation-{}", Utc::now().timestamp_millis());
+    }
+
+    log_info!("Saving emulation profile {} ({})", profile.name, profile.id);
+    let saved = profile.clone();
+    settings::update(move |settings| {
+        match settings.emulation.iter_mut().find(|existing| existing.id == profile.id) {
+            Some(existing) => *existing = profile,
+            None => settings.emulation.push(profile),
+        }
+    })?;
+
+    Ok(saved)
+}
+
+#[tauri::command]
+pub async fn delete_emulation_profile(id: String) -> Result<(), String> {
+    log_info!("Deleting emulation profile {}", id);
+    settings::update(|settings| settings.emulation.retain(|profile| profile.id != id))?;
+    Ok(())
+}
+
+// runs a saved profile over a pcap file; nothing touches live traffic
+#[tauri::command]
+pub async fn replay_emulation(
+    id: String,
+    input_path: String,
+    output_path: Option<String>,
+    local_addr: Option<String>,
+    holds: Option<Vec<HoldWindow>>,
+) -> Result<ReplayReport, String> {
+    let profile = settings::emulation_profiles()
+        .into_iter()
+        .find(|profile| profile.id == id)
+        .ok_or_else(|| format!("No emulation profile with id {}", id))?;
+
+    let local_addr = local_addr
+        .map(|addr| addr.parse::<IpAddr>().map_err(|_| format!("Invalid local address '{}'", addr)))
+        .transpose()?;
+
+    let holds = holds.unwrap_or_default();
+    for window in &holds {
+        emulator::validate_hold(&window.settings)?;
+    }
+
+    log_info!("Replaying {} through emulation profile {}", input_path, profile.name);
+    emulator::replay(&profile, Path::new(&input_path), output_path.as_deref().map(Path::new), local_addr, &holds)
+}
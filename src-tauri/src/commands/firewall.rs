@@ -0,0 +1,69 @@
+use std::net::{IpAddr, SocketAddr};
+use crate::SYSTEM_MONITOR;
+use crate::log_info;
+use crate::models::{DecisionAnswer, DecisionResolved, FirewallSettings, PendingDecision, Rule};
+use crate::modules::rules::ProcessIdentity;
+use crate::modules::{firewall, settings};
+
+#[tauri::command]
+pub async fn get_firewall_settings() -> Result<FirewallSettings, String> {
+    Ok(settings::firewall())
+}
+
+#[tauri::command]
+pub async fn set_firewall_settings(firewall: FirewallSettings) -> Result<FirewallSettings, String> {
+    if firewall.decision_timeout_ms == 0 {
+        return Err("The decision timeout must be above zero".to_string());
+    }
+    if firewall.ask_mode && !settings::proxy().enabled {
+        return Err("Only connections through the proxy can be held for a decision, enable the proxy first".to_string());
+    }
+
+    log_info!("Setting firewall ask mode {} (timeout: {} ms, allow on timeout: {})", firewall.ask_mode, firewall.decision_timeout_ms, firewall.allow_on_timeout);
+    settings::update(move |settings| settings.firewall = firewall)?;
+    Ok(firewall)
+}
+
+#[tauri::command]
+pub async fn get_pending_decisions() -> Result<Vec<PendingDecision>, String> {
+    Ok(firewall::pending())
+}
+
+#[tauri::command]
+pub async fn answer_decision(id: u64, answer: DecisionAnswer) -> Result<DecisionResolved, String> {
+    log_info!("Answering decision {} (allow: {}, scope: {:?}, remember: {})", id, answer.allow, answer.scope, answer.remember);
+    firewall::answer(id, answer)
+}
+
+fn remote(remote_addr: &str, remote_port: u16) -> Result<SocketAddr, String> {
+    let addr: IpAddr = remote_addr.trim().parse().map_err(|_| format!("Invalid address '{}'", remote_addr))?;
+    Ok(SocketAddr::new(addr, remote_port))
+}
+
+// remembered blocks are saved as a rule for the process's app and returned
+#[tauri::command]
+pub async fn block_connection(pid: u32, remote_addr: String, remote_port: u16, remember: Option<bool>) -> Result<Option<Rule>, String> {
+    if !settings::proxy().enabled {
+        return Err("Single connections can only be blocked through the proxy, enable the proxy first".to_string());
+    }
+    let remote = remote(&remote_addr, remote_port)?;
+    let process = SYSTEM_MONITOR.get_processes().iter()
+        .find(|process| process.pid == pid)
+        .map(ProcessIdentity::from)
+        .ok_or_else(|| format!("No running process with pid {}", pid))?;
+    firewall::block_connection(&process, remote, remember.unwrap_or(false))
+}
+
+#[tauri::command]
+pub async fn unblock_connection(pid: u32, remote_addr: String, remote_port: u16) -> Result<bool, String> {
+    let remote = remote(&remote_addr, remote_port)?;
+    log_info!("Unblocking connections of process {} to {}", pid, remote);
+    Ok(firewall::unblock_connection(pid, remote))
+}
+
+#[tauri::command]
+pub async fn reset_firewall_answers() -> Result<(), String> {
+    log_info!("Forgetting firewall answers");
+    firewall::reset_answers();
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use chrono::Utc;
+use crate::SYSTEM_MONITOR;
+use crate::models::{HistoryResolution, HistorySeries};
+use crate::modules::traffic_history::HistoryTarget;
+
+// with neither pid nor app set the machine-wide series is returned
+#[tauri::command]
+pub async fn get_history(
+    pid: Option<u32>,
+    app: Option<String>,
+    range_secs: u64,
+    resolution: Option<HistoryResolution>,
+) -> Result<HistorySeries, String> {
+    let target = match (pid, app) {
+        (Some(_), Some(_)) => return Err("Specify either pid or app, not both".to_string()),
+        (Some(pid), None) => HistoryTarget::Process(pid),
+        (None, Some(app)) => HistoryTarget::Application(app),
+        (None, None) => HistoryTarget::Global,
+    };
+
+    Ok(SYSTEM_MONITOR.get_traffic_history().series(&target, Utc::now().timestamp(), range_secs, resolution))
+}
@@ -0,0 +1,24 @@
+use crate::models::{HoldSettings, HoldStatus};
+use crate::modules::{emulator, hold, proxy};
+
+// Holds the process when it is not held and releases it otherwise. Only
+// traffic relayed by the proxy can be held, so a process without proxied
+// connections is refused rather than reported as held.
+#[tauri::command]
+pub async fn toggle_hold(pid: u32, settings: HoldSettings) -> Result<Option<HoldStatus>, String> {
+    emulator::validate_hold(&settings)?;
+    if !hold::is_held(pid) && !proxy::has_connections(pid) {
+        return Err(format!("Process {} has no connections through the proxy, only proxied traffic can be held", pid));
+    }
+    Ok(hold::toggle(pid, settings))
+}
+
+#[tauri::command]
+pub async fn release_hold(pid: u32) -> Result<HoldStatus, String> {
+    hold::release(pid).ok_or_else(|| format!("Process {} is not held", pid))
+}
+
+#[tauri::command]
+pub async fn get_hold_status() -> Result<Vec<HoldStatus>, String> {
+    Ok(hold::status())
+}
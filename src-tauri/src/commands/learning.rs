@@ -0,0 +1,66 @@
+use chrono::Utc;
+use tokio::time::Duration;
+use crate::log_info;
+use crate::models::{LearnedFlow, LearningStatus, Rule, RuleProposal};
+use crate::modules::{learning, rules};
+use super::rules::save_and_apply;
+
+// a week of traffic is plenty to learn from
+const MAX_LEARNING_MINUTES: u64 = 7 * 24 * 60;
+
+#[tauri::command]
+pub async fn start_learning(duration_minutes: u64) -> Result<LearningStatus, String> {
+    if duration_minutes == 0 {
+        return Err("Learning needs a duration of at least a minute".to_string());
+    }
+    let seconds = duration_minutes.checked_mul(60)
+        .filter(|&seconds| seconds <= MAX_LEARNING_MINUTES * 60)
+        .ok_or_else(|| format!("Learning can run for at most {} minutes", MAX_LEARNING_MINUTES))?;
+    Ok(learning::start(Duration::from_secs(seconds)))
+}
+
+#[tauri::command]
+pub async fn stop_learning() -> Result<LearningStatus, String> {
+    Ok(learning::stop())
+}
+
+#[tauri::command]
+pub async fn get_learning_status() -> Result<LearningStatus, String> {
+    Ok(learning::status())
+}
+
+#[tauri::command]
+pub async fn get_learned_flows() -> Result<Vec<LearnedFlow>, String> {
+    Ok(learning::flows())
+}
+
+#[tauri::command]
+pub async fn clear_learned_flows() -> Result<(), String> {
+    log_info!("Clearing learned flows");
+    learning::clear();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_rule_proposal() -> Result<RuleProposal, String> {
+    Ok(learning::propose())
+}
+
+// the reviewed rules are appended in order, each under a fresh id
+#[tauri::command]
+pub async fn accept_proposed_rules(mut proposed: Vec<Rule>) -> Result<Vec<Rule>, String> {
+    let now = Utc::now().timestamp_millis();
+    for (index, rule) in proposed.iter_mut().enumerate() {
+        rules::validate(rule)?;
+        rule.id = format!("rule-{}-{}", now, index + 1);
+    }
+
+    log_info!("Adding {} learned rules", proposed.len());
+    let accepted = proposed.clone();
+    save_and_apply(move |rules| {
+        rules.extend(proposed);
+        Ok(())
+    })?;
+
+    Ok(accepted)
+}
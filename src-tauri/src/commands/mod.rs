@@ -2,10 +2,42 @@ mod process_info;
 mod throttling;
 mod cache;
 mod network;
+mod settings;
+mod totals;
+mod history;
+mod usage;
+mod quotas;
+mod rules;
+mod proxy;
+mod emulation;
+mod hold;
+mod tamper;
+mod config_file;
+mod profiles;
+mod priority;
+mod firewall;
+mod learning;
+mod simulation;
 
 pub use process_info::get_processes;
-pub use throttling::{throttle_process, unthrottle_process};
-pub use network::get_network_usage;
+pub use throttling::{throttle_process, unthrottle_process, get_enforcement_status, get_global_limit, set_global_limit, get_app_limits, set_app_limit, remove_app_limit};
+pub use network::{get_network_usage, get_process_flows, get_attribution_coverage};
+pub use settings::{get_unit_preferences, set_unit_preferences, get_rate_config, set_rate_config};
+pub use totals::{get_totals, reset_counters};
+pub use history::get_history;
+pub use usage::{get_top_apps, get_app_usage};
+pub use quotas::{get_quotas, set_quota, delete_quota};
+pub use rules::{get_rules, create_rule, update_rule, delete_rule, reorder_rules, set_rule_enabled, get_schedule_status, get_scope_usage};
+pub use proxy::{get_proxy_status, set_proxy_settings};
+pub use emulation::{get_emulation_profiles, set_emulation_profile, delete_emulation_profile, replay_emulation};
+pub use hold::{toggle_hold, release_hold, get_hold_status};
+pub use tamper::{get_tamper_rules, set_tamper_rule, delete_tamper_rule, replay_tamper};
+pub use config_file::{export_config, import_config, validate_config};
+pub use profiles::{get_profiles, get_active_profile, set_profile, delete_profile, activate_profile};
+pub use priority::{get_link_capacity, set_link_capacity, get_priorities, set_process_priority, get_app_priorities, set_app_priority};
+pub use firewall::{get_firewall_settings, set_firewall_settings, get_pending_decisions, answer_decision, block_connection, unblock_connection, reset_firewall_answers};
+pub use learning::{start_learning, stop_learning, get_learning_status, get_learned_flows, clear_learned_flows, get_rule_proposal, accept_proposed_rules};
+pub use simulation::simulate_rules;
 pub use cache::{
     clear_all_cache,
     clear_process_cache,
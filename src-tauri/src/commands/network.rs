@@ -1,30 +1,33 @@
 use crate::SYSTEM_MONITOR;
-use crate::models::{NetworkUsage, ProcessNetworkUsage};
+use crate::models::{AttributionReport, ByteRate, FlowStats, ProcessNetworkUsage};
+use crate::modules::settings;
+
+const DEFAULT_TOP_ENDPOINTS: usize = 10;
 
 #[tauri::command]
 pub async fn get_network_usage() -> Result<ProcessNetworkUsage, String> {
     // Get network data directly from network cache
     let network_cache = SYSTEM_MONITOR.get_network_cache();
     let processes = network_cache.get_all_processes(); // Get all processes including those with low traffic
-    
-    let mut total_download = 0.0;
-    let mut total_upload = 0.0;
 
-    for process in &processes {
-        total_download += process.network_usage.download.value;
-        total_upload += process.network_usage.upload.value;
-    }
-    
-    let usage = ProcessNetworkUsage {
-        download: NetworkUsage {
-            value: total_download,
-            unit: "KB/s".to_string(),
-        },
-        upload: NetworkUsage {
-            value: total_upload,
-            unit: "KB/s".to_string(),
-        },
-    };
+    // sum raw rates, only the total gets formatted
+    let total_download: ByteRate = processes.iter()
+        .map(|process| process.network_usage.download.bytes_per_second)
+        .sum();
+    let total_upload: ByteRate = processes.iter()
+        .map(|process| process.network_usage.upload.bytes_per_second)
+        .sum();
+
+    Ok(ProcessNetworkUsage::from_rates(total_download, total_upload, &settings::unit_preferences()))
+}
 
-    Ok(usage)
-} 
\ No newline at end of file
+#[tauri::command]
+pub async fn get_process_flows(pid: u32) -> Result<Vec<FlowStats>, String> {
+    Ok(SYSTEM_MONITOR.get_network_cache().get_flows(pid))
+}
+
+// unmatched endpoints are also available as flows of UNATTRIBUTED_PID
+#[tauri::command]
+pub async fn get_attribution_coverage(limit: Option<usize>) -> Result<AttributionReport, String> {
+    Ok(SYSTEM_MONITOR.get_attribution().report(limit.unwrap_or(DEFAULT_TOP_ENDPOINTS)))
+}
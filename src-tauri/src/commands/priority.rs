@@ -0,0 +1,56 @@
+use crate::SYSTEM_MONITOR;
+use crate::log_info;
+use crate::models::{AppPriority, LinkCapacity, PriorityClass, ProcessPriority};
+use crate::modules::{app_limits, priority, settings};
+
+#[tauri::command]
+pub async fn get_link_capacity() -> Result<LinkCapacity, String> {
+    Ok(settings::link_capacity())
+}
+
+// KB/s; priority classes only start dividing bandwidth once this is set
+#[tauri::command]
+pub async fn set_link_capacity(capacity: LinkCapacity) -> Result<LinkCapacity, String> {
+    log_info!("Setting link capacity (down: {} KB/s, up: {} KB/s)", capacity.download_limit, capacity.upload_limit);
+    settings::update(move |settings| settings.link_capacity = capacity)?;
+    priority::apply_link_capacity();
+    Ok(capacity)
+}
+
+#[tauri::command]
+pub async fn get_priorities() -> Result<Vec<ProcessPriority>, String> {
+    Ok(priority::priorities())
+}
+
+// None drops the hand-set class
+#[tauri::command]
+pub async fn set_process_priority(pid: u32, class: Option<PriorityClass>) -> Result<(), String> {
+    log_info!("Setting priority of process {} to {:?}", pid, class);
+    priority::set_process_priority(pid, class, &SYSTEM_MONITOR.get_processes());
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_app_priorities() -> Result<Vec<AppPriority>, String> {
+    Ok(settings::app_priorities())
+}
+
+// keyed by the canonical executable path; None removes the app's class
+#[tauri::command]
+pub async fn set_app_priority(path: String, class: Option<PriorityClass>) -> Result<(), String> {
+    let path = app_limits::canonical_path(&path);
+    if path.is_empty() {
+        return Err("App priorities need an executable path".to_string());
+    }
+
+    log_info!("Setting priority of {} to {:?}", path, class);
+    settings::update(move |settings| {
+        settings.app_priorities.retain(|priority| !priority.path.eq_ignore_ascii_case(&path));
+        if let Some(class) = class {
+            settings.app_priorities.push(AppPriority { path, class });
+        }
+    })?;
+
+    priority::reconcile(&SYSTEM_MONITOR.get_processes());
+    Ok(())
+}
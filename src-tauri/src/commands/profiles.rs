@@ -0,0 +1,59 @@
+use chrono::Utc;
+use crate::SYSTEM_MONITOR;
+use crate::log_info;
+use crate::models::Profile;
+use crate::modules::{profiles, settings};
+
+#[tauri::command]
+pub async fn get_profiles() -> Result<Vec<Profile>, String> {
+    Ok(settings::profiles())
+}
+
+#[tauri::command]
+pub async fn get_active_profile() -> Result<Option<Profile>, String> {
+    Ok(settings::active_profile())
+}
+
+// creates the profile when the id is empty or unknown, replaces it otherwise
+#[tauri::command]
+pub async fn set_profile(mut profile: Profile) -> Result<Profile, String> {
+    profiles::validate(&profile)?;
+    if profile.id.is_empty() {
+        profile.id = format!("profile-{}", Utc::now().timestamp_millis());
+    }
+
+    log_info!("Saving profile {} ({})", profile.name, profile.id);
+    let saved = profile.clone();
+    settings::update(move |settings| {
+        match settings.profiles.iter_mut().find(|existing| existing.id == profile.id) {
+            Some(existing) => *existing = profile,
+            None => settings.profiles.push(profile),
+        }
+    })?;
+
+    if settings::active_profile().is_some_and(|active| active.id == saved.id) {
+        profiles::reapply(&SYSTEM_MONITOR.get_processes())?;
+    }
+
+    Ok(saved)
+}
+
+// the active profile is switched off before it goes
+#[tauri::command]
+pub async fn delete_profile(id: String) -> Result<(), String> {
+    log_info!("Deleting profile {}", id);
+    if settings::active_profile().is_some_and(|active| active.id == id) {
+        profiles::activate(None, &SYSTEM_MONITOR.get_processes())?;
+    }
+
+    settings::update(|settings| settings.profiles.retain(|profile| profile.id != id))?;
+    Ok(())
+}
+
+// None leaves only the rules that apply regardless of profile
+#[tauri::command]
+pub async fn activate_profile(id: Option<String>) -> Result<Option<Profile>, String> {
+    log_info!("Activating profile {:?}", id);
+    profiles::activate(id, &SYSTEM_MONITOR.get_processes())?;
+    Ok(settings::active_profile())
+}
@@ -0,0 +1,21 @@
+use crate::log_info;
+use crate::models::{ProxySettings, ProxyStatus};
+use crate::modules::{proxy, settings};
+
+#[tauri::command]
+pub async fn get_proxy_status() -> Result<ProxyStatus, String> {
+    Ok(proxy::status())
+}
+
+// the listener is switched first so a port that cannot be bound is never saved
+#[tauri::command]
+pub async fn set_proxy_settings(proxy_settings: ProxySettings) -> Result<ProxyStatus, String> {
+    if proxy_settings.port == 0 {
+        return Err("Proxy port must be between 1 and 65535".to_string());
+    }
+
+    log_info!("Setting proxy settings to {:?}", proxy_settings);
+    let status = proxy::apply(proxy_settings)?;
+    settings::update(|settings| settings.proxy = proxy_settings)?;
+    Ok(status)
+}
@@ -0,0 +1,40 @@
+use chrono::Utc;
+use crate::SYSTEM_MONITOR;
+use crate::log_info;
+use crate::models::{Quota, QuotaStatus};
+use crate::modules::{quotas, settings};
+
+#[tauri::command]
+pub async fn get_quotas() -> Result<Vec<QuotaStatus>, String> {
+    Ok(SYSTEM_MONITOR.get_quota_monitor().status(SYSTEM_MONITOR.get_usage_store()))
+}
+
+// creates the quota when the id is empty or unknown, replaces it otherwise
+#[tauri::command]
+pub async fn set_quota(mut quota: Quota) -> Result<Quota, String> {
+    quotas::validate(&quota)?;
+    if quota.id.is_empty() {
+        quota.id = format!("quota-{}", Utc::now().timestamp_millis());
+    }
+
+    log_info!("Saving quota {} ({})", quota.name, quota.id);
+    let saved = quota.clone();
+    settings::update(move |settings| {
+        match settings.quotas.iter_mut().find(|existing| existing.id == quota.id) {
+            Some(existing) => *existing = quota,
+            None => settings.quotas.push(quota),
+        }
+    })?;
+
+    SYSTEM_MONITOR.get_quota_monitor().reset(&saved.id);
+    SYSTEM_MONITOR.evaluate_quotas();
+    Ok(saved)
+}
+
+#[tauri::command]
+pub async fn delete_quota(id: String) -> Result<(), String> {
+    log_info!("Deleting quota {}", id);
+    settings::update(|settings| settings.quotas.retain(|quota| quota.id != id))?;
+    SYSTEM_MONITOR.get_quota_monitor().reset(&id);
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use chrono::Utc;
+use crate::SYSTEM_MONITOR;
+use crate::{log_info, log_error};
+use crate::models::{Rule, ScheduleStatus, ScopeUsage};
+use crate::modules::{rules, settings};
+
+// settings first, so enforcement only ever follows what was persisted
+pub(super) fn save_and_apply<F>(change: F) -> Result<Vec<Rule>, String>
+where
+    F: FnOnce(&mut Vec<Rule>) -> Result<(), String>
+{
+    let mut updated = settings::rules();
+    change(&mut updated)?;
+
+    let saved = settings::update(move |settings| settings.rules = updated)?.rules;
+    rules::reload();
+    if let Err(e) = rules::reconcile(&SYSTEM_MONITOR.get_processes()) {
+        log_error!("{}", e);
+    }
+    Ok(saved)
+}
+
+fn check_enforceable(rule: &Rule) -> Result<(), String> {
+    if rules::needs_proxy(rule) && !settings::proxy().enabled {
+        return Err(
+            "Delay and mark rules, and limits or blocks by protocol, port, address or host, only apply to proxied connections, enable the proxy first".to_string()
+        );
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_rules() -> Result<Vec<Rule>, String> {
+    Ok(settings::rules())
+}
+
+// new rules go to the end of the list unless a position is given
+#[tauri::command]
+pub async fn create_rule(mut rule: Rule, position: Option<usize>) -> Result<Rule, String> {
+    rules::validate(&rule)?;
+    check_enforceable(&rule)?;
+    rule.id = format!("rule-{}", Utc::now().timestamp_millis());
+
+    log_info!("Creating rule {} ({})", rule.name, rule.id);
+    let created = rule.clone();
+    save_and_apply(move |rules| {
+        let position = position.unwrap_or(rules.len()).min(rules.len());
+        rules.insert(position, rule);
+        Ok(())
+    })?;
+
+    Ok(created)
+}
+
+#[tauri::command]
+pub async fn update_rule(rule: Rule) -> Result<Rule, String> {
+    rules::validate(&rule)?;
+    check_enforceable(&rule)?;
+
+    log_info!("Updating rule {} ({})", rule.name, rule.id);
+    let updated = rule.clone();
+    save_and_apply(move |rules| {
+        let existing = rules.iter_mut()
+            .find(|existing| existing.id == rule.id)
+            .ok_or_else(|| format!("No rule with id {}", rule.id))?;
+        *existing = rule;
+        Ok(())
+    })?;
+
+    Ok(updated)
+}
+
+#[tauri::command]
+pub async fn delete_rule(id: String) -> Result<(), String> {
+    log_info!("Deleting rule {}", id);
+    save_and_apply(|rules| {
+        rules.retain(|rule| rule.id != id);
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+// ids must list every rule exactly once, in the new evaluation order
+#[tauri::command]
+pub async fn reorder_rules(ids: Vec<String>) -> Result<Vec<Rule>, String> {
+    save_and_apply(move |rules| {
+        let known: HashSet<&String> = rules.iter().map(|rule| &rule.id).collect();
+        let requested: HashSet<&String> = ids.iter().collect();
+        if ids.len() != rules.len() || known != requested {
+            return Err("Reordering must list every rule exactly once".to_string());
+        }
+
+        rules.sort_by_key(|rule| ids.iter().position(|id| *id == rule.id));
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub async fn set_rule_enabled(id: String, enabled: bool) -> Result<Rule, String> {
+    log_info!("{} rule {}", if enabled { "Enabling" } else { "Disabling" }, id);
+    let rules = save_and_apply(|rules| {
+        let rule = rules.iter_mut()
+            .find(|rule| rule.id == id)
+            .ok_or_else(|| format!("No rule with id {}", id))?;
+        rule.enabled = enabled;
+        Ok(())
+    })?;
+
+    rules.into_iter()
+        .find(|rule| rule.id == id)
+        .ok_or_else(|| format!("No rule with id {}", id))
+}
+
+// scheduled rules, including those of the active profile, and when they next switch
+#[tauri::command]
+pub async fn get_schedule_status() -> Result<Vec<ScheduleStatus>, String> {
+    Ok(rules::schedule_status())
+}
+
+// rules that only match some destinations, with what those flows measure right now
+#[tauri::command]
+pub async fn get_scope_usage() -> Result<Vec<ScopeUsage>, String> {
+    Ok(rules::scope_usage())
+}
@@ -0,0 +1,30 @@
+use crate::log_info;
+use crate::models::{RateConfig, UnitPreferences};
+use crate::modules::settings;
+
+#[tauri::command]
+pub async fn get_unit_preferences() -> Result<UnitPreferences, String> {
+    Ok(settings::unit_preferences())
+}
+
+#[tauri::command]
+pub async fn set_unit_preferences(preferences: UnitPreferences) -> Result<UnitPreferences, String> {
+    log_info!("Setting unit preferences to {:?}", preferences);
+    settings::update(|settings| settings.units = preferences).map(|settings| settings.units)
+}
+
+#[tauri::command]
+pub async fn get_rate_config() -> Result<RateConfig, String> {
+    Ok(settings::rate_config())
+}
+
+// takes effect the next time capture starts
+#[tauri::command]
+pub async fn set_rate_config(config: RateConfig) -> Result<RateConfig, String> {
+    if config.bucket_ms == 0 || config.window_ms < config.bucket_ms {
+        return Err("The rate window must span at least one bucket of at least 1 ms".to_string());
+    }
+
+    log_info!("Setting rate window to {} ms in {} ms buckets (half-life: {} ms)", config.window_ms, config.bucket_ms, config.smoothing_half_life_ms);
+    settings::update(|settings| settings.rates = config).map(|settings| settings.rates)
+}
@@ -0,0 +1,20 @@
+use crate::log_info;
+use crate::models::{Rule, SimulationReport, SimulationSource};
+use crate::modules::{profiles, rules, simulation};
+
+// runs the given rules, or those in effect with the active profile, over recorded traffic
+#[tauri::command]
+pub async fn simulate_rules(source: SimulationSource, rules: Option<Vec<Rule>>) -> Result<SimulationReport, String> {
+    let ruleset = match rules {
+        Some(ruleset) => {
+            for rule in &ruleset {
+                rules::validate(rule)?;
+            }
+            ruleset
+        }
+        None => profiles::effective_rules(),
+    };
+
+    log_info!("Simulating {} rules against {:?}", ruleset.len(), source);
+    simulation::simulate(&ruleset, &source)
+}
@@ -0,0 +1,60 @@
+use std::net::IpAddr;
+use std::path::Path;
+use chrono::Utc;
+use crate::log_info;
+use crate::models::{TamperReport, TamperRule};
+use crate::modules::{settings, tamper};
+
+#[tauri::command]
+pub async fn get_tamper_rules() -> Result<Vec<TamperRule>, String> {
+    Ok(settings::tamper_rules())
+}
+
+// creates the rule when the id is empty or unknown, replaces it otherwise
+#[tauri::command]
+pub async fn set_tamper_rule(mut rule: TamperRule) -> Result<TamperRule, String> {
+    tamper::validate(&rule)?;
+    if rule.id.is_empty() {
+        rule.id = format!("tamper-{}", Utc::now().timestamp_millis());
+    }
+
+    log_info!("Saving tamper rule {} ({})", rule.name, rule.id);
+    let saved = rule.clone();
+    settings::update(move |settings| {
+        match settings.tamper.iter_mut().find(|existing| existing.id == rule.id) {
+            Some(existing) => *existing = rule,
+            None => settings.tamper.push(rule),
+        }
+    })?;
+
+    Ok(saved)
+}
+
+#[tauri::command]
+pub async fn delete_tamper_rule(id: String) -> Result<(), String> {
+    log_info!("Deleting tamper rule {}", id);
+    settings::update(|settings| settings.tamper.retain(|rule| rule.id != id))?;
+    Ok(())
+}
+
+// runs the enabled tamper rules over a pcap file; nothing touches live traffic
+#[tauri::command]
+pub async fn replay_tamper(
+    input_path: String,
+    output_path: Option<String>,
+    local_addr: Option<String>,
+    seed: Option<u64>,
+) -> Result<TamperReport, String> {
+    let local_addr = local_addr
+        .map(|addr| addr.parse::<IpAddr>().map_err(|_| format!("Invalid local address '{}'", addr)))
+        .transpose()?;
+
+    log_info!("Replaying {} through the tamper rules", input_path);
+    tamper::replay(
+        &settings::tamper_rules(),
+        Path::new(&input_path),
+        output_path.as_deref().map(Path::new),
+        local_addr,
+        seed.unwrap_or_default(),
+    )
+}
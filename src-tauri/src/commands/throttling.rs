@@ -1,33 +1,85 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use parking_lot::RwLock;
-use once_cell::sync::Lazy;
 use crate::log_info;
-
-// thread-safe storage
-static THROTTLED_PROCESSES: Lazy<Arc<RwLock<HashMap<u32, (u64, u64)>>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(HashMap::new()))
-});
+use crate::models::{AppLimit, EnforcementStatus, GlobalLimit};
+use crate::modules::{app_limits, process_tree, settings};
+use crate::modules::throttle::{self, LimitSource, ProcessLimit};
 
 #[tauri::command]
 pub async fn throttle_process(pid: u32, download_limit: u64, upload_limit: u64) -> Result<(), String> {
     log_info!("Throttling process {} (down: {} KB/s, up: {} KB/s)", pid, download_limit, upload_limit);
-    THROTTLED_PROCESSES.write().insert(pid, (download_limit, upload_limit));
-    
-    // TODO: Implement actual network throttling using Windows API
-    // This will require additional setup with the Windows Network Controller
-    
-    Ok(())
+    throttle::set_limit(pid, ProcessLimit {
+        download_limit,
+        upload_limit,
+        blocked: false,
+        source: LimitSource::User,
+        inherited_from: None,
+    })
 }
 
 #[tauri::command]
 pub async fn unthrottle_process(pid: u32) -> Result<(), String> {
     log_info!("Removing throttling for process {}", pid);
-    
-    if THROTTLED_PROCESSES.write().remove(&pid).is_some() {
-        // TODO: Implement actual network throttling removal using Windows API
-        // this will require additional setup with the Windows Network Controller
+    throttle::remove_limit(pid, &LimitSource::User);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_global_limit() -> Result<GlobalLimit, String> {
+    Ok(settings::global_limit())
+}
+
+// KB/s across all processes, active apps split it evenly
+#[tauri::command]
+pub async fn set_global_limit(limit: GlobalLimit) -> Result<GlobalLimit, String> {
+    log_info!("Setting global limit (down: {} KB/s, up: {} KB/s)", limit.download_limit, limit.upload_limit);
+    settings::update(move |settings| settings.global_limit = limit)?;
+    throttle::apply_global_limit();
+    Ok(limit)
+}
+
+#[tauri::command]
+pub async fn get_enforcement_status() -> Result<EnforcementStatus, String> {
+    Ok(throttle::enforcement_status())
+}
+
+#[tauri::command]
+pub async fn get_app_limits() -> Result<Vec<AppLimit>, String> {
+    Ok(settings::app_limits())
+}
+
+// keyed by the canonical executable path; pin_hash restricts the limit to the
+// build currently on disk
+#[tauri::command]
+pub async fn set_app_limit(mut limit: AppLimit, pin_hash: Option<bool>) -> Result<AppLimit, String> {
+    limit.path = app_limits::canonical_path(&limit.path);
+    if pin_hash.unwrap_or(false) {
+        limit.sha256 = Some(app_limits::file_sha256(&limit.path)?);
+    }
+    app_limits::validate(&limit)?;
+
+    if limit.blocked {
+        log_info!("Blocking {}", limit.path);
+    } else {
+        log_info!("Limiting {} (down: {} KB/s, up: {} KB/s)", limit.path, limit.download_limit, limit.upload_limit);
     }
-    
+    let saved = limit.clone();
+    settings::update(move |settings| {
+        match settings.app_limits.iter_mut().find(|existing| existing.path.eq_ignore_ascii_case(&limit.path)) {
+            Some(existing) => *existing = limit,
+            None => settings.app_limits.push(limit),
+        }
+    })?;
+
+    app_limits::reconcile(&process_tree::processes());
+    Ok(saved)
+}
+
+#[tauri::command]
+pub async fn remove_app_limit(path: String) -> Result<(), String> {
+    let path = app_limits::canonical_path(&path);
+    log_info!("Removing limit for {}", path);
+    settings::update(|settings| settings.app_limits.retain(|limit| !limit.path.eq_ignore_ascii_case(&path)))?;
+
+    app_limits::reconcile(&process_tree::processes());
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,15 @@
+use crate::{log_info, SYSTEM_MONITOR};
+use crate::models::TotalsReport;
+
+#[tauri::command]
+pub async fn get_totals() -> Result<TotalsReport, String> {
+    Ok(SYSTEM_MONITOR.get_traffic_totals().report())
+}
+
+#[tauri::command]
+pub async fn reset_counters() -> Result<TotalsReport, String> {
+    log_info!("Resetting traffic counters");
+    let totals = SYSTEM_MONITOR.get_traffic_totals();
+    totals.reset();
+    Ok(totals.report())
+}
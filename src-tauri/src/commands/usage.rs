@@ -0,0 +1,18 @@
+use crate::SYSTEM_MONITOR;
+use crate::models::{AppUsageSummary, DailyUsage};
+use crate::modules::usage_store::UsageStore;
+
+const DEFAULT_TOP_APPS: usize = 10;
+const DEFAULT_USAGE_DAYS: u32 = 30;
+
+// month is YYYY-MM, defaults to the current month
+#[tauri::command]
+pub async fn get_top_apps(month: Option<String>, limit: Option<usize>) -> Result<Vec<AppUsageSummary>, String> {
+    let month = month.unwrap_or_else(UsageStore::current_month);
+    Ok(SYSTEM_MONITOR.get_usage_store().top_apps(&month, limit.unwrap_or(DEFAULT_TOP_APPS)))
+}
+
+#[tauri::command]
+pub async fn get_app_usage(path: String, days: Option<u32>) -> Result<Vec<DailyUsage>, String> {
+    Ok(SYSTEM_MONITOR.get_usage_store().daily_usage(&path, days.unwrap_or(DEFAULT_USAGE_DAYS)))
+}
@@ -5,6 +5,33 @@ mod modules {
     pub mod system_monitor;
     pub mod network_monitor;
     pub mod process_metadata;
+    pub mod traffic_stats;
+    pub mod rate_estimator;
+    pub mod settings;
+    pub mod traffic_totals;
+    pub mod traffic_history;
+    pub mod usage_store;
+    pub mod limiter;
+    pub mod enforcement;
+    pub mod throttle;
+    pub mod proxy;
+    pub mod quotas;
+    pub mod rules;
+    pub mod attribution;
+    pub mod packet;
+    pub mod emulator;
+    pub mod hold;
+    pub mod tamper;
+    pub mod config_file;
+    pub mod schedule;
+    pub mod profiles;
+    pub mod app_limits;
+    pub mod process_tree;
+    pub mod priority;
+    pub mod host_labels;
+    pub mod firewall;
+    pub mod learning;
+    pub mod simulation;
 }
 mod utils;
 pub use utils::logger::init as init_logger;
@@ -13,12 +40,82 @@ use std::sync::Arc;
 use tokio::runtime::Runtime;
 use once_cell::sync::Lazy;
 
-pub use models::{ProcessStatus, NetworkUsage, ProcessNetworkUsage, ApplicationProcess};
+pub use models::{ProcessStatus, ByteRate, NetworkUsage, ProcessNetworkUsage, ApplicationProcess, PacketStats, FlowStats, UnitPreferences, RateConfig, GlobalLimit, TrafficTotals, TotalsReport, HistorySeries, AppUsageSummary, DailyUsage, Quota, QuotaStatus, AttributionReport, EnforcementStatus, Rule, ProxySettings, ProxyStatus, EmulationProfile, ReplayReport, HoldStatus, TamperRule, TamperReport, ConfigDocument, ConfigValidation, Profile, ScheduleStatus, AppLimit, PriorityClass, LinkCapacity, AppPriority, ProcessPriority, ScopeUsage, FirewallSettings, PendingDecision, DecisionScope, DecisionAnswer, DecisionResolved, LearningStatus, LearnedFlow, ProposalChange, ProposedRule, RuleProposal, FlowRecord, SimulationSource, SimulatedFlow, SimulatedRule, SimulationReport};
 pub use commands::{
     get_processes,
     throttle_process,
     unthrottle_process,
+    get_enforcement_status,
+    get_global_limit,
+    set_global_limit,
+    get_app_limits,
+    set_app_limit,
+    remove_app_limit,
     get_network_usage,
+    get_process_flows,
+    get_attribution_coverage,
+    get_unit_preferences,
+    set_unit_preferences,
+    get_rate_config,
+    set_rate_config,
+    get_totals,
+    reset_counters,
+    get_history,
+    get_top_apps,
+    get_app_usage,
+    get_quotas,
+    set_quota,
+    delete_quota,
+    get_rules,
+    create_rule,
+    update_rule,
+    delete_rule,
+    reorder_rules,
+    set_rule_enabled,
+    get_schedule_status,
+    get_scope_usage,
+    get_proxy_status,
+    set_proxy_settings,
+    get_emulation_profiles,
+    set_emulation_profile,
+    delete_emulation_profile,
+    replay_emulation,
+    toggle_hold,
+    release_hold,
+    get_hold_status,
+    get_tamper_rules,
+    set_tamper_rule,
+    delete_tamper_rule,
+    replay_tamper,
+    export_config,
+    import_config,
+    validate_config,
+    get_profiles,
+    get_active_profile,
+    set_profile,
+    delete_profile,
+    activate_profile,
+    get_link_capacity,
+    set_link_capacity,
+    get_priorities,
+    set_process_priority,
+    get_app_priorities,
+    set_app_priority,
+    get_firewall_settings,
+    set_firewall_settings,
+    get_pending_decisions,
+    answer_decision,
+    block_connection,
+    unblock_connection,
+    reset_firewall_answers,
+    start_learning,
+    stop_learning,
+    get_learning_status,
+    get_learned_flows,
+    clear_learned_flows,
+    get_rule_proposal,
+    accept_proposed_rules,
+    simulate_rules,
     clear_all_cache,
     clear_process_cache,
     clear_network_cache,
@@ -55,10 +152,14 @@ pub fn run() {
         log_info!("Initializing network monitor...");
         let network_monitor = Arc::new(modules::network_monitor::NetworkMonitor::new());
 
+        modules::rules::reload();
+        modules::priority::apply_link_capacity();
+        modules::throttle::apply_global_limit();
         log_info!("Starting system monitoring task...");
         modules::system_monitor::start_monitoring(Arc::clone(&SYSTEM_MONITOR));
         log_info!("Starting network monitoring task...");
         modules::network_monitor::start_monitoring(Arc::clone(&network_monitor));
+        modules::proxy::init(Arc::clone(&network_monitor));
         log_info!("Background tasks initialized");
     });
 
@@ -77,15 +178,93 @@ pub fn run() {
             get_processes,
             throttle_process,
             unthrottle_process,
+            get_enforcement_status,
+            get_global_limit,
+            set_global_limit,
+            get_app_limits,
+            set_app_limit,
+            remove_app_limit,
             get_network_usage,
+            get_process_flows,
+            get_attribution_coverage,
+            get_unit_preferences,
+            set_unit_preferences,
+            get_rate_config,
+            set_rate_config,
+            get_totals,
+            reset_counters,
+            get_history,
+            get_top_apps,
+            get_app_usage,
+            get_quotas,
+            set_quota,
+            delete_quota,
+            get_rules,
+            create_rule,
+            update_rule,
+            delete_rule,
+            reorder_rules,
+            set_rule_enabled,
+            get_schedule_status,
+            get_scope_usage,
+            get_proxy_status,
+            set_proxy_settings,
+            get_emulation_profiles,
+            set_emulation_profile,
+            delete_emulation_profile,
+            replay_emulation,
+            toggle_hold,
+            release_hold,
+            get_hold_status,
+            get_tamper_rules,
+            set_tamper_rule,
+            delete_tamper_rule,
+            replay_tamper,
+            export_config,
+            import_config,
+            validate_config,
+            get_profiles,
+            get_active_profile,
+            set_profile,
+            delete_profile,
+            activate_profile,
+            get_link_capacity,
+            set_link_capacity,
+            get_priorities,
+            set_process_priority,
+            get_app_priorities,
+            set_app_priority,
+            get_firewall_settings,
+            set_firewall_settings,
+            get_pending_decisions,
+            answer_decision,
+            block_connection,
+            unblock_connection,
+            reset_firewall_answers,
+            start_learning,
+            stop_learning,
+            get_learning_status,
+            get_learned_flows,
+            clear_learned_flows,
+            get_rule_proposal,
+            accept_proposed_rules,
+            simulate_rules,
             clear_all_cache,
             clear_process_cache,
             clear_network_cache
         ])
-        .setup(|_app| {
+        .setup(|app| {
+            utils::events::init(app.handle().clone());
             log_info!("Tauri application initialized");
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|_app, event| {
+            if let tauri::RunEvent::Exit = event {
+                if let Err(e) = SYSTEM_MONITOR.get_usage_store().flush() {
+                    log_error!("{}", e);
+                }
+            }
+        });
 }
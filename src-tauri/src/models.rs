@@ -1,6 +1,18 @@
 use serde::{Deserialize, Serialize};
+use std::iter::Sum;
+use std::ops::Add;
+use crate::utils::units::format_rate;
 
 pub const PROCESS_SCAN_INTERVAL_MS: u64 = 1000;
+pub const RATE_PUBLISH_INTERVAL_MS: u64 = 50;
+pub const HISTORY_SAMPLE_INTERVAL_MS: u64 = 1000;
+pub const USAGE_FLUSH_INTERVAL_MS: u64 = 60_000;
+pub const QUOTA_CHECK_INTERVAL_MS: u64 = 10_000;
+pub const SCHEDULE_CHECK_INTERVAL_MS: u64 = 5_000;
+
+// pseudo-process that collects traffic no real process could be matched to
+pub const UNATTRIBUTED_PID: u32 = u32::MAX;
+pub const UNATTRIBUTED_NAME: &str = "Unknown / System";
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum ProcessStatus {
@@ -8,18 +20,1185 @@ pub enum ProcessStatus {
     Offline,
 }
 
+// raw bytes per second, serialized as a plain number
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[serde(transparent)]
+pub struct ByteRate(pub f64);
+
+impl ByteRate {
+    pub const ZERO: ByteRate = ByteRate(0.0);
+
+    pub fn bytes_per_second(self) -> f64 {
+        self.0
+    }
+
+    pub fn is_active(self) -> bool {
+        self.0 > 0.0
+    }
+}
+
+impl Add for ByteRate {
+    type Output = ByteRate;
+
+    fn add(self, other: ByteRate) -> ByteRate {
+        ByteRate(self.0 + other.0)
+    }
+}
+
+impl Sum for ByteRate {
+    fn sum<I: Iterator<Item = ByteRate>>(iter: I) -> ByteRate {
+        iter.fold(ByteRate::ZERO, Add::add)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum RateUnit {
+    #[default]
+    Bytes,
+    Bits,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum UnitPrefix {
+    // powers of 1024 labelled KB/MB, what the UI has always shown
+    #[default]
+    Jedec,
+    // powers of 1000, kB/MB
+    Si,
+    // powers of 1024, KiB/MiB
+    Iec,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct UnitPreferences {
+    pub unit: RateUnit,
+    pub prefix: UnitPrefix,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NetworkUsage {
+    pub bytes_per_second: ByteRate,
+    // compatibility view of bytes_per_second, formatted per UnitPreferences
     pub value: f64,
     pub unit: String,
 }
 
+impl NetworkUsage {
+    pub fn from_rate(rate: ByteRate, preferences: &UnitPreferences) -> Self {
+        let (value, unit) = format_rate(rate, preferences);
+        Self {
+            bytes_per_second: rate,
+            value,
+            unit,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_per_second.is_active()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProcessNetworkUsage {
     pub download: NetworkUsage,
     pub upload: NetworkUsage,
 }
 
+impl ProcessNetworkUsage {
+    pub fn from_rates(download: ByteRate, upload: ByteRate, preferences: &UnitPreferences) -> Self {
+        Self {
+            download: NetworkUsage::from_rate(download, preferences),
+            upload: NetworkUsage::from_rate(upload, preferences),
+        }
+    }
+
+    pub fn idle(preferences: &UnitPreferences) -> Self {
+        Self::from_rates(ByteRate::ZERO, ByteRate::ZERO, preferences)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.download.is_active() || self.upload.is_active()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct RateConfig {
+    pub window_ms: u64,
+    pub bucket_ms: u64,
+    // 0 averages the window evenly, otherwise older buckets fade with this half-life
+    pub smoothing_half_life_ms: u64,
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: 2000,
+            bucket_ms: 100,
+            smoothing_half_life_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ProxySettings {
+    pub enabled: bool,
+    // loopback port serving both SOCKS5 and HTTP CONNECT
+    pub port: u16,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 1080,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProxyStatus {
+    pub settings: ProxySettings,
+    pub running: bool,
+    pub active_connections: usize,
+    pub bytes_relayed: ByteTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct LimiterConfig {
+    // bucket depth when a limit does not set its own burst
+    pub default_burst_ms: u64,
+    // packets that would wait longer than this are dropped instead of delayed
+    pub max_delay_ms: u64,
+    // a child counts towards fair sharing for this long after its last packet
+    pub fair_share_window_ms: u64,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            default_burst_ms: 250,
+            max_delay_ms: 1000,
+            fair_share_window_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficDirection {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HistogramBucket {
+    pub min_size: u64,
+    pub max_size: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PacketStats {
+    pub download_pps: f64,
+    pub upload_pps: f64,
+    pub avg_download_packet_size: f64,
+    pub avg_upload_packet_size: f64,
+    pub size_histogram: Vec<HistogramBucket>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct ByteTotals {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl ByteTotals {
+    pub fn add(&mut self, sent: u64, received: u64) {
+        self.bytes_sent += sent;
+        self.bytes_received += received;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct TrafficTotals {
+    // since the app started
+    pub session: ByteTotals,
+    // since the last reset_counters call, equal to session until then
+    pub since_reset: ByteTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcessTotals {
+    pub pid: u32,
+    pub name: String,
+    pub path: String,
+    pub running: bool,
+    pub totals: TrafficTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TotalsReport {
+    // unix milliseconds
+    pub session_started_at: i64,
+    pub reset_at: i64,
+    pub totals: TrafficTotals,
+    pub processes: Vec<ProcessTotals>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum HistoryResolution {
+    Second,
+    Minute,
+    Hour,
+}
+
+impl HistoryResolution {
+    pub fn seconds(self) -> u64 {
+        match self {
+            HistoryResolution::Second => 1,
+            HistoryResolution::Minute => 60,
+            HistoryResolution::Hour => 3600,
+        }
+    }
+
+    // how far back this resolution is kept
+    pub fn retention_seconds(self) -> u64 {
+        match self {
+            HistoryResolution::Second => 10 * 60,
+            HistoryResolution::Minute => 24 * 3600,
+            HistoryResolution::Hour => 30 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct HistoryPoint {
+    // unix milliseconds at the start of the bucket
+    pub timestamp: i64,
+    pub download: ByteRate,
+    pub upload: ByteRate,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistorySeries {
+    pub resolution: HistoryResolution,
+    pub points: Vec<HistoryPoint>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AppUsageSummary {
+    pub path: String,
+    pub name: String,
+    pub category: String,
+    pub totals: ByteTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DailyUsage {
+    // local date, YYYY-MM-DD
+    pub date: String,
+    pub totals: ByteTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum QuotaScope {
+    Global,
+    Application { path: String },
+    Category { category: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum QuotaDirection {
+    #[default]
+    Both,
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum QuotaPeriod {
+    Daily,
+    // reset_day is 1 (Monday) to 7 (Sunday)
+    Weekly,
+    // reset_day is 1 to 31, clamped to the length of the month
+    Monthly,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum QuotaAction {
+    Notify,
+    // limits in KB/s, same as throttle_process
+    Throttle { download_limit: u64, upload_limit: u64 },
+    Block,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct QuotaThreshold {
+    pub percent: u32,
+    pub action: QuotaAction,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Quota {
+    pub id: String,
+    pub name: String,
+    pub scope: QuotaScope,
+    pub limit_bytes: u64,
+    #[serde(default)]
+    pub direction: QuotaDirection,
+    pub period: QuotaPeriod,
+    #[serde(default = "default_reset_day")]
+    pub reset_day: u32,
+    pub thresholds: Vec<QuotaThreshold>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_reset_day() -> u32 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QuotaStatus {
+    pub quota: Quota,
+    // local dates, YYYY-MM-DD; the end is exclusive
+    pub period_start: String,
+    pub period_end: String,
+    pub used_bytes: u64,
+    pub percent: f64,
+    // thresholds already reached this period
+    pub triggered: Vec<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QuotaEvent {
+    pub quota_id: String,
+    pub name: String,
+    pub percent: u32,
+    pub action: QuotaAction,
+    pub used_bytes: u64,
+    pub limit_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TransportProtocol {
+    Tcp,
+    Udp,
+}
+
+// why a packet could not be mapped to a process
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnattributedReason {
+    // the connection table lookup only covers IPv4
+    Ipv6,
+    // only the TCP table is consulted
+    Udp,
+    NoMatchingConnection,
+    LookupFailed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ReasonTotals {
+    pub reason: UnattributedReason,
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EndpointTotals {
+    pub remote_addr: String,
+    pub remote_port: u16,
+    pub protocol: TransportProtocol,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AttributionReport {
+    // everything seen on the captured interface this session
+    pub interface: ByteTotals,
+    pub attributed: ByteTotals,
+    pub unattributed: ByteTotals,
+    // payload relayed by the proxy for its clients, always attributed; counted
+    // without headers, so it is kept out of the interface bytes and coverage
+    pub proxied: ByteTotals,
+    // share of interface bytes mapped to a real process, 100 when nothing was seen
+    pub coverage_percent: f64,
+    pub reasons: Vec<ReasonTotals>,
+    pub top_endpoints: Vec<EndpointTotals>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum EnforcementAction {
+    // bytes per second, None is unlimited
+    Limit { upload: Option<u64>, download: Option<u64> },
+    Block,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ActiveEnforcement {
+    pub pid: u32,
+    pub action: EnforcementAction,
+}
+
+// KB/s shared by every process, split evenly between active apps; 0 leaves that direction unlimited
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GlobalLimit {
+    #[serde(default)]
+    pub download_limit: u64,
+    #[serde(default)]
+    pub upload_limit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EnforcementStatus {
+    pub backend: String,
+    pub active: Vec<ActiveEnforcement>,
+}
+
+// inclusive on both ends
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+// Every condition that is set has to hold. Paths, names and hostnames are
+// case-insensitive globs (* and ?); empty lists match anything.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RuleConditions {
+    pub path: Option<String>,
+    pub process_name: Option<String>,
+    pub pid: Option<u32>,
+    pub parent_pid: Option<u32>,
+    pub protocol: Option<TransportProtocol>,
+    pub local_ports: Vec<PortRange>,
+    pub remote_ports: Vec<PortRange>,
+    // CIDR blocks or single addresses
+    pub remote_cidrs: Vec<String>,
+    // matched against the name the proxy was asked for, or else the name a
+    // DNS answer or TLS server name tied to the remote address
+    pub remote_hosts: Vec<String>,
+    pub direction: Option<TrafficDirection>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum RuleAction {
+    // KB/s like throttle_process, 0 leaves a direction unlimited
+    Limit { download_limit: u64, upload_limit: u64 },
+    Block,
+    // only connections through the proxy can be held back
+    Delay { ms: u64 },
+    // lets matching traffic through untouched; later rules are not consulted
+    Allow,
+    // tags the decisions the proxy keeps for matching connections, and shows in simulations
+    Mark { mark: u32 },
+    Log,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub conditions: RuleConditions,
+    pub action: RuleAction,
+    // None keeps the rule in force around the clock
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    // children and their children match as well, whatever they are called
+    #[serde(default)]
+    pub apply_to_descendants: bool,
+}
+
+// a throttle on every process of one executable, KB/s; it outlives the
+// processes and picks up new ones as they start
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AppLimit {
+    // canonical executable path
+    pub path: String,
+    // hex SHA-256 of the executable; when set, only that exact build matches
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub download_limit: u64,
+    #[serde(default)]
+    pub upload_limit: u64,
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    // also limits whatever the app launches
+    #[serde(default)]
+    pub apply_to_descendants: bool,
+    // cuts the app off entirely, the rates are ignored
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+// how much of the link a process gets once demand exceeds the link capacity
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PriorityClass {
+    Critical,
+    High,
+    #[default]
+    Normal,
+    Low,
+    Background,
+}
+
+impl PriorityClass {
+    // each class gets twice the share of the one below it
+    pub fn weight(self) -> u32 {
+        match self {
+            PriorityClass::Critical => 16,
+            PriorityClass::High => 8,
+            PriorityClass::Normal => 4,
+            PriorityClass::Low => 2,
+            PriorityClass::Background => 1,
+        }
+    }
+}
+
+// KB/s the connection actually carries; 0 leaves that direction unshaped
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinkCapacity {
+    #[serde(default)]
+    pub download_limit: u64,
+    #[serde(default)]
+    pub upload_limit: u64,
+}
+
+// the priority class of every process of one executable
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AppPriority {
+    // canonical executable path
+    pub path: String,
+    pub class: PriorityClass,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProcessPriority {
+    pub pid: u32,
+    pub class: PriorityClass,
+    // set by hand for this process, rather than through its app
+    pub manual: bool,
+}
+
+// wall-clock times as "HH:MM"; an end at or before the start runs past
+// midnight into the next day
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TimeWindow {
+    // 1 (Monday) to 7 (Sunday), empty for every day; a window that runs past
+    // midnight belongs to the day it starts on
+    #[serde(default)]
+    pub weekdays: Vec<u32>,
+    pub start: String,
+    pub end: String,
+}
+
+// in force inside any of the windows
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Schedule {
+    pub windows: Vec<TimeWindow>,
+    // IANA name such as "Europe/Berlin", None follows the system timezone
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleStatus {
+    pub rule_id: String,
+    pub name: String,
+    pub active: bool,
+    // unix milliseconds, None if nothing changes within the next week
+    pub next_transition: Option<i64>,
+}
+
+// Prompting for apps no rule covers yet: their first outbound connection
+// through the proxy is held until the user answers or the timeout runs out.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct FirewallSettings {
+    pub ask_mode: bool,
+    pub decision_timeout_ms: u64,
+    // what a connection nobody answered for gets
+    pub allow_on_timeout: bool,
+}
+
+impl Default for FirewallSettings {
+    fn default() -> Self {
+        Self {
+            ask_mode: false,
+            decision_timeout_ms: 30_000,
+            allow_on_timeout: false,
+        }
+    }
+}
+
+// a held connection waiting for the user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PendingDecision {
+    pub id: u64,
+    pub pid: u32,
+    pub app_name: String,
+    pub app_path: String,
+    pub remote_addr: String,
+    pub remote_port: u16,
+    pub hostname: Option<String>,
+    pub protocol: TransportProtocol,
+    // unix milliseconds
+    pub requested_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum DecisionScope {
+    // every connection of the app
+    #[default]
+    App,
+    // only connections of the app to the same remote endpoint
+    Connection,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct DecisionAnswer {
+    pub allow: bool,
+    #[serde(default)]
+    pub scope: DecisionScope,
+    // saved as an allow or block rule; otherwise it lasts until restart
+    #[serde(default)]
+    pub remember: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DecisionResolved {
+    pub id: u64,
+    pub allowed: bool,
+    pub timed_out: bool,
+    // the rule the answer was remembered as
+    pub rule_id: Option<String>,
+}
+
+// measured traffic of the flows a rule with remote conditions decided, summed
+// over every process it covers
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScopeUsage {
+    pub rule_id: String,
+    pub name: String,
+    pub action: RuleAction,
+    pub usage: ProcessNetworkUsage,
+    pub flows: usize,
+    // only the proxy tells flows of one process apart, so without it the
+    // rule is measured but neither limits nor blocks anything
+    pub enforced: bool,
+}
+
+// While learning, the remote endpoints of every app are recorded so an
+// allowlist can be proposed from them. Nothing is enforced meanwhile.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LearningStatus {
+    pub active: bool,
+    // unix milliseconds, of the current or last session
+    pub started_at: Option<i64>,
+    pub ends_at: Option<i64>,
+    pub apps: usize,
+    pub flows: usize,
+}
+
+// one app talking to one remote endpoint, however many connections it took
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LearnedFlow {
+    pub app_path: String,
+    pub app_name: String,
+    pub protocol: TransportProtocol,
+    pub remote_addr: String,
+    pub remote_port: u16,
+    pub hostname: Option<String>,
+    // unix milliseconds
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ProposalChange {
+    // the current rules decide none of its flows
+    Added,
+    // the current rules already allow every one of its flows
+    Covered,
+    // some of its flows are blocked, limited or delayed by a current rule
+    Conflicting,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProposedRule {
+    pub rule: Rule,
+    pub change: ProposalChange,
+    // learned flows the rule was built from
+    pub flows: usize,
+    // current rules that decide any of those flows
+    pub existing_rule_ids: Vec<String>,
+}
+
+// a proposed allowlist, compared against the current rules
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RuleProposal {
+    pub rules: Vec<ProposedRule>,
+    // current allow rules for learned apps that none of the learned flows needed
+    pub unused_rule_ids: Vec<String>,
+}
+
+// one connection of a recorded flow log; only the protocol and remote end are required
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlowRecord {
+    #[serde(default)]
+    pub pid: u32,
+    #[serde(default)]
+    pub app_name: String,
+    #[serde(default)]
+    pub app_path: String,
+    pub protocol: TransportProtocol,
+    #[serde(default)]
+    pub local_port: u16,
+    pub remote_addr: String,
+    pub remote_port: u16,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub bytes_sent: u64,
+    #[serde(default)]
+    pub bytes_received: u64,
+    // unix milliseconds the flow started, which places it in rule schedules
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum SimulationSource {
+    // a capture carries no processes, so its packets all count as app_path's;
+    // without one, rules that look at the process never match
+    Pcap {
+        path: String,
+        #[serde(default)]
+        local_addr: Option<String>,
+        #[serde(default)]
+        app_path: Option<String>,
+    },
+    // FlowRecord entries, as a JSON array or one object per line
+    FlowLog { path: String },
+}
+
+// a flow and the rules that would have decided each of its directions
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SimulatedFlow {
+    pub app_path: String,
+    pub protocol: TransportProtocol,
+    pub local_port: u16,
+    pub remote_addr: String,
+    pub remote_port: u16,
+    pub hostname: Option<String>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub upload_rule_id: Option<String>,
+    pub download_rule_id: Option<String>,
+    // mark and log rules that matched along the way
+    pub tagged_by: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SimulatedRule {
+    pub rule_id: String,
+    pub name: String,
+    pub action: RuleAction,
+    pub flows: usize,
+    // of the directions the rule decided, or tagged for mark and log rules
+    pub bytes: u64,
+}
+
+// What a ruleset would have done to recorded traffic. A scheduled rule only
+// decides flows that started inside its windows, so none of a flow log
+// without timestamps.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SimulationReport {
+    pub flows: Vec<SimulatedFlow>,
+    // rules that matched at least once, in ruleset order
+    pub rules: Vec<SimulatedRule>,
+    // enabled rules no flow matched, including those shadowed by earlier rules
+    pub unmatched_rule_ids: Vec<String>,
+    pub bytes_blocked: u64,
+    // bytes that would have been subject to a limit, not what it would have cut
+    pub bytes_limited: u64,
+    pub bytes_delayed: u64,
+    pub bytes_allowed: u64,
+    // bytes no limit, block, delay or allow rule decided
+    pub bytes_undecided: u64,
+    // enabled rules with a schedule, in ruleset order
+    pub scheduled_rule_ids: Vec<String>,
+    // captures only
+    pub packets_read: u64,
+    pub unparsed: u64,
+    // IP packets of protocols other than TCP and UDP, like ICMP, which no rule sees
+    pub other_protocols: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ProfileTrigger {
+    // active while the schedule is, in its own timezone
+    Schedule(#[serde(deserialize_with = "schedule_or_window")] Schedule),
+    // active while a network interface of this name has an address
+    Interface { name: String },
+}
+
+// settings written before triggers took whole schedules hold a single window
+fn schedule_or_window<'de, D>(deserializer: D) -> Result<Schedule, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Trigger {
+        Schedule(Schedule),
+        Window(TimeWindow),
+    }
+
+    Ok(match Trigger::deserialize(deserializer)? {
+        Trigger::Schedule(schedule) => schedule,
+        Trigger::Window(window) => Schedule {
+            windows: vec![window],
+            timezone: None,
+        },
+    })
+}
+
+// a named set of rules and limits that is switched in and out as a whole
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Profile {
+    pub id: String,
+    pub name: String,
+    // evaluated before the rules that apply regardless of profile
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub limits: Vec<AppLimit>,
+    #[serde(default)]
+    pub triggers: Vec<ProfileTrigger>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileActivation {
+    Manual,
+    Schedule,
+    Interface,
+}
+
+// payload of the "profile-changed" event
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProfileChange {
+    pub previous: Option<String>,
+    pub active: Option<String>,
+    pub reason: ProfileActivation,
+}
+
+// what gets shared between machines; schema_version drives migrations
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ConfigDocument {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub limits: Vec<AppLimit>,
+    #[serde(default)]
+    pub quotas: Vec<Quota>,
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImportMode {
+    // entries replace existing ones with the same id and are added otherwise
+    #[default]
+    Merge,
+    // the file's rules, quotas, limits and profiles replace the current ones
+    Replace,
+}
+
+// lines and columns are 1-based
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConfigIssue {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    // e.g. rules[2]
+    pub location: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ConfigValidation {
+    pub schema_version: u32,
+    // set when the file was written for an older schema and upgraded on load
+    pub migrated_from: Option<u32>,
+    pub rules: usize,
+    pub limits: usize,
+    pub quotas: usize,
+    pub profiles: usize,
+    pub issues: Vec<ConfigIssue>,
+}
+
+// one step of the emulation pipeline; probabilities are percentages
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum EmulationStageKind {
+    Delay { ms: u64 },
+    RandomDelay { min_ms: u64, max_ms: u64 },
+    // shifts each packet by up to +/- ms, never before it arrived
+    Jitter { ms: u64 },
+    Drop { percent: f64 },
+    // Gilbert-Elliott: a two-state chain with its own loss rate per state
+    BurstLoss {
+        enter_bad_percent: f64,
+        exit_bad_percent: f64,
+        good_loss_percent: f64,
+        bad_loss_percent: f64,
+    },
+    Duplicate { percent: f64, copies: u32 },
+    // holds the packet back so the ones behind it overtake
+    Reorder { percent: f64, delay_ms: u64 },
+    // packets that would queue for longer than queue_ms are dropped
+    Bandwidth { bytes_per_second: u64, queue_ms: u64 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmulationStage {
+    #[serde(flatten)]
+    pub kind: EmulationStageKind,
+    #[serde(default = "default_enabled")]
+    pub upload: bool,
+    #[serde(default = "default_enabled")]
+    pub download: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum EmulationTarget {
+    Process { pid: u32 },
+    Application { path: String },
+    // unset fields match anything
+    Filter {
+        protocol: Option<TransportProtocol>,
+        remote_addr: Option<String>,
+        remote_port: Option<u16>,
+        local_port: Option<u16>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmulationProfile {
+    pub id: String,
+    pub name: String,
+    pub target: EmulationTarget,
+    pub stages: Vec<EmulationStage>,
+    // the same seed and input always produce the same output
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+// where tamper offsets are counted from
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PacketRegion {
+    Ip,
+    Transport,
+    #[default]
+    Payload,
+}
+
+// byte strings are hex, whitespace between bytes is ignored
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BytePattern {
+    #[serde(default)]
+    pub region: PacketRegion,
+    pub offset: usize,
+    pub bytes: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum TamperOperation {
+    // swaps `length` bytes (default: as many as given) for `bytes`, so the packet can grow or shrink
+    Replace { offset: usize, length: Option<usize>, bytes: String },
+    // xor with mask, 0xff inverts every bit
+    Flip { offset: usize, length: usize, mask: u8 },
+    Randomize { offset: usize, length: usize },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TamperRule {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // unset fields match anything
+    #[serde(default)]
+    pub pid: Option<u32>,
+    #[serde(default)]
+    pub direction: Option<TrafficDirection>,
+    #[serde(default)]
+    pub protocol: Option<TransportProtocol>,
+    #[serde(default)]
+    pub local_port: Option<u16>,
+    #[serde(default)]
+    pub remote_addr: Option<String>,
+    #[serde(default)]
+    pub remote_port: Option<u16>,
+    #[serde(default)]
+    pub pattern: Option<BytePattern>,
+    #[serde(default)]
+    pub region: PacketRegion,
+    pub operations: Vec<TamperOperation>,
+    #[serde(default = "default_tamper_percent")]
+    pub percent: f64,
+    #[serde(default = "default_enabled")]
+    pub recompute_checksums: bool,
+}
+
+fn default_tamper_percent() -> f64 {
+    100.0
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TamperRuleHits {
+    pub id: String,
+    pub matched: u64,
+    pub modified: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TamperReport {
+    pub packets_read: u64,
+    pub packets_modified: u64,
+    pub unparsed: u64,
+    pub rules: Vec<TamperRuleHits>,
+    pub output_path: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HoldMode {
+    // keep packets until the hold ends
+    #[default]
+    Buffer,
+    Drop,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HoldRelease {
+    // send everything buffered, in the original order
+    #[default]
+    Flush,
+    Discard,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct HoldSettings {
+    #[serde(default = "default_enabled")]
+    pub upload: bool,
+    #[serde(default = "default_enabled")]
+    pub download: bool,
+    #[serde(default)]
+    pub mode: HoldMode,
+    #[serde(default)]
+    pub release: HoldRelease,
+    // 0 holds until released by command
+    #[serde(default)]
+    pub timeout_ms: u64,
+    // packets arriving once the buffer is full are dropped
+    #[serde(default = "default_hold_buffer")]
+    pub max_buffer_bytes: u64,
+}
+
+fn default_hold_buffer() -> u64 {
+    4 * 1024 * 1024
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HoldStatus {
+    pub pid: u32,
+    pub settings: HoldSettings,
+    pub started_at: i64,
+    pub expires_at: Option<i64>,
+    pub held_packets: u64,
+    pub held_bytes: u64,
+    pub dropped_packets: u64,
+    pub dropped_bytes: u64,
+}
+
+// a hold applied during a replay, starting this long after the first packet
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct HoldWindow {
+    pub start_ms: u64,
+    pub settings: HoldSettings,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct EmulationStats {
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub dropped: u64,
+    // went through a hold buffer and were flushed afterwards
+    pub held: u64,
+    pub duplicated: u64,
+    // released after a packet that arrived later
+    pub reordered: u64,
+    pub average_delay_ms: f64,
+    pub max_delay_ms: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplayReport {
+    pub packets_read: u64,
+    // packets the profile target applied to, the rest pass through untouched
+    pub packets_matched: u64,
+    pub unparsed: u64,
+    pub stats: EmulationStats,
+    pub output_path: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlowStats {
+    pub protocol: TransportProtocol,
+    pub local_addr: String,
+    pub local_port: u16,
+    pub remote_addr: String,
+    pub remote_port: u16,
+    // from the DNS lookup or TLS server name that led to the remote address
+    #[serde(default)]
+    pub hostname: Option<String>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub packet_stats: PacketStats,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ApplicationProcess {
     pub id: i32,
@@ -28,10 +1207,15 @@ pub struct ApplicationProcess {
     pub path: String,
     pub icon: Option<String>,
     pub network_usage: ProcessNetworkUsage,
+    pub packet_stats: PacketStats,
+    pub totals: TrafficTotals,
     pub pid: u32,
     pub parent_pid: Option<u32>,
     pub children: Vec<u32>,
     pub status: ProcessStatus,
     pub is_system: bool,
     pub category: String,
+    // the ancestor whose limit or rule this process is under, if it inherited one
+    #[serde(default)]
+    pub inherited_from: Option<u32>,
 }
\ No newline at end of file
@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::time::SystemTime;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use sha2::{Digest, Sha256};
+use crate::models::{AppLimit, UNATTRIBUTED_PID};
+use crate::modules::{schedule, settings};
+use crate::modules::rules::ProcessIdentity;
+use crate::modules::throttle::{self, LimitSource, ProcessLimit};
+use crate::log_error;
+
+struct CachedHash {
+    modified: SystemTime,
+    len: u64,
+    sha256: String,
+}
+
+// executables are only rehashed when they change on disk
+static HASHES: Lazy<RwLock<HashMap<String, CachedHash>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+// limit paths that have an application bucket in the limiter
+static APP_BUCKETS: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+
+// Resolves links and relative parts so the same executable is always stored
+// under one path. Paths that cannot be resolved (say, the app is not installed
+// here yet) are kept as given.
+pub fn canonical_path(path: &str) -> String {
+    let canonical = fs::canonicalize(path)
+        .map(|resolved| resolved.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.trim().to_string());
+
+    // windows hands back verbatim paths
+    match canonical.strip_prefix(r"\\?\") {
+        Some(stripped) if !stripped.starts_with("UNC\\") => stripped.to_string(),
+        _ => canonical,
+    }
+}
+
+pub fn file_sha256(path: &str) -> Result<String, String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    if let Some(cached) = HASHES.read().get(path) {
+        if cached.modified == modified && cached.len == metadata.len() {
+            return Ok(cached.sha256.clone());
+        }
+    }
+
+    let mut file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| format!("Failed to hash {}: {}", path, e))?;
+    let sha256: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    HASHES.write().insert(path.to_string(), CachedHash {
+        modified,
+        len: metadata.len(),
+        sha256: sha256.clone(),
+    });
+    Ok(sha256)
+}
+
+pub fn validate(limit: &AppLimit) -> Result<(), String> {
+    if limit.path.trim().is_empty() {
+        return Err("Limits need an executable path".to_string());
+    }
+    if !limit.blocked && limit.download_limit == 0 && limit.upload_limit == 0 {
+        return Err(format!("Limit for {} does not restrict either direction", limit.path));
+    }
+    if let Some(sha256) = &limit.sha256 {
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid SHA-256 hash {}", sha256));
+        }
+    }
+    if let Some(limit_schedule) = &limit.schedule {
+        schedule::validate(limit_schedule)?;
+    }
+    Ok(())
+}
+
+fn same_file_name(a: &str, b: &str) -> bool {
+    let name = |path: &str| path.rsplit(['/', '\\']).next().unwrap_or(path).to_ascii_lowercase();
+    name(a) == name(b)
+}
+
+// whether a process path is the executable stored under `canonical`; the
+// file name is compared first so most paths are never resolved
+pub fn same_executable(canonical: &str, path: &str) -> bool {
+    if path.is_empty() || !same_file_name(canonical, path) {
+        return false;
+    }
+    canonical.eq_ignore_ascii_case(path) || canonical_path(path).eq_ignore_ascii_case(canonical)
+}
+
+// the cheap comparisons go first, so most processes never touch the disk
+fn matches(limit: &AppLimit, path: &str) -> bool {
+    if !same_executable(&limit.path, path) {
+        return false;
+    }
+
+    match &limit.sha256 {
+        None => true,
+        Some(expected) => match file_sha256(path) {
+            Ok(actual) => actual.eq_ignore_ascii_case(expected),
+            Err(e) => {
+                log_error!("Not applying limit for {}: {}", limit.path, e);
+                false
+            }
+        },
+    }
+}
+
+// The limit a process falls under, and the ancestor it came from when the
+// process is only covered as a descendant. Its own executable wins.
+fn limit_for<'a>(limits: &'a [AppLimit], process: &ProcessIdentity) -> Option<(&'a AppLimit, Option<u32>)> {
+    if let Some(limit) = limits.iter().find(|limit| matches(limit, &process.path)) {
+        return Some((limit, None));
+    }
+    if !limits.iter().any(|limit| limit.apply_to_descendants) {
+        return None;
+    }
+
+    process.ancestors.iter().find_map(|ancestor| {
+        limits.iter()
+            .filter(|limit| limit.apply_to_descendants)
+            .find(|limit| matches(limit, &ancestor.path))
+            .map(|limit| (limit, Some(ancestor.pid)))
+    })
+}
+
+// Gives every running process of a limited app its limit and lifts those no
+// longer wanted. Runs on every scan over all processes, not just the listed
+// ones, so new processes of the app, and new children of apps limited with
+// their descendants, are covered as they appear. In the limiter the processes
+// of an app draw from one bucket, kept under the limit's canonical path,
+// while descendants that are other programs get one each.
+pub fn reconcile(processes: &[ProcessIdentity]) {
+    let now = Utc::now();
+    let limits: Vec<AppLimit> = settings::app_limits()
+        .into_iter()
+        .filter(|limit| limit.schedule.as_ref().is_none_or(|limit_schedule| schedule::is_active(limit_schedule, now)))
+        .collect();
+
+    let desired: HashMap<u32, ProcessLimit> = processes.iter()
+        .filter(|process| process.pid != UNATTRIBUTED_PID)
+        .filter_map(|process| {
+            let (limit, inherited_from) = limit_for(&limits, process)?;
+            Some((process.pid, ProcessLimit {
+                download_limit: limit.download_limit,
+                upload_limit: limit.upload_limit,
+                blocked: limit.blocked,
+                source: LimitSource::App(limit.path.clone()),
+                inherited_from,
+            }))
+        })
+        .collect();
+
+    let buckets: HashMap<&str, ProcessLimit> = desired.values()
+        .filter_map(|limit| match &limit.source {
+            LimitSource::App(path) if limit.inherited_from.is_none() => Some((path.as_str(), limit.clone())),
+            _ => None,
+        })
+        .collect();
+    let limiter = throttle::limiter();
+    let mut app_buckets = APP_BUCKETS.write();
+    for path in app_buckets.iter().filter(|path| !buckets.contains_key(path.as_str())) {
+        limiter.set_app_limit(path, None);
+    }
+    for (path, limit) in &buckets {
+        limiter.set_app_limit(path, Some(limit.bandwidth_limit()));
+    }
+    *app_buckets = buckets.keys().map(|path| path.to_string()).collect();
+
+    for (pid, limit) in throttle::limits() {
+        if matches!(limit.source, LimitSource::App(_)) && desired.get(&pid) != Some(&limit) {
+            throttle::remove_limit(pid, &limit.source);
+        }
+    }
+
+    for (pid, limit) in desired {
+        if let Err(e) = throttle::set_limit(pid, limit) {
+            log_error!("Failed to apply application limit to process {}: {}", pid, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_limit(path: &str, apply_to_descendants: bool) -> AppLimit {
+        AppLimit {
+            path: path.to_string(),
+            sha256: None,
+            download_limit: 100,
+            upload_limit: 0,
+            schedule: None,
+            apply_to_descendants,
+            blocked: false,
+        }
+    }
+
+    fn identity(pid: u32, path: &str, ancestors: Vec<ProcessIdentity>) -> ProcessIdentity {
+        ProcessIdentity {
+            pid,
+            name: path.rsplit('/').next().unwrap_or(path).to_string(),
+            path: path.to_string(),
+            parent_pid: ancestors.first().map(|parent| parent.pid),
+            ancestors,
+        }
+    }
+
+    #[test]
+    fn descendants_inherit_only_when_asked() {
+        let launcher = identity(10, "/opt/limits-test/launcher", Vec::new());
+        let shell = identity(11, "/opt/limits-test/sh", vec![launcher.clone()]);
+        let worker = identity(12, "/opt/limits-test/worker", vec![shell.clone(), launcher.clone()]);
+
+        let limits = [app_limit("/opt/limits-test/launcher", false)];
+        assert_eq!(limit_for(&limits, &launcher).map(|(_, from)| from), Some(None));
+        assert!(limit_for(&limits, &worker).is_none());
+
+        // the nearest limited ancestor is the one recorded
+        let limits = [app_limit("/opt/limits-test/launcher", true)];
+        assert_eq!(limit_for(&limits, &worker).map(|(_, from)| from), Some(Some(10)));
+        assert_eq!(limit_for(&limits, &shell).map(|(_, from)| from), Some(Some(10)));
+    }
+
+    #[test]
+    fn a_limit_on_the_process_itself_wins() {
+        let launcher = identity(10, "/opt/limits-test/launcher", Vec::new());
+        let worker = identity(12, "/opt/limits-test/worker", vec![launcher]);
+
+        let limits = [app_limit("/opt/limits-test/launcher", true), app_limit("/opt/limits-test/worker", false)];
+        let (limit, from) = limit_for(&limits, &worker).unwrap();
+        assert_eq!(limit.path, "/opt/limits-test/worker");
+        assert_eq!(from, None);
+    }
+
+    #[test]
+    fn executables_match_by_path_regardless_of_case() {
+        assert!(same_executable("/opt/limits-test/App", "/opt/limits-test/app"));
+        assert!(!same_executable("/opt/limits-test/app", "/opt/other/app"));
+        assert!(!same_executable("/opt/limits-test/app", ""));
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use parking_lot::RwLock;
+use crate::models::{
+    AttributionReport, ByteTotals, EndpointTotals, ReasonTotals, TransportProtocol, UnattributedReason,
+};
+
+// endpoints beyond this are pruned down to the busiest half
+const MAX_TRACKED_ENDPOINTS: usize = 4096;
+
+#[derive(Default)]
+struct AttributionState {
+    attributed: ByteTotals,
+    unattributed: ByteTotals,
+    proxied: ByteTotals,
+    reasons: HashMap<UnattributedReason, ReasonTotals>,
+    endpoints: HashMap<(IpAddr, u16, TransportProtocol), EndpointTotals>,
+}
+
+// Session counters comparing what the capture saw against what could be
+// mapped to a process, with a breakdown of the unmatched remainder.
+pub struct AttributionStats {
+    state: RwLock<AttributionState>,
+}
+
+impl AttributionStats {
+    pub fn new() -> Self {
+        Self {
+            state: RwLock::new(AttributionState::default()),
+        }
+    }
+
+    pub fn record_attributed(&self, sent: u64, received: u64) {
+        self.state.write().attributed.add(sent, received);
+    }
+
+    // payload the proxy relayed, which the capture never sees as wire bytes
+    pub fn record_proxied(&self, sent: u64, received: u64) {
+        self.state.write().proxied.add(sent, received);
+    }
+
+    pub fn record_unattributed(
+        &self,
+        reason: UnattributedReason,
+        remote_addr: IpAddr,
+        remote_port: u16,
+        protocol: TransportProtocol,
+        sent: u64,
+        received: u64,
+    ) {
+        let mut state = self.state.write();
+        state.unattributed.add(sent, received);
+
+        let reason_totals = state.reasons.entry(reason).or_insert(ReasonTotals {
+            reason,
+            bytes: 0,
+            packets: 0,
+        });
+        reason_totals.bytes += sent + received;
+        reason_totals.packets += 1;
+
+        let endpoint = state.endpoints.entry((remote_addr, remote_port, protocol)).or_insert_with(|| EndpointTotals {
+            remote_addr: remote_addr.to_string(),
+            remote_port,
+            protocol,
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets: 0,
+        });
+        endpoint.bytes_sent += sent;
+        endpoint.bytes_received += received;
+        endpoint.packets += 1;
+
+        if state.endpoints.len() > MAX_TRACKED_ENDPOINTS {
+            prune_endpoints(&mut state.endpoints);
+        }
+    }
+
+    pub fn report(&self, endpoint_limit: usize) -> AttributionReport {
+        let state = self.state.read();
+        let mut interface = state.attributed;
+        interface.add(state.unattributed.bytes_sent, state.unattributed.bytes_received);
+
+        let interface_bytes = interface.bytes_sent + interface.bytes_received;
+        let attributed_bytes = state.attributed.bytes_sent + state.attributed.bytes_received;
+        let coverage_percent = if interface_bytes == 0 {
+            100.0
+        } else {
+            attributed_bytes as f64 * 100.0 / interface_bytes as f64
+        };
+
+        let mut reasons: Vec<ReasonTotals> = state.reasons.values().copied().collect();
+        reasons.sort_by_key(|reason| std::cmp::Reverse(reason.bytes));
+
+        let mut top_endpoints: Vec<EndpointTotals> = state.endpoints.values().cloned().collect();
+        top_endpoints.sort_by_key(|endpoint| std::cmp::Reverse(endpoint.bytes_sent + endpoint.bytes_received));
+        top_endpoints.truncate(endpoint_limit);
+
+        AttributionReport {
+            interface,
+            attributed: state.attributed,
+            unattributed: state.unattributed,
+            proxied: state.proxied,
+            coverage_percent,
+            reasons,
+            top_endpoints,
+        }
+    }
+}
+
+impl Default for AttributionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn prune_endpoints(endpoints: &mut HashMap<(IpAddr, u16, TransportProtocol), EndpointTotals>) {
+    let mut keys: Vec<_> = endpoints.iter()
+        .map(|(key, endpoint)| (endpoint.bytes_sent + endpoint.bytes_received, *key))
+        .collect();
+    keys.sort_by_key(|(bytes, _)| std::cmp::Reverse(*bytes));
+
+    for (_, key) in keys.into_iter().skip(MAX_TRACKED_ENDPOINTS / 2) {
+        endpoints.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unmatched(stats: &AttributionStats, reason: UnattributedReason, remote_port: u16, sent: u64, received: u64) {
+        stats.record_unattributed(reason, "192.0.2.1".parse().unwrap(), remote_port, TransportProtocol::Tcp, sent, received);
+    }
+
+    #[test]
+    fn coverage_is_the_attributed_share_of_captured_bytes() {
+        let stats = AttributionStats::new();
+        assert_eq!(stats.report(10).coverage_percent, 100.0);
+
+        stats.record_attributed(200, 100);
+        unmatched(&stats, UnattributedReason::NoMatchingConnection, 443, 60, 40);
+        let report = stats.report(10);
+        assert_eq!((report.interface.bytes_sent, report.interface.bytes_received), (260, 140));
+        assert_eq!(report.coverage_percent, 75.0);
+
+        // relayed payload is reported, but does not count as captured
+        stats.record_proxied(1_000, 1_000);
+        let report = stats.report(10);
+        assert_eq!((report.proxied.bytes_sent, report.proxied.bytes_received), (1_000, 1_000));
+        assert_eq!(report.interface.bytes_sent + report.interface.bytes_received, 400);
+        assert_eq!(report.coverage_percent, 75.0);
+    }
+
+    #[test]
+    fn unmatched_traffic_is_broken_down_busiest_first() {
+        let stats = AttributionStats::new();
+        unmatched(&stats, UnattributedReason::Udp, 53, 10, 10);
+        unmatched(&stats, UnattributedReason::Ipv6, 443, 500, 0);
+        unmatched(&stats, UnattributedReason::Udp, 53, 0, 30);
+
+        let report = stats.report(1);
+        let reasons: Vec<(UnattributedReason, u64, u64)> = report.reasons.iter().map(|reason| (reason.reason, reason.bytes, reason.packets)).collect();
+        assert_eq!(reasons, vec![(UnattributedReason::Ipv6, 500, 1), (UnattributedReason::Udp, 50, 2)]);
+        assert_eq!(report.top_endpoints.len(), 1);
+        assert_eq!(report.top_endpoints[0].remote_port, 443);
+    }
+
+    #[test]
+    fn endpoints_are_pruned_to_the_busiest() {
+        let stats = AttributionStats::new();
+        for port in 0..=MAX_TRACKED_ENDPOINTS as u16 {
+            unmatched(&stats, UnattributedReason::NoMatchingConnection, port, port as u64 + 1, 0);
+        }
+
+        let endpoints = &stats.state.read().endpoints;
+        assert_eq!(endpoints.len(), MAX_TRACKED_ENDPOINTS / 2);
+        assert!(endpoints.values().all(|endpoint| endpoint.remote_port as usize > MAX_TRACKED_ENDPOINTS / 2));
+    }
+}
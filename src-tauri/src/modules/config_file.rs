@@ -0,0 +1,506 @@
+use std::collections::HashSet;
+use std::path::Path;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
+use crate::models::{ConfigDocument, ConfigFormat, ConfigIssue, ConfigValidation};
+use crate::modules::{app_limits, profiles, quotas, rules};
+
+pub const SCHEMA_VERSION: u32 = 3;
+
+const SECTIONS: [&str; 4] = ["rules", "limits", "quotas", "profiles"];
+
+// MIGRATIONS[n] upgrades a version n + 1 document to version n + 2, so a file
+// from any older schema walks the chain up to SCHEMA_VERSION on load
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+const MIGRATIONS: &[Migration] = &[
+    // version 2 added profiles, which older files simply do not have
+    |_| Ok(()),
+    // version 3 gave schedule triggers whole schedules instead of one window
+    |root| {
+        let profiles = root.get_mut("profiles").and_then(Value::as_array_mut).into_iter().flatten();
+        let triggers = profiles.filter_map(|profile| profile.get_mut("triggers")?.as_array_mut()).flatten();
+        for trigger in triggers.filter_map(Value::as_object_mut) {
+            if trigger.get("type").and_then(Value::as_str) != Some("Schedule") || trigger.contains_key("windows") {
+                continue;
+            }
+            let mut window = Map::new();
+            for field in ["weekdays", "start", "end"] {
+                if let Some(value) = trigger.remove(field) {
+                    window.insert(field.to_string(), value);
+                }
+            }
+            trigger.insert("windows".to_string(), Value::Array(vec![Value::Object(window)]));
+        }
+        Ok(())
+    },
+];
+
+pub struct LoadedConfig {
+    pub document: ConfigDocument,
+    pub validation: ConfigValidation,
+}
+
+// the extension decides, anything else is sniffed from the first character
+pub fn detect_format(path: &Path, text: &str) -> ConfigFormat {
+    match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("json") => ConfigFormat::Json,
+        Some("toml") => ConfigFormat::Toml,
+        _ if text.trim_start().starts_with('{') => ConfigFormat::Json,
+        _ => ConfigFormat::Toml,
+    }
+}
+
+pub fn serialize(document: &ConfigDocument, format: ConfigFormat) -> Result<String, String> {
+    match format {
+        ConfigFormat::Toml => toml::to_string_pretty(document).map_err(|e| format!("Failed to write TOML: {}", e)),
+        ConfigFormat::Json => serde_json::to_string_pretty(document).map_err(|e| format!("Failed to write JSON: {}", e)),
+    }
+}
+
+// never fails outright: anything wrong ends up in validation.issues, and the
+// document only holds the entries that parsed and validated
+pub fn load(text: &str, format: ConfigFormat) -> LoadedConfig {
+    let mut validation = ConfigValidation::default();
+    let mut document = ConfigDocument {
+        schema_version: SCHEMA_VERSION,
+        ..ConfigDocument::default()
+    };
+
+    let mut root = match parse(text, format) {
+        Ok(Value::Object(root)) => root,
+        Ok(_) => {
+            validation.issues.push(issue(Some(1), None, None, "Expected a table of settings at the top level"));
+            return LoadedConfig { document, validation };
+        }
+        Err(e) => {
+            validation.issues.push(e);
+            return LoadedConfig { document, validation };
+        }
+    };
+
+    let version = match root.remove("schema_version").map(|value| value.as_u64()) {
+        None => {
+            validation.issues.push(issue(None, None, Some("schema_version"), "Missing schema_version"));
+            return LoadedConfig { document, validation };
+        }
+        Some(Some(version)) if (1..=SCHEMA_VERSION as u64).contains(&version) => version as u32,
+        Some(Some(version)) if version > SCHEMA_VERSION as u64 => {
+            let message = format!(
+                "Schema version {} is newer than this build supports ({}); update Meridian to import it",
+                version, SCHEMA_VERSION
+            );
+            validation.issues.push(issue(None, None, Some("schema_version"), &message));
+            return LoadedConfig { document, validation };
+        }
+        Some(_) => {
+            validation.issues.push(issue(None, None, Some("schema_version"), "schema_version must be a positive integer"));
+            return LoadedConfig { document, validation };
+        }
+    };
+
+    if let Err(e) = migrate(&mut root, version) {
+        let message = format!("Failed to upgrade from schema version {}: {}", version, e);
+        validation.issues.push(issue(None, None, Some("schema_version"), &message));
+        return LoadedConfig { document, validation };
+    }
+    if version < SCHEMA_VERSION {
+        validation.migrated_from = Some(version);
+    }
+    validation.schema_version = SCHEMA_VERSION;
+
+    for key in root.keys().filter(|key| !SECTIONS.contains(&key.as_str())) {
+        let message = format!("Unknown section {}", key);
+        validation.issues.push(issue(None, None, Some(key), &message));
+    }
+
+    let issues = &mut validation.issues;
+    document.rules = section(text, format, &mut root, "rules", issues, rules::validate);
+    document.limits = section(text, format, &mut root, "limits", issues, app_limits::validate);
+    document.quotas = section(text, format, &mut root, "quotas", issues, quotas::validate);
+    document.profiles = section(text, format, &mut root, "profiles", issues, profiles::validate);
+
+    duplicate_ids(document.rules.iter().map(|rule| &rule.id), "rules", issues);
+    duplicate_ids(document.quotas.iter().map(|quota| &quota.id), "quotas", issues);
+    duplicate_ids(document.profiles.iter().map(|profile| &profile.id), "profiles", issues);
+
+    validation.rules = document.rules.len();
+    validation.limits = document.limits.len();
+    validation.quotas = document.quotas.len();
+    validation.profiles = document.profiles.len();
+    LoadedConfig { document, validation }
+}
+
+pub fn describe(issue: &ConfigIssue) -> String {
+    let position = match (issue.line, issue.column) {
+        (Some(line), Some(column)) => format!("line {}, column {}: ", line, column),
+        (Some(line), None) => format!("line {}: ", line),
+        _ => String::new(),
+    };
+    let location = issue.location.as_ref().map(|location| format!("{}: ", location)).unwrap_or_default();
+    format!("{}{}{}", position, location, issue.message)
+}
+
+fn issue(line: Option<usize>, column: Option<usize>, location: Option<&str>, message: &str) -> ConfigIssue {
+    ConfigIssue {
+        line,
+        column,
+        location: location.map(str::to_string),
+        message: message.to_string(),
+    }
+}
+
+fn parse(text: &str, format: ConfigFormat) -> Result<Value, ConfigIssue> {
+    match format {
+        ConfigFormat::Json => serde_json::from_str(text).map_err(|e| ConfigIssue {
+            line: Some(e.line()),
+            column: Some(e.column()),
+            location: None,
+            message: e.to_string(),
+        }),
+        ConfigFormat::Toml => {
+            let table: toml::Table = toml::from_str(text).map_err(|e| {
+                let (line, column) = e.span()
+                    .map(|span| line_column(text, span.start))
+                    .map_or((None, None), |(line, column)| (Some(line), Some(column)));
+                ConfigIssue {
+                    line,
+                    column,
+                    location: None,
+                    message: e.message().to_string(),
+                }
+            })?;
+            serde_json::to_value(table).map_err(|e| issue(None, None, None, &e.to_string()))
+        }
+    }
+}
+
+fn migrate(root: &mut Map<String, Value>, version: u32) -> Result<(), String> {
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(root)?;
+    }
+    Ok(())
+}
+
+fn section<T, V>(
+    text: &str,
+    format: ConfigFormat,
+    root: &mut Map<String, Value>,
+    name: &str,
+    issues: &mut Vec<ConfigIssue>,
+    validate: V,
+) -> Vec<T>
+where
+    T: DeserializeOwned,
+    V: Fn(&T) -> Result<(), String>,
+{
+    let items = match root.remove(name) {
+        None => return Vec::new(),
+        Some(Value::Array(items)) => items,
+        Some(_) => {
+            issues.push(issue(None, None, Some(name), "Expected a list"));
+            return Vec::new();
+        }
+    };
+
+    let lines = item_lines(text, format, name);
+    let mut parsed = Vec::with_capacity(items.len());
+    for (index, item) in items.into_iter().enumerate() {
+        let line = lines.get(index).copied();
+        let location = format!("{}[{}]", name, index);
+        let value = match serde_path_to_error::deserialize::<_, T>(item) {
+            Ok(value) => value,
+            Err(e) => {
+                // point at the field serde gave up on rather than the entry
+                let mut field = location.clone();
+                let mut keys = Vec::new();
+                for segment in e.path().iter() {
+                    match segment {
+                        Segment::Seq { index } => field.push_str(&format!("[{}]", index)),
+                        Segment::Map { key } => {
+                            field.push_str(&format!(".{}", key));
+                            keys.push(key.as_str());
+                        }
+                        Segment::Enum { variant } => field.push_str(&format!(".{}", variant)),
+                        Segment::Unknown => field.push_str(".?"),
+                    }
+                }
+                let line = line.map(|start| field_line(text, format, name, start, lines.get(index + 1).copied(), &keys));
+                issues.push(issue(line, None, Some(&field), &e.inner().to_string()));
+                continue;
+            }
+        };
+
+        match validate(&value) {
+            Ok(()) => parsed.push(value),
+            Err(e) => issues.push(issue(line, None, Some(&location), &e)),
+        }
+    }
+    parsed
+}
+
+// empty ids are fine, they get assigned on import
+fn duplicate_ids<'a>(ids: impl Iterator<Item = &'a String>, name: &str, issues: &mut Vec<ConfigIssue>) {
+    let mut seen = HashSet::new();
+    for id in ids.filter(|id| !id.is_empty()) {
+        if !seen.insert(id) {
+            issues.push(issue(None, None, Some(name), &format!("Duplicate id {}", id)));
+        }
+    }
+}
+
+// 1-based
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
+
+// the line each entry of a top-level list starts on; entries written some
+// other way (inline TOML arrays, say) simply get no line
+fn item_lines(text: &str, format: ConfigFormat, name: &str) -> Vec<usize> {
+    match format {
+        ConfigFormat::Toml => {
+            let header = format!("[[{}]]", name);
+            text.lines()
+                .enumerate()
+                .filter(|(_, line)| line.trim() == header)
+                .map(|(index, _)| index + 1)
+                .collect()
+        }
+        ConfigFormat::Json => json_item_lines(text, name),
+    }
+}
+
+// The line a field of the entry starting at `start` is on, found by looking
+// for each key of its path in turn. A key that can not be found, say one
+// inside an inline table, leaves the line of the last one that could.
+fn field_line(text: &str, format: ConfigFormat, name: &str, start: usize, next: Option<usize>, keys: &[&str]) -> usize {
+    let entry: Vec<(usize, &str)> = text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .skip(start - 1)
+        .take_while(|(number, line)| match format {
+            // the entry runs until a table that is not one of its own
+            ConfigFormat::Toml => *number == start
+                || !line.starts_with('[')
+                || line.trim_start_matches('[').starts_with(&format!("{}.", name)),
+            ConfigFormat::Json => next.is_none_or(|next| *number < next),
+        })
+        .collect();
+
+    let mut found = start;
+    let mut from = 0;
+    for key in keys {
+        let Some(offset) = entry[from..].iter().position(|(_, line)| declares(format, line, key)) else {
+            break;
+        };
+        from += offset;
+        found = entry[from].0;
+    }
+    found
+}
+
+fn declares(format: ConfigFormat, line: &str, key: &str) -> bool {
+    match format {
+        ConfigFormat::Toml => {
+            let assigned = line.strip_prefix(key).is_some_and(|rest| rest.trim_start().starts_with('='));
+            let table = line.starts_with('[') && line.trim_end_matches(']').ends_with(&format!(".{}", key));
+            assigned || table
+        }
+        ConfigFormat::Json => {
+            let quoted = format!("\"{}\"", key);
+            line.match_indices(&quoted).any(|(at, _)| line[at + quoted.len()..].trim_start().starts_with(':'))
+        }
+    }
+}
+
+fn json_item_lines(text: &str, name: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut line = 1;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut string = String::new();
+    let mut key = String::new();
+    let mut in_section = false;
+    let mut expect_item = false;
+
+    for c in text.chars() {
+        if c == '\n' {
+            line += 1;
+        }
+
+        if in_string {
+            if escaped {
+                escaped = false;
+                string.push(c);
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+                if depth == 1 {
+                    key = std::mem::take(&mut string);
+                }
+            } else {
+                string.push(c);
+            }
+            continue;
+        }
+
+        if in_section && depth == 2 && expect_item && !c.is_whitespace() && c != ']' {
+            lines.push(line);
+            expect_item = false;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                string.clear();
+            }
+            '{' | '[' => {
+                depth += 1;
+                if depth == 2 && c == '[' && key == name {
+                    in_section = true;
+                    expect_item = true;
+                }
+            }
+            '}' | ']' => {
+                if depth == 2 {
+                    in_section = false;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            ',' if in_section && depth == 2 => expect_item = true,
+            _ => {}
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ProfileTrigger, TimeWindow};
+
+    fn locations(loaded: &LoadedConfig) -> Vec<(Option<usize>, Option<&str>)> {
+        loaded.validation.issues.iter().map(|issue| (issue.line, issue.location.as_deref())).collect()
+    }
+
+    #[test]
+    fn every_schema_version_loads() {
+        let v1 = load("schema_version = 1\n\n[[rules]]\nid = \"a\"\nname = \"A\"\naction = { type = \"Block\" }\n", ConfigFormat::Toml);
+        assert!(v1.validation.issues.is_empty(), "{:?}", v1.validation.issues);
+        assert_eq!(v1.validation.migrated_from, Some(1));
+        assert_eq!(v1.validation.schema_version, SCHEMA_VERSION);
+        assert_eq!(v1.document.rules.len(), 1);
+        assert!(v1.document.profiles.is_empty());
+
+        let v2 = load(r#"{"schema_version": 2, "profiles": [{"id": "p", "name": "Gaming", "triggers": [
+            {"type": "Schedule", "weekdays": [6, 7], "start": "10:00", "end": "22:00"},
+            {"type": "Interface", "name": "vpn0"}
+        ]}]}"#, ConfigFormat::Json);
+        assert!(v2.validation.issues.is_empty(), "{:?}", v2.validation.issues);
+        assert_eq!(v2.validation.migrated_from, Some(2));
+        let ProfileTrigger::Schedule(trigger_schedule) = &v2.document.profiles[0].triggers[0] else {
+            panic!("expected a schedule trigger");
+        };
+        assert_eq!(trigger_schedule.windows, vec![TimeWindow { weekdays: vec![6, 7], start: "10:00".to_string(), end: "22:00".to_string() }]);
+        assert_eq!(trigger_schedule.timezone, None);
+
+        let v3 = load(r#"schema_version = 3
+
+[[profiles]]
+id = "p"
+name = "Night"
+
+[[profiles.triggers]]
+type = "Schedule"
+timezone = "Europe/Berlin"
+windows = [{ weekdays = [], start = "22:00", end = "06:00" }]
+"#, ConfigFormat::Toml);
+        assert!(v3.validation.issues.is_empty(), "{:?}", v3.validation.issues);
+        assert_eq!(v3.validation.migrated_from, None);
+        assert_eq!(v3.document.profiles[0].triggers.len(), 1);
+        assert_eq!(MIGRATIONS.len() as u32, SCHEMA_VERSION - 1);
+    }
+
+    #[test]
+    fn unsupported_versions_are_refused() {
+        for text in ["schema_version = 0", "schema_version = 4", "schema_version = \"2\"", "rules = []"] {
+            let loaded = load(text, ConfigFormat::Toml);
+            assert_eq!(locations(&loaded), vec![(None, Some("schema_version"))], "{}", text);
+            assert_eq!(loaded.validation.schema_version, 0);
+        }
+    }
+
+    #[test]
+    fn toml_errors_point_at_the_field() {
+        let text = "\
+schema_version = 3
+
+[[rules]]
+id = \"a\"
+name = \"A\"
+action = { type = \"Block\" }
+
+[[rules]]
+id = \"b\"
+name = \"B\"
+action = { type = \"Block\" }
+
+[rules.conditions]
+path = \"*\\\\game.exe\"
+remote_ports = [{ start = 80, end = 99999 }]
+
+[[quotas]]
+id = \"q\"
+";
+        let loaded = load(text, ConfigFormat::Toml);
+        assert_eq!(loaded.document.rules.len(), 1);
+        assert_eq!(locations(&loaded), vec![
+            (Some(15), Some("rules[1].conditions.remote_ports[0].end")),
+            (Some(17), Some("quotas[0]")),
+        ]);
+    }
+
+    #[test]
+    fn json_errors_point_at_the_field() {
+        let text = r#"{
+  "schema_version": 3,
+  "rules": [
+    {"id": "a", "name": "A", "action": {"type": "Block"}},
+    {
+      "id": "b",
+      "name": "",
+      "action": {"type": "Allow"}
+    },
+    {
+      "id": "c",
+      "name": "C",
+      "action": {"type": "Block"},
+      "conditions": {
+        "path": "*",
+        "remote_ports": [{"start": 1, "end": 2}, {"start": 3, "end": -1}]
+      }
+    }
+  ]
+}"#;
+        let loaded = load(text, ConfigFormat::Json);
+        assert_eq!(loaded.document.rules.len(), 1);
+        assert_eq!(locations(&loaded), vec![
+            (Some(5), Some("rules[1]")),
+            (Some(16), Some("rules[2].conditions.remote_ports[1].end")),
+        ]);
+    }
+
+    #[test]
+    fn json_entries_are_found_past_strings_and_nesting() {
+        let text = "{\"limits\": [{\"path\": \"]\"}],\n \"rules\": [\n  {\"name\": \"[,\\\"\", \"conditions\": {\"remote_ports\": [{\"start\": 1}, {}]}},\n\n  {}, {}\n ]}";
+        assert_eq!(json_item_lines(text, "rules"), vec![3, 5, 5]);
+        assert_eq!(json_item_lines(text, "limits"), vec![1]);
+        assert!(json_item_lines(text, "quotas").is_empty());
+    }
+}
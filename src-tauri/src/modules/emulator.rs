@@ -0,0 +1,618 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+use crate::models::{EmulationProfile, EmulationStage, EmulationStageKind, EmulationStats, EmulationTarget, HoldMode, HoldRelease, HoldSettings, HoldWindow, ReplayReport, TrafficDirection};
+use crate::modules::network_monitor::{is_local_ipv4, is_local_ipv6};
+use crate::modules::packet::{self, PacketLayout};
+use crate::utils::pcap::{self, PcapFile, PcapPacket};
+use crate::utils::rng::SeededRng;
+
+const MAX_DUPLICATE_COPIES: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct EmulatedPacket {
+    pub data: Vec<u8>,
+    // length on the wire, data may be cut short by the capture's snaplen
+    pub original_len: u32,
+    pub direction: TrafficDirection,
+}
+
+enum StageState {
+    Delay(Duration),
+    RandomDelay { min_ms: f64, max_ms: f64 },
+    Jitter(f64),
+    Drop(f64),
+    BurstLoss { enter_bad: f64, exit_bad: f64, good_loss: f64, bad_loss: f64, in_bad: bool },
+    Duplicate { probability: f64, copies: u32 },
+    Reorder { probability: f64, delay: Duration },
+    // next_free is the time the link finishes its current backlog, per direction
+    Bandwidth { bytes_per_second: u64, queue: Duration, next_free: [Duration; 2] },
+}
+
+struct Stage {
+    state: StageState,
+    upload: bool,
+    download: bool,
+    rng: SeededRng,
+}
+
+impl Stage {
+    fn new(stage: &EmulationStage, rng: SeededRng) -> Self {
+        let state = match stage.kind {
+            EmulationStageKind::Delay { ms } => StageState::Delay(Duration::from_millis(ms)),
+            EmulationStageKind::RandomDelay { min_ms, max_ms } => StageState::RandomDelay {
+                min_ms: min_ms as f64,
+                max_ms: max_ms as f64,
+            },
+            EmulationStageKind::Jitter { ms } => StageState::Jitter(ms as f64),
+            EmulationStageKind::Drop { percent } => StageState::Drop(percent / 100.0),
+            EmulationStageKind::BurstLoss { enter_bad_percent, exit_bad_percent, good_loss_percent, bad_loss_percent } => StageState::BurstLoss {
+                enter_bad: enter_bad_percent / 100.0,
+                exit_bad: exit_bad_percent / 100.0,
+                good_loss: good_loss_percent / 100.0,
+                bad_loss: bad_loss_percent / 100.0,
+                in_bad: false,
+            },
+            EmulationStageKind::Duplicate { percent, copies } => StageState::Duplicate {
+                probability: percent / 100.0,
+                copies,
+            },
+            EmulationStageKind::Reorder { percent, delay_ms } => StageState::Reorder {
+                probability: percent / 100.0,
+                delay: Duration::from_millis(delay_ms),
+            },
+            EmulationStageKind::Bandwidth { bytes_per_second, queue_ms } => StageState::Bandwidth {
+                bytes_per_second,
+                queue: Duration::from_millis(queue_ms),
+                next_free: [Duration::ZERO; 2],
+            },
+        };
+
+        Self {
+            state,
+            upload: stage.upload,
+            download: stage.download,
+            rng,
+        }
+    }
+
+    // `at` is when the packet leaves the previous stage, `arrived` when it entered the pipeline
+    fn apply(&mut self, arrived: Duration, at: Duration, packet: EmulatedPacket, out: &mut Vec<(Duration, EmulatedPacket)>, stats: &mut EmulationStats) {
+        let applies = match packet.direction {
+            TrafficDirection::Upload => self.upload,
+            TrafficDirection::Download => self.download,
+        };
+        if !applies {
+            out.push((at, packet));
+            return;
+        }
+
+        let rng = &mut self.rng;
+        match &mut self.state {
+            StageState::Delay(delay) => out.push((at + *delay, packet)),
+            StageState::RandomDelay { min_ms, max_ms } => {
+                let ms = rng.range_f64(*min_ms, *max_ms);
+                out.push((at + Duration::from_secs_f64(ms / 1000.0), packet));
+            }
+            StageState::Jitter(ms) => {
+                let shifted = at.as_secs_f64() + rng.range_f64(-*ms, *ms) / 1000.0;
+                let release = Duration::from_secs_f64(shifted.max(0.0)).max(arrived);
+                out.push((release, packet));
+            }
+            StageState::Drop(probability) => {
+                if rng.chance(*probability) {
+                    stats.dropped += 1;
+                } else {
+                    out.push((at, packet));
+                }
+            }
+            StageState::BurstLoss { enter_bad, exit_bad, good_loss, bad_loss, in_bad } => {
+                if *in_bad {
+                    if rng.chance(*exit_bad) {
+                        *in_bad = false;
+                    }
+                } else if rng.chance(*enter_bad) {
+                    *in_bad = true;
+                }
+
+                let loss = if *in_bad { *bad_loss } else { *good_loss };
+                if rng.chance(loss) {
+                    stats.dropped += 1;
+                } else {
+                    out.push((at, packet));
+                }
+            }
+            StageState::Duplicate { probability, copies } => {
+                if rng.chance(*probability) {
+                    for _ in 0..*copies {
+                        out.push((at, packet.clone()));
+                    }
+                    stats.duplicated += *copies as u64;
+                }
+                out.push((at, packet));
+            }
+            StageState::Reorder { probability, delay } => {
+                let release = if rng.chance(*probability) { at + *delay } else { at };
+                out.push((release, packet));
+            }
+            StageState::Bandwidth { bytes_per_second, queue, next_free } => {
+                let lane = &mut next_free[packet.direction as usize];
+                let start = at.max(*lane);
+                if start - at > *queue {
+                    stats.dropped += 1;
+                    return;
+                }
+
+                let length = packet.original_len.max(packet.data.len() as u32);
+                let transmit = Duration::from_secs_f64(length as f64 / *bytes_per_second as f64);
+                *lane = start + transmit;
+                out.push((*lane, packet));
+            }
+        }
+    }
+}
+
+struct Hold {
+    settings: HoldSettings,
+    expires: Option<Duration>,
+    // arrival time and input position of each buffered packet
+    buffer: VecDeque<(Duration, u64, EmulatedPacket)>,
+    buffered_bytes: u64,
+}
+
+impl Hold {
+    fn applies(&self, direction: TrafficDirection) -> bool {
+        match direction {
+            TrafficDirection::Upload => self.settings.upload,
+            TrafficDirection::Download => self.settings.download,
+        }
+    }
+}
+
+struct InFlight {
+    release: Duration,
+    arrived: Duration,
+    // position in the input, shared by duplicates
+    index: u64,
+    seq: u64,
+    packet: EmulatedPacket,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.release, self.seq).cmp(&(other.release, other.seq))
+    }
+}
+
+// Runs packets through a profile's stages and holds them until their release
+// time. Time only comes from the callers, so a replay at packet timestamps is
+// as deterministic as the seed.
+pub struct Emulator {
+    stages: Vec<Stage>,
+    queue: BinaryHeap<Reverse<InFlight>>,
+    hold: Option<Hold>,
+    last_submit: Duration,
+    next_index: u64,
+    next_seq: u64,
+    highest_released: Option<u64>,
+    total_delay: Duration,
+    stats: EmulationStats,
+}
+
+impl Emulator {
+    pub fn new(profile: &EmulationProfile) -> Self {
+        Self {
+            // every stage draws from its own stream so adding one does not
+            // change the decisions of the others
+            stages: profile.stages.iter()
+                .enumerate()
+                .map(|(index, stage)| Stage::new(stage, SeededRng::derive(profile.seed, index as u64)))
+                .collect(),
+            queue: BinaryHeap::new(),
+            hold: None,
+            last_submit: Duration::ZERO,
+            next_index: 0,
+            next_seq: 0,
+            highest_released: None,
+            total_delay: Duration::ZERO,
+            stats: EmulationStats::default(),
+        }
+    }
+
+    pub fn submit(&mut self, now: Duration, packet: EmulatedPacket) {
+        self.expire_hold(now);
+        self.last_submit = self.last_submit.max(now);
+        self.stats.packets_in += 1;
+        let index = self.next_index;
+        self.next_index += 1;
+
+        if let Some(hold) = self.hold.as_mut().filter(|hold| hold.applies(packet.direction)) {
+            let length = packet.data.len() as u64;
+            if hold.settings.mode == HoldMode::Drop || hold.buffered_bytes + length > hold.settings.max_buffer_bytes {
+                self.stats.dropped += 1;
+            } else {
+                hold.buffered_bytes += length;
+                hold.buffer.push_back((now, index, packet));
+            }
+            return;
+        }
+
+        self.run_stages(now, now, index, packet);
+    }
+
+    // held packets enter the stages when the hold ends but keep their arrival time
+    fn run_stages(&mut self, arrived: Duration, now: Duration, index: u64, packet: EmulatedPacket) {
+        let mut batch = vec![(now, packet)];
+        for stage in &mut self.stages {
+            let mut next = Vec::with_capacity(batch.len());
+            for (at, packet) in batch {
+                stage.apply(now, at, packet, &mut next, &mut self.stats);
+            }
+            batch = next;
+        }
+
+        for (release, packet) in batch {
+            self.queue.push(Reverse(InFlight {
+                release,
+                arrived,
+                index,
+                seq: self.next_seq,
+                packet,
+            }));
+            self.next_seq += 1;
+        }
+    }
+
+    // starting a hold while one is active keeps the buffer and replaces the settings
+    pub fn hold(&mut self, now: Duration, settings: HoldSettings) {
+        let expires = (settings.timeout_ms > 0).then(|| now + Duration::from_millis(settings.timeout_ms));
+        match &mut self.hold {
+            Some(hold) => {
+                hold.settings = settings;
+                hold.expires = expires;
+            }
+            None => {
+                self.hold = Some(Hold {
+                    settings,
+                    expires,
+                    buffer: VecDeque::new(),
+                    buffered_bytes: 0,
+                });
+            }
+        }
+    }
+
+    pub fn release_hold(&mut self, now: Duration) {
+        let Some(hold) = self.hold.take() else {
+            return;
+        };
+
+        match hold.settings.release {
+            HoldRelease::Flush => {
+                for (arrived, index, packet) in hold.buffer {
+                    self.stats.held += 1;
+                    self.run_stages(arrived, now, index, packet);
+                }
+            }
+            HoldRelease::Discard => self.stats.dropped += hold.buffer.len() as u64,
+        }
+    }
+
+    fn expire_hold(&mut self, now: Duration) {
+        if let Some(expires) = self.hold.as_ref().and_then(|hold| hold.expires).filter(|&expires| expires <= now) {
+            self.release_hold(expires);
+        }
+    }
+
+    pub fn next_release(&self) -> Option<Duration> {
+        self.queue.peek().map(|Reverse(in_flight)| in_flight.release)
+    }
+
+    // packets due at or before now, in release order
+    pub fn poll(&mut self, now: Duration) -> Vec<(Duration, EmulatedPacket)> {
+        self.expire_hold(now);
+        let mut due = Vec::new();
+        while self.next_release().is_some_and(|release| release <= now) {
+            if let Some(Reverse(in_flight)) = self.queue.pop() {
+                due.push(self.release(in_flight));
+            }
+        }
+        due
+    }
+
+    // ends any hold as well, at its expiry or else at the last submitted packet
+    pub fn flush(&mut self) -> Vec<(Duration, EmulatedPacket)> {
+        if let Some(hold) = &self.hold {
+            let end = hold.expires.unwrap_or(self.last_submit);
+            self.release_hold(end);
+        }
+
+        let mut remaining = Vec::with_capacity(self.queue.len());
+        while let Some(Reverse(in_flight)) = self.queue.pop() {
+            remaining.push(self.release(in_flight));
+        }
+        remaining
+    }
+
+    fn release(&mut self, in_flight: InFlight) -> (Duration, EmulatedPacket) {
+        let delay = in_flight.release.saturating_sub(in_flight.arrived);
+        self.stats.packets_out += 1;
+        self.total_delay += delay;
+        self.stats.max_delay_ms = self.stats.max_delay_ms.max(delay.as_secs_f64() * 1000.0);
+
+        match self.highest_released {
+            Some(highest) if in_flight.index < highest => self.stats.reordered += 1,
+            _ => self.highest_released = Some(in_flight.index),
+        }
+
+        (in_flight.release, in_flight.packet)
+    }
+
+    pub fn stats(&self) -> EmulationStats {
+        let mut stats = self.stats;
+        if stats.packets_out > 0 {
+            stats.average_delay_ms = self.total_delay.as_secs_f64() * 1000.0 / stats.packets_out as f64;
+        }
+        stats
+    }
+}
+
+pub fn validate_hold(settings: &HoldSettings) -> Result<(), String> {
+    if !settings.upload && !settings.download {
+        return Err("A hold must apply to upload, download or both".to_string());
+    }
+    if settings.mode == HoldMode::Buffer && settings.max_buffer_bytes == 0 {
+        return Err("Hold buffer size must be greater than zero".to_string());
+    }
+    Ok(())
+}
+
+pub fn validate(profile: &EmulationProfile) -> Result<(), String> {
+    if profile.name.trim().is_empty() {
+        return Err("Emulation profile name cannot be empty".to_string());
+    }
+
+    if let EmulationTarget::Filter { remote_addr: Some(addr), .. } = &profile.target {
+        addr.parse::<IpAddr>().map_err(|_| format!("Invalid remote address '{}'", addr))?;
+    }
+
+    let check_percent = |name: &str, value: f64| {
+        if (0.0..=100.0).contains(&value) {
+            Ok(())
+        } else {
+            Err(format!("{} must be between 0 and 100", name))
+        }
+    };
+
+    for stage in &profile.stages {
+        match stage.kind {
+            EmulationStageKind::RandomDelay { min_ms, max_ms } if min_ms > max_ms => {
+                return Err("Random delay minimum cannot exceed its maximum".to_string());
+            }
+            EmulationStageKind::Drop { percent } => check_percent("Drop chance", percent)?,
+            EmulationStageKind::BurstLoss { enter_bad_percent, exit_bad_percent, good_loss_percent, bad_loss_percent } => {
+                check_percent("Burst loss entry chance", enter_bad_percent)?;
+                check_percent("Burst loss exit chance", exit_bad_percent)?;
+                check_percent("Good state loss", good_loss_percent)?;
+                check_percent("Bad state loss", bad_loss_percent)?;
+            }
+            EmulationStageKind::Duplicate { percent, copies } => {
+                check_percent("Duplicate chance", percent)?;
+                if copies == 0 || copies > MAX_DUPLICATE_COPIES {
+                    return Err(format!("Duplicate copies must be between 1 and {}", MAX_DUPLICATE_COPIES));
+                }
+            }
+            EmulationStageKind::Reorder { percent, .. } => check_percent("Reorder chance", percent)?,
+            EmulationStageKind::Bandwidth { bytes_per_second: 0, .. } => {
+                return Err("Bandwidth cap must be greater than zero".to_string());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// A capture carries no owning process, so during a replay process and
+// application targets apply to every packet and only filters narrow it down.
+fn replay_matches(target: &EmulationTarget, layout: &PacketLayout, direction: TrafficDirection) -> bool {
+    let EmulationTarget::Filter { protocol, remote_addr, remote_port, local_port } = target else {
+        return true;
+    };
+
+    let (local, remote) = match direction {
+        TrafficDirection::Upload => ((layout.src_addr, layout.src_port), (layout.dst_addr, layout.dst_port)),
+        TrafficDirection::Download => ((layout.dst_addr, layout.dst_port), (layout.src_addr, layout.src_port)),
+    };
+
+    protocol.is_none_or(|protocol| layout.protocol == Some(protocol))
+        && remote_addr.as_ref().is_none_or(|addr| addr.parse::<IpAddr>().ok() == Some(remote.0))
+        && remote_port.is_none_or(|port| port == remote.1)
+        && local_port.is_none_or(|port| port == local.1)
+}
+
+fn is_local(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => is_local_ipv4(&addr),
+        IpAddr::V6(addr) => is_local_ipv6(&addr),
+    }
+}
+
+// without a known local address, traffic from a private to a public address counts as upload
+pub fn direction_of(layout: &PacketLayout, local_addr: Option<IpAddr>) -> TrafficDirection {
+    let outbound = match local_addr {
+        Some(local) => layout.src_addr == local,
+        None => is_local(layout.src_addr) && !is_local(layout.dst_addr),
+    };
+
+    if outbound { TrafficDirection::Upload } else { TrafficDirection::Download }
+}
+
+// Plays a capture through the profile at its own timestamps and optionally
+// writes what would have come out of the other end. Hold windows are timed
+// from the first packet of the capture.
+pub fn replay(profile: &EmulationProfile, input: &Path, output: Option<&Path>, local_addr: Option<IpAddr>, holds: &[HoldWindow]) -> Result<ReplayReport, String> {
+    let capture = pcap::read(input)?;
+    let first = capture.packets.first().map(|packet| packet.timestamp).unwrap_or_default();
+    let mut holds: Vec<(Duration, HoldSettings)> = holds.iter()
+        .map(|window| (first + Duration::from_millis(window.start_ms), window.settings))
+        .collect();
+    holds.sort_by_key(|(start, _)| *start);
+    let mut holds = holds.into_iter().peekable();
+
+    let mut emulator = Emulator::new(profile);
+    let mut report = ReplayReport {
+        packets_read: capture.packets.len() as u64,
+        packets_matched: 0,
+        unparsed: 0,
+        stats: EmulationStats::default(),
+        output_path: output.map(|path| path.display().to_string()),
+    };
+
+    let released = |(timestamp, packet): (Duration, EmulatedPacket)| PcapPacket {
+        timestamp,
+        data: packet.data,
+        original_len: packet.original_len,
+    };
+    let mut emitted: Vec<PcapPacket> = Vec::with_capacity(capture.packets.len());
+    for captured in capture.packets {
+        while let Some((start, settings)) = holds.next_if(|(start, _)| *start <= captured.timestamp) {
+            emitted.extend(emulator.poll(start).into_iter().map(released));
+            emulator.hold(start, settings);
+        }
+        emitted.extend(emulator.poll(captured.timestamp).into_iter().map(released));
+
+        let Some(layout) = packet::parse(capture.link_type, &captured.data) else {
+            report.unparsed += 1;
+            emitted.push(captured);
+            continue;
+        };
+
+        let direction = direction_of(&layout, local_addr);
+        if !replay_matches(&profile.target, &layout, direction) {
+            emitted.push(captured);
+            continue;
+        }
+
+        report.packets_matched += 1;
+        emulator.submit(captured.timestamp, EmulatedPacket {
+            data: captured.data,
+            original_len: captured.original_len,
+            direction,
+        });
+    }
+
+    emitted.extend(emulator.flush().into_iter().map(released));
+    report.stats = emulator.stats();
+
+    if let Some(path) = output {
+        // stable, so packets released together keep their order
+        emitted.sort_by_key(|packet| packet.timestamp);
+        pcap::write(path, &PcapFile {
+            link_type: capture.link_type,
+            packets: emitted,
+        })?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::EmulationStage;
+
+    const LINKTYPE_IPV4: u32 = 228;
+
+    // an IPv4/UDP header pair from a private address to a public one
+    fn udp_header(port: u16) -> Vec<u8> {
+        let mut data = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0, 192, 168, 1, 2, 93, 184, 216, 34];
+        data.extend_from_slice(&port.to_be_bytes());
+        data.extend_from_slice(&[0, 53, 0, 8, 0, 0]);
+        data
+    }
+
+    fn profile(stages: Vec<EmulationStageKind>, seed: u64) -> EmulationProfile {
+        EmulationProfile {
+            id: "test".to_string(),
+            name: "test".to_string(),
+            target: EmulationTarget::Filter { protocol: None, remote_addr: None, remote_port: None, local_port: None },
+            stages: stages.into_iter().map(|kind| EmulationStage { kind, upload: true, download: true }).collect(),
+            seed,
+            enabled: true,
+        }
+    }
+
+    fn replay_to_file(name: &str, profile: &EmulationProfile, packets: Vec<PcapPacket>) -> (ReplayReport, PcapFile) {
+        let dir = std::env::temp_dir().join(format!("meridian-replay-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("in.pcap"), dir.join("out.pcap"));
+        pcap::write(&input, &PcapFile { link_type: LINKTYPE_IPV4, packets }).unwrap();
+
+        let report = replay(profile, &input, Some(&output), None, &[]).unwrap();
+        let written = pcap::read(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        (report, written)
+    }
+
+    #[test]
+    fn replay_keeps_wire_lengths_of_truncated_packets() {
+        // 28 captured bytes stand for 1000 on the wire, a second each at 1000 bytes/s
+        let packets = (0..3u16)
+            .map(|index| PcapPacket { timestamp: Duration::from_secs(100), data: udp_header(5000 + index), original_len: 1000 })
+            .chain([PcapPacket { timestamp: Duration::from_secs(100), data: vec![0x00; 4], original_len: 1200 }])
+            .collect();
+        let profile = profile(vec![EmulationStageKind::Bandwidth { bytes_per_second: 1000, queue_ms: 10_000 }], 0);
+
+        let (report, written) = replay_to_file("wire", &profile, packets);
+        assert_eq!((report.packets_matched, report.unparsed), (3, 1));
+        assert!(written.packets.iter().all(|packet| packet.original_len >= 1000));
+        let released: Vec<Duration> = written.packets.iter()
+            .filter(|packet| packet.data.len() > 4)
+            .map(|packet| packet.timestamp)
+            .collect();
+        assert_eq!(released, vec![Duration::from_secs(101), Duration::from_secs(102), Duration::from_secs(103)]);
+    }
+
+    #[test]
+    fn replays_with_one_seed_are_identical() {
+        let packets: Vec<PcapPacket> = (0..200u16)
+            .map(|index| PcapPacket {
+                timestamp: Duration::from_millis(1_000 + index as u64),
+                data: udp_header(index),
+                original_len: 28,
+            })
+            .collect();
+        let stages = || vec![
+            EmulationStageKind::Jitter { ms: 20 },
+            EmulationStageKind::Drop { percent: 10.0 },
+            EmulationStageKind::Duplicate { percent: 5.0, copies: 1 },
+        ];
+
+        let (first, first_out) = replay_to_file("seed-a", &profile(stages(), 9), packets.clone());
+        let (second, second_out) = replay_to_file("seed-b", &profile(stages(), 9), packets.clone());
+        let (_, other_out) = replay_to_file("seed-c", &profile(stages(), 10), packets);
+        let summary = |file: &PcapFile| file.packets.iter().map(|packet| (packet.timestamp, packet.data.clone())).collect::<Vec<_>>();
+
+        assert_eq!(first.stats.dropped, second.stats.dropped);
+        assert!(first.stats.dropped > 0);
+        assert_eq!(summary(&first_out), summary(&second_out));
+        assert_ne!(summary(&first_out), summary(&other_out));
+    }
+}
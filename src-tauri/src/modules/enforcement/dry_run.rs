@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use parking_lot::RwLock;
+use crate::models::{ActiveEnforcement, EnforcementAction, PriorityClass};
+use crate::modules::limiter::BandwidthLimit;
+use crate::log_info;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedAction {
+    Apply(u32, EnforcementAction),
+    Remove(u32),
+    LinkCapacity(Option<BandwidthLimit>),
+    Priority(u32, Option<PriorityClass>),
+}
+
+// Enforces nothing: keeps the active set and a log of every call so callers
+// can be checked without touching the system.
+pub struct DryRunBackend {
+    active: RwLock<BTreeMap<u32, EnforcementAction>>,
+    actions: RwLock<Vec<RecordedAction>>,
+}
+
+impl DryRunBackend {
+    pub fn new() -> Self {
+        Self {
+            active: RwLock::new(BTreeMap::new()),
+            actions: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub fn actions(&self) -> Vec<RecordedAction> {
+        self.actions.read().clone()
+    }
+
+    fn apply(&self, pid: u32, action: EnforcementAction) {
+        log_info!("[Dry run] {:?} for process {}", action, pid);
+        self.active.write().insert(pid, action.clone());
+        self.actions.write().push(RecordedAction::Apply(pid, action));
+    }
+}
+
+impl Default for DryRunBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::EnforcementBackend for DryRunBackend {
+    fn name(&self) -> &str {
+        "dry-run"
+    }
+
+    fn apply_limit(&self, pid: u32, limit: &BandwidthLimit) -> Result<(), String> {
+        if limit.blocked {
+            return self.block(pid);
+        }
+
+        self.apply(pid, EnforcementAction::Limit {
+            upload: limit.upload,
+            download: limit.download,
+        });
+        Ok(())
+    }
+
+    fn remove_limit(&self, pid: u32) -> Result<(), String> {
+        log_info!("[Dry run] removing enforcement for process {}", pid);
+        self.active.write().remove(&pid);
+        self.actions.write().push(RecordedAction::Remove(pid));
+        Ok(())
+    }
+
+    fn block(&self, pid: u32) -> Result<(), String> {
+        self.apply(pid, EnforcementAction::Block);
+        Ok(())
+    }
+
+    fn list_active(&self) -> Vec<ActiveEnforcement> {
+        self.active.read()
+            .iter()
+            .map(|(&pid, action)| ActiveEnforcement { pid, action: action.clone() })
+            .collect()
+    }
+
+    fn set_link_capacity(&self, capacity: Option<&BandwidthLimit>) -> Result<(), String> {
+        log_info!("[Dry run] link capacity {:?}", capacity);
+        self.actions.write().push(RecordedAction::LinkCapacity(capacity.copied()));
+        Ok(())
+    }
+
+    fn set_priority(&self, pid: u32, class: Option<PriorityClass>) -> Result<(), String> {
+        log_info!("[Dry run] priority {:?} for process {}", class, pid);
+        self.actions.write().push(RecordedAction::Priority(pid, class));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::enforcement::EnforcementBackend;
+
+    #[test]
+    fn records_every_call_in_order() {
+        let backend = DryRunBackend::new();
+        let limit = BandwidthLimit { upload: Some(1024), ..BandwidthLimit::default() };
+        backend.apply_limit(10, &limit).unwrap();
+        backend.block(11).unwrap();
+        backend.apply_limit(12, &BandwidthLimit { blocked: true, ..limit }).unwrap();
+        backend.remove_limit(10).unwrap();
+
+        assert_eq!(backend.actions(), vec![
+            RecordedAction::Apply(10, EnforcementAction::Limit { upload: Some(1024), download: None }),
+            RecordedAction::Apply(11, EnforcementAction::Block),
+            RecordedAction::Apply(12, EnforcementAction::Block),
+            RecordedAction::Remove(10),
+        ]);
+        let active: Vec<u32> = backend.list_active().iter().map(|active| active.pid).collect();
+        assert_eq!(active, vec![11, 12]);
+    }
+
+    #[test]
+    fn a_new_limit_replaces_the_old_one() {
+        let backend = DryRunBackend::new();
+        backend.block(5).unwrap();
+        backend.apply_limit(5, &BandwidthLimit { download: Some(2048), ..BandwidthLimit::default() }).unwrap();
+
+        let active = backend.list_active();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].action, EnforcementAction::Limit { upload: None, download: Some(2048) });
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use parking_lot::RwLock;
+use crate::models::{ActiveEnforcement, EnforcementAction, PriorityClass};
+use crate::modules::limiter::BandwidthLimit;
+use crate::{log_info, log_error};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const PROC_ROOT: &str = "/proc";
+const CGROUP_PARENT: &str = "meridian";
+const NFT_TABLE: &str = "meridian";
+// packet marks carry the tc class for upload shaping
+const MARK_BASE: u32 = 0x4d52_0000;
+const MAX_CLASSES: u16 = 0xfffd;
+// with a link capacity, everything is shaped below the link class, and
+// traffic of no enforced process goes to the default class in the normal share
+const LINK_CLASS: u16 = 0xffff;
+const DEFAULT_CLASS: u16 = 0xfffe;
+// tc and nft want a burst; keep it at a quarter second like the limiter
+const BURST_DIVISOR: u64 = 4;
+const MIN_BURST_BYTES: u64 = 16 * 1024;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    // tc minor class id, also the low bits of the packet mark
+    class_id: u16,
+    // an entry lives as long as it has a limit or block, a priority class, or both
+    action: Option<EnforcementAction>,
+    priority: Option<PriorityClass>,
+    // the group the process was in before, relative to the cgroup root
+    original_cgroup: String,
+}
+
+// Puts each enforced process in its own cgroup v2 group, then regenerates an
+// nftables table (blocks, download policing, upload marks) and an HTB tree on
+// the egress interface that shapes the marked upload traffic. With a link
+// capacity the tree splits the uplink by priority class; inbound traffic can
+// only be policed, so the download capacity is left to the limiter.
+pub struct LinuxBackend {
+    interface: String,
+    cgroup_root: PathBuf,
+    proc_root: PathBuf,
+    entries: RwLock<BTreeMap<u32, Entry>>,
+    // upload bytes per second, changed under the entries lock
+    link_capacity: RwLock<Option<u64>>,
+    // whether the root qdisc on the interface is ours to replace or delete
+    owns_qdisc: AtomicBool,
+}
+
+impl LinuxBackend {
+    pub fn detect() -> Result<Self, String> {
+        let cgroup_root = PathBuf::from(CGROUP_ROOT);
+        if !cgroup_root.join("cgroup.controllers").exists() {
+            return Err("cgroup v2 is not mounted".to_string());
+        }
+
+        let interface = default_interface().ok_or("No default route interface")?;
+        for (tool, version_flag) in [("tc", "-V"), ("nft", "--version")] {
+            run(tool, &[version_flag]).map_err(|e| format!("{} is not usable: {}", tool, e))?;
+        }
+
+        log_info!("Using Linux enforcement on interface {}", interface);
+        Ok(Self::with_roots(interface, cgroup_root, PathBuf::from(PROC_ROOT)))
+    }
+
+    fn with_roots(interface: String, cgroup_root: PathBuf, proc_root: PathBuf) -> Self {
+        Self {
+            interface,
+            cgroup_root,
+            proc_root,
+            entries: RwLock::new(BTreeMap::new()),
+            link_capacity: RwLock::new(None),
+            owns_qdisc: AtomicBool::new(false),
+        }
+    }
+
+    fn cgroup_name(pid: u32) -> String {
+        format!("{}/pid-{}", CGROUP_PARENT, pid)
+    }
+
+    // the unified hierarchy's line in /proc/<pid>/cgroup, e.g. "0::/user.slice/app.scope"
+    fn current_cgroup(&self, pid: u32) -> Result<String, String> {
+        let path = self.proc_root.join(pid.to_string()).join("cgroup");
+        let contents = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let group = contents.lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(|group| group.trim().trim_start_matches('/').to_string())
+            .ok_or_else(|| format!("Process {} is not in a cgroup v2 group", pid))?;
+        // left behind by an earlier run that didn't get to clean up
+        if group.starts_with(&format!("{}/", CGROUP_PARENT)) {
+            return Ok(String::new());
+        }
+        Ok(group)
+    }
+
+    // returns the group the process was in, so it can be put back
+    fn join_cgroup(&self, pid: u32) -> Result<String, String> {
+        let original = self.current_cgroup(pid)?;
+        let group = self.cgroup_root.join(Self::cgroup_name(pid));
+        fs::create_dir_all(&group).map_err(|e| format!("Failed to create cgroup {}: {}", group.display(), e))?;
+        write_pid(&group, pid)?;
+        Ok(original)
+    }
+
+    fn leave_cgroup(&self, pid: u32, original: &str) {
+        // the process may be gone already, in which case the group is empty
+        // anyway; if its old group is gone too, the root is the only place left
+        if write_pid(&self.cgroup_root.join(original), pid).is_err() {
+            let _ = write_pid(&self.cgroup_root, pid);
+        }
+        let _ = fs::remove_dir(self.cgroup_root.join(Self::cgroup_name(pid)));
+    }
+
+    fn set(&self, pid: u32, action: EnforcementAction) -> Result<(), String> {
+        self.change(pid, |entry| entry.action = Some(action))
+    }
+
+    // Changes what is enforced on one process. The process joins its group
+    // with its first entry and only goes back once the rules without it are
+    // in place; if they can not be, everything stays as it was.
+    fn change<F>(&self, pid: u32, change: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Entry)
+    {
+        let mut entries = self.entries.write();
+        let previous = entries.get(&pid).cloned();
+        let mut entry = match &previous {
+            Some(entry) => entry.clone(),
+            None => Entry {
+                class_id: next_class_id(&entries)?,
+                action: None,
+                priority: None,
+                original_cgroup: String::new(),
+            },
+        };
+        change(&mut entry);
+
+        let keep = entry.action.is_some() || entry.priority.is_some();
+        match (&previous, keep) {
+            (None, false) => return Ok(()),
+            (None, true) => entry.original_cgroup = self.join_cgroup(pid)?,
+            _ => {}
+        }
+        if keep {
+            entries.insert(pid, entry.clone());
+        } else {
+            entries.remove(&pid);
+        }
+
+        if let Err(e) = self.sync(&entries) {
+            match previous {
+                Some(previous) => {
+                    entries.insert(pid, previous);
+                }
+                None => {
+                    entries.remove(&pid);
+                    self.leave_cgroup(pid, &entry.original_cgroup);
+                }
+            }
+            self.restore(&entries);
+            return Err(e);
+        }
+        if !keep {
+            self.leave_cgroup(pid, &entry.original_cgroup);
+        }
+        Ok(())
+    }
+
+    // puts back what was in force before a failed sync, which may have got halfway
+    fn restore(&self, entries: &BTreeMap<u32, Entry>) {
+        if let Err(e) = self.sync(entries) {
+            log_error!("Failed to restore enforcement after an error: {}", e);
+        }
+    }
+
+    // Both configurations are rebuilt from scratch so they never drift from
+    // the entries. The root qdisc is only replaced or deleted once we added
+    // it; a qdisc someone else configured makes the add fail instead.
+    fn sync(&self, entries: &BTreeMap<u32, Entry>) -> Result<(), String> {
+        let link_capacity = *self.link_capacity.read();
+        run_with_input("nft", &["-f", "-"], &render_nftables(entries, link_capacity))?;
+
+        if self.owns_qdisc.load(Ordering::SeqCst) {
+            run("tc", &["qdisc", "del", "dev", &self.interface, "root"])?;
+            self.owns_qdisc.store(false, Ordering::SeqCst);
+        }
+        for (index, command) in render_tc(&self.interface, entries, link_capacity).iter().enumerate() {
+            let args: Vec<&str> = command.iter().map(String::as_str).collect();
+            run("tc", &args)?;
+            if index == 0 {
+                self.owns_qdisc.store(true, Ordering::SeqCst);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl super::EnforcementBackend for LinuxBackend {
+    fn name(&self) -> &str {
+        "linux-tc-nftables"
+    }
+
+    fn apply_limit(&self, pid: u32, limit: &BandwidthLimit) -> Result<(), String> {
+        if limit.blocked {
+            return self.block(pid);
+        }
+
+        self.set(pid, EnforcementAction::Limit {
+            upload: limit.upload,
+            download: limit.download,
+        })
+    }
+
+    fn remove_limit(&self, pid: u32) -> Result<(), String> {
+        self.change(pid, |entry| entry.action = None)
+    }
+
+    fn block(&self, pid: u32) -> Result<(), String> {
+        self.set(pid, EnforcementAction::Block)
+    }
+
+    fn list_active(&self) -> Vec<ActiveEnforcement> {
+        self.entries.read()
+            .iter()
+            .filter_map(|(&pid, entry)| Some(ActiveEnforcement { pid, action: entry.action.clone()? }))
+            .collect()
+    }
+
+    fn set_link_capacity(&self, capacity: Option<&BandwidthLimit>) -> Result<(), String> {
+        let entries = self.entries.write();
+        let upload = capacity.and_then(|capacity| capacity.upload);
+        let previous = std::mem::replace(&mut *self.link_capacity.write(), upload);
+        if let Err(e) = self.sync(&entries) {
+            *self.link_capacity.write() = previous;
+            self.restore(&entries);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn set_priority(&self, pid: u32, class: Option<PriorityClass>) -> Result<(), String> {
+        self.change(pid, |entry| entry.priority = class)
+    }
+}
+
+fn next_class_id(entries: &BTreeMap<u32, Entry>) -> Result<u16, String> {
+    (1..=MAX_CLASSES)
+        .find(|id| !entries.values().any(|entry| entry.class_id == *id))
+        .ok_or_else(|| "Too many enforced processes".to_string())
+}
+
+fn burst_bytes(rate: u64) -> u64 {
+    (rate / BURST_DIVISOR).max(MIN_BURST_BYTES)
+}
+
+fn upload_limit(entry: &Entry) -> Option<u64> {
+    match entry.action {
+        Some(EnforcementAction::Limit { upload, .. }) => upload,
+        _ => None,
+    }
+}
+
+// without a link capacity only upload limits need a class of their own
+fn is_shaped(entry: &Entry, link_capacity: Option<u64>) -> bool {
+    match entry.action {
+        Some(EnforcementAction::Block) => false,
+        _ => upload_limit(entry).is_some() || link_capacity.is_some(),
+    }
+}
+
+// `table` + `delete table` first makes the script replace the table whether or not it exists
+fn render_nftables(entries: &BTreeMap<u32, Entry>, link_capacity: Option<u64>) -> String {
+    let mut output = Vec::new();
+    let mut input = Vec::new();
+
+    for (&pid, entry) in entries {
+        let socket = format!("socket cgroupv2 level 2 \"{}\"", LinuxBackend::cgroup_name(pid));
+        if let Some(EnforcementAction::Block) = entry.action {
+            output.push(format!("{} drop", socket));
+            input.push(format!("{} drop", socket));
+            continue;
+        }
+        if is_shaped(entry, link_capacity) {
+            output.push(format!("{} meta mark set {:#x}", socket, MARK_BASE + entry.class_id as u32));
+        }
+        // inbound can only be policed here, there is no queue to hold it in
+        if let Some(EnforcementAction::Limit { download: Some(rate), .. }) = entry.action {
+            input.push(format!(
+                "{} limit rate over {} bytes/second burst {} bytes drop",
+                socket, rate, burst_bytes(rate)
+            ));
+        }
+    }
+
+    let chain = |name: &str, hook: &str, rules: &[String]| {
+        let mut lines = vec![
+            format!("    chain {} {{", name),
+            format!("        type filter hook {} priority 0; policy accept;", hook),
+        ];
+        lines.extend(rules.iter().map(|rule| format!("        {}", rule)));
+        lines.push("    }".to_string());
+        lines.join("\n")
+    };
+
+    format!(
+        "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n{}\n{}\n}}\n",
+        chain("output", "output", &output),
+        chain("input", "input", &input),
+        table = NFT_TABLE,
+    )
+}
+
+// Without a link capacity, unclassified traffic goes to the htb direct queue
+// and stays unshaped. With one, every class is guaranteed its weighted share
+// of the link and may borrow up to the link, or its own limit if lower.
+fn render_tc(interface: &str, entries: &BTreeMap<u32, Entry>, link_capacity: Option<u64>) -> Vec<Vec<String>> {
+    let shaped: Vec<(u16, Option<u64>, u32)> = entries.values()
+        .filter(|entry| is_shaped(entry, link_capacity))
+        .map(|entry| (entry.class_id, upload_limit(entry), entry.priority.unwrap_or_default().weight()))
+        .collect();
+
+    let args = |line: String| line.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+    let class = |parent: String, class_id: u16, rate: u64, ceil: u64| args(format!(
+        "class add dev {} parent {} classid 1:{:x} htb rate {}bps ceil {}bps burst {}",
+        interface, parent, class_id, rate, ceil, burst_bytes(ceil)
+    ));
+
+    // the default class counts as one more process in the normal class
+    let total_weight: u64 = shaped.iter().map(|&(_, _, weight)| weight as u64).sum::<u64>()
+        + PriorityClass::Normal.weight() as u64;
+    let share = |capacity: u64, weight: u32| (capacity * weight as u64 / total_weight).max(1);
+
+    let mut commands = match link_capacity {
+        None if shaped.is_empty() => return Vec::new(),
+        None => vec![args(format!("qdisc add dev {} root handle 1: htb", interface))],
+        Some(capacity) => vec![
+            args(format!("qdisc add dev {} root handle 1: htb default {:x}", interface, DEFAULT_CLASS)),
+            class("1:".to_string(), LINK_CLASS, capacity, capacity),
+            class(format!("1:{:x}", LINK_CLASS), DEFAULT_CLASS, share(capacity, PriorityClass::Normal.weight()), capacity),
+        ],
+    };
+
+    for &(class_id, limit, weight) in &shaped {
+        commands.push(match link_capacity {
+            Some(capacity) => {
+                let ceil = limit.map_or(capacity, |limit| limit.min(capacity));
+                class(format!("1:{:x}", LINK_CLASS), class_id, share(capacity, weight).min(ceil), ceil)
+            }
+            None => {
+                let rate = limit.unwrap_or_default();
+                class("1:".to_string(), class_id, rate, rate)
+            }
+        });
+        commands.push(args(format!(
+            "filter add dev {} parent 1: protocol all prio 1 handle {:#x} fw classid 1:{:x}",
+            interface, MARK_BASE + class_id as u32, class_id
+        )));
+    }
+
+    commands
+}
+
+fn write_pid(group: &Path, pid: u32) -> Result<(), String> {
+    fs::write(group.join("cgroup.procs"), pid.to_string())
+        .map_err(|e| format!("Failed to move process {} into {}: {}", pid, group.display(), e))
+}
+
+// the interface of the default IPv4 route
+fn default_interface() -> Option<String> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(1) == Some(&"00000000"))
+        .and_then(|fields| fields.first().map(|name| name.to_string()))
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+fn run_with_input(program: &str, args: &[&str], input: &str) -> Result<(), String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).map_err(|e| format!("Failed to write to {}: {}", program, e))?;
+    }
+
+    let output = child.wait_with_output().map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::enforcement::EnforcementBackend;
+
+    fn entries(actions: Vec<(u32, EnforcementAction)>) -> BTreeMap<u32, Entry> {
+        actions.into_iter()
+            .enumerate()
+            .map(|(index, (pid, action))| (pid, Entry {
+                class_id: index as u16 + 1,
+                action: Some(action),
+                priority: None,
+                original_cgroup: String::new(),
+            }))
+            .collect()
+    }
+
+    #[test]
+    fn nftables_marks_uploads_and_polices_downloads() {
+        let script = render_nftables(&entries(vec![
+            (100, EnforcementAction::Limit { upload: Some(100_000), download: Some(200_000) }),
+            (200, EnforcementAction::Block),
+        ]), None);
+
+        assert!(script.starts_with("table inet meridian\ndelete table inet meridian\n"));
+        assert!(script.contains("socket cgroupv2 level 2 \"meridian/pid-100\" meta mark set 0x4d520001"));
+        assert!(script.contains("socket cgroupv2 level 2 \"meridian/pid-100\" limit rate over 200000 bytes/second burst 50000 bytes drop"));
+        assert_eq!(script.matches("\"meridian/pid-200\" drop").count(), 2);
+    }
+
+    #[test]
+    fn tc_shapes_only_upload_limits() {
+        assert!(render_tc("eth0", &entries(vec![(1, EnforcementAction::Block)]), None).is_empty());
+
+        let commands: Vec<String> = render_tc("eth0", &entries(vec![
+            (1, EnforcementAction::Limit { upload: None, download: Some(1) }),
+            (2, EnforcementAction::Limit { upload: Some(1_000), download: None }),
+        ]), None).iter().map(|command| command.join(" ")).collect();
+        assert_eq!(commands, vec![
+            "qdisc add dev eth0 root handle 1: htb",
+            "class add dev eth0 parent 1: classid 1:2 htb rate 1000bps ceil 1000bps burst 16384",
+            "filter add dev eth0 parent 1: protocol all prio 1 handle 0x4d520002 fw classid 1:2",
+        ]);
+    }
+
+    #[test]
+    fn tc_splits_the_link_capacity_by_priority_class() {
+        let mut entries = entries(vec![
+            (1, EnforcementAction::Limit { upload: Some(1_000), download: None }),
+            (2, EnforcementAction::Block),
+        ]);
+        entries.get_mut(&1).unwrap().priority = Some(PriorityClass::High);
+        entries.insert(3, Entry { class_id: 3, action: None, priority: Some(PriorityClass::Background), original_cgroup: String::new() });
+
+        let script = render_nftables(&entries, Some(13_000));
+        assert!(script.contains("\"meridian/pid-3\" meta mark set 0x4d520003"));
+        assert!(!script.contains("\"meridian/pid-2\" meta mark"));
+
+        // weights 8 and 1, plus 4 for everything else
+        let commands: Vec<String> = render_tc("eth0", &entries, Some(13_000)).iter().map(|command| command.join(" ")).collect();
+        assert_eq!(commands, vec![
+            "qdisc add dev eth0 root handle 1: htb default fffe",
+            "class add dev eth0 parent 1: classid 1:ffff htb rate 13000bps ceil 13000bps burst 16384",
+            "class add dev eth0 parent 1:ffff classid 1:fffe htb rate 4000bps ceil 13000bps burst 16384",
+            "class add dev eth0 parent 1:ffff classid 1:1 htb rate 1000bps ceil 1000bps burst 16384",
+            "filter add dev eth0 parent 1: protocol all prio 1 handle 0x4d520001 fw classid 1:1",
+            "class add dev eth0 parent 1:ffff classid 1:3 htb rate 1000bps ceil 13000bps burst 16384",
+            "filter add dev eth0 parent 1: protocol all prio 1 handle 0x4d520003 fw classid 1:3",
+        ]);
+    }
+
+    #[test]
+    fn leaving_puts_the_process_back_in_its_group() {
+        let root = std::env::temp_dir().join(format!("meridian-cgroup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (cgroups, proc) = (root.join("cgroup"), root.join("proc"));
+        fs::create_dir_all(cgroups.join("user.slice/app.scope")).unwrap();
+        fs::create_dir_all(proc.join("42")).unwrap();
+        fs::write(proc.join("42/cgroup"), "0::/user.slice/app.scope\n").unwrap();
+        let backend = LinuxBackend::with_roots("eth0".to_string(), cgroups.clone(), proc.clone());
+
+        let original = backend.join_cgroup(42).unwrap();
+        assert_eq!(original, "user.slice/app.scope");
+        assert_eq!(fs::read_to_string(cgroups.join("meridian/pid-42/cgroup.procs")).unwrap(), "42");
+
+        // a real cgroup directory can be removed once empty; here the file keeps it
+        backend.leave_cgroup(42, &original);
+        assert_eq!(fs::read_to_string(cgroups.join("user.slice/app.scope/cgroup.procs")).unwrap(), "42");
+        assert!(!cgroups.join("cgroup.procs").exists());
+
+        // a group from an earlier run is not taken for the original one
+        fs::write(proc.join("42/cgroup"), "0::/meridian/pid-42\n").unwrap();
+        assert_eq!(backend.current_cgroup(42).unwrap(), "");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn failed_joins_leave_nothing_behind() {
+        let root = std::env::temp_dir().join(format!("meridian-cgroup-missing-{}", std::process::id()));
+        let backend = LinuxBackend::with_roots("eth0".to_string(), root.join("cgroup"), root.join("proc"));
+        assert!(backend.set(7, EnforcementAction::Block).is_err());
+        assert!(backend.list_active().is_empty());
+        assert!(!root.exists());
+    }
+}
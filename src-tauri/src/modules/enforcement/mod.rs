@@ -0,0 +1,46 @@
+mod dry_run;
+#[cfg(target_os = "linux")]
+mod linux;
+
+use crate::models::{ActiveEnforcement, PriorityClass};
+use crate::modules::limiter::BandwidthLimit;
+use crate::log_info;
+
+pub use dry_run::DryRunBackend;
+#[cfg(target_os = "linux")]
+pub use linux::LinuxBackend;
+
+// Something that can actually hold or drop a process's packets. Limits are the
+// same ones the limiter works with; a backend that shapes in the kernel only
+// needs the rates, one that sees packets itself can ask the limiter instead.
+pub trait EnforcementBackend: Send + Sync {
+    fn name(&self) -> &str;
+    fn apply_limit(&self, pid: u32, limit: &BandwidthLimit) -> Result<(), String>;
+    fn remove_limit(&self, pid: u32) -> Result<(), String>;
+    fn block(&self, pid: u32) -> Result<(), String>;
+    fn list_active(&self) -> Vec<ActiveEnforcement>;
+
+    // Below the link capacity processes share the link by the weight of
+    // their priority class. Backends that cannot shape by class leave both to
+    // the limiter.
+    fn set_link_capacity(&self, _capacity: Option<&BandwidthLimit>) -> Result<(), String> {
+        Ok(())
+    }
+    fn set_priority(&self, _pid: u32, _class: Option<PriorityClass>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// platforms without a real backend only record what they would have done
+pub fn default_backend() -> Box<dyn EnforcementBackend> {
+    #[cfg(target_os = "linux")]
+    {
+        match LinuxBackend::detect() {
+            Ok(backend) => return Box::new(backend),
+            Err(e) => log_info!("Linux enforcement unavailable ({}), using dry run", e),
+        }
+    }
+
+    log_info!("No enforcement backend for this platform, using dry run");
+    Box::new(DryRunBackend::new())
+}
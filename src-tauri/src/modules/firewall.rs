@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+use crate::models::{
+    DecisionAnswer, DecisionResolved, DecisionScope, FirewallSettings, PendingDecision, PortRange, Rule, RuleAction, RuleConditions,
+    TransportProtocol,
+};
+use crate::modules::rules::{self, ProcessIdentity};
+use crate::modules::{host_labels, settings};
+use crate::utils::events;
+use crate::SYSTEM_MONITOR;
+use crate::{log_info, log_error};
+
+pub const PENDING_EVENT: &str = "firewall-pending";
+pub const RESOLVED_EVENT: &str = "firewall-resolved";
+
+struct Waiting {
+    decision: PendingDecision,
+    process: ProcessIdentity,
+    remote: SocketAddr,
+    reply: oneshot::Sender<bool>,
+}
+
+// held connections by id, so the oldest of an app is always announced first
+static WAITING: Lazy<Mutex<BTreeMap<u64, Waiting>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+// answers not saved as rules, by lowercased app path; they last until restart
+static ANSWERED: Lazy<RwLock<HashMap<String, bool>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+// connections blocked by hand, by pid and remote endpoint
+static BLOCKED: Lazy<RwLock<HashSet<(u32, SocketAddr)>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+// numbers the rules saved within one millisecond apart
+static NEXT_RULE: AtomicU64 = AtomicU64::new(1);
+
+fn app_key(path: &str) -> String {
+    path.to_lowercase()
+}
+
+pub fn is_connection_blocked(pid: u32, remote: SocketAddr) -> bool {
+    BLOCKED.read().contains(&(pid, remote))
+}
+
+// Whether a new outbound connection may go ahead. Connections a rule already
+// decided are never asked about, nor are apps answered for since startup. In
+// ask mode the first connection of any other app is announced and held;
+// further ones of the same app wait quietly for the same answer.
+pub async fn admit(process: &ProcessIdentity, remote: SocketAddr, hostname: Option<String>, decided: bool) -> bool {
+    admit_with(settings::firewall(), process, remote, hostname, decided).await
+}
+
+async fn admit_with(firewall: FirewallSettings, process: &ProcessIdentity, remote: SocketAddr, hostname: Option<String>, decided: bool) -> bool {
+    if is_connection_blocked(process.pid, remote) {
+        return false;
+    }
+    if !firewall.ask_mode || decided || process.path.is_empty() {
+        return true;
+    }
+    let key = app_key(&process.path);
+    if let Some(&allowed) = ANSWERED.read().get(&key) {
+        return allowed;
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let decision = PendingDecision {
+        id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+        pid: process.pid,
+        app_name: process.name.clone(),
+        app_path: process.path.clone(),
+        remote_addr: remote.ip().to_string(),
+        remote_port: remote.port(),
+        hostname,
+        protocol: TransportProtocol::Tcp,
+        requested_at: now,
+        expires_at: now + firewall.decision_timeout_ms as i64,
+    };
+    let id = decision.id;
+
+    let (reply, mut answer) = oneshot::channel();
+    let announce = {
+        let mut waiting = WAITING.lock();
+        let first = !waiting.values().any(|entry| app_key(&entry.decision.app_path) == key);
+        waiting.insert(id, Waiting {
+            decision: decision.clone(),
+            process: process.clone(),
+            remote,
+            reply,
+        });
+        first
+    };
+    if announce {
+        log_info!("Asking about {} connecting to {}", decision.app_path, remote);
+        events::emit(PENDING_EVENT, decision);
+    }
+
+    match timeout(Duration::from_millis(firewall.decision_timeout_ms), &mut answer).await {
+        Ok(Ok(allowed)) => allowed,
+        _ => {
+            // an answer that raced the timeout wins over it, and already reported itself
+            if let Ok(allowed) = answer.try_recv() {
+                return allowed;
+            }
+            let (expired, next) = {
+                let mut waiting = WAITING.lock();
+                (waiting.remove(&id).is_some(), next_for(&waiting, &key))
+            };
+            if !expired {
+                // the answer took the connection but had not sent it yet
+                return answer.await.unwrap_or(firewall.allow_on_timeout);
+            }
+
+            log_info!("No answer about {} in time, {}", process.path, if firewall.allow_on_timeout { "allowing" } else { "blocking" });
+            events::emit(RESOLVED_EVENT, DecisionResolved {
+                id,
+                allowed: firewall.allow_on_timeout,
+                timed_out: true,
+                rule_id: None,
+            });
+            if let Some(next) = next {
+                events::emit(PENDING_EVENT, next);
+            }
+            firewall.allow_on_timeout
+        }
+    }
+}
+
+fn next_for(waiting: &BTreeMap<u64, Waiting>, key: &str) -> Option<PendingDecision> {
+    waiting.values()
+        .find(|entry| app_key(&entry.decision.app_path) == key)
+        .map(|entry| entry.decision.clone())
+}
+
+pub fn pending() -> Vec<PendingDecision> {
+    WAITING.lock().values().map(|entry| entry.decision.clone()).collect()
+}
+
+fn answer_rule(process: &ProcessIdentity, remote: SocketAddr, hostname: Option<&str>, allow: bool, scope: DecisionScope) -> Rule {
+    let verb = if allow { "Allow" } else { "Block" };
+    let (name, conditions) = match scope {
+        DecisionScope::App => (
+            format!("{} {}", verb, process.name),
+            RuleConditions {
+                path: Some(process.path.clone()),
+                ..RuleConditions::default()
+            },
+        ),
+        DecisionScope::Connection => {
+            // a hostname survives the address changing, an address is all there is otherwise
+            let (target, remote_hosts, remote_cidrs) = match hostname {
+                Some(hostname) => (hostname.to_string(), vec![hostname.to_string()], Vec::new()),
+                None => (remote.ip().to_string(), Vec::new(), vec![remote.ip().to_string()]),
+            };
+            (
+                format!("{} {} to {}:{}", verb, process.name, target, remote.port()),
+                RuleConditions {
+                    path: Some(process.path.clone()),
+                    protocol: Some(TransportProtocol::Tcp),
+                    remote_ports: vec![PortRange { start: remote.port(), end: remote.port() }],
+                    remote_hosts,
+                    remote_cidrs,
+                    ..RuleConditions::default()
+                },
+            )
+        }
+    };
+
+    Rule {
+        id: format!("rule-{}-{}", Utc::now().timestamp_millis(), NEXT_RULE.fetch_add(1, Ordering::SeqCst)),
+        name,
+        enabled: true,
+        conditions,
+        action: if allow { RuleAction::Allow } else { RuleAction::Block },
+        schedule: None,
+        apply_to_descendants: false,
+    }
+}
+
+// appended, since no rule ahead of it matched or nobody would have been asked
+fn save_rule(rule: Rule) -> Result<(), String> {
+    log_info!("Saving firewall rule {} ({})", rule.name, rule.id);
+    settings::update(move |settings| settings.rules.push(rule))?;
+    rules::reload();
+    if let Err(e) = rules::reconcile(&SYSTEM_MONITOR.get_processes()) {
+        log_error!("{}", e);
+    }
+    Ok(())
+}
+
+// Releases the held connection, and with an app-wide answer every other one
+// of the same app. Connection answers also cover others held for the same
+// endpoint. Whatever of the app is still held afterwards is announced next.
+pub fn answer(id: u64, answer: DecisionAnswer) -> Result<DecisionResolved, String> {
+    let (answered, released, next) = {
+        let mut waiting = WAITING.lock();
+        let answered = waiting.remove(&id).ok_or_else(|| format!("No pending decision with id {}", id))?;
+        let key = app_key(&answered.decision.app_path);
+        let covered: Vec<u64> = waiting.iter()
+            .filter(|(_, entry)| app_key(&entry.decision.app_path) == key)
+            .filter(|(_, entry)| answer.scope == DecisionScope::App || entry.remote == answered.remote)
+            .map(|(&id, _)| id)
+            .collect();
+        let released: Vec<Waiting> = covered.iter().filter_map(|id| waiting.remove(id)).collect();
+
+        if answer.scope == DecisionScope::App && !answer.remember {
+            ANSWERED.write().insert(key.clone(), answer.allow);
+        }
+        let next = next_for(&waiting, &key);
+        (answered, released, next)
+    };
+
+    let rule = answer.remember.then(|| {
+        answer_rule(&answered.process, answered.remote, answered.decision.hostname.as_deref(), answer.allow, answer.scope)
+    });
+    let rule_id = rule.as_ref().map(|rule| rule.id.clone());
+    let saved = rule.map(save_rule).unwrap_or(Ok(()));
+
+    for entry in std::iter::once(answered).chain(released) {
+        let resolved = DecisionResolved {
+            id: entry.decision.id,
+            allowed: answer.allow,
+            timed_out: false,
+            rule_id: rule_id.clone(),
+        };
+        let _ = entry.reply.send(answer.allow);
+        events::emit(RESOLVED_EVENT, resolved);
+    }
+    if let Some(next) = next {
+        events::emit(PENDING_EVENT, next);
+    }
+
+    saved.map(|_| DecisionResolved {
+        id,
+        allowed: answer.allow,
+        timed_out: false,
+        rule_id,
+    })
+}
+
+// Blocks one remote endpoint for one process, cutting live relayed
+// connections to it as well. Remembered blocks become a rule for the app.
+pub fn block_connection(process: &ProcessIdentity, remote: SocketAddr, remember: bool) -> Result<Option<Rule>, String> {
+    log_info!("Blocking connections of process {} to {}", process.pid, remote);
+    BLOCKED.write().insert((process.pid, remote));
+    if !remember || process.path.is_empty() {
+        return Ok(None);
+    }
+
+    let hostname = host_labels::hostname(remote.ip());
+    let rule = answer_rule(process, remote, hostname.as_deref(), false, DecisionScope::Connection);
+    save_rule(rule.clone())?;
+    Ok(Some(rule))
+}
+
+pub fn unblock_connection(pid: u32, remote: SocketAddr) -> bool {
+    BLOCKED.write().remove(&(pid, remote))
+}
+
+// Forgets answers that were not saved as rules, so those apps are asked
+// about again.
+pub fn reset_answers() {
+    ANSWERED.write().clear();
+}
+
+pub fn forget_exited(running: &HashSet<u32>) {
+    BLOCKED.write().retain(|(pid, _)| running.contains(pid));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, path: &str) -> ProcessIdentity {
+        ProcessIdentity {
+            pid,
+            name: path.rsplit('\\').next().unwrap_or_default().to_string(),
+            path: path.to_string(),
+            parent_pid: None,
+            ancestors: Vec::new(),
+        }
+    }
+
+    fn asking(decision_timeout_ms: u64, allow_on_timeout: bool) -> FirewallSettings {
+        FirewallSettings { ask_mode: true, decision_timeout_ms, allow_on_timeout }
+    }
+
+    fn spawn_admit(firewall: FirewallSettings, process: &ProcessIdentity, remote: &str) -> tokio::task::JoinHandle<bool> {
+        let process = process.clone();
+        let remote: SocketAddr = remote.parse().unwrap();
+        tokio::spawn(async move { admit_with(firewall, &process, remote, None, false).await })
+    }
+
+    // waits until `count` connections of the app are held, oldest first
+    async fn held(path: &str, count: usize) -> Vec<PendingDecision> {
+        loop {
+            let held: Vec<PendingDecision> = pending().into_iter().filter(|decision| decision.app_path == path).collect();
+            if held.len() >= count {
+                return held;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn unanswered_connections_get_the_default() {
+        let app = process(101, "C:\\Apps\\timeout.exe");
+        let remote: SocketAddr = "1.2.3.4:443".parse().unwrap();
+        assert!(admit_with(asking(20, true), &app, remote, None, false).await);
+        assert!(!admit_with(asking(20, false), &app, remote, None, false).await);
+        assert!(held(&app.path, 0).await.is_empty());
+
+        // decided by a rule, or with ask mode off, nobody is asked
+        assert!(admit_with(asking(20, false), &app, remote, None, true).await);
+        assert!(admit_with(FirewallSettings { ask_mode: false, ..asking(20, false) }, &app, remote, None, false).await);
+    }
+
+    #[tokio::test]
+    async fn an_answer_racing_the_timeout_wins() {
+        let app = process(102, "C:\\Apps\\race.exe");
+        let admitted = spawn_admit(asking(50, false), &app, "1.2.3.4:443");
+        let id = held(&app.path, 1).await[0].id;
+
+        // the answer took the connection, then the timeout fired before it was sent
+        let entry = WAITING.lock().remove(&id).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        entry.reply.send(true).unwrap();
+        assert!(admitted.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connections_of_an_app_queue_behind_the_first() {
+        let app = process(103, "C:\\Apps\\queue.exe");
+        let first = spawn_admit(asking(10_000, false), &app, "1.2.3.4:443");
+        held(&app.path, 1).await;
+        let second = spawn_admit(asking(10_000, false), &app, "5.6.7.8:443");
+        let queued = held(&app.path, 2).await;
+
+        // an answer for one endpoint leaves the other held
+        let resolved = answer(queued[0].id, DecisionAnswer { allow: true, scope: DecisionScope::Connection, remember: false }).unwrap();
+        assert!(resolved.allowed && resolved.rule_id.is_none());
+        assert!(first.await.unwrap());
+        assert_eq!(held(&app.path, 1).await.iter().map(|decision| decision.id).collect::<Vec<_>>(), vec![queued[1].id]);
+
+        // an app-wide answer holds for later connections too
+        answer(queued[1].id, DecisionAnswer { allow: false, scope: DecisionScope::App, remember: false }).unwrap();
+        assert!(!second.await.unwrap());
+        assert!(!admit_with(asking(10_000, true), &app, "9.9.9.9:80".parse().unwrap(), None, false).await);
+        assert!(answer(queued[1].id, DecisionAnswer { allow: true, scope: DecisionScope::App, remember: false }).is_err());
+    }
+
+    #[test]
+    fn remembered_answers_become_rules() {
+        let app = process(104, "C:\\Apps\\remember.exe");
+        let remote: SocketAddr = "1.2.3.4:443".parse().unwrap();
+
+        let whole_app = answer_rule(&app, remote, None, true, DecisionScope::App);
+        assert_eq!(whole_app.action, RuleAction::Allow);
+        assert_eq!(whole_app.conditions, RuleConditions { path: Some(app.path.clone()), ..RuleConditions::default() });
+
+        // a hostname is kept over the address it resolved to
+        let named = answer_rule(&app, remote, Some("cdn.example.com"), false, DecisionScope::Connection);
+        assert_eq!(named.action, RuleAction::Block);
+        assert_eq!(named.conditions.remote_hosts, vec!["cdn.example.com".to_string()]);
+        assert!(named.conditions.remote_cidrs.is_empty());
+        assert_eq!(named.conditions.remote_ports, vec![PortRange { start: 443, end: 443 }]);
+        let unnamed = answer_rule(&app, remote, None, false, DecisionScope::Connection);
+        assert_eq!(unnamed.conditions.remote_cidrs, vec!["1.2.3.4".to_string()]);
+
+        // rules saved within the same millisecond still get their own ids
+        assert_ne!(named.id, unnamed.id);
+        assert_ne!(whole_app.id, named.id);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+use crate::models::{HoldMode, HoldRelease, HoldSettings, HoldStatus, TrafficDirection};
+use crate::{log_info, RUNTIME};
+
+struct ActiveHold {
+    // a restarted hold gets a new id so the old timeout leaves it alone
+    id: u64,
+    status: HoldStatus,
+    // tells everything waiting on this hold how it ended
+    released: watch::Sender<Option<HoldRelease>>,
+}
+
+impl ActiveHold {
+    fn applies(&self, direction: TrafficDirection) -> bool {
+        match direction {
+            TrafficDirection::Upload => self.status.settings.upload,
+            TrafficDirection::Download => self.status.settings.download,
+        }
+    }
+}
+
+static HOLDS: Lazy<RwLock<HashMap<u32, ActiveHold>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// starting a hold on a process that is already held keeps its counters and
+// waiting traffic, and only replaces the settings and the timeout
+pub fn start(pid: u32, settings: HoldSettings) -> HoldStatus {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    let now = Utc::now().timestamp_millis();
+    let expires_at = (settings.timeout_ms > 0).then(|| now + settings.timeout_ms as i64);
+
+    let status = {
+        let mut holds = HOLDS.write();
+        let hold = holds.entry(pid).or_insert_with(|| ActiveHold {
+            id,
+            status: HoldStatus {
+                pid,
+                settings,
+                started_at: now,
+                expires_at,
+                held_packets: 0,
+                held_bytes: 0,
+                dropped_packets: 0,
+                dropped_bytes: 0,
+            },
+            released: watch::channel(None).0,
+        });
+        hold.id = id;
+        hold.status.settings = settings;
+        hold.status.expires_at = expires_at;
+        hold.status.clone()
+    };
+
+    log_info!("Holding traffic of process {} ({:?})", pid, settings);
+    if settings.timeout_ms > 0 {
+        RUNTIME.spawn(async move {
+            sleep(Duration::from_millis(settings.timeout_ms)).await;
+            if HOLDS.read().get(&pid).is_some_and(|hold| hold.id == id) {
+                release(pid);
+            }
+        });
+    }
+
+    status
+}
+
+pub fn release(pid: u32) -> Option<HoldStatus> {
+    let hold = HOLDS.write().remove(&pid)?;
+    log_info!(
+        "Releasing hold on process {}: {} packets held, {} dropped, {:?}",
+        pid, hold.status.held_packets, hold.status.dropped_packets, hold.status.settings.release
+    );
+    hold.released.send_replace(Some(hold.status.settings.release));
+    Some(hold.status)
+}
+
+pub fn is_held(pid: u32) -> bool {
+    HOLDS.read().contains_key(&pid)
+}
+
+// returns the new status when the process is now held, None when it was released
+pub fn toggle(pid: u32, settings: HoldSettings) -> Option<HoldStatus> {
+    if HOLDS.read().contains_key(&pid) {
+        release(pid);
+        None
+    } else {
+        Some(start(pid, settings))
+    }
+}
+
+pub fn status() -> Vec<HoldStatus> {
+    let mut statuses: Vec<HoldStatus> = HOLDS.read().values()
+        .map(|hold| hold.status.clone())
+        .collect();
+    statuses.sort_by_key(|status| status.pid);
+    statuses
+}
+
+// Waits out a hold for traffic that cannot be reordered or reinjected later,
+// such as a proxied stream. Returns false when the data must not be sent:
+// right away when it arrived in drop mode or overflowed the buffer, and once
+// the hold ends when it was discarded.
+pub async fn wait(pid: u32, direction: TrafficDirection, length: u64) -> bool {
+    let mut released = {
+        let mut holds = HOLDS.write();
+        let Some(hold) = holds.get_mut(&pid).filter(|hold| hold.applies(direction)) else {
+            return true;
+        };
+
+        let status = &mut hold.status;
+        let buffered = status.settings.mode == HoldMode::Buffer
+            && status.held_bytes + length <= status.settings.max_buffer_bytes;
+        if buffered {
+            status.held_packets += 1;
+            status.held_bytes += length;
+        } else {
+            status.dropped_packets += 1;
+            status.dropped_bytes += length;
+        }
+        if !buffered {
+            return false;
+        }
+        hold.released.subscribe()
+    };
+
+    let outcome = released.wait_for(Option::is_some).await
+        .map(|outcome| *outcome)
+        .unwrap_or(Some(HoldRelease::Flush));
+    outcome == Some(HoldRelease::Flush)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: HoldMode, release: HoldRelease, max_buffer_bytes: u64) -> HoldSettings {
+        HoldSettings { upload: true, download: false, mode, release, timeout_ms: 0, max_buffer_bytes }
+    }
+
+    async fn released_after(pid: u32, wait_for: Duration) -> Option<HoldStatus> {
+        sleep(wait_for).await;
+        release(pid)
+    }
+
+    #[tokio::test]
+    async fn drop_mode_fails_without_waiting() {
+        start(9_001, settings(HoldMode::Drop, HoldRelease::Flush, 1024));
+        let waited = tokio::time::timeout(Duration::from_secs(1), wait(9_001, TrafficDirection::Upload, 100)).await;
+        assert_eq!(waited, Ok(false));
+        assert_eq!(release(9_001).map(|status| status.dropped_bytes), Some(100));
+    }
+
+    #[tokio::test]
+    async fn buffered_data_waits_for_the_release() {
+        start(9_002, settings(HoldMode::Buffer, HoldRelease::Flush, 1024));
+        // directions the hold doesn't cover pass straight through
+        assert!(wait(9_002, TrafficDirection::Download, 100).await);
+
+        let (sent, status) = tokio::join!(wait(9_002, TrafficDirection::Upload, 100), released_after(9_002, Duration::from_millis(50)));
+        assert!(sent);
+        assert_eq!(status.map(|status| (status.held_packets, status.held_bytes)), Some((1, 100)));
+        assert!(!is_held(9_002));
+    }
+
+    #[tokio::test]
+    async fn overflow_fails_at_once_and_discard_after_release() {
+        start(9_003, settings(HoldMode::Buffer, HoldRelease::Discard, 150));
+        let first = tokio::spawn(wait(9_003, TrafficDirection::Upload, 100));
+        sleep(Duration::from_millis(20)).await;
+
+        let second = tokio::time::timeout(Duration::from_millis(20), wait(9_003, TrafficDirection::Upload, 100)).await;
+        assert_eq!(second, Ok(false));
+        assert!(is_held(9_003));
+
+        release(9_003);
+        assert!(!first.await.unwrap());
+    }
+
+    #[test]
+    fn toggle_starts_then_releases() {
+        let hold = settings(HoldMode::Buffer, HoldRelease::Flush, 1024);
+        assert!(toggle(9_004, hold).is_some());
+        assert!(is_held(9_004));
+        assert!(toggle(9_004, hold).is_none());
+        assert!(!is_held(9_004));
+    }
+}
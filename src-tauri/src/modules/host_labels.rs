@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use crate::models::TransportProtocol;
+use crate::modules::packet::PacketLayout;
+
+const DNS_PORT: u16 = 53;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+// compression pointers followed before a name is given up on
+const MAX_NAME_JUMPS: usize = 16;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_SERVER_NAME: u16 = 0;
+
+// answers are kept at least this long, whatever their TTL says, since apps
+// keep using addresses well past it
+const MIN_LABEL_TTL: Duration = Duration::from_secs(300);
+const MAX_LABEL_TTL: Duration = Duration::from_secs(6 * 3600);
+const MAX_LABELS: usize = 16_384;
+
+struct Label {
+    hostname: String,
+    expires: Instant,
+}
+
+// remote address to the name it was looked up or connected by
+static LABELS: Lazy<RwLock<HashMap<IpAddr, Label>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub fn record(addr: IpAddr, hostname: &str, ttl: Duration) {
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    if hostname.is_empty() {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut labels = LABELS.write();
+    if labels.len() >= MAX_LABELS {
+        labels.retain(|_, label| label.expires > now);
+        if labels.len() >= MAX_LABELS {
+            labels.clear();
+        }
+    }
+    labels.insert(addr, Label {
+        hostname,
+        expires: now + ttl.clamp(MIN_LABEL_TTL, MAX_LABEL_TTL),
+    });
+}
+
+pub fn hostname(addr: IpAddr) -> Option<String> {
+    LABELS.read().get(&addr)
+        .filter(|label| label.expires > Instant::now())
+        .map(|label| label.hostname.clone())
+}
+
+// Picks names out of captured traffic: answers to DNS lookups, and the server
+// name a TLS client asks for. The SNI wins for its address since it names the
+// connection itself rather than whatever lookup happened to return it.
+pub fn observe(layout: &PacketLayout, payload: &[u8]) {
+    for (addr, hostname, ttl) in extract(layout, payload) {
+        record(addr, &hostname, ttl);
+    }
+}
+
+// the names one packet ties to addresses, without recording them
+pub fn extract(layout: &PacketLayout, payload: &[u8]) -> Vec<(IpAddr, String, Duration)> {
+    match layout.protocol {
+        Some(TransportProtocol::Udp) if layout.src_port == DNS_PORT => parse_dns_response(payload),
+        Some(TransportProtocol::Tcp) if payload.first() == Some(&TLS_HANDSHAKE) => {
+            parse_sni(payload).map(|hostname| (layout.dst_addr, hostname, MAX_LABEL_TTL)).into_iter().collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// a possibly compressed name, and the offset just past it
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+
+    for _ in 0..MAX_NAME_JUMPS {
+        loop {
+            let length = *message.get(offset)? as usize;
+            if length & 0xc0 == 0xc0 {
+                let pointer = (read_u16(message, offset)? & 0x3fff) as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+                break;
+            }
+            if length == 0 {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            let label = message.get(offset + 1..offset + 1 + length)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + length;
+        }
+    }
+
+    None
+}
+
+// Addresses from the answers of a DNS response, all labelled with the name
+// that was asked for, so a CDN reached through a chain of CNAMEs still reads
+// as the host the app wanted.
+fn parse_dns_response(message: &[u8]) -> Vec<(IpAddr, String, Duration)> {
+    let mut answers = Vec::new();
+    let (Some(flags), Some(questions), Some(records)) = (read_u16(message, 2), read_u16(message, 4), read_u16(message, 6)) else {
+        return answers;
+    };
+    // responses only, and only those that did not fail
+    if flags & 0x8000 == 0 || flags & 0x000f != 0 || questions == 0 {
+        return answers;
+    }
+
+    let mut offset = 12;
+    let mut asked = None;
+    for _ in 0..questions {
+        let Some((name, next)) = read_name(message, offset) else {
+            return answers;
+        };
+        asked.get_or_insert(name);
+        offset = next + 4;
+    }
+    let Some(asked) = asked else {
+        return answers;
+    };
+
+    for _ in 0..records {
+        let Some((_, next)) = read_name(message, offset) else {
+            break;
+        };
+        let (Some(record_type), Some(ttl), Some(length)) = (read_u16(message, next), read_u32(message, next + 4), read_u16(message, next + 8)) else {
+            break;
+        };
+        let data_offset = next + 10;
+        let Some(data) = message.get(data_offset..data_offset + length as usize) else {
+            break;
+        };
+
+        let addr = match (record_type, data.len()) {
+            (DNS_TYPE_A, 4) => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (DNS_TYPE_AAAA, 16) => <[u8; 16]>::try_from(data).ok().map(|octets| IpAddr::V6(Ipv6Addr::from(octets))),
+            _ => None,
+        };
+        if let Some(addr) = addr {
+            answers.push((addr, asked.clone(), Duration::from_secs(ttl as u64)));
+        }
+        offset = data_offset + length as usize;
+    }
+
+    answers
+}
+
+// The server name extension of a TLS ClientHello. Only hellos that fit the
+// first segment are read, which is nearly all of them.
+fn parse_sni(record: &[u8]) -> Option<String> {
+    if *record.first()? != TLS_HANDSHAKE || *record.get(5)? != TLS_CLIENT_HELLO {
+        return None;
+    }
+
+    // record header, handshake header, client version and random
+    let mut offset = 5 + 4 + 2 + 32;
+    offset += 1 + *record.get(offset)? as usize;
+    offset += 2 + read_u16(record, offset)? as usize;
+    offset += 1 + *record.get(offset)? as usize;
+
+    let extensions_end = offset + 2 + read_u16(record, offset)? as usize;
+    offset += 2;
+    while offset + 4 <= extensions_end {
+        let extension_type = read_u16(record, offset)?;
+        let length = read_u16(record, offset + 2)? as usize;
+        if extension_type == TLS_SERVER_NAME {
+            // list length, name type, name length
+            let name_type = *record.get(offset + 6)?;
+            let name_length = read_u16(record, offset + 7)? as usize;
+            let name = record.get(offset + 9..offset + 9 + name_length)?;
+            return (name_type == 0).then(|| String::from_utf8_lossy(name).into_owned());
+        }
+        offset += 4 + length;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(labels: &[&str]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in labels {
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    // a response to one question with a CNAME and an A record, both naming
+    // their owner by a pointer to the question
+    fn dns_response() -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+        message.extend(name(&["www", "example", "com"]));
+        message.extend([0, 1, 0, 1]);
+        let target = name(&["cdn", "example", "net"]);
+        message.extend([0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, target.len() as u8]);
+        message.extend(target);
+        message.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 93, 184, 216, 34]);
+        message
+    }
+
+    fn client_hello(hostname: &str) -> Vec<u8> {
+        let mut server_name = vec![0];
+        server_name.extend((hostname.len() as u16).to_be_bytes());
+        server_name.extend(hostname.as_bytes());
+        let mut extension = vec![0, 0];
+        extension.extend((server_name.len() as u16 + 2).to_be_bytes());
+        extension.extend((server_name.len() as u16).to_be_bytes());
+        extension.extend(server_name);
+        // an unrelated extension first, so the walk has to skip it
+        let mut extensions = vec![0, 0x17, 0, 0];
+        extensions.extend(extension);
+
+        // version, random, empty session id, one cipher suite, null compression
+        let mut hello = vec![3, 3];
+        hello.extend([0; 32]);
+        hello.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![TLS_CLIENT_HELLO, 0];
+        handshake.extend((hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+        let mut record = vec![TLS_HANDSHAKE, 3, 1];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn dns_answers_are_labelled_with_the_question() {
+        assert_eq!(parse_dns_response(&dns_response()), vec![
+            ("93.184.216.34".parse().unwrap(), "www.example.com".to_string(), Duration::from_secs(3600)),
+        ]);
+
+        let mut failed = dns_response();
+        failed[3] |= 0x03;
+        assert!(parse_dns_response(&failed).is_empty());
+        let mut query = dns_response();
+        query[2] &= 0x7f;
+        assert!(parse_dns_response(&query).is_empty());
+    }
+
+    #[test]
+    fn truncated_dns_responses_yield_no_partial_answers() {
+        let message = dns_response();
+        for end in 0..message.len() {
+            assert!(parse_dns_response(&message[..end]).is_empty(), "cut at {}", end);
+        }
+    }
+
+    #[test]
+    fn hostile_dns_names_are_given_up_on() {
+        // the question points at itself
+        let mut looping = vec![0, 0, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, 0xc0, 12];
+        looping.extend([0, 1, 0, 1]);
+        assert!(read_name(&looping, 12).is_none());
+        assert!(parse_dns_response(&looping).is_empty());
+
+        // a pointer past the end, and a label longer than the message
+        assert!(read_name(&[0xc0, 0xff], 0).is_none());
+        assert!(read_name(&[63, b'a', b'b'], 0).is_none());
+
+        // more records claimed than sent, and a record whose data runs past the end
+        let mut overclaimed = dns_response();
+        overclaimed[7] = 0xff;
+        assert_eq!(parse_dns_response(&overclaimed).len(), 1);
+        let mut overlong = dns_response();
+        let data_length = overlong.len() - 5;
+        overlong[data_length] = 0xff;
+        assert!(parse_dns_response(&overlong).is_empty());
+    }
+
+    #[test]
+    fn sni_is_read_from_a_client_hello() {
+        assert_eq!(parse_sni(&client_hello("api.example.com")).as_deref(), Some("api.example.com"));
+
+        // anything but a hello, or a name that is not a host name
+        let mut server_hello = client_hello("api.example.com");
+        server_hello[5] = 0x02;
+        assert!(parse_sni(&server_hello).is_none());
+        let mut other_name_type = client_hello("api.example.com");
+        let name_type = other_name_type.len() - "api.example.com".len() - 3;
+        other_name_type[name_type] = 1;
+        assert!(parse_sni(&other_name_type).is_none());
+    }
+
+    #[test]
+    fn truncated_and_hostile_client_hellos_yield_no_name() {
+        let hello = client_hello("api.example.com");
+        for end in 0..hello.len() {
+            assert!(parse_sni(&hello[..end]).is_none(), "cut at {}", end);
+        }
+
+        // a name longer than the record
+        let mut overlong = hello.clone();
+        let name_length = overlong.len() - "api.example.com".len() - 2;
+        overlong[name_length..name_length + 2].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert!(parse_sni(&overlong).is_none());
+
+        // a session id running past the end, and an extensions length that
+        // does, which still only reads what is there
+        let mut session = hello.clone();
+        session[43] = 0xff;
+        assert!(parse_sni(&session).is_none());
+        let mut extensions = hello;
+        let extensions_length = 5 + 4 + 2 + 32 + 1 + 2 + 2 + 1 + 1;
+        extensions[extensions_length..extensions_length + 2].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert_eq!(parse_sni(&extensions).as_deref(), Some("api.example.com"));
+    }
+}
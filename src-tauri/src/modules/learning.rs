@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use tokio::time::{sleep, Duration};
+use crate::models::{
+    LearnedFlow, LearningStatus, PortRange, ProposalChange, ProposedRule, Rule, RuleAction, RuleConditions, RuleProposal, TrafficDirection,
+    TransportProtocol,
+};
+use crate::modules::rules::{glob_matches, Flow, ProcessIdentity, RuleEngine};
+use crate::modules::{host_labels, profiles};
+use crate::utils::events;
+use crate::RUNTIME;
+use crate::log_info;
+
+pub const FINISHED_EVENT: &str = "learning-finished";
+
+// endpoints past this many are not recorded, the session keeps what it has
+const MAX_FLOWS: usize = 65_536;
+// an app reaching more remote ports than this over one protocol gets rules
+// that leave the port open
+const MAX_PORTS_PER_RULE: usize = 16;
+// hostnames sharing their last two labels fold into a wildcard from this many on
+const MIN_WILDCARD_HOSTS: usize = 3;
+
+struct Session {
+    id: u64,
+    started_at: i64,
+    ends_at: i64,
+    active: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FlowKey {
+    app: String,
+    protocol: TransportProtocol,
+    remote_addr: IpAddr,
+    remote_port: u16,
+}
+
+#[derive(Clone)]
+struct Learned {
+    // the process last seen using the endpoint
+    pid: u32,
+    remote_addr: IpAddr,
+    flow: LearnedFlow,
+}
+
+static SESSION: Lazy<RwLock<Option<Session>>> = Lazy::new(|| RwLock::new(None));
+static FLOWS: Lazy<RwLock<HashMap<FlowKey, Learned>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+pub fn is_active() -> bool {
+    SESSION.read().as_ref().is_some_and(|session| session.active)
+}
+
+pub fn status() -> LearningStatus {
+    let session = SESSION.read();
+    let flows = FLOWS.read();
+    let apps: HashSet<&str> = flows.keys().map(|key| key.app.as_str()).collect();
+    LearningStatus {
+        active: session.as_ref().is_some_and(|session| session.active),
+        started_at: session.as_ref().map(|session| session.started_at),
+        ends_at: session.as_ref().map(|session| session.ends_at),
+        apps: apps.len(),
+        flows: flows.len(),
+    }
+}
+
+// starts over, dropping whatever an earlier session learned
+pub fn start(duration: Duration) -> LearningStatus {
+    let id = NEXT_SESSION.fetch_add(1, Ordering::SeqCst);
+    let now = Utc::now().timestamp_millis();
+    FLOWS.write().clear();
+    *SESSION.write() = Some(Session {
+        id,
+        started_at: now,
+        ends_at: now.saturating_add(i64::try_from(duration.as_millis()).unwrap_or(i64::MAX)),
+        active: true,
+    });
+
+    log_info!("Learning traffic for {} s", duration.as_secs());
+    RUNTIME.spawn(async move {
+        sleep(duration).await;
+        finish(Some(id));
+    });
+
+    status()
+}
+
+// what was learned is kept until the next session or clear()
+pub fn stop() -> LearningStatus {
+    finish(None)
+}
+
+// with an id, only that session is finished, so a stale timer cannot end a newer one
+fn finish(id: Option<u64>) -> LearningStatus {
+    let finished = {
+        let mut session = SESSION.write();
+        match session.as_mut() {
+            Some(session) if session.active && id.is_none_or(|id| id == session.id) => {
+                session.active = false;
+                session.ends_at = session.ends_at.min(Utc::now().timestamp_millis());
+                true
+            }
+            _ => false,
+        }
+    };
+
+    let status = status();
+    if finished {
+        log_info!("Stopped learning with {} endpoints of {} apps", status.flows, status.apps);
+        events::emit(FINISHED_EVENT, status.clone());
+    }
+    status
+}
+
+pub fn clear() {
+    FLOWS.write().clear();
+}
+
+// records the remote endpoints a process currently has connections to
+pub fn observe(pid: u32, name: &str, path: &str, endpoints: impl Iterator<Item = (TransportProtocol, IpAddr, u16)>) {
+    if path.is_empty() {
+        return;
+    }
+
+    let app = path.to_lowercase();
+    let now = Utc::now().timestamp_millis();
+    let mut flows = FLOWS.write();
+    for (protocol, remote_addr, remote_port) in endpoints {
+        let key = FlowKey {
+            app: app.clone(),
+            protocol,
+            remote_addr,
+            remote_port,
+        };
+        let hostname = host_labels::hostname(remote_addr);
+        if let Some(learned) = flows.get_mut(&key) {
+            learned.pid = pid;
+            learned.flow.last_seen = now;
+            if hostname.is_some() {
+                learned.flow.hostname = hostname;
+            }
+            continue;
+        }
+        if flows.len() >= MAX_FLOWS {
+            continue;
+        }
+
+        flows.insert(key, Learned {
+            pid,
+            remote_addr,
+            flow: LearnedFlow {
+                app_path: path.to_string(),
+                app_name: name.to_string(),
+                protocol,
+                remote_addr: remote_addr.to_string(),
+                remote_port,
+                hostname,
+                first_seen: now,
+                last_seen: now,
+            },
+        });
+    }
+}
+
+pub fn flows() -> Vec<LearnedFlow> {
+    let mut flows: Vec<LearnedFlow> = FLOWS.read().values().map(|learned| learned.flow.clone()).collect();
+    flows.sort_by(|a, b| {
+        a.app_path.to_lowercase().cmp(&b.app_path.to_lowercase())
+            .then_with(|| a.hostname.cmp(&b.hostname))
+            .then_with(|| a.remote_addr.cmp(&b.remote_addr))
+            .then_with(|| a.remote_port.cmp(&b.remote_port))
+    });
+    flows
+}
+
+// Numbered hosts of one domain, like the cache servers of a CDN, become a
+// single wildcard once there are enough of them. The domain is taken as the
+// last two labels, which is too short for names under a country's second
+// level domain; those simply stay listed one by one more often.
+fn host_patterns<'a>(hostnames: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut by_domain: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+    for hostname in hostnames {
+        let labels: Vec<&str> = hostname.rsplitn(3, '.').collect();
+        let domain = match labels.as_slice() {
+            [top, second, _] => format!("{}.{}", second, top),
+            _ => hostname.to_string(),
+        };
+        by_domain.entry(domain).or_default().insert(hostname);
+    }
+
+    by_domain.into_iter()
+        .flat_map(|(domain, hosts)| {
+            if hosts.len() < MIN_WILDCARD_HOSTS {
+                return hosts.into_iter().map(String::from).collect();
+            }
+            let mut patterns = vec![format!("*.{}", domain)];
+            if hosts.contains(domain.as_str()) {
+                patterns.push(domain);
+            }
+            patterns
+        })
+        .collect()
+}
+
+fn describe(targets: &[String], kind: &str) -> String {
+    match targets {
+        [target] => target.clone(),
+        _ => format!("{} {}", targets.len(), kind),
+    }
+}
+
+// An allow rule for one group of learned flows, and how the current rules
+// already treat those flows. Local ports are not learned, so rules that
+// depend on one never match here.
+fn propose_rule(
+    engine: &RuleEngine,
+    used: &mut HashSet<String>,
+    protocol: TransportProtocol,
+    port: Option<u16>,
+    remote_hosts: Vec<String>,
+    remote_cidrs: Vec<String>,
+    flows: &[&Learned],
+) -> ProposedRule {
+    let app = &flows[0].flow;
+    let target = if remote_hosts.is_empty() {
+        describe(&remote_cidrs, "addresses")
+    } else {
+        describe(&remote_hosts, "hosts")
+    };
+    let ports = port.map_or_else(|| "any port".to_string(), |port| format!("port {}", port));
+    let rule = Rule {
+        id: String::new(),
+        name: format!("Allow {} to {} on {:?} {}", app.app_name, target, protocol, ports),
+        enabled: true,
+        conditions: RuleConditions {
+            path: Some(app.app_path.clone()),
+            protocol: Some(protocol),
+            remote_ports: port.map(|port| vec![PortRange { start: port, end: port }]).unwrap_or_default(),
+            remote_hosts,
+            remote_cidrs,
+            ..RuleConditions::default()
+        },
+        action: RuleAction::Allow,
+        schedule: None,
+        apply_to_descendants: false,
+    };
+
+    let mut existing_rule_ids: Vec<String> = Vec::new();
+    let (mut allowed, mut conflicting) = (true, false);
+    for learned in flows {
+        let process = ProcessIdentity {
+            pid: learned.pid,
+            name: learned.flow.app_name.clone(),
+            path: learned.flow.app_path.clone(),
+            parent_pid: None,
+            ancestors: Vec::new(),
+        };
+        for direction in [TrafficDirection::Upload, TrafficDirection::Download] {
+            let decision = engine.evaluate(&process, &Flow {
+                pid: learned.pid,
+                protocol: Some(protocol),
+                local_port: 0,
+                remote_addr: learned.remote_addr,
+                remote_port: learned.flow.remote_port,
+                direction,
+                hostname: learned.flow.hostname.clone(),
+            });
+            if let Some(rule_id) = &decision.rule_id {
+                used.insert(rule_id.clone());
+                if !existing_rule_ids.contains(rule_id) {
+                    existing_rule_ids.push(rule_id.clone());
+                }
+            }
+            match decision.action {
+                Some(RuleAction::Allow) => {}
+                Some(_) => {
+                    allowed = false;
+                    conflicting = true;
+                }
+                None => allowed = false,
+            }
+        }
+    }
+
+    let change = if conflicting {
+        ProposalChange::Conflicting
+    } else if allowed {
+        ProposalChange::Covered
+    } else {
+        ProposalChange::Added
+    };
+    ProposedRule {
+        rule,
+        change,
+        flows: flows.len(),
+        existing_rule_ids,
+    }
+}
+
+// Turns what was learned into allow rules, one per app, protocol and remote
+// port, naming hosts where a name is known and addresses otherwise. Each is
+// compared against the rules in effect, the active profile's included. No block rules are proposed: with ask
+// mode on, whatever the allowlist misses is asked about instead.
+pub fn propose() -> RuleProposal {
+    let learned: Vec<Learned> = FLOWS.read().values().cloned().collect();
+    let current = profiles::effective_rules();
+    let engine = RuleEngine::quiet(&current);
+    let mut used = HashSet::new();
+
+    let mut by_app: BTreeMap<(String, TransportProtocol), Vec<&Learned>> = BTreeMap::new();
+    for flow in &learned {
+        by_app.entry((flow.flow.app_path.to_lowercase(), flow.flow.protocol)).or_default().push(flow);
+    }
+
+    let mut proposed = Vec::new();
+    for ((_, protocol), flows) in &by_app {
+        let ports: BTreeSet<u16> = flows.iter().map(|flow| flow.flow.remote_port).collect();
+        let groups: Vec<(Option<u16>, Vec<&Learned>)> = if ports.len() > MAX_PORTS_PER_RULE {
+            vec![(None, flows.clone())]
+        } else {
+            ports.iter()
+                .map(|&port| (Some(port), flows.iter().copied().filter(|flow| flow.flow.remote_port == port).collect()))
+                .collect()
+        };
+
+        for (port, group) in groups {
+            let (named, bare): (Vec<&Learned>, Vec<&Learned>) = group.into_iter().partition(|flow| flow.flow.hostname.is_some());
+            if !named.is_empty() {
+                let hosts = host_patterns(named.iter().filter_map(|flow| flow.flow.hostname.as_deref()));
+                proposed.push(propose_rule(&engine, &mut used, *protocol, port, hosts, Vec::new(), &named));
+            }
+            if !bare.is_empty() {
+                let addrs: BTreeSet<String> = bare.iter().map(|flow| flow.flow.remote_addr.clone()).collect();
+                proposed.push(propose_rule(&engine, &mut used, *protocol, port, Vec::new(), addrs.into_iter().collect(), &bare));
+            }
+        }
+    }
+    for (index, proposal) in proposed.iter_mut().enumerate() {
+        proposal.rule.id = format!("proposed-{}", index + 1);
+    }
+
+    let apps: BTreeSet<&str> = learned.iter().map(|flow| flow.flow.app_path.as_str()).collect();
+    let unused_rule_ids = current.iter()
+        .filter(|rule| rule.enabled && rule.action == RuleAction::Allow && !used.contains(&rule.id))
+        .filter(|rule| rule.conditions.path.as_deref().is_some_and(|pattern| apps.iter().any(|path| glob_matches(pattern, path))))
+        .map(|rule| rule.id.clone())
+        .collect();
+
+    RuleProposal {
+        rules: proposed,
+        unused_rule_ids,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(hostnames: &[&str]) -> Vec<String> {
+        host_patterns(hostnames.iter().copied())
+    }
+
+    #[test]
+    fn few_hosts_of_a_domain_stay_listed() {
+        assert_eq!(patterns(&["a.example.com", "b.example.com", "example.org"]), vec![
+            "a.example.com", "b.example.com", "example.org",
+        ]);
+        assert_eq!(patterns(&["localhost", "a.example.com", "a.example.com"]), vec!["a.example.com", "localhost"]);
+    }
+
+    #[test]
+    fn many_hosts_of_a_domain_become_a_wildcard() {
+        assert_eq!(patterns(&["cache1.cdn.net", "cache2.cdn.net", "eu.cache3.cdn.net", "api.other.com"]), vec![
+            "*.cdn.net", "api.other.com",
+        ]);
+        // the wildcard does not cover the bare domain, so it is kept next to it
+        assert_eq!(patterns(&["a.cdn.net", "b.cdn.net", "cdn.net"]), vec!["*.cdn.net", "cdn.net"]);
+    }
+}
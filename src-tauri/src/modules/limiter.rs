@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use parking_lot::RwLock;
+use crate::models::{LimiterConfig, PriorityClass, TrafficDirection};
+use crate::utils::clock::Clock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Send,
+    // hold the packet this long before sending it
+    Delay(Duration),
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BandwidthLimit {
+    // bytes per second, None leaves that direction unlimited
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    // bucket depth in bytes, None uses the configured default
+    pub burst: Option<u64>,
+    pub blocked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ChildKey {
+    Process(u32),
+    App(String),
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Duration,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64, now: Duration) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Duration) {
+        let elapsed = now.saturating_sub(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = self.updated.max(now);
+    }
+
+    fn set_rate(&mut self, rate: f64, burst: f64, now: Duration) {
+        self.refill(now);
+        self.rate = rate;
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    // packets larger than the bucket only need a full bucket, then drive it negative
+    fn wait_for(&self, amount: f64) -> f64 {
+        let needed = amount.min(self.burst);
+        if self.tokens >= needed {
+            0.0
+        } else {
+            (needed - self.tokens) / self.rate
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+fn direction_index(direction: TrafficDirection) -> usize {
+    match direction {
+        TrafficDirection::Upload => 0,
+        TrafficDirection::Download => 1,
+    }
+}
+
+struct ChildShare {
+    last_seen: Duration,
+    // relative size of the share, 1 everywhere except below the link capacity
+    weight: f64,
+    buckets: [Option<TokenBucket>; 2],
+}
+
+#[derive(Default)]
+struct LimitNode {
+    buckets: [Option<TokenBucket>; 2],
+    blocked: bool,
+    // fair-share state of the processes or apps below this node
+    children: HashMap<ChildKey, ChildShare>,
+}
+
+impl LimitNode {
+    fn configure(&mut self, limit: &BandwidthLimit, config: &LimiterConfig, now: Duration) {
+        self.blocked = limit.blocked;
+
+        for (index, rate) in [limit.upload, limit.download].into_iter().enumerate() {
+            self.buckets[index] = rate.filter(|&rate| rate > 0).map(|rate| {
+                let rate = rate as f64;
+                let burst = limit.burst
+                    .map(|burst| burst as f64)
+                    .unwrap_or(rate * config.default_burst_ms as f64 / 1000.0)
+                    .max(1.0);
+
+                match self.buckets[index].take() {
+                    Some(mut bucket) => {
+                        bucket.set_rate(rate, burst, now);
+                        bucket
+                    }
+                    None => TokenBucket::new(rate, burst, now),
+                }
+            });
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.blocked || self.buckets.iter().any(Option::is_some)
+    }
+
+    // seconds until `amount` may pass this node, and whether it passes on the
+    // child's own share (charged to it) or by borrowing unused parent capacity
+    fn wait(&mut self, child: Option<(&ChildKey, f64)>, index: usize, amount: f64, now: Duration, window: Duration) -> (f64, bool) {
+        let LimitNode { buckets, children, .. } = self;
+        let Some(parent) = buckets[index].as_mut() else {
+            return (0.0, false);
+        };
+
+        parent.refill(now);
+        let parent_wait = parent.wait_for(amount);
+        let Some((child, weight)) = child else {
+            return (parent_wait, false);
+        };
+
+        children.retain(|key, share| key == child || now.saturating_sub(share.last_seen) < window);
+        let other_weight: f64 = children.iter()
+            .filter(|(key, _)| *key != child)
+            .map(|(_, share)| share.weight)
+            .sum();
+        let share = children.entry(child.clone()).or_insert_with(|| ChildShare {
+            last_seen: now,
+            weight,
+            buckets: [None, None],
+        });
+        share.last_seen = now;
+        share.weight = weight;
+
+        if other_weight <= 0.0 {
+            return (parent_wait, false);
+        }
+
+        let fraction = weight / (weight + other_weight);
+        let share_rate = parent.rate * fraction;
+        let share_burst = parent.burst * fraction;
+        let share_bucket = match share.buckets[index].as_mut() {
+            Some(bucket) => {
+                bucket.set_rate(share_rate, share_burst, now);
+                bucket
+            }
+            None => share.buckets[index].insert(TokenBucket::new(share_rate, share_burst, now)),
+        };
+
+        // the shares add up to the parent rate, so traffic within a share is
+        // guaranteed; beyond it a child may only borrow while the parent is
+        // more than half full, which keeps room for the others
+        let share_wait = share_bucket.wait_for(amount);
+        let floor = parent.burst / 2.0;
+        let borrow_wait = ((amount.min(floor) + floor - parent.tokens) / parent.rate).max(0.0);
+
+        if share_wait > 0.0 && borrow_wait < share_wait {
+            (borrow_wait, false)
+        } else {
+            (share_wait, true)
+        }
+    }
+
+    fn take(&mut self, child: Option<&ChildKey>, index: usize, amount: f64, charge_share: bool) {
+        let Some(parent) = self.buckets[index].as_mut() else {
+            return;
+        };
+        parent.take(amount);
+
+        if !charge_share {
+            return;
+        }
+        let share_bucket = child
+            .and_then(|child| self.children.get_mut(child))
+            .and_then(|share| share.buckets[index].as_mut());
+        if let Some(bucket) = share_bucket {
+            bucket.take(amount);
+        }
+    }
+}
+
+#[derive(Default)]
+struct LimiterState {
+    processes: HashMap<u32, LimitNode>,
+    apps: HashMap<String, LimitNode>,
+    // the app bucket each process draws from, if any
+    members: HashMap<u32, String>,
+    // shared by all flows a rule or other scope applies to, across processes
+    scopes: HashMap<String, LimitNode>,
+    global: LimitNode,
+    // what the connection carries; below it shares follow priority classes
+    link: LimitNode,
+    priorities: HashMap<u32, PriorityClass>,
+}
+
+// Hierarchical token buckets: a packet has to fit the process bucket, its
+// application bucket, the global bucket and the link capacity. Below a limited
+// app or the global limit, active children split the rate evenly; below the
+// link capacity processes split it by the weight of their priority class. Either
+// way they may borrow what others leave unused, so an idle link is never held
+// back. Purely decides verdicts; enforcement backends act on them.
+pub struct Limiter {
+    clock: Arc<dyn Clock>,
+    config: LimiterConfig,
+    state: RwLock<LimiterState>,
+}
+
+impl Limiter {
+    pub fn new(clock: Arc<dyn Clock>, config: LimiterConfig) -> Self {
+        Self {
+            clock,
+            config,
+            state: RwLock::new(LimiterState::default()),
+        }
+    }
+
+    pub fn set_process_limit(&self, pid: u32, limit: Option<BandwidthLimit>) {
+        let now = self.clock.now();
+        let mut state = self.state.write();
+        match limit {
+            Some(limit) => state.processes.entry(pid).or_default().configure(&limit, &self.config, now),
+            None => {
+                state.processes.remove(&pid);
+            }
+        }
+    }
+
+    pub fn set_app_limit(&self, app_path: &str, limit: Option<BandwidthLimit>) {
+        let now = self.clock.now();
+        let mut state = self.state.write();
+        match limit {
+            Some(limit) => state.apps.entry(app_path.to_string()).or_default().configure(&limit, &self.config, now),
+            None => {
+                state.apps.remove(app_path);
+            }
+        }
+    }
+
+    // None takes the process out of its app's bucket, say when a limit of
+    // its own overrides the app's
+    pub fn set_app_member(&self, pid: u32, app_path: Option<&str>) {
+        let mut state = self.state.write();
+        match app_path {
+            Some(app_path) => {
+                state.members.insert(pid, app_path.to_string());
+            }
+            None => {
+                state.members.remove(&pid);
+            }
+        }
+    }
+
+    pub fn set_scope_limit(&self, scope: &str, limit: Option<BandwidthLimit>) {
+        let now = self.clock.now();
+        let mut state = self.state.write();
+        match limit {
+            Some(limit) => state.scopes.entry(scope.to_string()).or_default().configure(&limit, &self.config, now),
+            None => {
+                state.scopes.remove(scope);
+            }
+        }
+    }
+
+    pub fn set_global_limit(&self, limit: Option<BandwidthLimit>) {
+        let now = self.clock.now();
+        let mut state = self.state.write();
+        state.global = LimitNode::default();
+        if let Some(limit) = limit {
+            state.global.configure(&limit, &self.config, now);
+        }
+    }
+
+    pub fn set_link_capacity(&self, capacity: Option<BandwidthLimit>) {
+        let now = self.clock.now();
+        let mut state = self.state.write();
+        state.link = LimitNode::default();
+        if let Some(capacity) = capacity {
+            state.link.configure(&capacity, &self.config, now);
+        }
+    }
+
+    // None puts the process back in the normal class
+    pub fn set_priority(&self, pid: u32, class: Option<PriorityClass>) {
+        let mut state = self.state.write();
+        match class {
+            Some(class) => {
+                state.priorities.insert(pid, class);
+            }
+            None => {
+                state.priorities.remove(&pid);
+            }
+        }
+    }
+
+    pub fn is_blocked(&self, pid: u32) -> bool {
+        let state = self.state.read();
+        state.global.blocked
+            || state.processes.get(&pid).is_some_and(|node| node.blocked)
+            || state.members.get(&pid).and_then(|path| state.apps.get(path)).is_some_and(|node| node.blocked)
+    }
+
+    // The app bucket is the one the process is a member of; `app_path` only
+    // groups processes of one app under the global limit. With a scope, the
+    // packet also has to fit the bucket of that rule.
+    pub fn check_scoped(&self, pid: u32, app_path: Option<&str>, scope: Option<&str>, direction: TrafficDirection, size: u64) -> Verdict {
+        let now = self.clock.now();
+        let window = Duration::from_millis(self.config.fair_share_window_ms);
+        let index = direction_index(direction);
+        let amount = size as f64;
+
+        let mut guard = self.state.write();
+        let state = &mut *guard;
+        let mut process = state.processes.get_mut(&pid);
+        let mut app = state.members.get(&pid).and_then(|path| state.apps.get_mut(path));
+        let mut scope = scope.and_then(|key| state.scopes.get_mut(key));
+        let global = &mut state.global;
+        let link = &mut state.link;
+        let weight = state.priorities.get(&pid).copied().unwrap_or_default().weight() as f64;
+
+        if process.is_none() && app.is_none() && scope.is_none() && !global.is_limited() && !link.is_limited() {
+            return Verdict::Send;
+        }
+
+        let blocked = process.as_ref().is_some_and(|node| node.blocked)
+            || app.as_ref().is_some_and(|node| node.blocked)
+            || scope.as_ref().is_some_and(|node| node.blocked)
+            || global.blocked;
+        if blocked {
+            return Verdict::Drop;
+        }
+
+        let process_key = ChildKey::Process(pid);
+        let global_key = app_path
+            .map(|path| ChildKey::App(path.to_string()))
+            .unwrap_or_else(|| process_key.clone());
+
+        let (process_wait, _) = process.as_mut()
+            .map(|node| node.wait(None, index, amount, now, window))
+            .unwrap_or_default();
+        let (app_wait, app_share) = app.as_mut()
+            .map(|node| node.wait(Some((&process_key, 1.0)), index, amount, now, window))
+            .unwrap_or_default();
+        let (scope_wait, scope_share) = scope.as_mut()
+            .map(|node| node.wait(Some((&process_key, 1.0)), index, amount, now, window))
+            .unwrap_or_default();
+        let (global_wait, global_share) = global.wait(Some((&global_key, 1.0)), index, amount, now, window);
+        // priority classes belong to processes, so the link is shared by process
+        let (link_wait, link_share) = link.wait(Some((&process_key, weight)), index, amount, now, window);
+
+        let delay = Duration::from_secs_f64(process_wait.max(app_wait).max(scope_wait).max(global_wait).max(link_wait));
+        if delay > Duration::from_millis(self.config.max_delay_ms) {
+            return Verdict::Drop;
+        }
+
+        // delayed packets are charged now so the ones behind them queue up too
+        if let Some(node) = process {
+            node.take(None, index, amount, false);
+        }
+        if let Some(node) = app {
+            node.take(Some(&process_key), index, amount, app_share);
+        }
+        if let Some(node) = scope {
+            node.take(Some(&process_key), index, amount, scope_share);
+        }
+        global.take(Some(&global_key), index, amount, global_share);
+        link.take(Some(&process_key), index, amount, link_share);
+
+        if delay.is_zero() {
+            Verdict::Send
+        } else {
+            Verdict::Delay(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::ManualClock;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn limiter() -> (Arc<ManualClock>, Limiter) {
+        let clock = Arc::new(ManualClock::default());
+        let limiter = Limiter::new(clock.clone(), LimiterConfig::default());
+        (clock, limiter)
+    }
+
+    fn upload(rate: u64) -> Option<BandwidthLimit> {
+        Some(BandwidthLimit { upload: Some(rate), ..BandwidthLimit::default() })
+    }
+
+    // every sender offers one packet per tick for `duration`, returns the
+    // bytes each got through (sent now or after a delay)
+    fn offer(clock: &ManualClock, limiter: &Limiter, senders: &[(u32, &str)], size: u64, duration: Duration) -> Vec<u64> {
+        let mut passed = vec![0; senders.len()];
+        let end = clock.now() + duration;
+        while clock.now() < end {
+            for (index, (pid, app)) in senders.iter().enumerate() {
+                if limiter.check_scoped(*pid, Some(app), None, TrafficDirection::Upload, size) != Verdict::Drop {
+                    passed[index] += size;
+                }
+            }
+            clock.advance(TICK);
+        }
+        passed
+    }
+
+    #[test]
+    fn burst_passes_then_packets_wait_for_tokens() {
+        let (clock, limiter) = limiter();
+        limiter.set_process_limit(1, upload(10_000));
+
+        // the default burst is 250 ms worth of the rate
+        assert_eq!(limiter.check_scoped(1, None, None, TrafficDirection::Upload, 2_500), Verdict::Send);
+        assert_eq!(limiter.check_scoped(1, None, None, TrafficDirection::Upload, 1_000), Verdict::Delay(Duration::from_millis(100)));
+        assert_eq!(limiter.check_scoped(1, None, None, TrafficDirection::Download, 100_000), Verdict::Send);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.check_scoped(1, None, None, TrafficDirection::Upload, 1_000), Verdict::Send);
+    }
+
+    #[test]
+    fn packets_past_the_longest_delay_are_dropped_uncharged() {
+        let (clock, limiter) = limiter();
+        limiter.set_process_limit(1, upload(1_000));
+
+        let verdicts: Vec<Verdict> = (0..6).map(|_| limiter.check_scoped(1, None, None, TrafficDirection::Upload, 250)).collect();
+        assert_eq!(verdicts[0], Verdict::Send);
+        assert_eq!(verdicts[4], Verdict::Delay(Duration::from_secs(1)));
+        assert_eq!(verdicts[5], Verdict::Drop);
+
+        clock.advance(Duration::from_millis(1_250));
+        assert_eq!(limiter.check_scoped(1, None, None, TrafficDirection::Upload, 250), Verdict::Send);
+    }
+
+    #[test]
+    fn blocks_drop_everything() {
+        let (_, limiter) = limiter();
+        limiter.set_app_limit("/usr/bin/app", Some(BandwidthLimit { blocked: true, ..BandwidthLimit::default() }));
+        limiter.set_app_member(7, Some("/usr/bin/app"));
+        assert!(limiter.is_blocked(7));
+        assert!(!limiter.is_blocked(8));
+        assert_eq!(limiter.check_scoped(7, Some("/usr/bin/app"), None, TrafficDirection::Download, 1), Verdict::Drop);
+
+        limiter.set_app_limit("/usr/bin/app", None);
+        assert_eq!(limiter.check_scoped(7, Some("/usr/bin/app"), None, TrafficDirection::Download, 1), Verdict::Send);
+    }
+
+    #[test]
+    fn only_members_draw_from_the_app_bucket() {
+        let (_, limiter) = limiter();
+        limiter.set_app_limit("/app", upload(10_000));
+        limiter.set_app_member(1, Some("/app"));
+
+        // past the burst the member waits
+        assert_eq!(limiter.check_scoped(1, Some("/app"), None, TrafficDirection::Upload, 2_500), Verdict::Send);
+        assert_eq!(limiter.check_scoped(1, Some("/app"), None, TrafficDirection::Upload, 1_000), Verdict::Delay(Duration::from_millis(100)));
+        // a process of the app under a stronger limit of its own is not held back by the app
+        assert_eq!(limiter.check_scoped(2, Some("/app"), None, TrafficDirection::Upload, 1_000), Verdict::Send);
+
+        limiter.set_app_member(1, None);
+        assert_eq!(limiter.check_scoped(1, Some("/app"), None, TrafficDirection::Upload, 1_000), Verdict::Send);
+    }
+
+    #[test]
+    fn processes_of_a_limited_app_share_it_fairly() {
+        let (clock, limiter) = limiter();
+        limiter.set_app_limit("/app", upload(10_000));
+        limiter.set_app_member(1, Some("/app"));
+        limiter.set_app_member(2, Some("/app"));
+
+        // one sender offers five times what the other does, both more than the limit
+        let mut passed = [0u64; 2];
+        let end = clock.now() + Duration::from_secs(10);
+        while clock.now() < end {
+            for _ in 0..5 {
+                if limiter.check_scoped(1, Some("/app"), None, TrafficDirection::Upload, 200) != Verdict::Drop {
+                    passed[0] += 200;
+                }
+            }
+            if limiter.check_scoped(2, Some("/app"), None, TrafficDirection::Upload, 200) != Verdict::Drop {
+                passed[1] += 200;
+            }
+            clock.advance(TICK);
+        }
+
+        // what passed includes up to the longest delay of queued packets, plus the burst
+        let total = passed[0] + passed[1];
+        assert!((95_000..=125_000).contains(&total), "{:?}", passed);
+        assert!(passed[1] * 10 >= total * 4, "{:?}", passed);
+    }
+
+    #[test]
+    fn an_idle_sibling_leaves_its_share_to_borrow() {
+        let (clock, limiter) = limiter();
+        limiter.set_app_limit("/app", upload(10_000));
+        limiter.set_app_member(1, Some("/app"));
+        limiter.set_app_member(2, Some("/app"));
+        offer(&clock, &limiter, &[(1, "/app"), (2, "/app")], 200, Duration::from_secs(2));
+
+        // after the fair share window, pid 2 no longer counts as active
+        let passed = offer(&clock, &limiter, &[(1, "/app")], 200, Duration::from_secs(10));
+        assert!(passed[0] >= 90_000, "{:?}", passed);
+    }
+
+    #[test]
+    fn global_limit_splits_between_apps() {
+        let (clock, limiter) = limiter();
+        limiter.set_global_limit(upload(20_000));
+
+        // two processes of one app against one of another, the apps split evenly
+        let passed = offer(&clock, &limiter, &[(1, "/a"), (2, "/a"), (3, "/b")], 300, Duration::from_secs(10));
+        let app_a = passed[0] + passed[1];
+        assert!((190_000..=250_000).contains(&(app_a + passed[2])), "{:?}", passed);
+        assert!(passed[2] * 10 >= (app_a + passed[2]) * 4, "{:?}", passed);
+
+        limiter.set_global_limit(None);
+        assert_eq!(limiter.check_scoped(3, Some("/b"), None, TrafficDirection::Upload, 1_000_000), Verdict::Send);
+    }
+
+    #[test]
+    fn link_capacity_follows_the_class_of_each_process() {
+        let (clock, limiter) = limiter();
+        limiter.set_link_capacity(upload(30_000));
+        limiter.set_priority(1, Some(PriorityClass::High));
+        limiter.set_priority(2, Some(PriorityClass::Low));
+
+        // two processes of one app in different classes do not share a weight
+        let passed = offer(&clock, &limiter, &[(1, "/a"), (2, "/a"), (3, "/b")], 300, Duration::from_secs(10));
+        let total: u64 = passed.iter().sum();
+        assert!((290_000..=350_000).contains(&total), "{:?}", passed);
+        // weights 8, 2 and 4
+        assert!(passed[0] > passed[2] && passed[2] > passed[1], "{:?}", passed);
+        assert!(passed[0] * 10 >= total * 4, "{:?}", passed);
+    }
+}
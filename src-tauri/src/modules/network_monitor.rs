@@ -8,56 +8,171 @@ use windows::Win32::Foundation::{HANDLE, TRUE};
 use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
 use crate::models::{
     ProcessStatus, ApplicationProcess, 
-    ProcessNetworkUsage, NetworkUsage
+    ProcessNetworkUsage, ByteRate, UnitPreferences,
+    PacketStats, FlowStats, TransportProtocol, UnattributedReason, TrafficDirection,
+    UNATTRIBUTED_PID, UNATTRIBUTED_NAME
 };
+use crate::models::{RateConfig, RATE_PUBLISH_INTERVAL_MS, HISTORY_SAMPLE_INTERVAL_MS};
+use crate::modules::traffic_stats::{PacketSizeHistogram, average_packet_size};
+use crate::modules::rate_estimator::TrafficRates;
+use crate::modules::settings;
+use crate::modules::proxy;
+use crate::modules::process_tree;
+use crate::modules::rules::{self, Flow, ProcessIdentity, ScopeRates};
+use crate::modules::host_labels;
+use crate::modules::learning;
+use crate::modules::packet::{parse as parse_frame, LINKTYPE_ETHERNET};
+use crate::modules::traffic_history::HistorySample;
+use crate::utils::clock::{Clock, SystemClock};
 use crate::log_info;
 use crate::SYSTEM_MONITOR;
 use std::collections::HashMap;
 use parking_lot::RwLock;
 use pcap::{Device, Capture};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::Instant;
 use tokio::sync::mpsc;
 use get_if_addrs;
 use sysinfo::{System, SystemExt, ProcessExt, PidExt};
+use chrono::Utc;
+
+// flows without packets for this long are dropped from the per-flow view
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 struct ProcessTraffic {
     bytes_sent: u64,
     bytes_received: u64,
-    last_update: std::time::Instant,
+    packets_sent: u64,
+    packets_received: u64,
     active_connections: HashMap<String, ConnectionInfo>,
-    current_upload_rate: f64,
-    current_download_rate: f64,
+    rates: TrafficRates,
+    size_histogram: PacketSizeHistogram,
+    app_path: Option<String>,
+    // name and category the usage store files the app under, seen with the path
+    app_name: String,
+    app_category: String,
+    // bytes not yet handed to the usage store, which needs the app path first
+    unpersisted_sent: u64,
+    unpersisted_received: u64,
 }
 
-impl Default for ProcessTraffic {
-    fn default() -> Self {
+impl ProcessTraffic {
+    fn new(rate_config: &RateConfig) -> Self {
         Self {
             bytes_sent: 0,
             bytes_received: 0,
-            last_update: std::time::Instant::now(),
+            packets_sent: 0,
+            packets_received: 0,
             active_connections: HashMap::new(),
-            current_upload_rate: 0.0,
-            current_download_rate: 0.0,
+            rates: TrafficRates::new(rate_config),
+            size_histogram: PacketSizeHistogram::default(),
+            app_path: None,
+            app_name: String::new(),
+            app_category: String::new(),
+            unpersisted_sent: 0,
+            unpersisted_received: 0,
+        }
+    }
+
+    // hands the bytes counted since the last call to the usage store, once the app is known
+    fn persist_usage(&mut self) {
+        if let Some(app_path) = &self.app_path {
+            SYSTEM_MONITOR.get_usage_store().record(
+                app_path,
+                &self.app_name,
+                &self.app_category,
+                std::mem::take(&mut self.unpersisted_sent),
+                std::mem::take(&mut self.unpersisted_received),
+            );
+        }
+    }
+
+    fn packet_stats(&self, now: Duration) -> PacketStats {
+        PacketStats {
+            download_pps: self.rates.download_pps(now),
+            upload_pps: self.rates.upload_pps(now),
+            avg_download_packet_size: average_packet_size(self.bytes_received, self.packets_received),
+            avg_upload_packet_size: average_packet_size(self.bytes_sent, self.packets_sent),
+            size_histogram: self.size_histogram.buckets(),
         }
     }
 }
 
 struct ConnectionInfo {
+    protocol: TransportProtocol,
     local_addr: IpAddr,
     local_port: u16,
     remote_addr: IpAddr,
     remote_port: u16,
     bytes_sent: u64,
     bytes_received: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    last_seen: Duration,
+    rates: Option<TrafficRates>,
+    size_histogram: PacketSizeHistogram,
+}
+
+impl ConnectionInfo {
+    fn new(protocol: TransportProtocol, local_addr: IpAddr, local_port: u16, remote_addr: IpAddr, remote_port: u16) -> Self {
+        Self {
+            protocol,
+            local_addr,
+            local_port,
+            remote_addr,
+            remote_port,
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets_sent: 0,
+            packets_received: 0,
+            last_seen: Duration::ZERO,
+            rates: None,
+            size_histogram: PacketSizeHistogram::default(),
+        }
+    }
+
+    // TCP and UDP flows between the same ports are separate connections
+    fn key(&self) -> String {
+        format!("{:?} {}:{}-{}:{}",
+            self.protocol,
+            self.local_addr, self.local_port,
+            self.remote_addr, self.remote_port)
+    }
+
+    fn flow_stats(&self, now: Duration) -> FlowStats {
+        let (download_pps, upload_pps) = self.rates.as_ref()
+            .map(|rates| (rates.download_pps(now), rates.upload_pps(now)))
+            .unwrap_or((0.0, 0.0));
+
+        FlowStats {
+            protocol: self.protocol,
+            local_addr: self.local_addr.to_string(),
+            local_port: self.local_port,
+            remote_addr: self.remote_addr.to_string(),
+            remote_port: self.remote_port,
+            hostname: host_labels::hostname(self.remote_addr),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            packet_stats: PacketStats {
+                download_pps,
+                upload_pps,
+                avg_download_packet_size: average_packet_size(self.bytes_received, self.packets_received),
+                avg_upload_packet_size: average_packet_size(self.bytes_sent, self.packets_sent),
+                size_histogram: self.size_histogram.buckets(),
+            },
+        }
+    }
 }
 
-struct PacketData {
+pub(crate) struct PacketData {
     source_addr: IpAddr,
     source_port: u16,
     dest_addr: IpAddr,
     dest_port: u16,
     length: u64,
+    protocol: TransportProtocol,
 }
 
 // track per process
@@ -65,20 +180,16 @@ pub struct NetworkMonitor {
     process_traffic: Arc<RwLock<HashMap<u32, ProcessTraffic>>>,
     packet_receiver: RwLock<Option<mpsc::Receiver<PacketData>>>,
     system: RwLock<System>,
+    clock: Arc<dyn Clock>,
+    // unix milliseconds at the clock's origin, so history follows the clock too
+    wall_origin_ms: i64,
+    rate_config: RateConfig,
 }
 
 impl NetworkMonitor {
     pub fn new() -> Self {
         let (tx, rx) = mpsc::channel(1024);
-        
-        let mut system = System::new_all();
-        system.refresh_all();
-        
-        let monitor = Self {
-            process_traffic: Arc::new(RwLock::new(HashMap::new())),
-            packet_receiver: RwLock::new(Some(rx)),
-            system: RwLock::new(system),
-        };
+        let monitor = Self::with_clock(Arc::new(SystemClock::new()), settings::rate_config(), rx);
 
         let sender = tx;
         std::thread::spawn(move || {
@@ -187,8 +298,12 @@ impl NetworkMonitor {
                                                     packet_count = 0;
                                                     last_log = now;
                                                 }
+
+                                                if let Some(layout) = parse_frame(LINKTYPE_ETHERNET, packet.data) {
+                                                    host_labels::observe(&layout, packet.data.get(layout.payload_offset..).unwrap_or_default());
+                                                }
                                                 
-                                                if let Some(ethernet) = etherparse::SlicedPacket::from_ethernet(packet.data).ok() {
+                                                if let Ok(ethernet) = etherparse::SlicedPacket::from_ethernet(packet.data) {
                                                     if let Some(ip) = ethernet.ip {
                                                         let packet_data = match ip {
                                                             etherparse::InternetSlice::Ipv4(ipv4_header, _) => {
@@ -200,6 +315,7 @@ impl NetworkMonitor {
                                                                             dest_addr: IpAddr::V4(Ipv4Addr::from(ipv4_header.destination())),
                                                                             dest_port: tcp.destination_port(),
                                                                             length: packet.header.len as u64,
+                                                                            protocol: TransportProtocol::Tcp,
                                                                         })
                                                                     },
                                                                     Some(etherparse::TransportSlice::Udp(ref udp)) => Some(PacketData {
@@ -208,6 +324,7 @@ impl NetworkMonitor {
                                                                         dest_addr: IpAddr::V4(Ipv4Addr::from(ipv4_header.destination())),
                                                                         dest_port: udp.destination_port(),
                                                                         length: packet.header.len as u64,
+                                                                        protocol: TransportProtocol::Udp,
                                                                     }),
                                                                     _ => None,
                                                                 }
@@ -220,6 +337,7 @@ impl NetworkMonitor {
                                                                         dest_addr: IpAddr::V6(Ipv6Addr::from(ipv6_header.destination())),
                                                                         dest_port: tcp.destination_port(),
                                                                         length: packet.header.len as u64,
+                                                                        protocol: TransportProtocol::Tcp,
                                                                     }),
                                                                     Some(etherparse::TransportSlice::Udp(ref udp)) => Some(PacketData {
                                                                         source_addr: IpAddr::V6(Ipv6Addr::from(ipv6_header.source())),
@@ -227,6 +345,7 @@ impl NetworkMonitor {
                                                                         dest_addr: IpAddr::V6(Ipv6Addr::from(ipv6_header.destination())),
                                                                         dest_port: udp.destination_port(),
                                                                         length: packet.header.len as u64,
+                                                                        protocol: TransportProtocol::Udp,
                                                                     }),
                                                                     _ => None,
                                                                 }
@@ -274,62 +393,63 @@ impl NetworkMonitor {
         monitor
     }
 
+    // no capture thread is started here, packets are fed through the receiver
+    // and timed by `clock`, which is what makes replays reproducible
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>, rate_config: RateConfig, receiver: mpsc::Receiver<PacketData>) -> Self {
+        let mut system = System::new_all();
+        system.refresh_all();
+
+        Self {
+            process_traffic: Arc::new(RwLock::new(HashMap::new())),
+            packet_receiver: RwLock::new(Some(receiver)),
+            system: RwLock::new(system),
+            wall_origin_ms: Utc::now().timestamp_millis() - clock.now().as_millis() as i64,
+            clock,
+            rate_config,
+        }
+    }
+
     // Process received packets and update traffic statistics
     async fn process_packets(&self) {
-        if let Some(mut receiver) = self.packet_receiver.write().take() {
+        // taken out first, the lock must not be held while waiting for packets
+        let receiver = self.packet_receiver.write().take();
+        if let Some(mut receiver) = receiver {
             while let Some(packet) = receiver.recv().await {
-                let connection = ConnectionInfo {
-                    local_addr: packet.source_addr,
-                    local_port: packet.source_port,
-                    remote_addr: packet.dest_addr,
-                    remote_port: packet.dest_port,
-                    bytes_sent: 0,
-                    bytes_received: 0,
+                let is_local_source = match packet.source_addr {
+                    IpAddr::V4(addr) => is_local_ipv4(&addr),
+                    IpAddr::V6(addr) => is_local_ipv6(&addr),
                 };
 
-                if let Some(pid) = self.get_process_for_connection(&connection) {
-                    let mut traffic = self.process_traffic.write();
-                    let process_traffic = traffic.entry(pid).or_default();
-                    
-                    let conn_key = format!("{}:{}-{}:{}", 
-                        connection.local_addr, connection.local_port,
-                        connection.remote_addr, connection.remote_port);
-
-                    let conn_info = process_traffic.active_connections
-                        .entry(conn_key)
-                        .or_insert_with(|| connection);
+                // orient the flow so both directions land on the same connection entry
+                let connection = if is_local_source {
+                    ConnectionInfo::new(packet.protocol, packet.source_addr, packet.source_port, packet.dest_addr, packet.dest_port)
+                } else {
+                    ConnectionInfo::new(packet.protocol, packet.dest_addr, packet.dest_port, packet.source_addr, packet.source_port)
+                };
 
-                    let now = Instant::now();
-                    let time_delta = now.duration_since(process_traffic.last_update).as_secs_f64();
+                // the proxy reports its upstream legs itself, on behalf of the client
+                if packet.protocol == TransportProtocol::Tcp && proxy::is_upstream_port(connection.local_port) {
+                    continue;
+                }
 
-                    let is_local_source = match packet.source_addr {
-                        IpAddr::V4(addr) => is_local_ipv4(&addr),
-                        IpAddr::V6(addr) => is_local_ipv6(&addr),
-                    };
+                let (sent, received) = if is_local_source { (packet.length, 0) } else { (0, packet.length) };
+                let attribution = SYSTEM_MONITOR.get_attribution();
 
-                    if is_local_source {
-                        conn_info.bytes_sent += packet.length;
-                        process_traffic.bytes_sent += packet.length;
-                        
-                        let instant_rate = (packet.length as f64 / 1024.0) / time_delta.max(0.05);
-                        process_traffic.current_upload_rate = process_traffic.current_upload_rate * 0.6 + instant_rate * 0.4;
-                    } else {
-                        conn_info.bytes_received += packet.length;
-                        process_traffic.bytes_received += packet.length;
-                        
-                        let instant_rate = (packet.length as f64 / 1024.0) / time_delta.max(0.05);
-                        process_traffic.current_download_rate = process_traffic.current_download_rate * 0.6 + instant_rate * 0.4;
+                match self.get_process_for_connection(&connection, packet.protocol) {
+                    Ok(pid) => {
+                        attribution.record_attributed(sent, received);
+                        self.record_packet(pid, connection, packet.length, is_local_source);
                     }
-                    process_traffic.last_update = now;
-                } else {
-                    if packet.length > 1024 {
-                        log_info!(
-                            "No process found for connection {}:{} -> {}:{}",
-                            packet.source_addr,
-                            packet.source_port,
-                            packet.dest_addr,
-                            packet.dest_port
+                    Err(reason) => {
+                        attribution.record_unattributed(
+                            reason,
+                            connection.remote_addr,
+                            connection.remote_port,
+                            packet.protocol,
+                            sent,
+                            received,
                         );
+                        self.record_packet(UNATTRIBUTED_PID, connection, packet.length, is_local_source);
                     }
                 }
             }
@@ -337,8 +457,66 @@ impl NetworkMonitor {
         }
     }
 
+    fn record_packet(&self, pid: u32, connection: ConnectionInfo, length: u64, is_local_source: bool) {
+        let now = self.clock.now();
+        let mut traffic = self.process_traffic.write();
+        let process_traffic = traffic.entry(pid)
+            .or_insert_with(|| ProcessTraffic::new(&self.rate_config));
+
+        let conn_info = process_traffic.active_connections
+            .entry(connection.key())
+            .or_insert(connection);
+        let conn_rates = conn_info.rates
+            .get_or_insert_with(|| TrafficRates::new(&self.rate_config));
+
+        conn_info.size_histogram.record(length);
+        process_traffic.size_histogram.record(length);
+
+        if is_local_source {
+            conn_info.bytes_sent += length;
+            conn_info.packets_sent += 1;
+            conn_rates.record_upload(now, length);
+            process_traffic.bytes_sent += length;
+            process_traffic.packets_sent += 1;
+            process_traffic.rates.record_upload(now, length);
+            process_traffic.unpersisted_sent += length;
+            SYSTEM_MONITOR.get_traffic_totals().record(pid, length, 0);
+        } else {
+            conn_info.bytes_received += length;
+            conn_info.packets_received += 1;
+            conn_rates.record_download(now, length);
+            process_traffic.bytes_received += length;
+            process_traffic.packets_received += 1;
+            process_traffic.rates.record_download(now, length);
+            process_traffic.unpersisted_received += length;
+            SYSTEM_MONITOR.get_traffic_totals().record(pid, 0, length);
+        }
+        conn_info.last_seen = now;
+    }
+
+    // traffic relayed by the proxy, counted against the client process; chunks
+    // stand in for packets, so packet sizes and rates are approximate here
+    pub fn record_proxied(&self, pid: u32, local: SocketAddr, remote: SocketAddr, direction: TrafficDirection, length: u64) {
+        let connection = ConnectionInfo::new(TransportProtocol::Tcp, local.ip(), local.port(), remote.ip(), remote.port());
+        let is_upload = direction == TrafficDirection::Upload;
+        let (sent, received) = if is_upload { (length, 0) } else { (0, length) };
+
+        SYSTEM_MONITOR.get_attribution().record_proxied(sent, received);
+        self.record_packet(pid, connection, length, is_upload);
+    }
+
+    pub fn process_identity(&self, pid: u32) -> Option<ProcessIdentity> {
+        self.get_process_info(pid).map(|process| ProcessIdentity {
+            pid,
+            name: process.name,
+            path: process.path,
+            parent_pid: process.parent_pid,
+            ancestors: process_tree::ancestors(pid),
+        })
+    }
+
     // Map network connection to process ID using GetTcpTable2
-    fn get_process_for_connection(&self, connection: &ConnectionInfo) -> Option<u32> {
+    fn get_process_for_connection(&self, connection: &ConnectionInfo, protocol: TransportProtocol) -> Result<u32, UnattributedReason> {
         unsafe {
             let mut table_size: u32 = 0;
             let mut result = GetTcpTable2(
@@ -349,7 +527,7 @@ impl NetworkMonitor {
 
             if result != 0 && result != 122 { // ERROR_INSUFFICIENT_BUFFER = 122
                 log_info!("Failed to get TCP table size: error {}", result);
-                return None;
+                return Err(UnattributedReason::LookupFailed);
             }
 
             let mut buffer = vec![0u8; table_size as usize];
@@ -361,7 +539,7 @@ impl NetworkMonitor {
 
             if result != 0 {
                 log_info!("Failed to get TCP table: error {}", result);
-                return None;
+                return Err(UnattributedReason::LookupFailed);
             }
 
             let table = &*(buffer.as_ptr() as *const MIB_TCPTABLE2);
@@ -372,11 +550,11 @@ impl NetworkMonitor {
 
             let local_addr = match connection.local_addr {
                 IpAddr::V4(addr) => u32::from_be_bytes(addr.octets()),
-                _ => return None,
+                _ => return Err(UnattributedReason::Ipv6),
             };
             let remote_addr = match connection.remote_addr {
                 IpAddr::V4(addr) => u32::from_be_bytes(addr.octets()),
-                _ => return None,
+                _ => return Err(UnattributedReason::Ipv6),
             };
 
             let _local_port = connection.local_port.to_be();
@@ -394,14 +572,30 @@ impl NetworkMonitor {
                                   entry.dwRemoteAddr == local_addr.to_be();
 
                 if forward_match || reverse_match {
-                    return Some(entry.dwOwningPid);
+                    return Ok(entry.dwOwningPid);
                 }
             }
         }
-        None
+
+        match protocol {
+            TransportProtocol::Udp => Err(UnattributedReason::Udp),
+            TransportProtocol::Tcp => Err(UnattributedReason::NoMatchingConnection),
+        }
     }
 
     fn get_process_info(&self, pid: u32) -> Option<ProcessInfo> {
+        if pid == UNATTRIBUTED_PID {
+            return Some(ProcessInfo {
+                name: UNATTRIBUTED_NAME.to_string(),
+                display_name: Some(UNATTRIBUTED_NAME.to_string()),
+                path: String::new(),
+                icon: None,
+                parent_pid: None,
+                is_system: true,
+                category: "Unattributed".to_string(),
+            });
+        }
+
         let mut system = self.system.write();
         system.refresh_process(sysinfo::Pid::from(pid as usize));
         
@@ -430,53 +624,164 @@ impl NetworkMonitor {
         }
     }
 
-    fn update_process_stats(&self, pid: u32, download_kbps: f64, upload_kbps: f64, process: &ProcessInfo) {
+    fn update_process_stats(&self, pid: u32, download: ByteRate, upload: ByteRate, packet_stats: PacketStats, process: &ProcessInfo, units: &UnitPreferences) {
+        let network_usage = ProcessNetworkUsage::from_rates(download, upload, units);
+        let status = if network_usage.is_active() {
+            ProcessStatus::Online
+        } else {
+            ProcessStatus::Offline
+        };
+
         let process_info = ApplicationProcess {
             id: pid as i32,
             name: process.name.clone(),
             display_name: process.display_name.clone(),
             path: process.path.clone(),
             icon: process.icon.clone(),
-            network_usage: ProcessNetworkUsage {
-                download: NetworkUsage {
-                    value: if download_kbps >= 1024.0 {
-                        download_kbps / 1024.0
-                    } else {
-                        download_kbps
-                    },
-                    unit: if download_kbps >= 1024.0 {
-                        "MB/s".to_string()
-                    } else {
-                        "KB/s".to_string()
-                    },
-                },
-                upload: NetworkUsage {
-                    value: if upload_kbps >= 1024.0 {
-                        upload_kbps / 1024.0
-                    } else {
-                        upload_kbps
-                    },
-                    unit: if upload_kbps >= 1024.0 {
-                        "MB/s".to_string()
-                    } else {
-                        "KB/s".to_string()
-                    },
-                },
-            },
+            network_usage,
+            packet_stats,
+            totals: SYSTEM_MONITOR.get_traffic_totals().get(pid),
             pid,
             parent_pid: process.parent_pid,
             children: Vec::new(),
-            status: if download_kbps > 0.0 || upload_kbps > 0.0 {
-                ProcessStatus::Online
-            } else {
-                ProcessStatus::Offline
-            },
+            status,
             is_system: process.is_system,
             category: process.category.clone(),
+            inherited_from: None,
         };
 
         SYSTEM_MONITOR.get_network_cache().update_process(process_info);
     }
+
+    fn update_flow_stats(&self, pid: u32, process_traffic: &mut ProcessTraffic, now: Duration) {
+        process_traffic.active_connections.retain(|_, conn| {
+            now.saturating_sub(conn.last_seen) < FLOW_IDLE_TIMEOUT
+        });
+
+        let flows = process_traffic.active_connections
+            .values()
+            .map(|conn| conn.flow_stats(now))
+            .collect();
+
+        SYSTEM_MONITOR.get_network_cache().update_flows(pid, flows);
+    }
+
+    // adds each flow's rates to the rule that decided it, so limits on
+    // destinations can be compared with what those destinations actually get
+    fn measure_scopes(&self, pid: u32, process: &ProcessInfo, process_traffic: &ProcessTraffic, now: Duration, measured: &mut HashMap<String, ScopeRates>) {
+        let identity = ProcessIdentity {
+            pid,
+            name: process.name.clone(),
+            path: process.path.clone(),
+            parent_pid: process.parent_pid,
+            ancestors: process_tree::ancestors(pid),
+        };
+
+        for conn in process_traffic.active_connections.values() {
+            let Some(rates) = conn.rates.as_ref() else {
+                continue;
+            };
+            let hostname = host_labels::hostname(conn.remote_addr);
+            let mut counted = Vec::new();
+            for direction in [TrafficDirection::Upload, TrafficDirection::Download] {
+                let decision = rules::engine().evaluate(&identity, &Flow {
+                    pid,
+                    protocol: Some(conn.protocol),
+                    local_port: conn.local_port,
+                    remote_addr: conn.remote_addr,
+                    remote_port: conn.remote_port,
+                    direction,
+                    hostname: hostname.clone(),
+                });
+                let Some(rule_id) = decision.rule_id.clone() else {
+                    continue;
+                };
+
+                let scope = measured.entry(rule_id.clone()).or_default();
+                match direction {
+                    TrafficDirection::Upload => scope.upload = scope.upload + ByteRate(rates.upload_rate(now)),
+                    TrafficDirection::Download => scope.download = scope.download + ByteRate(rates.download_rate(now)),
+                }
+                if !counted.contains(&rule_id) {
+                    scope.flows += 1;
+                    counted.push(rule_id);
+                }
+            }
+        }
+    }
+
+    fn publish_rates(&self) {
+        let now = self.clock.now();
+        let units = settings::unit_preferences();
+        let mut traffic = self.process_traffic.write();
+        let mut pids_to_remove = Vec::new();
+        let mut measured = HashMap::new();
+
+        for (&pid, process_traffic) in traffic.iter_mut() {
+            // idle processes still publish once so their last rate reads as zero
+            if process_traffic.rates.is_idle(now) {
+                pids_to_remove.push(pid);
+            }
+
+            if let Some(process) = self.get_process_info(pid) {
+                SYSTEM_MONITOR.get_traffic_totals().identify(pid, &process.name, &process.path);
+                if !process.path.is_empty() {
+                    process_traffic.app_path = Some(process.path.clone());
+                    process_traffic.app_name = process.name.clone();
+                    process_traffic.app_category = process.category.clone();
+                    process_traffic.persist_usage();
+                }
+                self.update_process_stats(
+                    pid,
+                    ByteRate(process_traffic.rates.download_rate(now)),
+                    ByteRate(process_traffic.rates.upload_rate(now)),
+                    process_traffic.packet_stats(now),
+                    &process,
+                    &units
+                );
+                self.measure_scopes(pid, &process, process_traffic, now, &mut measured);
+                if learning::is_active() {
+                    let endpoints = process_traffic.active_connections.values()
+                        .map(|conn| (conn.protocol, conn.remote_addr, conn.remote_port));
+                    learning::observe(pid, &process.name, &process.path, endpoints);
+                }
+            }
+            self.update_flow_stats(pid, process_traffic, now);
+        }
+        rules::set_scope_rates(measured);
+
+        // an exited process is no longer found above, what it did last still counts
+        for pid in pids_to_remove {
+            if let Some(mut process_traffic) = traffic.remove(&pid) {
+                process_traffic.persist_usage();
+            }
+        }
+
+        // cleanup inactive processes
+        let active_pids: Vec<u32> = traffic.keys().cloned().collect();
+        SYSTEM_MONITOR.get_network_cache().cleanup_inactive(&active_pids);
+    }
+
+    fn sample_history(&self) {
+        let now = self.clock.now();
+        let samples: Vec<HistorySample> = self.process_traffic.read()
+            .iter()
+            .map(|(&pid, process_traffic)| HistorySample {
+                pid,
+                app_path: process_traffic.app_path.clone(),
+                download: ByteRate(process_traffic.rates.download_rate(now)),
+                upload: ByteRate(process_traffic.rates.upload_rate(now)),
+            })
+            .collect();
+
+        SYSTEM_MONITOR.get_traffic_history().record(self.wall_origin_ms + now.as_millis() as i64, &samples);
+    }
+}
+
+impl Default for NetworkMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 struct ProcessInfo {
@@ -513,46 +818,23 @@ pub fn start_monitoring(monitor: Arc<NetworkMonitor>) {
     std::thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
-            let mut interval = interval(Duration::from_millis(50));
+            let mut interval = interval(Duration::from_millis(RATE_PUBLISH_INTERVAL_MS));
             loop {
                 interval.tick().await;
-                let mut traffic = update_monitor.process_traffic.write();
-                let mut pids_to_remove = Vec::new();
-
-                for (&pid, process_traffic) in traffic.iter_mut() {
-                    let now = Instant::now();
-                    let time_delta = now.duration_since(process_traffic.last_update).as_secs_f64();
-
-                    if time_delta >= 0.05 {
-                        process_traffic.current_download_rate *= 0.95;
-                        process_traffic.current_upload_rate *= 0.95;
-
-                        if process_traffic.current_download_rate < 0.01 && process_traffic.current_upload_rate < 0.01 {
-                            pids_to_remove.push(pid);
-                            continue;
-                        }
-
-                        if let Some(process) = update_monitor.get_process_info(pid) {
-                            if process_traffic.current_download_rate >= 0.0 || process_traffic.current_upload_rate >= 0.0 {
-                                update_monitor.update_process_stats(
-                                    pid,
-                                    process_traffic.current_download_rate,
-                                    process_traffic.current_upload_rate,
-                                    &process
-                                );
-                            }
-                        }
-                        process_traffic.last_update = now;
-                    }
-                }
-
-                for pid in pids_to_remove {
-                    traffic.remove(&pid);
-                }
+                update_monitor.publish_rates();
+            }
+        });
+    });
 
-                // cleanup inactive processes
-                let active_pids: Vec<u32> = traffic.keys().cloned().collect();
-                SYSTEM_MONITOR.get_network_cache().cleanup_inactive(&active_pids);
+    // thread for history samples
+    let history_monitor = Arc::clone(&monitor);
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut interval = interval(Duration::from_millis(HISTORY_SAMPLE_INTERVAL_MS));
+            loop {
+                interval.tick().await;
+                history_monitor.sample_history();
             }
         });
     });
@@ -801,7 +1083,7 @@ fn get_local_ip_addresses() -> Vec<IpAddr> {
     addresses
 }
 
-fn is_local_ipv4(addr: &Ipv4Addr) -> bool {
+pub(crate) fn is_local_ipv4(addr: &Ipv4Addr) -> bool {
     if addr.is_loopback() {
         return true;
     }
@@ -817,7 +1099,7 @@ fn is_local_ipv4(addr: &Ipv4Addr) -> bool {
     false
 }
 
-fn is_local_ipv6(addr: &Ipv6Addr) -> bool {
+pub(crate) fn is_local_ipv6(addr: &Ipv6Addr) -> bool {
     if addr.is_loopback() {
         return true;
     }
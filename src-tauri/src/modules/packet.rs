@@ -0,0 +1,165 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::models::TransportProtocol;
+
+// link types seen in pcap files, see https://www.tcpdump.org/linktypes.html
+const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+// Offsets into a captured frame. Kept as plain offsets rather than borrowed
+// slices so callers can rewrite the bytes in place once they know the layout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacketLayout {
+    pub ip_offset: usize,
+    pub src_addr: IpAddr,
+    pub dst_addr: IpAddr,
+    // None for anything other than TCP and UDP
+    pub protocol: Option<TransportProtocol>,
+    pub transport_offset: usize,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload_offset: usize,
+}
+
+pub fn parse(link_type: u32, data: &[u8]) -> Option<PacketLayout> {
+    let (ethertype, ip_offset) = match link_type {
+        LINKTYPE_ETHERNET => ethernet_payload(data)?,
+        LINKTYPE_LINUX_SLL => (read_u16(data, 14)?, 16),
+        LINKTYPE_NULL => {
+            // address family in the capturing host's byte order
+            let family = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+            let family = if family > 0xffff { family.swap_bytes() } else { family };
+            match family {
+                2 => (ETHERTYPE_IPV4, 4),
+                24 | 28 | 30 => (ETHERTYPE_IPV6, 4),
+                _ => return None,
+            }
+        }
+        LINKTYPE_RAW => match data.first()? >> 4 {
+            4 => (ETHERTYPE_IPV4, 0),
+            6 => (ETHERTYPE_IPV6, 0),
+            _ => return None,
+        },
+        LINKTYPE_IPV4 => (ETHERTYPE_IPV4, 0),
+        LINKTYPE_IPV6 => (ETHERTYPE_IPV6, 0),
+        _ => return None,
+    };
+
+    match ethertype {
+        ETHERTYPE_IPV4 => parse_ipv4(data, ip_offset),
+        ETHERTYPE_IPV6 => parse_ipv6(data, ip_offset),
+        _ => None,
+    }
+}
+
+fn ethernet_payload(data: &[u8]) -> Option<(u16, usize)> {
+    let mut offset = 12;
+    let mut ethertype = read_u16(data, offset)?;
+    while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+        offset += 4;
+        ethertype = read_u16(data, offset)?;
+    }
+    Some((ethertype, offset + 2))
+}
+
+fn parse_ipv4(data: &[u8], offset: usize) -> Option<PacketLayout> {
+    let header = data.get(offset..offset + 20)?;
+    if header[0] >> 4 != 4 {
+        return None;
+    }
+
+    let header_len = ((header[0] & 0x0f) as usize) * 4;
+    let src_addr = IpAddr::V4(Ipv4Addr::new(header[12], header[13], header[14], header[15]));
+    let dst_addr = IpAddr::V4(Ipv4Addr::new(header[16], header[17], header[18], header[19]));
+    // only the first fragment carries the transport header
+    let fragment_offset = u16::from_be_bytes([header[6], header[7]]) & 0x1fff;
+    let protocol = if fragment_offset == 0 { header[9] } else { 0 };
+
+    Some(with_transport(data, offset, offset + header_len, protocol, src_addr, dst_addr))
+}
+
+fn parse_ipv6(data: &[u8], offset: usize) -> Option<PacketLayout> {
+    let header = data.get(offset..offset + 40)?;
+    if header[0] >> 4 != 6 {
+        return None;
+    }
+
+    let src: [u8; 16] = header[8..24].try_into().ok()?;
+    let dst: [u8; 16] = header[24..40].try_into().ok()?;
+    let mut next_header = header[6];
+    let mut transport_offset = offset + 40;
+
+    // walk hop-by-hop, routing, fragment and destination option headers
+    loop {
+        match next_header {
+            0 | 43 | 60 => {
+                let extension = data.get(transport_offset..transport_offset + 2)?;
+                next_header = extension[0];
+                transport_offset += (extension[1] as usize + 1) * 8;
+            }
+            44 => {
+                let extension = data.get(transport_offset..transport_offset + 8)?;
+                let fragment_offset = u16::from_be_bytes([extension[2], extension[3]]) >> 3;
+                next_header = if fragment_offset == 0 { extension[0] } else { 59 };
+                transport_offset += 8;
+            }
+            _ => break,
+        }
+    }
+
+    Some(with_transport(
+        data,
+        offset,
+        transport_offset,
+        next_header,
+        IpAddr::V6(Ipv6Addr::from(src)),
+        IpAddr::V6(Ipv6Addr::from(dst)),
+    ))
+}
+
+fn with_transport(data: &[u8], ip_offset: usize, transport_offset: usize, protocol: u8, src_addr: IpAddr, dst_addr: IpAddr) -> PacketLayout {
+    let mut layout = PacketLayout {
+        ip_offset,
+        src_addr,
+        dst_addr,
+        protocol: None,
+        transport_offset,
+        src_port: 0,
+        dst_port: 0,
+        payload_offset: transport_offset,
+    };
+
+    let (protocol, header_len) = match protocol {
+        IPPROTO_TCP => match data.get(transport_offset + 12) {
+            Some(data_offset) => (TransportProtocol::Tcp, ((data_offset >> 4) as usize) * 4),
+            None => return layout,
+        },
+        IPPROTO_UDP => (TransportProtocol::Udp, 8),
+        _ => return layout,
+    };
+
+    if let (Some(src_port), Some(dst_port)) = (read_u16(data, transport_offset), read_u16(data, transport_offset + 2)) {
+        layout.protocol = Some(protocol);
+        layout.src_port = src_port;
+        layout.dst_port = dst_port;
+        layout.payload_offset = (transport_offset + header_len).min(data.len());
+    }
+
+    layout
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
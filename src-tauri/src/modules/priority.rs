@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use crate::models::{AppPriority, ApplicationProcess, LinkCapacity, PriorityClass, ProcessPriority, UNATTRIBUTED_PID};
+use crate::modules::limiter::BandwidthLimit;
+use crate::modules::{app_limits, settings, throttle};
+use crate::log_error;
+
+// classes set by hand for a single process; they beat the app's class
+static MANUAL: Lazy<RwLock<HashMap<u32, PriorityClass>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+// what the limiter currently has, so only changes are pushed to it
+static APPLIED: Lazy<RwLock<HashMap<u32, ProcessPriority>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn capacity_limit(capacity: LinkCapacity) -> Option<BandwidthLimit> {
+    let bytes_per_second = |kb: u64| (kb > 0).then(|| kb * 1024);
+    let limit = BandwidthLimit {
+        upload: bytes_per_second(capacity.upload_limit),
+        download: bytes_per_second(capacity.download_limit),
+        burst: None,
+        blocked: false,
+    };
+    (limit.upload.is_some() || limit.download.is_some()).then_some(limit)
+}
+
+// pushes the configured link capacity to the limiter; without one, classes
+// are remembered but nothing is shaped
+pub fn apply_link_capacity() {
+    if let Err(e) = throttle::set_link_capacity(capacity_limit(settings::link_capacity())) {
+        log_error!("Failed to apply the link capacity: {}", e);
+    }
+}
+
+fn app_class(app_priorities: &[AppPriority], path: &str) -> Option<PriorityClass> {
+    app_priorities.iter()
+        .find(|priority| app_limits::same_executable(&priority.path, path))
+        .map(|priority| priority.class)
+}
+
+fn apply(pid: u32, priority: Option<ProcessPriority>) {
+    let result = throttle::set_priority(pid, priority.as_ref().map(|priority| priority.class));
+    record(&mut APPLIED.write(), pid, priority, result);
+}
+
+// a class that failed to apply is not recorded, so the next reconcile tries again
+fn record(applied: &mut HashMap<u32, ProcessPriority>, pid: u32, priority: Option<ProcessPriority>, result: Result<(), String>) {
+    if let Err(e) = result {
+        log_error!("Failed to apply the priority class of process {}: {}", pid, e);
+        return;
+    }
+    match priority {
+        Some(priority) => {
+            applied.insert(pid, priority);
+        }
+        None => {
+            applied.remove(&pid);
+        }
+    }
+}
+
+// None hands the process back to its app's class, or the normal one
+pub fn set_process_priority(pid: u32, class: Option<PriorityClass>, processes: &[ApplicationProcess]) {
+    match class {
+        Some(class) => {
+            MANUAL.write().insert(pid, class);
+        }
+        None => {
+            MANUAL.write().remove(&pid);
+        }
+    }
+    reconcile(processes);
+}
+
+// Gives running processes the class of their app unless one was set by hand.
+// Called whenever the process list changes, so new processes start out in
+// the right class.
+pub fn reconcile(processes: &[ApplicationProcess]) {
+    let app_priorities = settings::app_priorities();
+    let mut desired: HashMap<u32, ProcessPriority> = processes.iter()
+        .filter(|process| process.pid != UNATTRIBUTED_PID)
+        .filter_map(|process| {
+            let class = app_class(&app_priorities, &process.path)?;
+            Some((process.pid, ProcessPriority { pid: process.pid, class, manual: false }))
+        })
+        .collect();
+    for (&pid, &class) in MANUAL.read().iter() {
+        desired.insert(pid, ProcessPriority { pid, class, manual: true });
+    }
+
+    let applied = APPLIED.read().clone();
+    for pid in applied.keys().filter(|pid| !desired.contains_key(pid)) {
+        apply(*pid, None);
+    }
+    for (pid, priority) in desired {
+        if applied.get(&pid) != Some(&priority) {
+            apply(pid, Some(priority));
+        }
+    }
+}
+
+// a reused pid starts out in the normal class
+pub fn forget_exited(running: &HashSet<u32>) {
+    MANUAL.write().retain(|pid, _| running.contains(pid));
+    let exited: Vec<u32> = APPLIED.read().keys()
+        .filter(|pid| !running.contains(pid))
+        .copied()
+        .collect();
+    for pid in exited {
+        apply(pid, None);
+    }
+}
+
+// every process whose class was set, by hand or through its app
+pub fn priorities() -> Vec<ProcessPriority> {
+    APPLIED.read().values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn priority(pid: u32, class: PriorityClass) -> Option<ProcessPriority> {
+        Some(ProcessPriority { pid, class, manual: false })
+    }
+
+    #[test]
+    fn only_applied_classes_are_recorded() {
+        let mut applied = HashMap::new();
+        record(&mut applied, 1, priority(1, PriorityClass::High), Err("refused".to_string()));
+        assert!(applied.is_empty());
+
+        record(&mut applied, 1, priority(1, PriorityClass::High), Ok(()));
+        assert_eq!(applied.get(&1), priority(1, PriorityClass::High).as_ref());
+
+        // a failed change or removal keeps what is actually in force
+        record(&mut applied, 1, priority(1, PriorityClass::Low), Err("refused".to_string()));
+        assert_eq!(applied.get(&1), priority(1, PriorityClass::High).as_ref());
+        record(&mut applied, 1, None, Err("refused".to_string()));
+        assert_eq!(applied.get(&1), priority(1, PriorityClass::High).as_ref());
+
+        record(&mut applied, 1, None, Ok(()));
+        assert!(applied.is_empty());
+    }
+
+    #[test]
+    fn capacity_is_converted_and_zero_is_unlimited() {
+        assert_eq!(capacity_limit(LinkCapacity { download_limit: 0, upload_limit: 0 }), None);
+
+        let limit = capacity_limit(LinkCapacity { download_limit: 100, upload_limit: 0 }).unwrap();
+        assert_eq!(limit.download, Some(102_400));
+        assert_eq!(limit.upload, None);
+    }
+
+    #[test]
+    fn apps_get_the_class_set_for_their_executable() {
+        let priorities = [AppPriority { path: "/opt/priority-test/game".to_string(), class: PriorityClass::Critical }];
+        assert_eq!(app_class(&priorities, "/opt/priority-test/game"), Some(PriorityClass::Critical));
+        assert_eq!(app_class(&priorities, "/opt/priority-test/other"), None);
+    }
+}
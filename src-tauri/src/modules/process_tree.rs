@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use crate::modules::rules::ProcessIdentity;
+
+// deeper chains than this are treated as broken
+const MAX_DEPTH: usize = 64;
+
+pub struct TreeEntry {
+    pub identity: ProcessIdentity,
+    // seconds since the epoch, used to tell a parent from a process that reused its pid
+    pub start_time: u64,
+}
+
+// every running process, not just the network-capable ones the cache keeps,
+// so a launcher without connections of its own still links its children
+static TREE: Lazy<RwLock<HashMap<u32, TreeEntry>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub fn update(entries: HashMap<u32, TreeEntry>) {
+    *TREE.write() = entries;
+}
+
+// Parent first, then its parent and so on. The walk stops at a pid that is
+// gone, a loop, or a "parent" that started after its child, which means the
+// real parent exited and its pid went to someone else.
+pub fn ancestors(pid: u32) -> Vec<ProcessIdentity> {
+    walk(&TREE.read(), pid)
+}
+
+// every running process with its ancestors, for limits that have to reach
+// processes the cache leaves out
+pub fn processes() -> Vec<ProcessIdentity> {
+    let tree = TREE.read();
+    tree.values()
+        .map(|entry| ProcessIdentity {
+            ancestors: walk(&tree, entry.identity.pid),
+            ..entry.identity.clone()
+        })
+        .collect()
+}
+
+fn walk(tree: &HashMap<u32, TreeEntry>, pid: u32) -> Vec<ProcessIdentity> {
+    let mut ancestors = Vec::new();
+    let mut seen = HashSet::from([pid]);
+    let Some(mut current) = tree.get(&pid) else {
+        return ancestors;
+    };
+
+    while let Some(parent_pid) = current.identity.parent_pid {
+        let Some(parent) = tree.get(&parent_pid) else {
+            break;
+        };
+        if !seen.insert(parent_pid) || parent.start_time > current.start_time || ancestors.len() >= MAX_DEPTH {
+            break;
+        }
+        ancestors.push(parent.identity.clone());
+        current = parent;
+    }
+
+    ancestors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pid: u32, parent_pid: Option<u32>, start_time: u64) -> (u32, TreeEntry) {
+        (pid, TreeEntry {
+            identity: ProcessIdentity {
+                pid,
+                name: format!("process-{}", pid),
+                path: format!("/opt/tree-test/process-{}", pid),
+                parent_pid,
+                ancestors: Vec::new(),
+            },
+            start_time,
+        })
+    }
+
+    fn pids(ancestors: &[ProcessIdentity]) -> Vec<u32> {
+        ancestors.iter().map(|ancestor| ancestor.pid).collect()
+    }
+
+    #[test]
+    fn ancestors_come_nearest_first() {
+        let tree = HashMap::from([entry(1, None, 10), entry(2, Some(1), 11), entry(3, Some(2), 12)]);
+        assert_eq!(pids(&walk(&tree, 3)), vec![2, 1]);
+        assert!(walk(&tree, 1).is_empty());
+        assert!(walk(&tree, 4).is_empty());
+    }
+
+    #[test]
+    fn a_reused_parent_pid_is_not_an_ancestor() {
+        // 2's parent exited and its pid went to a process started later
+        let tree = HashMap::from([entry(1, None, 30), entry(2, Some(1), 20), entry(3, Some(2), 21)]);
+        assert_eq!(pids(&walk(&tree, 3)), vec![2]);
+        assert!(walk(&tree, 2).is_empty());
+    }
+
+    #[test]
+    fn the_walk_stops_at_gone_parents_and_loops() {
+        let tree = HashMap::from([entry(2, Some(1), 10), entry(3, Some(2), 10)]);
+        assert_eq!(pids(&walk(&tree, 3)), vec![2]);
+
+        // equal start times, so only the loop check ends it
+        let tree = HashMap::from([entry(1, Some(3), 10), entry(2, Some(1), 10), entry(3, Some(2), 10)]);
+        assert_eq!(pids(&walk(&tree, 3)), vec![2, 1]);
+        let tree = HashMap::from([entry(1, Some(1), 10)]);
+        assert!(walk(&tree, 1).is_empty());
+    }
+
+    #[test]
+    fn chains_are_cut_at_the_maximum_depth() {
+        let tree: HashMap<u32, TreeEntry> = (1..=100)
+            .map(|pid| entry(pid, (pid > 1).then(|| pid - 1), 10))
+            .collect();
+        let ancestors = walk(&tree, 100);
+        assert_eq!(ancestors.len(), MAX_DEPTH);
+        assert_eq!(ancestors.first().map(|ancestor| ancestor.pid), Some(99));
+    }
+}
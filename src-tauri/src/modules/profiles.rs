@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use crate::models::{
+    AppLimit, ApplicationProcess, Profile, ProfileActivation, ProfileChange, ProfileTrigger, Rule, RuleAction, RuleConditions,
+};
+use crate::modules::{app_limits, rules, schedule, settings};
+use crate::utils::events;
+use crate::{log_info, log_error};
+
+pub const PROFILE_EVENT: &str = "profile-changed";
+
+// a profile a trigger switched to, and what to go back to once it ends
+struct AutoActivation {
+    profile: String,
+    previous: Option<String>,
+    reason: ProfileActivation,
+}
+
+#[derive(Default)]
+struct TriggerState {
+    // profiles whose triggers matched on the last evaluation
+    matched: HashSet<String>,
+    auto: Option<AutoActivation>,
+}
+
+// held for the whole of a switch, so triggers and commands never interleave
+static TRIGGERS: Lazy<Mutex<TriggerState>> = Lazy::new(|| Mutex::new(TriggerState::default()));
+
+// profile limits are matched by path alone
+pub fn validate_limit(limit: &AppLimit) -> Result<(), String> {
+    if limit.sha256.is_some() {
+        return Err(format!("Profile limit for {} cannot pin a hash", limit.path));
+    }
+    app_limits::validate(limit)
+}
+
+pub fn validate(profile: &Profile) -> Result<(), String> {
+    if profile.name.trim().is_empty() {
+        return Err("Profile name cannot be empty".to_string());
+    }
+    for rule in &profile.rules {
+        rules::validate(rule).map_err(|e| format!("Rule {}: {}", rule.name, e))?;
+    }
+    for limit in &profile.limits {
+        validate_limit(limit)?;
+    }
+    for trigger in &profile.triggers {
+        match trigger {
+            ProfileTrigger::Schedule(trigger_schedule) => schedule::validate(trigger_schedule)?,
+            ProfileTrigger::Interface { name } if name.trim().is_empty() => {
+                return Err("Interface triggers need an interface name".to_string());
+            }
+            ProfileTrigger::Interface { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+// The rules the engine runs: the active profile's limits, then its rules,
+// then the ones that apply whatever the profile. Profile entries get ids of
+// their own so their limits can be told apart from the global rules.
+pub fn effective_rules() -> Vec<Rule> {
+    effective_rules_with(settings::active_profile().as_ref())
+}
+
+fn effective_rules_with(profile: Option<&Profile>) -> Vec<Rule> {
+    let mut effective = profile.map(profile_rules).unwrap_or_default();
+    effective.extend(settings::rules());
+    effective
+}
+
+fn profile_rules(profile: &Profile) -> Vec<Rule> {
+    let limits = profile.limits.iter().enumerate().map(|(index, limit)| Rule {
+        id: format!("profile:{}:limit:{}", profile.id, index),
+        name: format!("{}: {}", profile.name, limit.path),
+        enabled: true,
+        conditions: RuleConditions {
+            path: Some(limit.path.clone()),
+            ..RuleConditions::default()
+        },
+        action: if limit.blocked {
+            RuleAction::Block
+        } else {
+            RuleAction::Limit {
+                download_limit: limit.download_limit,
+                upload_limit: limit.upload_limit,
+            }
+        },
+        schedule: limit.schedule.clone(),
+        apply_to_descendants: limit.apply_to_descendants,
+    });
+    let rules = profile.rules.iter().map(|rule| Rule {
+        id: format!("profile:{}:{}", profile.id, rule.id),
+        ..rule.clone()
+    });
+
+    limits.chain(rules).collect()
+}
+
+fn enforce(rules: Vec<Rule>, processes: &[ApplicationProcess]) -> Result<(), String> {
+    rules::reload_with(rules);
+    rules::reconcile(processes)
+}
+
+// Enforces the target profile's rules first and only persists it once they
+// all applied. On failure the saved profile is enforced again, and if even
+// that fails the caller hears about it too.
+fn switch(target: Option<String>, reason: ProfileActivation, processes: &[ApplicationProcess]) -> Result<(), String> {
+    let previous = settings::active_profile().map(|profile| profile.id);
+    if previous == target {
+        return Ok(());
+    }
+    let profile = target.as_ref()
+        .map(|id| settings::profiles().into_iter()
+            .find(|profile| &profile.id == id)
+            .ok_or_else(|| format!("No profile with id {}", id)))
+        .transpose()?;
+
+    let active = target.clone();
+    let switched = enforce(effective_rules_with(profile.as_ref()), processes)
+        .and_then(|_| settings::update(move |settings| settings.active_profile = active).map(|_| ()));
+    if let Err(e) = switched {
+        log_error!("Switching to profile {:?} failed, rolling back: {}", target, e);
+        return match enforce(effective_rules(), processes) {
+            Ok(()) => Err(format!("Failed to switch profiles, the previous one is still active: {}", e)),
+            Err(restore) => Err(format!("Failed to switch profiles: {}; re-applying the previous one failed as well: {}", e, restore)),
+        };
+    }
+
+    log_info!("Switched profile from {:?} to {:?} ({:?})", previous, target, reason);
+    events::emit(PROFILE_EVENT, ProfileChange {
+        previous,
+        active: target,
+        reason,
+    });
+    Ok(())
+}
+
+// None switches back to the global rules alone
+pub fn activate(id: Option<String>, processes: &[ApplicationProcess]) -> Result<(), String> {
+    let mut state = TRIGGERS.lock();
+    switch(id, ProfileActivation::Manual, processes)?;
+    state.auto = None;
+    Ok(())
+}
+
+// after the active profile itself was edited
+pub fn reapply(processes: &[ApplicationProcess]) -> Result<(), String> {
+    let _state = TRIGGERS.lock();
+    enforce(effective_rules(), processes)
+}
+
+fn interface_names() -> HashSet<String> {
+    get_if_addrs::get_if_addrs()
+        .map(|interfaces| {
+            interfaces.into_iter()
+                .filter(|interface| !interface.is_loopback())
+                .map(|interface| interface.name.to_lowercase())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn trigger_match(profile: &Profile, now: DateTime<Utc>, interfaces: &HashSet<String>) -> Option<ProfileActivation> {
+    profile.triggers.iter().find_map(|trigger| match trigger {
+        ProfileTrigger::Schedule(trigger_schedule) => {
+            schedule::is_active(trigger_schedule, now).then_some(ProfileActivation::Schedule)
+        }
+        ProfileTrigger::Interface { name } => {
+            interfaces.contains(&name.to_lowercase()).then_some(ProfileActivation::Interface)
+        }
+    })
+}
+
+pub fn evaluate_triggers(processes: &[ApplicationProcess]) {
+    evaluate_triggers_at(Utc::now(), &interface_names(), processes);
+}
+
+// Triggers act on edges: a profile is switched to when one of its triggers
+// starts matching, and when that stops the profile that was active before
+// comes back, unless someone switched by hand in the meantime.
+pub fn evaluate_triggers_at(now: DateTime<Utc>, interfaces: &HashSet<String>, processes: &[ApplicationProcess]) {
+    let mut state = TRIGGERS.lock();
+    let matched: Vec<(String, ProfileActivation)> = settings::profiles()
+        .iter()
+        .filter_map(|profile| trigger_match(profile, now, interfaces).map(|reason| (profile.id.clone(), reason)))
+        .collect();
+
+    let ended = state.auto.as_ref()
+        .is_some_and(|auto| !matched.iter().any(|(id, _)| *id == auto.profile));
+    if ended {
+        if let Some(auto) = state.auto.take() {
+            let active = settings::active_profile().map(|profile| profile.id);
+            if active.as_deref() == Some(auto.profile.as_str()) {
+                if let Err(e) = switch(auto.previous, auto.reason, processes) {
+                    log_error!("{}", e);
+                }
+            }
+        }
+    }
+
+    let started = matched.iter().find(|(id, _)| !state.matched.contains(id));
+    if let Some((id, reason)) = started {
+        let previous = settings::active_profile().map(|profile| profile.id);
+        if previous.as_deref() != Some(id.as_str()) {
+            match switch(Some(id.clone()), *reason, processes) {
+                Ok(()) => {
+                    state.auto = Some(AutoActivation {
+                        profile: id.clone(),
+                        previous,
+                        reason: *reason,
+                    });
+                }
+                Err(e) => log_error!("{}", e),
+            }
+        }
+    }
+
+    state.matched = matched.into_iter().map(|(id, _)| id).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::models::{Schedule, TimeWindow};
+
+    fn profile(triggers: Vec<ProfileTrigger>) -> Profile {
+        Profile {
+            id: "p".to_string(),
+            name: "Profile".to_string(),
+            rules: Vec::new(),
+            limits: Vec::new(),
+            triggers,
+        }
+    }
+
+    #[test]
+    fn schedule_triggers_follow_their_timezone() {
+        let evening = profile(vec![ProfileTrigger::Schedule(Schedule {
+            windows: vec![TimeWindow { weekdays: vec![5], start: "20:00".to_string(), end: "23:00".to_string() }],
+            timezone: Some("America/New_York".to_string()),
+        })]);
+        let none = HashSet::new();
+
+        // Friday 20:30 in New York is already Saturday in UTC
+        let friday_evening = Utc.with_ymd_and_hms(2026, 10, 17, 0, 30, 0).unwrap();
+        assert_eq!(trigger_match(&evening, friday_evening, &none), Some(ProfileActivation::Schedule));
+        assert_eq!(trigger_match(&evening, Utc.with_ymd_and_hms(2026, 10, 16, 20, 30, 0).unwrap(), &none), None);
+        assert_eq!(trigger_match(&evening, Utc.with_ymd_and_hms(2026, 10, 17, 3, 0, 0).unwrap(), &none), None);
+    }
+
+    #[test]
+    fn interface_triggers_ignore_case() {
+        let vpn = profile(vec![ProfileTrigger::Interface { name: "VPN0".to_string() }]);
+        let now = Utc::now();
+        assert_eq!(trigger_match(&vpn, now, &HashSet::new()), None);
+        assert_eq!(trigger_match(&vpn, now, &["vpn0".to_string()].into()), Some(ProfileActivation::Interface));
+    }
+
+    #[test]
+    fn old_single_window_triggers_still_load() {
+        let trigger: ProfileTrigger = serde_json::from_str(r#"{"type": "Schedule", "weekdays": [], "start": "08:00", "end": "17:00"}"#).unwrap();
+        assert_eq!(trigger, ProfileTrigger::Schedule(Schedule {
+            windows: vec![TimeWindow { weekdays: Vec::new(), start: "08:00".to_string(), end: "17:00".to_string() }],
+            timezone: None,
+        }));
+    }
+}
@@ -0,0 +1,851 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use crate::models::{ByteTotals, ProxySettings, ProxyStatus, RuleAction, TrafficDirection, TransportProtocol, UNATTRIBUTED_PID};
+use crate::modules::limiter::Verdict;
+use crate::modules::network_monitor::NetworkMonitor;
+use crate::modules::rules::{self, Flow, ProcessIdentity, RuleDecision};
+use crate::modules::{firewall, hold, host_labels, settings, throttle};
+use crate::{log_info, log_error, RUNTIME};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+const MAX_HTTP_HEADER_SIZE: usize = 8 * 1024;
+// how long to back off when the limiter has no room at all for a chunk
+const DROP_RETRY: Duration = Duration::from_millis(50);
+// chunks a delayed direction keeps in flight, which bounds its throughput to
+// this many buffers per delay
+const DELAY_QUEUE_CHUNKS: usize = 256;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Socks5,
+    HttpConnect,
+}
+
+#[derive(Debug)]
+enum Target {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReplyError {
+    General,
+    NotAllowed,
+    HostUnreachable,
+    Refused,
+    CommandNotSupported,
+    AddressNotSupported,
+}
+
+impl ReplyError {
+    fn socks_code(self) -> u8 {
+        match self {
+            ReplyError::General => 1,
+            ReplyError::NotAllowed => 2,
+            ReplyError::HostUnreachable => 4,
+            ReplyError::Refused => 5,
+            ReplyError::CommandNotSupported => 7,
+            ReplyError::AddressNotSupported => 8,
+        }
+    }
+
+    fn http_status(self) -> &'static str {
+        match self {
+            ReplyError::NotAllowed => "403 Forbidden",
+            ReplyError::HostUnreachable => "504 Gateway Timeout",
+            ReplyError::CommandNotSupported => "405 Method Not Allowed",
+            ReplyError::AddressNotSupported => "400 Bad Request",
+            ReplyError::General | ReplyError::Refused => "502 Bad Gateway",
+        }
+    }
+}
+
+// Local SOCKS5 / HTTP CONNECT proxy on loopback. Clients are identified
+// through the socket ownership table, their relayed bytes go through the same
+// limiter as throttle_process and are reported as their own traffic.
+struct ProxyServer {
+    monitor: Arc<NetworkMonitor>,
+    listener: RwLock<Option<(ProxySettings, JoinHandle<()>)>>,
+    // local port of each upstream connection -> client pid
+    upstream_ports: RwLock<HashMap<u16, u32>>,
+    active_connections: AtomicUsize,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl ProxyServer {
+    fn new(monitor: Arc<NetworkMonitor>) -> Self {
+        Self {
+            monitor,
+            listener: RwLock::new(None),
+            upstream_ports: RwLock::new(HashMap::new()),
+            active_connections: AtomicUsize::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        }
+    }
+}
+
+// keeps an upstream connection's port registered until it goes out of scope,
+// whichever way the connection ends
+struct UpstreamPort<'a> {
+    server: &'a ProxyServer,
+    port: u16,
+}
+
+impl<'a> UpstreamPort<'a> {
+    fn register(server: &'a ProxyServer, port: u16, pid: u32) -> Self {
+        server.upstream_ports.write().insert(port, pid);
+        Self { server, port }
+    }
+}
+
+impl Drop for UpstreamPort<'_> {
+    fn drop(&mut self) {
+        self.server.upstream_ports.write().remove(&self.port);
+    }
+}
+
+static PROXY: OnceCell<Arc<ProxyServer>> = OnceCell::new();
+
+pub fn init(monitor: Arc<NetworkMonitor>) {
+    PROXY.get_or_init(|| Arc::new(ProxyServer::new(monitor)));
+
+    let proxy_settings = settings::proxy();
+    if proxy_settings.enabled {
+        if let Err(e) = apply(proxy_settings) {
+            log_error!("{}", e);
+        }
+    }
+}
+
+// starts, stops or moves the listener; connections already open are left alone
+pub fn apply(proxy_settings: ProxySettings) -> Result<ProxyStatus, String> {
+    let server = PROXY.get().ok_or("Proxy is not initialized")?;
+    {
+        let mut listener = server.listener.write();
+        let unchanged = listener.as_ref()
+            .is_some_and(|(current, handle)| *current == proxy_settings && !handle.is_finished());
+
+        if !unchanged {
+            if let Some((current, handle)) = listener.take() {
+                log_info!("Stopping proxy on port {}", current.port);
+                handle.abort();
+            }
+
+            if proxy_settings.enabled {
+                let handle = listen(Arc::clone(server), proxy_settings.port)?;
+                *listener = Some((proxy_settings, handle));
+            }
+        }
+    }
+
+    Ok(status())
+}
+
+pub fn status() -> ProxyStatus {
+    let Some(server) = PROXY.get() else {
+        return ProxyStatus {
+            settings: settings::proxy(),
+            running: false,
+            active_connections: 0,
+            bytes_relayed: ByteTotals::default(),
+        };
+    };
+
+    let listener = server.listener.read();
+    ProxyStatus {
+        settings: listener.as_ref().map(|(current, _)| *current).unwrap_or_else(settings::proxy),
+        running: listener.as_ref().is_some_and(|(_, handle)| !handle.is_finished()),
+        active_connections: server.active_connections.load(Ordering::SeqCst),
+        bytes_relayed: ByteTotals {
+            bytes_sent: server.bytes_sent.load(Ordering::SeqCst),
+            bytes_received: server.bytes_received.load(Ordering::SeqCst),
+        },
+    }
+}
+
+pub fn is_upstream_port(port: u16) -> bool {
+    PROXY.get().is_some_and(|server| server.upstream_ports.read().contains_key(&port))
+}
+
+// whether the process has a connection open through the proxy right now
+pub fn has_connections(pid: u32) -> bool {
+    PROXY.get().is_some_and(|server| server.upstream_ports.read().values().any(|&owner| owner == pid))
+}
+
+fn listen(server: Arc<ProxyServer>, port: u16) -> Result<JoinHandle<()>, String> {
+    // bound here rather than in the task so a taken port is reported to the caller
+    let std_listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .map_err(|e| format!("Failed to start proxy on port {}: {}", port, e))?;
+
+    let _runtime = RUNTIME.enter();
+    let listener = TcpListener::from_std(std_listener)
+        .map_err(|e| format!("Failed to start proxy on port {}: {}", port, e))?;
+
+    log_info!("Proxy listening on 127.0.0.1:{}", port);
+    Ok(RUNTIME.spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let server = Arc::clone(&server);
+                    tokio::spawn(async move {
+                        server.active_connections.fetch_add(1, Ordering::SeqCst);
+                        if let Err(e) = handle_client(&server, stream, peer, port).await {
+                            log_info!("Proxy connection from {} closed: {}", peer, e);
+                        }
+                        server.active_connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => {
+                    log_error!("Proxy failed to accept a connection: {}", e);
+                    sleep(DROP_RETRY).await;
+                }
+            }
+        }
+    }))
+}
+
+async fn handle_client(server: &ProxyServer, mut client: TcpStream, peer: SocketAddr, port: u16) -> Result<(), String> {
+    let pid = tokio::task::spawn_blocking(move || find_owner(peer, port))
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or(UNATTRIBUTED_PID);
+
+    let mut first_byte = [0u8; 1];
+    client.peek(&mut first_byte).await.map_err(|e| e.to_string())?;
+    let protocol = if first_byte[0] == SOCKS_VERSION { Protocol::Socks5 } else { Protocol::HttpConnect };
+
+    let (target, pending) = timeout(HANDSHAKE_TIMEOUT, read_request(&mut client, protocol))
+        .await
+        .map_err(|_| "handshake timed out".to_string())??;
+
+    let process = server.monitor.process_identity(pid).unwrap_or_else(|| ProcessIdentity {
+        pid,
+        name: String::new(),
+        path: String::new(),
+        parent_pid: None,
+        ancestors: Vec::new(),
+    });
+    let app_path = Some(process.path.clone()).filter(|path| !path.is_empty());
+    if throttle::limiter().is_blocked(pid) {
+        let _ = reply(&mut client, protocol, Err(ReplyError::NotAllowed)).await;
+        return Err(format!("process {} is blocked", pid));
+    }
+
+    // the connection is decided on before it is made, so nothing reaches a
+    // blocked or held destination; a domain is resolved first to have an address
+    let remote = match timeout(CONNECT_TIMEOUT, resolve(&target)).await {
+        Ok(Ok(remote)) => remote,
+        Ok(Err(e)) => {
+            let _ = reply(&mut client, protocol, Err(ReplyError::HostUnreachable)).await;
+            return Err(format!("could not resolve {:?}: {}", target, e));
+        }
+        Err(_) => {
+            let _ = reply(&mut client, protocol, Err(ReplyError::HostUnreachable)).await;
+            return Err(format!("timed out resolving {:?}", target));
+        }
+    };
+
+    // bound up front so rules see the local port the connection will use
+    let socket = match bind(remote) {
+        Ok(socket) => socket,
+        Err(e) => {
+            let _ = reply(&mut client, protocol, Err(ReplyError::General)).await;
+            return Err(format!("could not open a socket for {}: {}", remote, e));
+        }
+    };
+    let local_port = socket.local_addr().map_err(|e| e.to_string())?.port();
+    // registered as soon as the socket exists, so capture skips all of its packets
+    let _upstream_port = UpstreamPort::register(server, local_port, pid);
+
+    // a stream cannot be blocked one way only, so a block in either direction refuses it
+    let hostname = match &target {
+        Target::Domain(host, _) => {
+            host_labels::record(remote.ip(), host, Duration::ZERO);
+            Some(host.clone())
+        }
+        Target::Addr(_) => host_labels::hostname(remote.ip()),
+    };
+    let decisions = [TrafficDirection::Upload, TrafficDirection::Download].map(|direction| {
+        rules::engine().evaluate(&process, &Flow {
+            pid,
+            protocol: Some(TransportProtocol::Tcp),
+            local_port,
+            remote_addr: remote.ip(),
+            remote_port: remote.port(),
+            direction,
+            hostname: hostname.clone(),
+        })
+    });
+    if decisions.iter().any(|decision| decision.action == Some(RuleAction::Block)) {
+        let _ = reply(&mut client, protocol, Err(ReplyError::NotAllowed)).await;
+        return Err(format!("connection of process {} to {:?} is blocked by a rule", pid, target));
+    }
+    let decided = decisions.iter().any(|decision| decision.rule_id.is_some());
+    if !firewall::admit(&process, remote, hostname, decided).await {
+        let _ = reply(&mut client, protocol, Err(ReplyError::NotAllowed)).await;
+        return Err(format!("connection of process {} to {:?} was not allowed", pid, target));
+    }
+
+    let mut upstream = match timeout(CONNECT_TIMEOUT, socket.connect(remote)).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            let error = match e.kind() {
+                io::ErrorKind::ConnectionRefused => ReplyError::Refused,
+                io::ErrorKind::NotFound | io::ErrorKind::TimedOut => ReplyError::HostUnreachable,
+                _ => ReplyError::General,
+            };
+            let _ = reply(&mut client, protocol, Err(error)).await;
+            return Err(format!("could not connect to {:?}: {}", target, e));
+        }
+        Err(_) => {
+            let _ = reply(&mut client, protocol, Err(ReplyError::HostUnreachable)).await;
+            return Err(format!("timed out connecting to {:?}", target));
+        }
+    };
+    let local = upstream.local_addr().map_err(|e| e.to_string())?;
+
+    reply(&mut client, protocol, Ok(local)).await.map_err(|e| e.to_string())?;
+
+    let context = RelayContext { server, pid, app_path, local, remote, decisions };
+    context.run(&mut client, &mut upstream, &pending).await.map_err(|e| e.to_string())
+}
+
+// the client's end of the loopback connection is the socket whose local port is the peer port
+fn find_owner(peer: SocketAddr, proxy_port: u16) -> Option<u32> {
+    let sockets = netstat2::get_sockets_info(
+        netstat2::AddressFamilyFlags::IPV4 | netstat2::AddressFamilyFlags::IPV6,
+        netstat2::ProtocolFlags::TCP
+    ).ok()?;
+
+    sockets.into_iter().find_map(|socket| match socket.protocol_socket_info {
+        netstat2::ProtocolSocketInfo::Tcp(tcp) if tcp.local_port == peer.port() && tcp.remote_port == proxy_port => {
+            socket.associated_pids.first().copied()
+        }
+        _ => None,
+    })
+}
+
+// the first address a domain resolves to is the one decided on and connected to
+async fn resolve(target: &Target) -> io::Result<SocketAddr> {
+    match target {
+        Target::Addr(addr) => Ok(*addr),
+        Target::Domain(host, port) => lookup_host((host.as_str(), *port)).await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host))),
+    }
+}
+
+fn bind(remote: SocketAddr) -> io::Result<TcpSocket> {
+    let (socket, unspecified) = match remote {
+        SocketAddr::V4(_) => (TcpSocket::new_v4()?, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        SocketAddr::V6(_) => (TcpSocket::new_v6()?, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    socket.bind(SocketAddr::new(unspecified, 0))?;
+    Ok(socket)
+}
+
+// returns the target and any bytes the client sent past the request
+async fn read_request(client: &mut TcpStream, protocol: Protocol) -> Result<(Target, Vec<u8>), String> {
+    match protocol {
+        Protocol::Socks5 => socks5_request(client).await.map(|target| (target, Vec::new())),
+        Protocol::HttpConnect => http_connect_request(client).await,
+    }
+}
+
+async fn socks5_request(client: &mut TcpStream) -> Result<Target, String> {
+    let io_error = |e: io::Error| e.to_string();
+
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting).await.map_err(io_error)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    client.read_exact(&mut methods).await.map_err(io_error)?;
+
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        let _ = client.write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD]).await;
+        return Err("client offered no supported authentication method".to_string());
+    }
+    client.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH]).await.map_err(io_error)?;
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await.map_err(io_error)?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS_VERSION {
+        return Err(format!("unsupported SOCKS version {}", version));
+    }
+
+    let host = match address_type {
+        SOCKS_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            client.read_exact(&mut octets).await.map_err(io_error)?;
+            Ok(IpAddr::from(octets))
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            client.read_exact(&mut octets).await.map_err(io_error)?;
+            Ok(IpAddr::from(octets))
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let length = client.read_u8().await.map_err(io_error)?;
+            let mut name = vec![0u8; length as usize];
+            client.read_exact(&mut name).await.map_err(io_error)?;
+            Err(String::from_utf8_lossy(&name).to_string())
+        }
+        _ => {
+            let _ = reply(client, Protocol::Socks5, Err(ReplyError::AddressNotSupported)).await;
+            return Err(format!("unsupported SOCKS address type {}", address_type));
+        }
+    };
+    let port = client.read_u16().await.map_err(io_error)?;
+
+    if command != SOCKS_CONNECT {
+        let _ = reply(client, Protocol::Socks5, Err(ReplyError::CommandNotSupported)).await;
+        return Err(format!("unsupported SOCKS command {}", command));
+    }
+
+    Ok(match host {
+        Ok(ip) => Target::Addr(SocketAddr::new(ip, port)),
+        Err(domain) => Target::Domain(domain, port),
+    })
+}
+
+async fn http_connect_request(client: &mut TcpStream) -> Result<(Target, Vec<u8>), String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    let header_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if buffer.len() > MAX_HTTP_HEADER_SIZE {
+            let _ = reply(client, Protocol::HttpConnect, Err(ReplyError::AddressNotSupported)).await;
+            return Err("request header too large".to_string());
+        }
+
+        let read = client.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("connection closed during the request".to_string());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+
+    if request_line.next() != Some("CONNECT") {
+        let _ = reply(client, Protocol::HttpConnect, Err(ReplyError::CommandNotSupported)).await;
+        return Err("only CONNECT requests are supported".to_string());
+    }
+
+    match request_line.next().and_then(parse_authority) {
+        Some(target) => Ok((target, buffer[header_end..].to_vec())),
+        None => {
+            let _ = reply(client, Protocol::HttpConnect, Err(ReplyError::AddressNotSupported)).await;
+            Err("invalid CONNECT target".to_string())
+        }
+    }
+}
+
+// host:port, with IPv6 literals in brackets
+fn parse_authority(authority: &str) -> Option<Target> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Some(Target::Addr(addr));
+    }
+
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    (!host.is_empty()).then(|| Target::Domain(host.to_string(), port))
+}
+
+async fn reply(client: &mut TcpStream, protocol: Protocol, outcome: Result<SocketAddr, ReplyError>) -> io::Result<()> {
+    let bytes = match protocol {
+        Protocol::Socks5 => {
+            let (code, bound) = match outcome {
+                Ok(bound) => (0, bound),
+                Err(error) => (error.socks_code(), SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+            };
+
+            let mut bytes = vec![SOCKS_VERSION, code, 0];
+            match bound.ip() {
+                IpAddr::V4(ip) => {
+                    bytes.push(SOCKS_ATYP_IPV4);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    bytes.push(SOCKS_ATYP_IPV6);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+            }
+            bytes.extend_from_slice(&bound.port().to_be_bytes());
+            bytes
+        }
+        Protocol::HttpConnect => match outcome {
+            Ok(_) => b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec(),
+            Err(error) => format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                error.http_status()
+            ).into_bytes(),
+        },
+    };
+
+    client.write_all(&bytes).await
+}
+
+struct RelayContext<'a> {
+    server: &'a ProxyServer,
+    pid: u32,
+    app_path: Option<String>,
+    local: SocketAddr,
+    remote: SocketAddr,
+    // upload, then download
+    decisions: [Arc<RuleDecision>; 2],
+}
+
+impl RelayContext<'_> {
+    async fn run(&self, client: &mut TcpStream, upstream: &mut TcpStream, pending: &[u8]) -> io::Result<()> {
+        if !pending.is_empty() {
+            if let Some(delay) = self.delay(TrafficDirection::Upload) {
+                sleep(delay).await;
+            }
+            self.admit(TrafficDirection::Upload, pending.len()).await?;
+            upstream.write_all(pending).await?;
+            self.record(TrafficDirection::Upload, pending.len());
+        }
+
+        let (client_read, client_write) = client.split();
+        let (upstream_read, upstream_write) = upstream.split();
+
+        // a half-close in one direction leaves the other running, an error ends both
+        tokio::try_join!(
+            self.relay(client_read, upstream_write, TrafficDirection::Upload),
+            self.relay(upstream_read, client_write, TrafficDirection::Download),
+        )?;
+
+        Ok(())
+    }
+
+    fn delay(&self, direction: TrafficDirection) -> Option<Duration> {
+        match self.decisions[direction as usize].action {
+            Some(RuleAction::Delay { ms }) => Some(Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+
+    async fn relay<R, W>(&self, mut from: R, mut to: W, direction: TrafficDirection) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if let Some(delay) = self.delay(direction) {
+            return self.relay_delayed(from, to, direction, delay).await;
+        }
+
+        let mut buffer = vec![0u8; RELAY_BUFFER_SIZE];
+        loop {
+            let read = from.read(&mut buffer).await?;
+            if read == 0 {
+                return to.shutdown().await;
+            }
+
+            self.admit(direction, read).await?;
+            to.write_all(&buffer[..read]).await?;
+            self.record(direction, read);
+        }
+    }
+
+    // Every chunk goes out `delay` after it was read while reading carries on,
+    // so the connection gains latency rather than losing throughput.
+    async fn relay_delayed<R, W>(&self, mut from: R, mut to: W, direction: TrafficDirection, delay: Duration) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let (sender, mut receiver) = mpsc::channel::<(Instant, Vec<u8>)>(DELAY_QUEUE_CHUNKS);
+        let read = async move {
+            let mut buffer = vec![0u8; RELAY_BUFFER_SIZE];
+            loop {
+                let read = from.read(&mut buffer).await?;
+                // dropping the sender lets the writer drain the queue and shut down
+                if read == 0 || sender.send((Instant::now() + delay, buffer[..read].to_vec())).await.is_err() {
+                    return Ok::<(), io::Error>(());
+                }
+            }
+        };
+        let write = async {
+            while let Some((due, chunk)) = receiver.recv().await {
+                sleep_until(due).await;
+                self.admit(direction, chunk.len()).await?;
+                to.write_all(&chunk).await?;
+                self.record(direction, chunk.len());
+            }
+            to.shutdown().await
+        };
+
+        tokio::try_join!(read, write)?;
+        Ok(())
+    }
+
+    // A stream cannot lose bytes, so a dropped chunk just waits for room. For
+    // the same reason a hold that throws data away ends the connection.
+    async fn admit(&self, direction: TrafficDirection, length: usize) -> io::Result<()> {
+        if !hold::wait(self.pid, direction, length as u64).await {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "held traffic was discarded"));
+        }
+        if firewall::is_connection_blocked(self.pid, self.remote) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "connection was blocked"));
+        }
+
+        let decision = &self.decisions[direction as usize];
+        // limits of flow-scoped rules live in a scope shared by every flow they match
+        let scope = match decision.action {
+            Some(RuleAction::Limit { .. }) => decision.rule_id.as_deref().map(rules::scope_key),
+            _ => None,
+        };
+
+        let limiter = throttle::limiter();
+        loop {
+            match limiter.check_scoped(self.pid, self.app_path.as_deref(), scope.as_deref(), direction, length as u64) {
+                Verdict::Send => return Ok(()),
+                Verdict::Delay(delay) => {
+                    sleep(delay).await;
+                    return Ok(());
+                }
+                Verdict::Drop => {
+                    if limiter.is_blocked(self.pid) {
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "process is blocked"));
+                    }
+                    sleep(DROP_RETRY).await;
+                }
+            }
+        }
+    }
+
+    fn record(&self, direction: TrafficDirection, length: usize) {
+        let counter = match direction {
+            TrafficDirection::Upload => &self.server.bytes_sent,
+            TrafficDirection::Download => &self.server.bytes_received,
+        };
+        counter.fetch_add(length as u64, Ordering::SeqCst);
+        self.server.monitor.record_proxied(self.pid, self.local, self.remote, direction, length as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{PortRange, RateConfig, Rule, RuleConditions};
+    use crate::utils::clock::SystemClock;
+
+    fn server() -> ProxyServer {
+        let (_, receiver) = mpsc::channel(1);
+        ProxyServer::new(Arc::new(NetworkMonitor::with_clock(Arc::new(SystemClock::new()), RateConfig::default(), receiver)))
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn upstream_port_is_released_however_the_scope_ends() {
+        let server = server();
+        let failing = || -> Result<(), String> {
+            let _port = UpstreamPort::register(&server, 40_000, 7);
+            assert_eq!(server.upstream_ports.read().get(&40_000), Some(&7));
+            Err("refused".to_string())
+        };
+        assert!(failing().is_err());
+        assert!(server.upstream_ports.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn socks5_relay_over_loopback() {
+        let echo = echo_server().await;
+        let server = Arc::new(server());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let handler = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                handle_client(&server, stream, peer, proxy_port).await
+            }
+        });
+
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, proxy_port)).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTH]).await.unwrap();
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [SOCKS_VERSION, SOCKS_NO_AUTH]);
+
+        let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_ATYP_IPV4, 127, 0, 0, 1];
+        request.extend(echo.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        // the bound address in the reply is the upstream connection's own end
+        let upstream_port = u16::from_be_bytes([reply[8], reply[9]]);
+        assert!(server.upstream_ports.read().contains_key(&upstream_port));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        drop(client);
+        let _ = handler.await.unwrap();
+        assert!(server.upstream_ports.read().is_empty());
+        assert_eq!(server.bytes_sent.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn blocked_connections_never_reach_the_destination() {
+        let destination = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let destination_port = destination.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let accepted = Arc::clone(&accepted);
+            async move {
+                while destination.accept().await.is_ok() {
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+        rules::engine().set_rules(&[Rule {
+            id: "block-destination".to_string(),
+            name: "Block destination".to_string(),
+            enabled: true,
+            conditions: RuleConditions {
+                remote_ports: vec![PortRange { start: destination_port, end: destination_port }],
+                ..RuleConditions::default()
+            },
+            action: RuleAction::Block,
+            schedule: None,
+            apply_to_descendants: false,
+        }]);
+
+        let server = Arc::new(server());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let handler = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                handle_client(&server, stream, peer, proxy_port).await
+            }
+        });
+
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, proxy_port)).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTH]).await.unwrap();
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_ATYP_IPV4, 127, 0, 0, 1];
+        request.extend(destination_port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], ReplyError::NotAllowed.socks_code());
+
+        assert!(handler.await.unwrap().is_err());
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 0);
+        assert!(server.upstream_ports.read().is_empty());
+        rules::engine().set_rules(&[]);
+    }
+
+    #[tokio::test]
+    async fn delayed_chunks_are_time_shifted_not_serialised() {
+        let server = server();
+        let delay = RuleDecision { action: Some(RuleAction::Delay { ms: 200 }), ..RuleDecision::default() };
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        let context = RelayContext {
+            server: &server,
+            pid: 7,
+            app_path: None,
+            local: addr,
+            remote: addr,
+            decisions: [Arc::new(delay), Arc::new(RuleDecision::default())],
+        };
+        let (mut client, relay_in) = tokio::io::duplex(1024);
+        let (relay_out, mut upstream) = tokio::io::duplex(1024);
+
+        let started = std::time::Instant::now();
+        let writer = async {
+            for _ in 0..10 {
+                client.write_all(b"chunk").await.unwrap();
+                sleep(Duration::from_millis(20)).await;
+            }
+            drop(client);
+        };
+        let reader = async {
+            let mut received = Vec::new();
+            upstream.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (relayed, _, received) = tokio::join!(context.relay(relay_in, relay_out, TrafficDirection::Upload), writer, reader);
+
+        relayed.unwrap();
+        assert_eq!(received, b"chunk".repeat(10));
+        // ten chunks 20 ms apart arrive 200 ms late, not 200 ms each
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(380) && elapsed < Duration::from_millis(1_000), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn refused_targets_register_nothing() {
+        let closed = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap().local_addr().unwrap();
+        let server = Arc::new(server());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let handler = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                handle_client(&server, stream, peer, proxy_port).await
+            }
+        });
+
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, proxy_port)).await.unwrap();
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", closed, closed).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+
+        assert!(handler.await.unwrap().is_err());
+        assert!(server.upstream_ports.read().is_empty());
+    }
+}
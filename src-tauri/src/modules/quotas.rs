@@ -0,0 +1,429 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use chrono::{Datelike, Duration as ChronoDuration, Local, NaiveDate};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use crate::models::{
+    ApplicationProcess, Quota, QuotaAction, QuotaDirection, QuotaEvent, QuotaPeriod, QuotaScope, QuotaStatus, QuotaThreshold, UNATTRIBUTED_PID,
+};
+use crate::modules::settings;
+use crate::modules::throttle::{self, LimitSource, ProcessLimit};
+use crate::modules::usage_store::UsageStore;
+use crate::utils::events;
+use crate::utils::paths::data_dir;
+use crate::{log_info, log_error};
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const QUOTA_STATE_FILE: &str = "quota_state.json";
+pub const QUOTA_EVENT: &str = "quota-threshold";
+
+struct QuotaState {
+    period_start: NaiveDate,
+    triggered: BTreeSet<u32>,
+}
+
+// what fired in which period, so a restart doesn't notify again
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    period_start: String,
+    triggered: BTreeSet<u32>,
+}
+
+fn load(path: &PathBuf) -> HashMap<String, QuotaState> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    let saved: HashMap<String, SavedState> = serde_json::from_str(&contents).unwrap_or_else(|e| {
+        log_error!("Failed to parse quota state at {}: {}", path.display(), e);
+        HashMap::new()
+    });
+
+    saved.into_iter()
+        .filter_map(|(id, saved)| {
+            let period_start = NaiveDate::parse_from_str(&saved.period_start, DATE_FORMAT).ok()?;
+            Some((id, QuotaState { period_start, triggered: saved.triggered }))
+        })
+        .collect()
+}
+
+fn save(path: &PathBuf, state: &HashMap<String, QuotaState>) -> Result<(), String> {
+    let saved: HashMap<&String, SavedState> = state.iter()
+        .map(|(id, state)| (id, SavedState {
+            period_start: state.period_start.format(DATE_FORMAT).to_string(),
+            triggered: state.triggered.clone(),
+        }))
+        .collect();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create data directory: {}", e))?;
+    }
+
+    let contents = serde_json::to_string(&saved).map_err(|e| format!("Failed to serialize quota state: {}", e))?;
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, contents)
+        .and_then(|_| fs::rename(&temp_path, path))
+        .map_err(|e| format!("Failed to write quota state: {}", e))
+}
+
+// Checks quotas from the settings against the persisted usage, fires each
+// threshold once per billing period and keeps its throttle/block applied to
+// matching processes until the period rolls over.
+pub struct QuotaMonitor {
+    path: Option<PathBuf>,
+    state: RwLock<HashMap<String, QuotaState>>,
+}
+
+impl QuotaMonitor {
+    pub fn new() -> Self {
+        Self::open_at(data_dir().map(|dir| dir.join(QUOTA_STATE_FILE)))
+    }
+
+    fn open_at(path: Option<PathBuf>) -> Self {
+        let state = path.as_ref().map(load).unwrap_or_default();
+        Self {
+            path,
+            state: RwLock::new(state),
+        }
+    }
+
+    fn persist(&self, state: &HashMap<String, QuotaState>) {
+        if let Some(path) = &self.path {
+            if let Err(e) = save(path, state) {
+                log_error!("{}", e);
+            }
+        }
+    }
+
+    pub fn evaluate(&self, usage: &UsageStore, processes: &[ApplicationProcess]) {
+        let quotas = settings::quotas();
+        let today = Local::now().date_naive();
+        let mut state = self.state.write();
+        let mut changed = false;
+
+        // quotas that were deleted or disabled since the last run
+        state.retain(|id, _| {
+            let keep = quotas.iter().any(|quota| &quota.id == id && quota.enabled);
+            if !keep {
+                lift(id);
+                changed = true;
+            }
+            keep
+        });
+
+        for quota in quotas.iter().filter(|quota| quota.enabled) {
+            let (period_start, _) = period_bounds(quota.period, quota.reset_day, today);
+            let quota_state = state.entry(quota.id.clone()).or_insert_with(|| {
+                changed = true;
+                QuotaState {
+                    period_start,
+                    triggered: BTreeSet::new(),
+                }
+            });
+
+            if quota_state.period_start != period_start {
+                log_info!("Quota {} rolled over to a new period", quota.name);
+                lift(&quota.id);
+                quota_state.period_start = period_start;
+                quota_state.triggered.clear();
+                changed = true;
+            }
+
+            let used_bytes = used_bytes(quota, usage, period_start);
+            let percent = usage_percent(used_bytes, quota.limit_bytes);
+            let mut enforced = None;
+
+            for threshold in reached(&quota.thresholds, percent) {
+                if quota_state.triggered.insert(threshold.percent) {
+                    changed = true;
+                    log_info!("Quota {} reached {}% ({} of {} bytes)", quota.name, threshold.percent, used_bytes, quota.limit_bytes);
+                    events::emit(QUOTA_EVENT, QuotaEvent {
+                        quota_id: quota.id.clone(),
+                        name: quota.name.clone(),
+                        percent: threshold.percent,
+                        action: threshold.action.clone(),
+                        used_bytes,
+                        limit_bytes: quota.limit_bytes,
+                    });
+                }
+
+                enforced = strongest(enforced, &threshold.action);
+            }
+
+            // re-applied every run so processes started after the threshold are covered too
+            if let Some(action) = enforced {
+                enforce(quota, action, processes);
+            }
+        }
+
+        if changed {
+            self.persist(&state);
+        }
+    }
+
+    // forget what fired so an edited quota is evaluated from scratch
+    pub fn reset(&self, id: &str) {
+        let mut state = self.state.write();
+        if state.remove(id).is_some() {
+            self.persist(&state);
+        }
+        lift(id);
+    }
+
+    pub fn status(&self, usage: &UsageStore) -> Vec<QuotaStatus> {
+        let today = Local::now().date_naive();
+        let state = self.state.read();
+
+        settings::quotas()
+            .into_iter()
+            .map(|quota| {
+                let (period_start, period_end) = period_bounds(quota.period, quota.reset_day, today);
+                let used_bytes = used_bytes(&quota, usage, period_start);
+                let triggered = state.get(&quota.id)
+                    .filter(|s| s.period_start == period_start)
+                    .map(|s| s.triggered.iter().copied().collect())
+                    .unwrap_or_default();
+
+                QuotaStatus {
+                    period_start: period_start.format(DATE_FORMAT).to_string(),
+                    period_end: period_end.format(DATE_FORMAT).to_string(),
+                    used_bytes,
+                    percent: usage_percent(used_bytes, quota.limit_bytes),
+                    triggered,
+                    quota,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for QuotaMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn validate(quota: &Quota) -> Result<(), String> {
+    if quota.name.trim().is_empty() {
+        return Err("Quota name cannot be empty".to_string());
+    }
+    if quota.limit_bytes == 0 {
+        return Err("Quota limit must be greater than zero".to_string());
+    }
+    match quota.period {
+        QuotaPeriod::Weekly if !(1..=7).contains(&quota.reset_day) => {
+            return Err("Weekly reset day must be between 1 (Monday) and 7 (Sunday)".to_string());
+        }
+        QuotaPeriod::Monthly if !(1..=31).contains(&quota.reset_day) => {
+            return Err("Monthly reset day must be between 1 and 31".to_string());
+        }
+        _ => {}
+    }
+    if quota.thresholds.iter().any(|t| t.percent == 0) {
+        return Err("Threshold percentages must be greater than zero".to_string());
+    }
+    if let QuotaScope::Application { path } = &quota.scope {
+        if path.is_empty() {
+            return Err("Application quotas need an executable path".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+fn scope_matches(scope: &QuotaScope, path: &str, category: &str) -> bool {
+    match scope {
+        QuotaScope::Global => true,
+        QuotaScope::Application { path: quota_path } => quota_path.eq_ignore_ascii_case(path),
+        QuotaScope::Category { category: quota_category } => quota_category == category,
+    }
+}
+
+fn used_bytes(quota: &Quota, usage: &UsageStore, period_start: NaiveDate) -> u64 {
+    let totals = usage.totals_since(period_start, |path, category| scope_matches(&quota.scope, path, category));
+
+    match quota.direction {
+        QuotaDirection::Both => totals.bytes_sent + totals.bytes_received,
+        QuotaDirection::Download => totals.bytes_received,
+        QuotaDirection::Upload => totals.bytes_sent,
+    }
+}
+
+fn usage_percent(used_bytes: u64, limit_bytes: u64) -> f64 {
+    if limit_bytes == 0 {
+        0.0
+    } else {
+        used_bytes as f64 * 100.0 / limit_bytes as f64
+    }
+}
+
+// lowest first, however they are listed, so higher thresholds are applied last
+fn reached(thresholds: &[QuotaThreshold], percent: f64) -> Vec<&QuotaThreshold> {
+    let mut reached: Vec<&QuotaThreshold> = thresholds.iter()
+        .filter(|threshold| percent >= threshold.percent as f64)
+        .collect();
+    reached.sort_by_key(|threshold| threshold.percent);
+    reached
+}
+
+// block beats throttle, and of thresholds taken lowest first, the last throttle wins
+fn strongest<'a>(current: Option<&'a QuotaAction>, next: &'a QuotaAction) -> Option<&'a QuotaAction> {
+    match (current, next) {
+        (_, QuotaAction::Notify) => current,
+        (Some(QuotaAction::Block), _) => current,
+        _ => Some(next),
+    }
+}
+
+fn enforce(quota: &Quota, action: &QuotaAction, processes: &[ApplicationProcess]) {
+    let limit = match action {
+        QuotaAction::Notify => return,
+        QuotaAction::Throttle { download_limit, upload_limit } => ProcessLimit {
+            download_limit: *download_limit,
+            upload_limit: *upload_limit,
+            blocked: false,
+            source: LimitSource::Quota(quota.id.clone()),
+            inherited_from: None,
+        },
+        QuotaAction::Block => ProcessLimit {
+            download_limit: 0,
+            upload_limit: 0,
+            blocked: true,
+            source: LimitSource::Quota(quota.id.clone()),
+            inherited_from: None,
+        },
+    };
+
+    // a throttle the user set by hand wins over a quota throttle, but not over a block
+    // the unattributed bucket is not a process and can't be throttled
+    let matching = processes.iter()
+        .filter(|p| p.pid != UNATTRIBUTED_PID && scope_matches(&quota.scope, &p.path, &p.category));
+    for process in matching {
+        if let Err(e) = throttle::set_limit(process.pid, limit.clone()) {
+            log_error!("Failed to enforce quota {} on process {}: {}", quota.name, process.pid, e);
+        }
+    }
+}
+
+fn lift(id: &str) {
+    let pids = throttle::remove_by_source(&LimitSource::Quota(id.to_string()));
+    if !pids.is_empty() {
+        log_info!("Lifted quota {} limits from {} processes", id, pids.len());
+    }
+}
+
+// start is inclusive, end exclusive
+pub fn period_bounds(period: QuotaPeriod, reset_day: u32, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+    match period {
+        QuotaPeriod::Daily => (today, today + ChronoDuration::days(1)),
+        QuotaPeriod::Weekly => {
+            let reset_day = reset_day.clamp(1, 7);
+            let weekday = today.weekday().number_from_monday();
+            let start = today - ChronoDuration::days(((weekday + 7 - reset_day) % 7) as i64);
+            (start, start + ChronoDuration::days(7))
+        }
+        QuotaPeriod::Monthly => {
+            let this_month = clamped_date(today.year(), today.month(), reset_day);
+            let start = if today >= this_month {
+                this_month
+            } else {
+                let (year, month) = previous_month(today.year(), today.month());
+                clamped_date(year, month, reset_day)
+            };
+            let (year, month) = next_month(start.year(), start.month());
+            (start, clamped_date(year, month, reset_day))
+        }
+    }
+}
+
+// reset day 31 lands on the last day of shorter months
+fn clamped_date(year: i32, month: u32, day: u32) -> NaiveDate {
+    let mut day = day.clamp(1, 31);
+    loop {
+        if let Some(date) = NaiveDate::from_ymd_opt(year, month, day) {
+            return date;
+        }
+        day -= 1;
+    }
+}
+
+fn previous_month(year: i32, month: u32) -> (i32, u32) {
+    if month == 1 { (year - 1, 12) } else { (year, month - 1) }
+}
+
+fn next_month(year: i32, month: u32) -> (i32, u32) {
+    if month == 12 { (year + 1, 1) } else { (year, month + 1) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn monthly_period_clamps_to_short_months() {
+        assert_eq!(period_bounds(QuotaPeriod::Monthly, 31, date(2025, 2, 10)), (date(2025, 1, 31), date(2025, 2, 28)));
+        assert_eq!(period_bounds(QuotaPeriod::Monthly, 31, date(2025, 2, 28)), (date(2025, 2, 28), date(2025, 3, 31)));
+        assert_eq!(period_bounds(QuotaPeriod::Monthly, 1, date(2025, 12, 31)), (date(2025, 12, 1), date(2026, 1, 1)));
+        assert_eq!(period_bounds(QuotaPeriod::Monthly, 15, date(2025, 1, 3)), (date(2024, 12, 15), date(2025, 1, 15)));
+    }
+
+    #[test]
+    fn weekly_period_starts_on_the_reset_day() {
+        // 2025-03-12 is a Wednesday
+        assert_eq!(period_bounds(QuotaPeriod::Weekly, 1, date(2025, 3, 12)), (date(2025, 3, 10), date(2025, 3, 17)));
+        assert_eq!(period_bounds(QuotaPeriod::Weekly, 3, date(2025, 3, 12)), (date(2025, 3, 12), date(2025, 3, 19)));
+        assert_eq!(period_bounds(QuotaPeriod::Weekly, 4, date(2025, 3, 12)), (date(2025, 3, 6), date(2025, 3, 13)));
+    }
+
+    #[test]
+    fn stronger_actions_win() {
+        let throttle = QuotaAction::Throttle { download_limit: 1000, upload_limit: 1000 };
+        assert_eq!(strongest(None, &QuotaAction::Notify), None);
+        assert_eq!(strongest(None, &throttle), Some(&throttle));
+        assert_eq!(strongest(Some(&throttle), &QuotaAction::Block), Some(&QuotaAction::Block));
+        assert_eq!(strongest(Some(&QuotaAction::Block), &throttle), Some(&QuotaAction::Block));
+    }
+
+    #[test]
+    fn the_highest_reached_throttle_wins_whatever_the_order() {
+        let throttle = |limit: u64| QuotaAction::Throttle { download_limit: limit, upload_limit: limit };
+        let thresholds = vec![
+            QuotaThreshold { percent: 90, action: throttle(10) },
+            QuotaThreshold { percent: 50, action: throttle(100) },
+            QuotaThreshold { percent: 75, action: QuotaAction::Notify },
+        ];
+        let enforced = |percent: f64| reached(&thresholds, percent).into_iter()
+            .fold(None, |enforced, threshold| strongest(enforced, &threshold.action));
+
+        assert_eq!(enforced(40.0), None);
+        assert_eq!(enforced(80.0), Some(&throttle(100)));
+        assert_eq!(enforced(95.0), Some(&throttle(10)));
+        assert_eq!(reached(&thresholds, 95.0).iter().map(|threshold| threshold.percent).collect::<Vec<_>>(), vec![50, 75, 90]);
+    }
+
+    #[test]
+    fn triggered_thresholds_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("meridian-quotas-{}", std::process::id()));
+        let path = dir.join(QUOTA_STATE_FILE);
+        let _ = fs::remove_dir_all(&dir);
+
+        let monitor = QuotaMonitor::open_at(Some(path.clone()));
+        let mut state = monitor.state.write();
+        state.insert("q1".to_string(), QuotaState {
+            period_start: date(2025, 3, 1),
+            triggered: [50, 90].into_iter().collect(),
+        });
+        monitor.persist(&state);
+        drop(state);
+
+        let reopened = QuotaMonitor::open_at(Some(path));
+        let state = reopened.state.read();
+        let restored = &state["q1"];
+        assert_eq!(restored.period_start, date(2025, 3, 1));
+        assert_eq!(restored.triggered.iter().copied().collect::<Vec<_>>(), vec![50, 90]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
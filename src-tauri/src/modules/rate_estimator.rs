@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use crate::models::RateConfig;
+
+// Time-bucketed sliding window. Amounts are summed into fixed-width buckets
+// keyed by clock time, so the rate only depends on what was recorded and when,
+// never on how often it is sampled.
+#[derive(Debug, Clone)]
+pub struct RateEstimator {
+    bucket_width: Duration,
+    bucket_count: u64,
+    half_life: Option<Duration>,
+    buckets: VecDeque<(u64, f64)>,
+    first_bucket: Option<u64>,
+}
+
+impl RateEstimator {
+    pub fn new(config: &RateConfig) -> Self {
+        let bucket_ms = config.bucket_ms.max(1);
+        let bucket_count = (config.window_ms / bucket_ms).max(1);
+
+        Self {
+            bucket_width: Duration::from_millis(bucket_ms),
+            bucket_count,
+            half_life: if config.smoothing_half_life_ms > 0 {
+                Some(Duration::from_millis(config.smoothing_half_life_ms))
+            } else {
+                None
+            },
+            buckets: VecDeque::with_capacity(bucket_count as usize),
+            first_bucket: None,
+        }
+    }
+
+    fn bucket_index(&self, now: Duration) -> u64 {
+        (now.as_nanos() / self.bucket_width.as_nanos()) as u64
+    }
+
+    pub fn record(&mut self, now: Duration, amount: f64) {
+        let index = self.bucket_index(now);
+        self.first_bucket.get_or_insert(index);
+
+        match self.buckets.back_mut() {
+            Some((last, total)) if *last == index => *total += amount,
+            // late samples (clock jitter between threads) go into the newest bucket
+            Some((last, total)) if *last > index => *total += amount,
+            _ => self.buckets.push_back((index, amount)),
+        }
+
+        let oldest_kept = index.saturating_sub(self.bucket_count - 1);
+        while matches!(self.buckets.front(), Some((i, _)) if *i < oldest_kept) {
+            self.buckets.pop_front();
+        }
+    }
+
+    // amount per second over the window ending at `now`
+    pub fn rate(&self, now: Duration) -> f64 {
+        let Some(first_bucket) = self.first_bucket else {
+            return 0.0;
+        };
+
+        let current = self.bucket_index(now);
+        let oldest = current
+            .saturating_sub(self.bucket_count - 1)
+            .max(first_bucket);
+
+        let bucket_secs = self.bucket_width.as_secs_f64();
+        let current_start = current as u128 * self.bucket_width.as_nanos();
+        let current_elapsed = (now.as_nanos() - current_start) as f64 / 1e9;
+
+        let mut weighted_amount = 0.0;
+        let mut weighted_time = 0.0;
+
+        for index in oldest..=current {
+            let age = current - index;
+            let weight = self.weight(age);
+            let span = if age == 0 { current_elapsed } else { bucket_secs };
+            weighted_time += span * weight;
+        }
+
+        for &(index, amount) in self.buckets.iter() {
+            if index < oldest || index > current {
+                continue;
+            }
+            weighted_amount += amount * self.weight(current - index);
+        }
+
+        // at least one full bucket, so a lone first packet doesn't read as a spike
+        weighted_amount / weighted_time.max(bucket_secs)
+    }
+
+    pub fn is_idle(&self, now: Duration) -> bool {
+        let oldest = self.bucket_index(now).saturating_sub(self.bucket_count - 1);
+        self.buckets.iter().all(|&(index, _)| index < oldest)
+    }
+
+    fn weight(&self, age_in_buckets: u64) -> f64 {
+        match self.half_life {
+            Some(half_life) => {
+                let age = self.bucket_width.as_secs_f64() * age_in_buckets as f64;
+                0.5f64.powf(age / half_life.as_secs_f64())
+            }
+            None => 1.0,
+        }
+    }
+}
+
+// byte and packet rates in both directions
+#[derive(Debug, Clone)]
+pub struct TrafficRates {
+    upload_bytes: RateEstimator,
+    download_bytes: RateEstimator,
+    upload_packets: RateEstimator,
+    download_packets: RateEstimator,
+}
+
+impl TrafficRates {
+    pub fn new(config: &RateConfig) -> Self {
+        Self {
+            upload_bytes: RateEstimator::new(config),
+            download_bytes: RateEstimator::new(config),
+            upload_packets: RateEstimator::new(config),
+            download_packets: RateEstimator::new(config),
+        }
+    }
+
+    pub fn record_upload(&mut self, now: Duration, bytes: u64) {
+        self.upload_bytes.record(now, bytes as f64);
+        self.upload_packets.record(now, 1.0);
+    }
+
+    pub fn record_download(&mut self, now: Duration, bytes: u64) {
+        self.download_bytes.record(now, bytes as f64);
+        self.download_packets.record(now, 1.0);
+    }
+
+    pub fn upload_rate(&self, now: Duration) -> f64 {
+        self.upload_bytes.rate(now)
+    }
+
+    pub fn download_rate(&self, now: Duration) -> f64 {
+        self.download_bytes.rate(now)
+    }
+
+    pub fn upload_pps(&self, now: Duration) -> f64 {
+        self.upload_packets.rate(now)
+    }
+
+    pub fn download_pps(&self, now: Duration) -> f64 {
+        self.download_packets.rate(now)
+    }
+
+    pub fn is_idle(&self, now: Duration) -> bool {
+        self.upload_bytes.is_idle(now) && self.download_bytes.is_idle(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::{Clock, ManualClock};
+
+    fn config(window_ms: u64, bucket_ms: u64, smoothing_half_life_ms: u64) -> RateConfig {
+        RateConfig { window_ms, bucket_ms, smoothing_half_life_ms }
+    }
+
+    // 100 bytes every 10 ms, i.e. 10 000 bytes/s, for `duration`
+    fn feed(estimator: &mut RateEstimator, clock: &ManualClock, duration: Duration) {
+        let end = clock.now() + duration;
+        while clock.now() < end {
+            estimator.record(clock.now(), 100.0);
+            clock.advance(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn nothing_recorded_is_zero() {
+        let estimator = RateEstimator::new(&RateConfig::default());
+        assert_eq!(estimator.rate(Duration::from_secs(5)), 0.0);
+    }
+
+    #[test]
+    fn first_packet_counts_over_a_full_bucket() {
+        let clock = ManualClock::new(Duration::from_secs(10));
+        let mut estimator = RateEstimator::new(&config(2000, 100, 0));
+        estimator.record(clock.now(), 1000.0);
+        clock.advance(Duration::from_micros(50));
+        assert_eq!(estimator.rate(clock.now()), 10_000.0);
+    }
+
+    #[test]
+    fn steady_stream_reads_steady_at_every_phase() {
+        let clock = ManualClock::default();
+        let mut estimator = RateEstimator::new(&config(2000, 100, 0));
+        feed(&mut estimator, &clock, Duration::from_secs(3));
+        for _ in 0..10 {
+            let rate = estimator.rate(clock.now());
+            assert!((rate - 10_000.0).abs() < 600.0, "rate {} at {:?}", rate, clock.now());
+            feed(&mut estimator, &clock, Duration::from_millis(30));
+        }
+    }
+
+    #[test]
+    fn rate_drops_to_zero_after_the_window() {
+        let clock = ManualClock::default();
+        let mut estimator = RateEstimator::new(&config(500, 50, 0));
+        feed(&mut estimator, &clock, Duration::from_secs(1));
+        assert!(!estimator.is_idle(clock.now()));
+
+        clock.advance(Duration::from_millis(550));
+        assert!(estimator.is_idle(clock.now()));
+        assert_eq!(estimator.rate(clock.now()), 0.0);
+    }
+
+    #[test]
+    fn shorter_window_follows_a_change_sooner() {
+        let clock = ManualClock::default();
+        let mut short = RateEstimator::new(&config(200, 50, 0));
+        let mut long = RateEstimator::new(&config(2000, 50, 0));
+        for estimator in [&mut short, &mut long] {
+            clock.set(Duration::ZERO);
+            feed(estimator, &clock, Duration::from_secs(2));
+        }
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(short.rate(clock.now()), 0.0);
+        assert!(long.rate(clock.now()) > 5_000.0);
+    }
+
+    #[test]
+    fn smoothing_favours_recent_buckets() {
+        let clock = ManualClock::default();
+        let mut even = RateEstimator::new(&config(2000, 100, 0));
+        let mut smoothed = RateEstimator::new(&config(2000, 100, 200));
+        for estimator in [&mut even, &mut smoothed] {
+            clock.set(Duration::ZERO);
+            feed(estimator, &clock, Duration::from_secs(1));
+        }
+        clock.advance(Duration::from_millis(500));
+        assert!(smoothed.rate(clock.now()) < even.rate(clock.now()));
+    }
+
+    #[test]
+    fn late_samples_land_in_the_newest_bucket() {
+        let mut estimator = RateEstimator::new(&config(1000, 100, 0));
+        estimator.record(Duration::from_millis(450), 100.0);
+        estimator.record(Duration::from_millis(120), 100.0);
+        assert_eq!(estimator.rate(Duration::from_millis(499)), 2000.0);
+    }
+}
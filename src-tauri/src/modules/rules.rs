@@ -0,0 +1,713 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use crate::models::{
+    ApplicationProcess, ByteRate, PortRange, ProcessNetworkUsage, Rule, RuleAction, RuleConditions, ScheduleStatus, ScopeUsage, TrafficDirection,
+    TransportProtocol, UNATTRIBUTED_PID,
+};
+use crate::modules::limiter::BandwidthLimit;
+use crate::modules::{process_tree, profiles, schedule, settings};
+use crate::modules::throttle::{self, LimitSource, ProcessLimit};
+use crate::{log_info, log_error};
+
+// past this many flows the cache starts over rather than tracking recency
+const FLOW_CACHE_LIMIT: usize = 65_536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // a bare address is a /32 or /128
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid CIDR '{}'", text);
+        let (addr, prefix) = match text.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (text.trim(), None),
+        };
+
+        let network: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (network, addr, bits) = match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => (u32::from(network) as u128, u32::from(addr) as u128, 32),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(network), u128::from(addr), 128),
+            _ => return false,
+        };
+
+        let host_bits = bits - self.prefix as u32;
+        host_bits == bits || network >> host_bits == addr >> host_bits
+    }
+}
+
+// case-insensitive * and ? matching; / and \ are treated alike so paths match on any platform
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let normalize = |s: &str| -> Vec<char> {
+        s.chars().map(|c| if c == '/' { '\\' } else { c.to_ascii_lowercase() }).collect()
+    };
+    let pattern = normalize(pattern);
+    let text = normalize(text);
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last star swallow one more character
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn in_ranges(ranges: &[PortRange], port: u16) -> bool {
+    ranges.is_empty() || ranges.iter().any(|range| (range.start..=range.end).contains(&port))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessIdentity {
+    pub pid: u32,
+    pub name: String,
+    pub path: String,
+    pub parent_pid: Option<u32>,
+    // nearest first, for rules that apply to descendants
+    pub ancestors: Vec<ProcessIdentity>,
+}
+
+impl From<&ApplicationProcess> for ProcessIdentity {
+    fn from(process: &ApplicationProcess) -> Self {
+        Self {
+            pid: process.pid,
+            name: process.name.clone(),
+            path: process.path.clone(),
+            parent_pid: process.parent_pid,
+            ancestors: process_tree::ancestors(process.pid),
+        }
+    }
+}
+
+// one direction of a connection as seen from the local process
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub pid: u32,
+    pub protocol: Option<TransportProtocol>,
+    pub local_port: u16,
+    pub remote_addr: IpAddr,
+    pub remote_port: u16,
+    pub direction: TrafficDirection,
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleDecision {
+    // the first matching limit, block, delay or allow rule; later ones are not consulted
+    pub rule_id: Option<String>,
+    pub action: Option<RuleAction>,
+    // the first matching mark and the rule it came from, and every log rule on the way
+    pub mark: Option<u32>,
+    pub marked_by: Option<String>,
+    pub logged_by: Vec<String>,
+}
+
+struct CompiledRule {
+    rule: Rule,
+    cidrs: Vec<Cidr>,
+}
+
+impl CompiledRule {
+    fn compile(rule: &Rule) -> Result<Self, String> {
+        let cidrs = rule.conditions.remote_cidrs.iter()
+            .map(|cidr| Cidr::parse(cidr))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self {
+            rule: rule.clone(),
+            cidrs,
+        })
+    }
+
+    fn is_process_scoped(&self) -> bool {
+        is_process_scoped(&self.rule.conditions)
+    }
+
+    fn matches_own(&self, process: &ProcessIdentity) -> bool {
+        let conditions = &self.rule.conditions;
+        conditions.pid.is_none_or(|pid| pid == process.pid)
+            && conditions.parent_pid.is_none_or(|parent| process.parent_pid == Some(parent))
+            && conditions.path.as_deref().is_none_or(|pattern| glob_matches(pattern, &process.path))
+            && conditions.process_name.as_deref().is_none_or(|pattern| glob_matches(pattern, &process.name))
+    }
+
+    // Some(None) when the process matches itself, Some(Some(pid)) when the
+    // rule reaches it through the ancestor with that pid
+    fn process_match(&self, process: &ProcessIdentity) -> Option<Option<u32>> {
+        if self.matches_own(process) {
+            return Some(None);
+        }
+        if !self.rule.apply_to_descendants {
+            return None;
+        }
+        process.ancestors.iter()
+            .find(|ancestor| self.matches_own(ancestor))
+            .map(|ancestor| Some(ancestor.pid))
+    }
+
+    fn matches_process(&self, process: &ProcessIdentity) -> bool {
+        self.process_match(process).is_some()
+    }
+
+    fn matches_flow(&self, process: &ProcessIdentity, flow: &Flow) -> bool {
+        let conditions = &self.rule.conditions;
+        self.matches_process(process)
+            && conditions.protocol.is_none_or(|protocol| flow.protocol == Some(protocol))
+            && conditions.direction.is_none_or(|direction| direction == flow.direction)
+            && in_ranges(&conditions.local_ports, flow.local_port)
+            && in_ranges(&conditions.remote_ports, flow.remote_port)
+            && (self.cidrs.is_empty() || self.cidrs.iter().any(|cidr| cidr.contains(flow.remote_addr)))
+            && (conditions.remote_hosts.is_empty() || flow.hostname.as_deref().is_some_and(|hostname| {
+                conditions.remote_hosts.iter().any(|pattern| glob_matches(pattern, hostname))
+            }))
+    }
+}
+
+// rules that only look at the process can be enforced per process by any backend
+fn is_process_scoped(conditions: &RuleConditions) -> bool {
+    conditions.protocol.is_none()
+        && conditions.local_ports.is_empty()
+        && conditions.remote_ports.is_empty()
+        && conditions.remote_cidrs.is_empty()
+        && conditions.remote_hosts.is_empty()
+        && conditions.direction.is_none()
+}
+
+// The backends act on whole processes, so delays, marks, and limits or blocks
+// of some flows of a process only happen to connections through the proxy.
+pub fn needs_proxy(rule: &Rule) -> bool {
+    match rule.action {
+        RuleAction::Delay { .. } | RuleAction::Mark { .. } => true,
+        RuleAction::Limit { .. } | RuleAction::Block => !is_process_scoped(&rule.conditions),
+        _ => false,
+    }
+}
+
+pub fn validate(rule: &Rule) -> Result<(), String> {
+    if rule.name.trim().is_empty() {
+        return Err("Rule name cannot be empty".to_string());
+    }
+    let conditions = &rule.conditions;
+    if let Some(range) = conditions.local_ports.iter().chain(&conditions.remote_ports).find(|range| range.start > range.end) {
+        return Err(format!("Port range {}-{} is reversed", range.start, range.end));
+    }
+    if let Some(rule_schedule) = &rule.schedule {
+        schedule::validate(rule_schedule)?;
+    }
+    CompiledRule::compile(rule).map(|_| ())
+}
+
+pub fn scope_key(rule_id: &str) -> String {
+    format!("rule:{}", rule_id)
+}
+
+fn bandwidth_limit(download_limit: u64, upload_limit: u64) -> BandwidthLimit {
+    let bytes_per_second = |kb: u64| (kb > 0).then(|| kb * 1024);
+    BandwidthLimit {
+        upload: bytes_per_second(upload_limit),
+        download: bytes_per_second(download_limit),
+        burst: None,
+        blocked: false,
+    }
+}
+
+// Ordered rules with a per-flow decision cache. The cache is dropped whenever
+// the rules change and pruned of processes that exited.
+pub struct RuleEngine {
+    rules: RwLock<Arc<Vec<CompiledRule>>>,
+    cache: RwLock<HashMap<Flow, Arc<RuleDecision>>>,
+    // set for engines that only simulate, so log rules do not write to the log
+    quiet: bool,
+}
+
+impl RuleEngine {
+    pub fn new(rules: &[Rule]) -> Self {
+        let engine = Self {
+            rules: RwLock::new(Arc::new(Vec::new())),
+            cache: RwLock::new(HashMap::new()),
+            quiet: false,
+        };
+        engine.set_rules(rules);
+        engine
+    }
+
+    pub fn quiet(rules: &[Rule]) -> Self {
+        let engine = Self {
+            quiet: true,
+            ..Self::new(&[])
+        };
+        engine.set_rules(rules);
+        engine
+    }
+
+    // rules that fail to compile are skipped, they were validated when saved
+    pub fn set_rules(&self, rules: &[Rule]) {
+        let compiled = rules.iter()
+            .filter(|rule| rule.enabled)
+            .filter_map(|rule| CompiledRule::compile(rule)
+                .map_err(|e| log_error!("Skipping rule {}: {}", rule.name, e))
+                .ok())
+            .collect();
+
+        // swapped under the cache lock, see evaluate
+        let mut cache = self.cache.write();
+        *self.rules.write() = Arc::new(compiled);
+        cache.clear();
+    }
+
+    pub fn evaluate(&self, process: &ProcessIdentity, flow: &Flow) -> Arc<RuleDecision> {
+        if let Some(decision) = self.cache.read().get(flow) {
+            return Arc::clone(decision);
+        }
+
+        let rules = Arc::clone(&self.rules.read());
+        let mut decision = RuleDecision::default();
+        for compiled in rules.iter().filter(|compiled| compiled.matches_flow(process, flow)) {
+            match &compiled.rule.action {
+                RuleAction::Log => {
+                    if !self.quiet {
+                        log_info!(
+                            "Rule {} matched {:?} {:?} flow of {} ({}) with {}:{}",
+                            compiled.rule.name, flow.protocol, flow.direction, process.name, process.pid, flow.remote_addr, flow.remote_port
+                        );
+                    }
+                    decision.logged_by.push(compiled.rule.id.clone());
+                }
+                RuleAction::Mark { mark } => {
+                    if decision.mark.is_none() {
+                        decision.mark = Some(*mark);
+                        decision.marked_by = Some(compiled.rule.id.clone());
+                    }
+                }
+                action => {
+                    decision.rule_id = Some(compiled.rule.id.clone());
+                    decision.action = Some(action.clone());
+                    break;
+                }
+            }
+        }
+
+        let decision = Arc::new(decision);
+        let mut cache = self.cache.write();
+        // the rules changed while this one was being decided, so it is not kept
+        if !Arc::ptr_eq(&rules, &self.rules.read()) {
+            return decision;
+        }
+        if cache.len() >= FLOW_CACHE_LIMIT {
+            cache.clear();
+        }
+        cache.insert(flow.clone(), Arc::clone(&decision));
+        decision
+    }
+
+    // the limit or block the first matching process-scoped rule puts on the whole
+    // process; an allow rule that matches first leaves it alone
+    pub fn process_limit(&self, process: &ProcessIdentity) -> Option<ProcessLimit> {
+        let rules = self.rules.read();
+        let (compiled, inherited_from) = rules.iter()
+            .filter(|compiled| compiled.is_process_scoped())
+            .filter_map(|compiled| compiled.process_match(process).map(|inherited_from| (compiled, inherited_from)))
+            .find(|(compiled, _)| matches!(
+                compiled.rule.action,
+                RuleAction::Limit { .. } | RuleAction::Block | RuleAction::Delay { .. } | RuleAction::Allow
+            ))?;
+
+        let source = LimitSource::Rule(compiled.rule.id.clone());
+        match compiled.rule.action {
+            RuleAction::Limit { download_limit, upload_limit } => Some(ProcessLimit {
+                download_limit,
+                upload_limit,
+                blocked: false,
+                source,
+                inherited_from,
+            }),
+            RuleAction::Block => Some(ProcessLimit {
+                download_limit: 0,
+                upload_limit: 0,
+                blocked: true,
+                source,
+                inherited_from,
+            }),
+            _ => None,
+        }
+    }
+
+    // limits of rules that depend on the flow, each shared by all flows it matches
+    pub fn scope_limits(&self) -> Vec<(String, BandwidthLimit)> {
+        self.rules.read().iter()
+            .filter(|compiled| !compiled.is_process_scoped())
+            .filter_map(|compiled| match compiled.rule.action {
+                RuleAction::Limit { download_limit, upload_limit } => {
+                    Some((scope_key(&compiled.rule.id), bandwidth_limit(download_limit, upload_limit)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // limit, block, delay and allow rules that depend on the flow, in order
+    fn flow_scoped(&self) -> Vec<Rule> {
+        self.rules.read().iter()
+            .filter(|compiled| !compiled.is_process_scoped())
+            .filter(|compiled| matches!(compiled.rule.action, RuleAction::Limit { .. } | RuleAction::Block | RuleAction::Delay { .. } | RuleAction::Allow))
+            .map(|compiled| compiled.rule.clone())
+            .collect()
+    }
+
+    pub fn forget_exited(&self, running: &HashSet<u32>) {
+        self.cache.write().retain(|flow, _| running.contains(&flow.pid));
+    }
+}
+
+// measured rates of the flows each rule decided, by rule id
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScopeRates {
+    pub download: ByteRate,
+    pub upload: ByteRate,
+    pub flows: usize,
+}
+
+static SCOPE_RATES: Lazy<RwLock<HashMap<String, ScopeRates>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// replaced wholesale by the network monitor each time it publishes rates
+pub fn set_scope_rates(rates: HashMap<String, ScopeRates>) {
+    *SCOPE_RATES.write() = rates;
+}
+
+// every flow-scoped rule in force, idle ones included
+pub fn scope_usage() -> Vec<ScopeUsage> {
+    let units = settings::unit_preferences();
+    let proxied = settings::proxy().enabled;
+    let measured = SCOPE_RATES.read();
+    ENGINE.flow_scoped()
+        .into_iter()
+        .map(|rule| {
+            let rates = measured.get(&rule.id).copied().unwrap_or_default();
+            ScopeUsage {
+                enforced: proxied || !needs_proxy(&rule),
+                usage: ProcessNetworkUsage::from_rates(rates.download, rates.upload, &units),
+                flows: rates.flows,
+                rule_id: rule.id,
+                name: rule.name,
+                action: rule.action,
+            }
+        })
+        .collect()
+}
+
+static ENGINE: Lazy<RuleEngine> = Lazy::new(|| RuleEngine::new(&in_force(profiles::effective_rules(), Utc::now()).0));
+static SCOPES: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(Vec::new()));
+// scheduled rules that were inside a window at the last reload
+static SCHEDULED: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+
+pub fn engine() -> &'static RuleEngine {
+    &ENGINE
+}
+
+// drops scheduled rules outside their windows, and returns which scheduled ones stay
+fn in_force(rules: Vec<Rule>, now: DateTime<Utc>) -> (Vec<Rule>, HashSet<String>) {
+    let mut scheduled = HashSet::new();
+    let rules = rules.into_iter()
+        .filter(|rule| match &rule.schedule {
+            None => true,
+            Some(rule_schedule) if schedule::is_active(rule_schedule, now) => {
+                scheduled.insert(rule.id.clone());
+                true
+            }
+            Some(_) => false,
+        })
+        .collect();
+
+    (rules, scheduled)
+}
+
+// picks up the rules of the active profile and the settings that are in
+// force right now and swaps the flow-scoped limits
+pub fn reload() {
+    reload_with(profiles::effective_rules());
+}
+
+// the same for a set of rules that was not saved yet
+pub fn reload_with(rules: Vec<Rule>) {
+    let limiter = throttle::limiter();
+    let (rules, scheduled) = in_force(rules, Utc::now());
+    ENGINE.set_rules(&rules);
+    *SCHEDULED.write() = scheduled;
+
+    let limits = ENGINE.scope_limits();
+    let mut scopes = SCOPES.write();
+    for scope in scopes.iter().filter(|scope| !limits.iter().any(|(key, _)| key == *scope)) {
+        limiter.set_scope_limit(scope, None);
+    }
+    for (scope, limit) in &limits {
+        limiter.set_scope_limit(scope, Some(*limit));
+    }
+    *scopes = limits.into_iter().map(|(scope, _)| scope).collect();
+}
+
+// reloads once a scheduled rule crossed a window boundary
+pub fn apply_schedules(processes: &[ApplicationProcess]) {
+    let (_, scheduled) = in_force(profiles::effective_rules(), Utc::now());
+    if *SCHEDULED.read() == scheduled {
+        return;
+    }
+
+    log_info!("Rule schedules changed, {} scheduled rules now in force", scheduled.len());
+    reload();
+    if let Err(e) = reconcile(processes) {
+        log_error!("{}", e);
+    }
+}
+
+pub fn schedule_status() -> Vec<ScheduleStatus> {
+    let now = Utc::now();
+    profiles::effective_rules()
+        .into_iter()
+        .filter(|rule| rule.enabled)
+        .filter_map(|rule| {
+            let rule_schedule = rule.schedule.as_ref()?;
+            Some(ScheduleStatus {
+                active: schedule::is_active(rule_schedule, now),
+                next_transition: schedule::next_transition(rule_schedule, now).map(|at| at.timestamp_millis()),
+                rule_id: rule.id,
+                name: rule.name,
+            })
+        })
+        .collect()
+}
+
+// Applies process-scoped rule limits to the running processes and lifts the
+// ones whose rule no longer matches. Called whenever the process list changes.
+// Fails if any limit could not be applied; the others are applied regardless.
+pub fn reconcile(processes: &[ApplicationProcess]) -> Result<(), String> {
+    let desired: HashMap<u32, ProcessLimit> = processes.iter()
+        .filter(|process| process.pid != UNATTRIBUTED_PID)
+        .filter_map(|process| ENGINE.process_limit(&ProcessIdentity::from(process)).map(|limit| (process.pid, limit)))
+        .collect();
+
+    for (pid, limit) in throttle::limits() {
+        if matches!(limit.source, LimitSource::Rule(_)) && desired.get(&pid) != Some(&limit) {
+            throttle::remove_limit(pid, &limit.source);
+        }
+    }
+
+    let mut failures = Vec::new();
+    for (pid, limit) in desired {
+        if let Err(e) = throttle::set_limit(pid, limit) {
+            failures.push(format!("process {}: {}", pid, e));
+        }
+    }
+
+    ENGINE.forget_exited(&processes.iter().map(|process| process.pid).collect());
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Failed to apply rule limits to {}", failures.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::RuleConditions;
+
+    fn rule(id: &str, conditions: RuleConditions, action: RuleAction) -> Rule {
+        Rule {
+            id: id.to_string(),
+            name: id.to_string(),
+            enabled: true,
+            conditions,
+            action,
+            schedule: None,
+            apply_to_descendants: false,
+        }
+    }
+
+    fn process(pid: u32, path: &str) -> ProcessIdentity {
+        ProcessIdentity {
+            pid,
+            name: path.rsplit('\\').next().unwrap_or_default().to_string(),
+            path: path.to_string(),
+            parent_pid: None,
+            ancestors: Vec::new(),
+        }
+    }
+
+    fn flow(pid: u32, remote_addr: &str, remote_port: u16) -> Flow {
+        Flow {
+            pid,
+            protocol: Some(TransportProtocol::Tcp),
+            local_port: 50_000,
+            remote_addr: remote_addr.parse().unwrap(),
+            remote_port,
+            direction: TrafficDirection::Upload,
+            hostname: None,
+        }
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*.exe", "C:\\Apps\\Game.EXE"));
+        assert!(glob_matches("c:/apps/*/game.exe", "C:\\Apps\\v2\\game.exe"));
+        assert!(glob_matches("g?me", "game"));
+        assert!(glob_matches("*cdn*", "eu-cdn-3.example.com"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("**", ""));
+        assert!(!glob_matches("g?me", "gme"));
+        assert!(!glob_matches("*.exe", "game.exe.bak"));
+        assert!(!glob_matches("a*b", "ac"));
+        assert!(!glob_matches("", "a"));
+    }
+
+    #[test]
+    fn cidrs() {
+        let lan = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(lan.contains("192.168.1.200".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(!lan.contains("::ffff:192.168.1.1".parse().unwrap()));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(Cidr::parse("2001:db8::/32").unwrap().contains("2001:db8:1::1".parse().unwrap()));
+        let host = Cidr::parse(" 10.0.0.1 ").unwrap();
+        assert!(host.contains("10.0.0.1".parse().unwrap()));
+        assert!(!host.contains("10.0.0.2".parse().unwrap()));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", ""] {
+            assert!(Cidr::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn first_deciding_rule_wins() {
+        let lan = RuleConditions { remote_cidrs: vec!["10.0.0.0/8".to_string()], ..RuleConditions::default() };
+        let game = RuleConditions { path: Some("*\\game.exe".to_string()), ..RuleConditions::default() };
+        let engine = RuleEngine::quiet(&[
+            rule("log", RuleConditions::default(), RuleAction::Log),
+            rule("mark", game.clone(), RuleAction::Mark { mark: 7 }),
+            rule("second-mark", RuleConditions::default(), RuleAction::Mark { mark: 9 }),
+            rule("allow-lan", lan, RuleAction::Allow),
+            rule("block-game", game, RuleAction::Block),
+            rule("limit-all", RuleConditions::default(), RuleAction::Limit { download_limit: 10, upload_limit: 10 }),
+        ]);
+
+        let game = process(1, "C:\\Games\\game.exe");
+        let lan = engine.evaluate(&game, &flow(1, "10.1.2.3", 443));
+        assert_eq!(lan.rule_id.as_deref(), Some("allow-lan"));
+        assert_eq!(lan.mark, Some(7));
+        assert_eq!(lan.marked_by.as_deref(), Some("mark"));
+        assert_eq!(lan.logged_by, vec!["log".to_string()]);
+
+        let internet = engine.evaluate(&game, &flow(1, "8.8.8.8", 443));
+        assert_eq!(internet.action, Some(RuleAction::Block));
+
+        let browser = engine.evaluate(&process(2, "C:\\Apps\\browser.exe"), &flow(2, "8.8.8.8", 443));
+        assert_eq!(browser.rule_id.as_deref(), Some("limit-all"));
+        assert_eq!(browser.mark, Some(9));
+    }
+
+    #[test]
+    fn disabled_rules_and_new_rules() {
+        let mut block = rule("block", RuleConditions::default(), RuleAction::Block);
+        block.enabled = false;
+        let engine = RuleEngine::quiet(&[block.clone()]);
+        let (process, flow) = (process(1, "C:\\game.exe"), flow(1, "8.8.8.8", 53));
+        assert_eq!(engine.evaluate(&process, &flow).action, None);
+
+        // the cached decision does not outlive the rules it came from
+        block.enabled = true;
+        engine.set_rules(&[block]);
+        assert_eq!(engine.evaluate(&process, &flow).action, Some(RuleAction::Block));
+    }
+
+    #[test]
+    fn flow_conditions() {
+        let conditions = RuleConditions {
+            protocol: Some(TransportProtocol::Tcp),
+            remote_ports: vec![PortRange { start: 27_000, end: 27_100 }],
+            remote_hosts: vec!["*.example.com".to_string()],
+            ..RuleConditions::default()
+        };
+        let engine = RuleEngine::quiet(&[rule("block", conditions, RuleAction::Block)]);
+        let game = process(1, "C:\\game.exe");
+
+        let mut named = flow(1, "1.2.3.4", 27_015);
+        assert_eq!(engine.evaluate(&game, &named).action, None);
+        named.hostname = Some("eu.example.com".to_string());
+        assert_eq!(engine.evaluate(&game, &named).action, Some(RuleAction::Block));
+
+        let mut other_port = named.clone();
+        other_port.remote_port = 443;
+        assert_eq!(engine.evaluate(&game, &other_port).action, None);
+        let mut udp = named;
+        udp.protocol = Some(TransportProtocol::Udp);
+        assert_eq!(engine.evaluate(&game, &udp).action, None);
+    }
+
+    #[test]
+    fn only_whole_process_limits_and_blocks_work_without_the_proxy() {
+        let lan = RuleConditions { remote_cidrs: vec!["10.0.0.0/8".to_string()], ..RuleConditions::default() };
+        let limit = RuleAction::Limit { download_limit: 10, upload_limit: 0 };
+
+        assert!(!needs_proxy(&rule("block", RuleConditions::default(), RuleAction::Block)));
+        assert!(!needs_proxy(&rule("limit", RuleConditions::default(), limit.clone())));
+        assert!(!needs_proxy(&rule("allow", lan.clone(), RuleAction::Allow)));
+        assert!(needs_proxy(&rule("block", lan.clone(), RuleAction::Block)));
+        assert!(needs_proxy(&rule("limit", lan, limit)));
+        assert!(needs_proxy(&rule("delay", RuleConditions::default(), RuleAction::Delay { ms: 10 })));
+    }
+
+    #[test]
+    fn descendants_inherit_process_rules_only_when_asked() {
+        let launcher = process(1, "C:\\launcher.exe");
+        let shell = ProcessIdentity { parent_pid: Some(1), ancestors: vec![launcher.clone()], ..process(2, "C:\\cmd.exe") };
+        let worker = ProcessIdentity { parent_pid: Some(2), ancestors: vec![shell.clone(), launcher.clone()], ..process(3, "C:\\worker.exe") };
+        let conditions = RuleConditions { path: Some("*launcher.exe".to_string()), ..RuleConditions::default() };
+        let mut limit = rule("limit", conditions, RuleAction::Limit { download_limit: 10, upload_limit: 0 });
+
+        let engine = RuleEngine::quiet(std::slice::from_ref(&limit));
+        assert_eq!(engine.process_limit(&launcher).map(|limit| limit.inherited_from), Some(None));
+        assert_eq!(engine.process_limit(&worker), None);
+
+        limit.apply_to_descendants = true;
+        let engine = RuleEngine::quiet(&[limit]);
+        assert_eq!(engine.process_limit(&launcher).map(|limit| limit.inherited_from), Some(None));
+        assert_eq!(engine.process_limit(&worker).map(|limit| limit.inherited_from), Some(Some(1)));
+        assert_eq!(engine.evaluate(&worker, &flow(3, "1.2.3.4", 443)).action, Some(RuleAction::Limit { download_limit: 10, upload_limit: 0 }));
+    }
+}
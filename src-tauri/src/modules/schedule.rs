@@ -0,0 +1,189 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use crate::models::{Schedule, TimeWindow};
+
+// how far ahead next_transition looks
+const LOOKAHEAD_DAYS: i64 = 8;
+
+// minutes since midnight
+fn parse_time(time: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid time {}, expected HH:MM", time);
+    let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+pub fn validate_window(window: &TimeWindow) -> Result<(), String> {
+    parse_time(&window.start)?;
+    parse_time(&window.end)?;
+    if window.weekdays.iter().any(|day| !(1..=7).contains(day)) {
+        return Err("Weekdays must be between 1 (Monday) and 7 (Sunday)".to_string());
+    }
+    Ok(())
+}
+
+fn on_day(window: &TimeWindow, weekday: Weekday) -> bool {
+    window.weekdays.is_empty() || window.weekdays.contains(&weekday.number_from_monday())
+}
+
+// invalid windows never match
+pub fn window_contains(window: &TimeWindow, at: NaiveDateTime) -> bool {
+    let (Ok(start), Ok(end)) = (parse_time(&window.start), parse_time(&window.end)) else {
+        return false;
+    };
+    let minute = at.hour() * 60 + at.minute();
+    let weekday = at.weekday();
+
+    if start < end {
+        on_day(window, weekday) && (start..end).contains(&minute)
+    } else {
+        (on_day(window, weekday) && minute >= start) || (on_day(window, weekday.pred()) && minute < end)
+    }
+}
+
+fn timezone(schedule: &Schedule) -> Result<Option<Tz>, String> {
+    schedule.timezone.as_deref()
+        .map(|name| name.parse::<Tz>().map_err(|_| format!("Unknown timezone {}", name)))
+        .transpose()
+}
+
+pub fn validate(schedule: &Schedule) -> Result<(), String> {
+    if schedule.windows.is_empty() {
+        return Err("Schedules need at least one time window".to_string());
+    }
+    for window in &schedule.windows {
+        validate_window(window)?;
+    }
+    timezone(schedule).map(|_| ())
+}
+
+fn local_time(timezone: Option<Tz>, at: DateTime<Utc>) -> NaiveDateTime {
+    match timezone {
+        Some(timezone) => at.with_timezone(&timezone).naive_local(),
+        None => at.with_timezone(&Local).naive_local(),
+    }
+}
+
+// None for wall-clock times skipped by a daylight saving change
+fn to_utc(timezone: Option<Tz>, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    match timezone {
+        Some(timezone) => timezone.from_local_datetime(&local).earliest().map(|at| at.with_timezone(&Utc)),
+        None => Local.from_local_datetime(&local).earliest().map(|at| at.with_timezone(&Utc)),
+    }
+}
+
+pub fn is_active(schedule: &Schedule, at: DateTime<Utc>) -> bool {
+    let Ok(timezone) = timezone(schedule) else {
+        return false;
+    };
+    let local = local_time(timezone, at);
+    schedule.windows.iter().any(|window| window_contains(window, local))
+}
+
+// The earliest window boundary after `now` where the schedule actually flips;
+// boundaries of overlapping windows that change nothing are skipped. One that
+// falls into a daylight saving gap is not reported, though is_active still
+// switches correctly once the clocks have moved.
+pub fn next_transition(schedule: &Schedule, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let timezone = timezone(schedule).ok()?;
+    let today = local_time(timezone, now).date();
+    let minute = |minutes: u32| NaiveTime::from_hms_opt(minutes / 60, minutes % 60, 0);
+
+    let mut boundaries = Vec::new();
+    for offset in -1..=LOOKAHEAD_DAYS {
+        let date = today + Duration::days(offset);
+        for window in schedule.windows.iter().filter(|window| on_day(window, date.weekday())) {
+            let (Ok(start), Ok(end)) = (parse_time(&window.start), parse_time(&window.end)) else {
+                continue;
+            };
+            let end_date = if end <= start { date + Duration::days(1) } else { date };
+            boundaries.extend(minute(start).map(|time| date.and_time(time)));
+            boundaries.extend(minute(end).map(|time| end_date.and_time(time)));
+        }
+    }
+
+    boundaries.into_iter()
+        .filter_map(|local| to_utc(timezone, local))
+        .filter(|&at| at > now && is_active(schedule, at) != is_active(schedule, at - Duration::seconds(1)))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(windows: &[(&[u32], &str, &str)], timezone: &str) -> Schedule {
+        Schedule {
+            windows: windows.iter()
+                .map(|(weekdays, start, end)| TimeWindow { weekdays: weekdays.to_vec(), start: start.to_string(), end: end.to_string() })
+                .collect(),
+            timezone: Some(timezone.to_string()),
+        }
+    }
+
+    // 2026-10-19 is a Monday
+    fn utc(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn next_transition_of_a_daily_window() {
+        let office = schedule(&[(&[], "09:00", "17:00")], "UTC");
+        assert_eq!(next_transition(&office, utc(19, 8, 0)), Some(utc(19, 9, 0)));
+        assert_eq!(next_transition(&office, utc(19, 12, 0)), Some(utc(19, 17, 0)));
+        // a boundary right now is already behind us
+        assert_eq!(next_transition(&office, utc(19, 17, 0)), Some(utc(20, 9, 0)));
+    }
+
+    #[test]
+    fn next_transition_past_midnight() {
+        let night = schedule(&[(&[], "22:00", "06:00")], "UTC");
+        assert_eq!(next_transition(&night, utc(19, 23, 0)), Some(utc(20, 6, 0)));
+        assert_eq!(next_transition(&night, utc(20, 3, 0)), Some(utc(20, 6, 0)));
+        assert_eq!(next_transition(&night, utc(20, 7, 0)), Some(utc(20, 22, 0)));
+    }
+
+    #[test]
+    fn next_transition_skips_boundaries_that_change_nothing() {
+        let overlapping = schedule(&[(&[], "09:00", "12:00"), (&[], "11:00", "14:00")], "UTC");
+        assert_eq!(next_transition(&overlapping, utc(19, 10, 0)), Some(utc(19, 14, 0)));
+
+        let always = schedule(&[(&[], "00:00", "00:00")], "UTC");
+        assert!(is_active(&always, utc(19, 10, 0)));
+        assert_eq!(next_transition(&always, utc(19, 10, 0)), None);
+    }
+
+    #[test]
+    fn next_transition_on_other_weekdays() {
+        let weekend = schedule(&[(&[6, 7], "10:00", "12:00")], "UTC");
+        assert_eq!(next_transition(&weekend, utc(19, 10, 0)), Some(utc(24, 10, 0)));
+        // Sunday's window ends, then nothing until Saturday
+        assert_eq!(next_transition(&weekend, utc(25, 12, 0)), Some(utc(31, 10, 0)));
+    }
+
+    #[test]
+    fn next_transition_in_another_timezone() {
+        // 20:00 in New York is midnight UTC while daylight saving lasts
+        let evening = schedule(&[(&[], "20:00", "23:00")], "America/New_York");
+        assert_eq!(next_transition(&evening, utc(19, 12, 0)), Some(utc(20, 0, 0)));
+        assert_eq!(next_transition(&evening, utc(20, 1, 0)), Some(utc(20, 3, 0)));
+
+        let unknown = schedule(&[(&[], "20:00", "23:00")], "Mars/Olympus_Mons");
+        assert_eq!(next_transition(&unknown, utc(19, 12, 0)), None);
+        assert!(!is_active(&unknown, utc(20, 1, 0)));
+    }
+
+    #[test]
+    fn next_transition_across_a_daylight_saving_gap() {
+        // Berlin skips from 02:00 to 03:00 on 2026-03-29, so 02:30 never happens;
+        // the window is in force from 03:00 and ends at 04:00 local, 02:00 UTC
+        let early = schedule(&[(&[], "02:30", "04:00")], "Europe/Berlin");
+        let now = Utc.with_ymd_and_hms(2026, 3, 28, 12, 0, 0).unwrap();
+        assert_eq!(next_transition(&early, now), Some(Utc.with_ymd_and_hms(2026, 3, 29, 2, 0, 0).unwrap()));
+        assert!(is_active(&early, Utc.with_ymd_and_hms(2026, 3, 29, 1, 0, 0).unwrap()));
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::PathBuf;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use crate::models::{
+    AppLimit, AppPriority, EmulationProfile, FirewallSettings, GlobalLimit, LinkCapacity, Profile, ProxySettings, Quota, RateConfig, Rule, TamperRule,
+    UnitPreferences,
+};
+use crate::utils::paths::config_dir;
+use crate::{log_info, log_error};
+
+const SETTINGS_FILE: &str = "settings.json";
+
+// unknown or missing keys fall back to defaults so older files keep loading
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub units: UnitPreferences,
+    pub quotas: Vec<Quota>,
+    // evaluated in this order
+    pub rules: Vec<Rule>,
+    // throttles keyed by executable rather than pid
+    pub app_limits: Vec<AppLimit>,
+    pub global_limit: GlobalLimit,
+    pub proxy: ProxySettings,
+    pub emulation: Vec<EmulationProfile>,
+    pub tamper: Vec<TamperRule>,
+    pub profiles: Vec<Profile>,
+    // id of the profile whose rules and limits are in force, if any
+    pub active_profile: Option<String>,
+    // priority classes only take effect once a capacity is set
+    pub link_capacity: LinkCapacity,
+    pub app_priorities: Vec<AppPriority>,
+    pub firewall: FirewallSettings,
+    // read when capture starts, changes apply from the next start
+    pub rates: RateConfig,
+}
+
+static SETTINGS: Lazy<RwLock<Settings>> = Lazy::new(|| RwLock::new(load()));
+
+fn settings_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(SETTINGS_FILE))
+}
+
+fn load() -> Settings {
+    let Some(path) = settings_path() else {
+        return Settings::default();
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            log_error!("Failed to parse settings at {}: {}", path.display(), e);
+            Settings::default()
+        }),
+        Err(_) => {
+            log_info!("No settings file at {}, using defaults", path.display());
+            Settings::default()
+        }
+    }
+}
+
+fn save(settings: &Settings) -> Result<(), String> {
+    let path = settings_path().ok_or("Could not determine settings directory")?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create settings directory: {}", e))?;
+    }
+
+    let contents = serde_json::to_string_pretty(settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    fs::write(&path, contents).map_err(|e| format!("Failed to write settings: {}", e))
+}
+
+pub fn unit_preferences() -> UnitPreferences {
+    SETTINGS.read().units
+}
+
+pub fn quotas() -> Vec<Quota> {
+    SETTINGS.read().quotas.clone()
+}
+
+pub fn rules() -> Vec<Rule> {
+    SETTINGS.read().rules.clone()
+}
+
+pub fn app_limits() -> Vec<AppLimit> {
+    SETTINGS.read().app_limits.clone()
+}
+
+pub fn global_limit() -> GlobalLimit {
+    SETTINGS.read().global_limit
+}
+
+pub fn proxy() -> ProxySettings {
+    SETTINGS.read().proxy
+}
+
+pub fn emulation_profiles() -> Vec<EmulationProfile> {
+    SETTINGS.read().emulation.clone()
+}
+
+pub fn tamper_rules() -> Vec<TamperRule> {
+    SETTINGS.read().tamper.clone()
+}
+
+pub fn profiles() -> Vec<Profile> {
+    SETTINGS.read().profiles.clone()
+}
+
+pub fn link_capacity() -> LinkCapacity {
+    SETTINGS.read().link_capacity
+}
+
+pub fn app_priorities() -> Vec<AppPriority> {
+    SETTINGS.read().app_priorities.clone()
+}
+
+pub fn firewall() -> FirewallSettings {
+    SETTINGS.read().firewall
+}
+
+pub fn rate_config() -> RateConfig {
+    SETTINGS.read().rates
+}
+
+pub fn active_profile() -> Option<Profile> {
+    let settings = SETTINGS.read();
+    let id = settings.active_profile.as_ref()?;
+    settings.profiles.iter().find(|profile| &profile.id == id).cloned()
+}
+
+// applies the change in memory and persists it, rolling back if the write fails
+pub fn update<F>(change: F) -> Result<Settings, String>
+where
+    F: FnOnce(&mut Settings)
+{
+    let mut settings = SETTINGS.write();
+    let previous = settings.clone();
+    change(&mut settings);
+
+    if let Err(e) = save(&settings) {
+        *settings = previous;
+        return Err(e);
+    }
+
+    Ok(settings.clone())
+}
@@ -0,0 +1,333 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use crate::models::{
+    FlowRecord, Rule, RuleAction, SimulatedFlow, SimulatedRule, SimulationReport, SimulationSource, TrafficDirection, TransportProtocol,
+};
+use crate::modules::rules::{Flow, ProcessIdentity, RuleEngine};
+use crate::modules::{emulator, host_labels, packet, schedule};
+use crate::utils::pcap;
+
+// a flow as it was recorded, before any rule looks at it
+struct Recorded {
+    process: ProcessIdentity,
+    // distinct for every process, since the engine caches decisions by flow
+    key_pid: u32,
+    protocol: TransportProtocol,
+    local_port: u16,
+    remote_addr: IpAddr,
+    remote_port: u16,
+    hostname: Option<String>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    // when the flow started, if that was recorded
+    started_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct Hits {
+    flows: usize,
+    bytes: u64,
+}
+
+fn identity(pid: u32, name: &str, path: &str) -> ProcessIdentity {
+    let name = if name.is_empty() {
+        path.rsplit(['\\', '/']).next().unwrap_or_default()
+    } else {
+        name
+    };
+    ProcessIdentity {
+        pid,
+        name: name.to_string(),
+        path: path.to_string(),
+        parent_pid: None,
+        ancestors: Vec::new(),
+    }
+}
+
+fn read_flow_log(path: &Path) -> Result<Vec<FlowRecord>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if text.trim_start().starts_with('[') {
+        return serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| serde_json::from_str(line).map_err(|e| format!("{}: line {}: {}", path.display(), index + 1, e)))
+        .collect()
+}
+
+fn logged_flows(records: Vec<FlowRecord>) -> Result<Vec<Recorded>, String> {
+    let mut key_pids: HashMap<(u32, String), u32> = HashMap::new();
+    records.into_iter()
+        .enumerate()
+        .map(|(index, record)| {
+            let remote_addr = record.remote_addr.parse()
+                .map_err(|_| format!("Invalid remote address '{}' in flow {}", record.remote_addr, index + 1))?;
+            let next = key_pids.len() as u32 + 1;
+            let key_pid = *key_pids.entry((record.pid, record.app_path.to_lowercase())).or_insert(next);
+            Ok(Recorded {
+                process: identity(record.pid, &record.app_name, &record.app_path),
+                key_pid,
+                protocol: record.protocol,
+                local_port: record.local_port,
+                remote_addr,
+                remote_port: record.remote_port,
+                hostname: record.hostname,
+                bytes_sent: record.bytes_sent,
+                bytes_received: record.bytes_received,
+                started_at: record.timestamp.and_then(DateTime::from_timestamp_millis),
+            })
+        })
+        .collect()
+}
+
+// Packets grouped into flows by protocol and ports, in the order they first
+// appear. Names come from DNS answers and TLS server names anywhere in the
+// capture, without touching the labels of live traffic.
+fn captured_flows(path: &Path, local_addr: Option<IpAddr>, app_path: &str, report: &mut SimulationReport) -> Result<Vec<Recorded>, String> {
+    let capture = pcap::read(path)?;
+    report.packets_read = capture.packets.len() as u64;
+
+    let mut labels: HashMap<IpAddr, String> = HashMap::new();
+    let mut index: HashMap<(TransportProtocol, u16, IpAddr, u16), usize> = HashMap::new();
+    let mut flows: Vec<Recorded> = Vec::new();
+    for captured in &capture.packets {
+        let Some(layout) = packet::parse(capture.link_type, &captured.data) else {
+            report.unparsed += 1;
+            continue;
+        };
+        let payload = captured.data.get(layout.payload_offset..).unwrap_or_default();
+        for (addr, hostname, _) in host_labels::extract(&layout, payload) {
+            labels.insert(addr, hostname);
+        }
+        let Some(protocol) = layout.protocol else {
+            report.other_protocols += 1;
+            continue;
+        };
+
+        let direction = emulator::direction_of(&layout, local_addr);
+        let (local_port, remote_addr, remote_port) = match direction {
+            TrafficDirection::Upload => (layout.src_port, layout.dst_addr, layout.dst_port),
+            TrafficDirection::Download => (layout.dst_port, layout.src_addr, layout.src_port),
+        };
+        let position = *index.entry((protocol, local_port, remote_addr, remote_port)).or_insert_with(|| {
+            flows.push(Recorded {
+                process: identity(0, "", app_path),
+                key_pid: 0,
+                protocol,
+                local_port,
+                remote_addr,
+                remote_port,
+                hostname: None,
+                bytes_sent: 0,
+                bytes_received: 0,
+                started_at: DateTime::from_timestamp(captured.timestamp.as_secs() as i64, captured.timestamp.subsec_nanos()),
+            });
+            flows.len() - 1
+        });
+
+        let flow = &mut flows[position];
+        match direction {
+            TrafficDirection::Upload => flow.bytes_sent += captured.original_len as u64,
+            TrafficDirection::Download => flow.bytes_received += captured.original_len as u64,
+        }
+    }
+
+    for flow in &mut flows {
+        flow.hostname = labels.get(&flow.remote_addr).cloned();
+    }
+    Ok(flows)
+}
+
+// Evaluates every direction that carried traffic, or both when a flow log
+// records no byte counts, against the rules in order as live traffic would be.
+// Each flow sees the rules in force when it started, with one engine for every
+// set of scheduled rules that were.
+fn evaluate(rules: &[Rule], flows: Vec<Recorded>, report: &mut SimulationReport) {
+    let mut engines: HashMap<BTreeSet<&str>, RuleEngine> = HashMap::new();
+    let mut hits: HashMap<String, Hits> = HashMap::new();
+
+    for recorded in flows {
+        let scheduled: BTreeSet<&str> = rules.iter()
+            .filter(|rule| match (&rule.schedule, recorded.started_at) {
+                (Some(rule_schedule), Some(started_at)) => schedule::is_active(rule_schedule, started_at),
+                _ => false,
+            })
+            .map(|rule| rule.id.as_str())
+            .collect();
+        let engine = engines.entry(scheduled).or_insert_with_key(|scheduled| {
+            let in_force: Vec<Rule> = rules.iter()
+                .filter(|rule| rule.schedule.is_none() || scheduled.contains(rule.id.as_str()))
+                .cloned()
+                .collect();
+            RuleEngine::quiet(&in_force)
+        });
+
+        let counted_bytes = recorded.bytes_sent + recorded.bytes_received > 0;
+        let mut decided: [Option<String>; 2] = [None, None];
+        let mut tagged_by: Vec<String> = Vec::new();
+        let mut matched: Vec<String> = Vec::new();
+
+        let directions = [(TrafficDirection::Upload, recorded.bytes_sent), (TrafficDirection::Download, recorded.bytes_received)];
+        for (slot, (direction, bytes)) in directions.into_iter().enumerate() {
+            if counted_bytes && bytes == 0 {
+                continue;
+            }
+            let decision = engine.evaluate(&recorded.process, &Flow {
+                pid: recorded.key_pid,
+                protocol: Some(recorded.protocol),
+                local_port: recorded.local_port,
+                remote_addr: recorded.remote_addr,
+                remote_port: recorded.remote_port,
+                direction,
+                hostname: recorded.hostname.clone(),
+            });
+
+            let total = match decision.action {
+                Some(RuleAction::Block) => &mut report.bytes_blocked,
+                Some(RuleAction::Limit { .. }) => &mut report.bytes_limited,
+                Some(RuleAction::Delay { .. }) => &mut report.bytes_delayed,
+                Some(RuleAction::Allow) => &mut report.bytes_allowed,
+                _ => &mut report.bytes_undecided,
+            };
+            *total += bytes;
+
+            for rule_id in decision.marked_by.iter().chain(decision.logged_by.iter()) {
+                if !tagged_by.contains(rule_id) {
+                    tagged_by.push(rule_id.clone());
+                }
+            }
+            for rule_id in decision.rule_id.iter().chain(decision.marked_by.iter()).chain(decision.logged_by.iter()) {
+                let hit = hits.entry(rule_id.clone()).or_default();
+                hit.bytes += bytes;
+                if !matched.contains(rule_id) {
+                    hit.flows += 1;
+                    matched.push(rule_id.clone());
+                }
+            }
+            decided[slot] = decision.rule_id.clone();
+        }
+
+        let [upload_rule_id, download_rule_id] = decided;
+        report.flows.push(SimulatedFlow {
+            app_path: recorded.process.path,
+            protocol: recorded.protocol,
+            local_port: recorded.local_port,
+            remote_addr: recorded.remote_addr.to_string(),
+            remote_port: recorded.remote_port,
+            hostname: recorded.hostname,
+            bytes_sent: recorded.bytes_sent,
+            bytes_received: recorded.bytes_received,
+            upload_rule_id,
+            download_rule_id,
+            tagged_by,
+        });
+    }
+
+    for rule in rules.iter().filter(|rule| rule.enabled) {
+        if rule.schedule.is_some() {
+            report.scheduled_rule_ids.push(rule.id.clone());
+        }
+        match hits.get(&rule.id) {
+            Some(hit) => report.rules.push(SimulatedRule {
+                rule_id: rule.id.clone(),
+                name: rule.name.clone(),
+                action: rule.action.clone(),
+                flows: hit.flows,
+                bytes: hit.bytes,
+            }),
+            None => report.unmatched_rule_ids.push(rule.id.clone()),
+        }
+    }
+}
+
+// nothing here touches live traffic or the rules in force
+pub fn simulate(rules: &[Rule], source: &SimulationSource) -> Result<SimulationReport, String> {
+    let mut report = SimulationReport::default();
+    let flows = match source {
+        SimulationSource::Pcap { path, local_addr, app_path } => {
+            let local_addr = local_addr.as_deref()
+                .map(|addr| addr.parse::<IpAddr>().map_err(|_| format!("Invalid local address '{}'", addr)))
+                .transpose()?;
+            captured_flows(Path::new(path), local_addr, app_path.as_deref().unwrap_or_default(), &mut report)?
+        }
+        SimulationSource::FlowLog { path } => {
+            logged_flows(read_flow_log(Path::new(path))?)?
+        }
+    };
+
+    evaluate(rules, flows, &mut report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{RuleConditions, Schedule, TimeWindow};
+    use crate::utils::pcap::{PcapFile, PcapPacket};
+
+    fn recorded(remote_port: u16, started_at: Option<&str>) -> Recorded {
+        Recorded {
+            process: identity(1, "", "/usr/bin/app"),
+            key_pid: 1,
+            protocol: TransportProtocol::Tcp,
+            local_port: 50_000,
+            remote_addr: "1.2.3.4".parse().unwrap(),
+            remote_port,
+            hostname: None,
+            bytes_sent: 100,
+            bytes_received: 0,
+            started_at: started_at.map(|at| at.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn scheduled_rules_only_decide_flows_inside_their_windows() {
+        let office_hours = Schedule {
+            windows: vec![TimeWindow { weekdays: Vec::new(), start: "09:00".to_string(), end: "17:00".to_string() }],
+            timezone: Some("UTC".to_string()),
+        };
+        let rules = vec![Rule {
+            id: "block".to_string(),
+            name: "block".to_string(),
+            enabled: true,
+            conditions: RuleConditions::default(),
+            action: RuleAction::Block,
+            schedule: Some(office_hours),
+            apply_to_descendants: false,
+        }];
+
+        let mut report = SimulationReport::default();
+        evaluate(&rules, vec![
+            recorded(1, Some("2026-03-02T10:00:00Z")),
+            recorded(2, Some("2026-03-02T20:00:00Z")),
+            recorded(3, None),
+        ], &mut report);
+
+        let decided: Vec<Option<&str>> = report.flows.iter().map(|flow| flow.upload_rule_id.as_deref()).collect();
+        assert_eq!(decided, vec![Some("block"), None, None]);
+        assert_eq!((report.bytes_blocked, report.bytes_undecided), (100, 200));
+        assert_eq!(report.scheduled_rule_ids, vec!["block".to_string()]);
+    }
+
+    #[test]
+    fn other_protocols_are_counted_apart_from_unparsed_packets() {
+        // an ICMP echo request over raw IPv4
+        let mut icmp = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        icmp.extend([8, 0, 0, 0, 0, 1, 0, 1]);
+        let packet = |data: Vec<u8>| PcapPacket { timestamp: Default::default(), original_len: data.len() as u32, data };
+        let capture = PcapFile { link_type: 101, packets: vec![packet(icmp), packet(vec![0x45, 0, 0])] };
+        let path = std::env::temp_dir().join(format!("meridian-simulation-{}.pcap", std::process::id()));
+        pcap::write(&path, &capture).unwrap();
+
+        let report = simulate(&[], &SimulationSource::Pcap { path: path.display().to_string(), local_addr: None, app_path: None });
+        fs::remove_file(&path).ok();
+        let report = report.unwrap();
+        assert_eq!((report.packets_read, report.unparsed, report.other_protocols), (2, 1, 1));
+        assert!(report.flows.is_empty());
+    }
+}
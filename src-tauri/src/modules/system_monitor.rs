@@ -1,10 +1,24 @@
 use std::sync::Arc;
 use tokio::time::{interval, Duration};
 use sysinfo::{System, SystemExt, Process, ProcessExt, PidExt};
-use crate::models::{ApplicationProcess, ProcessStatus, ProcessNetworkUsage, NetworkUsage};
+use crate::models::{ApplicationProcess, ProcessStatus, ProcessNetworkUsage, UNATTRIBUTED_PID};
+use crate::modules::settings;
+use crate::modules::traffic_totals::TotalsStore;
+use crate::modules::traffic_history::TrafficHistory;
+use crate::modules::usage_store::UsageStore;
+use crate::modules::quotas::QuotaMonitor;
+use crate::modules::rules;
+use crate::modules::profiles;
+use crate::modules::app_limits;
+use crate::modules::throttle;
+use crate::modules::priority;
+use crate::modules::firewall;
+use crate::modules::process_tree::{self, TreeEntry};
+use crate::modules::rules::ProcessIdentity;
+use crate::modules::attribution::AttributionStats;
 use crate::cache::{ProcessCache, NetworkCache, traits::Cache};
 use crate::{log_info, log_error};
-use crate::models::PROCESS_SCAN_INTERVAL_MS;
+use crate::models::{PROCESS_SCAN_INTERVAL_MS, USAGE_FLUSH_INTERVAL_MS, QUOTA_CHECK_INTERVAL_MS, SCHEDULE_CHECK_INTERVAL_MS};
 use std::collections::HashSet;
 use parking_lot::RwLock;
 use crate::modules::process_metadata::get_process_metadata;
@@ -14,6 +28,11 @@ use std::collections::HashMap;
 pub struct SystemMonitor {
     process_cache: Arc<ProcessCache>,
     network_cache: Arc<NetworkCache>,
+    traffic_totals: Arc<TotalsStore>,
+    traffic_history: Arc<TrafficHistory>,
+    usage_store: Arc<UsageStore>,
+    quota_monitor: Arc<QuotaMonitor>,
+    attribution: Arc<AttributionStats>,
     system: Arc<RwLock<System>>,
     last_process_list: Arc<RwLock<HashSet<u32>>>,
 }
@@ -26,19 +45,33 @@ impl SystemMonitor {
         Self {
             process_cache: Arc::new(ProcessCache::new()),
             network_cache: Arc::new(NetworkCache::new()),
+            traffic_totals: Arc::new(TotalsStore::new()),
+            traffic_history: Arc::new(TrafficHistory::new()),
+            usage_store: Arc::new(UsageStore::open()),
+            quota_monitor: Arc::new(QuotaMonitor::new()),
+            attribution: Arc::new(AttributionStats::new()),
             system: Arc::new(RwLock::new(system)),
             last_process_list: Arc::new(RwLock::new(HashSet::new())),
         }
     }
 
     pub fn get_processes(&self) -> Vec<ApplicationProcess> {
-        let processes = self.process_cache.get_all_processes();
+        let mut processes = self.process_cache.get_all_processes();
+        for process in &mut processes {
+            process.inherited_from = throttle::get_limit(process.pid).and_then(|limit| limit.inherited_from);
+        }
+
+        // unmatched traffic has no real process behind it, so it only lives in the network cache
+        if let Some(unattributed) = self.network_cache.get_process(UNATTRIBUTED_PID) {
+            processes.push(unattributed);
+        }
+
         processes
     }
 
     fn has_network_capabilities(&self, process: &Process, exe_path: &str) -> bool {
         if let Some(network_usage) = self.network_cache.get_usage(process.pid().as_u32()) {
-            if network_usage.download.value > 0.0 || network_usage.upload.value > 0.0 {
+            if network_usage.is_active() {
                 return true;
             }
         }
@@ -71,7 +104,7 @@ impl SystemMonitor {
         ) {
             if sockets.iter().any(|socket| {
                 socket.associated_pids.first()
-                    .map(|&pid| pid as u32 == process.pid().as_u32())
+                    .map(|&pid| pid == process.pid().as_u32())
                     .unwrap_or(false)
             }) {
                 return true;
@@ -82,13 +115,14 @@ impl SystemMonitor {
     }
 
     async fn update_processes(&self) {
-        {
+        // limits are reconciled after every scan, listed processes changed or not
+        'scan: {
             let mut system = self.system.write();
             system.refresh_all();
             
             let current_pids: HashSet<u32> = system.processes()
-                .iter()
-                .map(|(pid, _)| pid.as_u32())
+                .keys()
+                .map(|pid| pid.as_u32())
                 .collect();
             
             log_info!("Found {} PIDs in system", current_pids.len());
@@ -104,20 +138,14 @@ impl SystemMonitor {
                         if let Some(mut cached_process) = self.process_cache.get_process(*pid) {
                             if self.has_network_capabilities(process, &process.exe().to_string_lossy()) {
                                 let network_usage = self.network_cache.get_usage(*pid)
-                                    .unwrap_or_else(|| ProcessNetworkUsage {
-                                        download: NetworkUsage {
-                                            value: 0.0,
-                                            unit: "KB/s".to_string(),
-                                        },
-                                        upload: NetworkUsage {
-                                            value: 0.0,
-                                            unit: "KB/s".to_string(),
-                                        },
-                                    });
+                                    .unwrap_or_else(|| ProcessNetworkUsage::idle(&settings::unit_preferences()));
                                 
                                 cached_process.network_usage = network_usage.clone();
+                                cached_process.packet_stats = self.network_cache.get_packet_stats(*pid)
+                                    .unwrap_or_default();
+                                cached_process.totals = self.traffic_totals.get(*pid);
                                 
-                                if network_usage.download.value > 0.0 || network_usage.upload.value > 0.0 {
+                                if network_usage.is_active() {
                                     cached_process.status = ProcessStatus::Online;
                                 }
                                 
@@ -126,7 +154,7 @@ impl SystemMonitor {
                         }
                     }
                 }
-                return;
+                break 'scan;
             }
 
             log_info!("Performing full update for {} processes", current_pids.len());
@@ -136,6 +164,20 @@ impl SystemMonitor {
             let mut parent_map = HashMap::new();
             let mut name_parent_map = HashMap::new(); // Track name+parent combinations
 
+            // the whole tree, so limits reach children through parents that are not listed
+            process_tree::update(system.processes().iter()
+                .map(|(pid, process)| (pid.as_u32(), TreeEntry {
+                    identity: ProcessIdentity {
+                        pid: pid.as_u32(),
+                        name: process.name().to_string(),
+                        path: process.exe().to_string_lossy().to_string(),
+                        parent_pid: process.parent().map(|p| p.as_u32()),
+                        ancestors: Vec::new(),
+                    },
+                    start_time: process.start_time(),
+                }))
+                .collect());
+
             // First pass: collect all processes and their parent PIDs
             for (pid, process) in system.processes() {
                 if process.name().to_lowercase() == "system idle process" {
@@ -144,6 +186,7 @@ impl SystemMonitor {
 
                 let process_pid = pid.as_u32();
                 let exe_path = process.exe().to_string_lossy().to_string();
+                self.traffic_totals.identify(process_pid, process.name(), &exe_path);
                 if exe_path.is_empty() {
                     log_error!("[Icon] Empty executable path for process {} ({})", process.name(), process_pid);
                     continue;
@@ -162,8 +205,8 @@ impl SystemMonitor {
                     // unless it has more network activity
                     if let Some(existing_usage) = self.network_cache.get_usage(existing_pid) {
                         if let Some(current_usage) = self.network_cache.get_usage(process_pid) {
-                            if current_usage.download.value <= existing_usage.download.value &&
-                               current_usage.upload.value <= existing_usage.upload.value {
+                            if current_usage.download.bytes_per_second <= existing_usage.download.bytes_per_second &&
+                               current_usage.upload.bytes_per_second <= existing_usage.upload.bytes_per_second {
                                 continue;
                             }
                         } else {
@@ -176,16 +219,7 @@ impl SystemMonitor {
 
                 let network_usage = self.network_cache
                     .get_usage(process_pid)
-                    .unwrap_or_else(|| ProcessNetworkUsage {
-                        download: NetworkUsage {
-                            value: 0.0,
-                            unit: "KB/s".to_string(),
-                        },
-                        upload: NetworkUsage {
-                            value: 0.0,
-                            unit: "KB/s".to_string(),
-                        },
-                    });
+                    .unwrap_or_else(|| ProcessNetworkUsage::idle(&settings::unit_preferences()));
 
                 let metadata = get_process_metadata(&exe_path);
 
@@ -195,16 +229,14 @@ impl SystemMonitor {
                 ) {
                     sockets.iter().any(|socket| {
                         socket.associated_pids.first()
-                            .map(|&pid| pid as u32 == process_pid)
+                            .map(|&pid| pid == process_pid)
                             .unwrap_or(false)
                     })
                 } else {
                     false
                 };
 
-                let status = if network_usage.download.value > 0.0 
-                    || network_usage.upload.value > 0.0 
-                    || has_active_connections {
+                let status = if network_usage.is_active() || has_active_connections {
                     ProcessStatus::Online
                 } else {
                     ProcessStatus::Offline
@@ -217,6 +249,8 @@ impl SystemMonitor {
                     path: exe_path.clone(),
                     icon: metadata.icon_base64,
                     network_usage: network_usage.clone(),
+                    packet_stats: self.network_cache.get_packet_stats(process_pid).unwrap_or_default(),
+                    totals: self.traffic_totals.get(process_pid),
                     pid: process_pid,
                     parent_pid: process.parent().map(|p| p.as_u32()),
                     children: Vec::new(),
@@ -225,6 +259,7 @@ impl SystemMonitor {
                         || process.name().to_lowercase().contains("svchost")
                         || exe_path.to_lowercase().contains("\\windows\\"),
                     category: self.determine_process_category(process, &network_usage),
+                    inherited_from: None,
                 };
 
                 if let Some(parent_pid) = app_process.parent_pid {
@@ -251,13 +286,27 @@ impl SystemMonitor {
             // cleanup processes and maintaining order
             self.process_cache.cleanup_inactive_processes(&active_pids);
 
+            // totals outlive the process, they just stop being attributed to a running pid
+            self.traffic_totals.mark_exited(&current_pids);
+            self.traffic_history.forget_exited(&current_pids);
+            throttle::forget_exited(&current_pids);
+            priority::forget_exited(&current_pids);
+            firewall::forget_exited(&current_pids);
+
             log_info!("Updated {} network-capable processes in cache", updated_count);
             *self.last_process_list.write() = current_pids.clone();
         }
+
+        let processes = self.get_processes();
+        if let Err(e) = rules::reconcile(&processes) {
+            log_error!("{}", e);
+        }
+        app_limits::reconcile(&process_tree::processes());
+        priority::reconcile(&processes);
     }
 
     fn determine_process_category(&self, process: &Process, network_usage: &ProcessNetworkUsage) -> String {
-        if network_usage.download.value > 0.0 || network_usage.upload.value > 0.0 {
+        if network_usage.is_active() {
             if process.name().to_lowercase().contains("svchost")
                 || process.name().to_lowercase().contains("system")
                 || process.name().to_lowercase().contains("service") {
@@ -289,15 +338,82 @@ impl SystemMonitor {
     pub fn get_network_cache(&self) -> &Arc<NetworkCache> {
         &self.network_cache
     }
+
+    pub fn get_traffic_totals(&self) -> &Arc<TotalsStore> {
+        &self.traffic_totals
+    }
+
+    pub fn get_traffic_history(&self) -> &Arc<TrafficHistory> {
+        &self.traffic_history
+    }
+
+    pub fn get_usage_store(&self) -> &Arc<UsageStore> {
+        &self.usage_store
+    }
+
+    pub fn get_quota_monitor(&self) -> &Arc<QuotaMonitor> {
+        &self.quota_monitor
+    }
+
+    pub fn get_attribution(&self) -> &Arc<AttributionStats> {
+        &self.attribution
+    }
+
+    pub fn evaluate_quotas(&self) {
+        self.quota_monitor.evaluate(&self.usage_store, &self.get_processes());
+    }
+}
+
+impl Default for SystemMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub fn start_monitoring(monitor: Arc<SystemMonitor>) {
+    let scan_monitor = Arc::clone(&monitor);
     tokio::spawn(async move {
         let mut interval = interval(Duration::from_millis(PROCESS_SCAN_INTERVAL_MS));
 
         loop {
             interval.tick().await;
-            monitor.update_processes().await;
+            scan_monitor.update_processes().await;
+        }
+    });
+
+    let usage_monitor = Arc::clone(&monitor);
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_millis(USAGE_FLUSH_INTERVAL_MS));
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = usage_monitor.usage_store.flush() {
+                log_error!("{}", e);
+            }
+        }
+    });
+
+    let quota_monitor = Arc::clone(&monitor);
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_millis(QUOTA_CHECK_INTERVAL_MS));
+
+        loop {
+            interval.tick().await;
+            quota_monitor.evaluate_quotas();
+        }
+    });
+
+    // profile triggers and rule and limit schedules, which switch on their own
+    let schedule_monitor = Arc::clone(&monitor);
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_millis(SCHEDULE_CHECK_INTERVAL_MS));
+
+        loop {
+            interval.tick().await;
+            let processes = schedule_monitor.get_processes();
+            profiles::evaluate_triggers(&processes);
+            rules::apply_schedules(&processes);
+            app_limits::reconcile(&process_tree::processes());
         }
     });
 
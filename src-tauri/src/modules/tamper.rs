@@ -0,0 +1,470 @@
+use std::net::IpAddr;
+use std::path::Path;
+use crate::models::{PacketRegion, TamperOperation, TamperReport, TamperRule, TamperRuleHits, TrafficDirection, TransportProtocol};
+use crate::modules::emulator::direction_of;
+use crate::modules::packet::{self, PacketLayout};
+use crate::utils::pcap::{self, PcapFile};
+use crate::utils::rng::SeededRng;
+
+enum Operation {
+    Replace { offset: usize, length: usize, bytes: Vec<u8> },
+    Flip { offset: usize, length: usize, mask: u8 },
+    Randomize { offset: usize, length: usize },
+}
+
+struct CompiledRule {
+    rule: TamperRule,
+    remote_addr: Option<IpAddr>,
+    pattern: Option<(PacketRegion, usize, Vec<u8>)>,
+    operations: Vec<Operation>,
+    hits: TamperRuleHits,
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("Hex string '{}' has an odd number of digits", hex));
+    }
+
+    digits.chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16).map_err(|_| format!("Invalid hex byte '{}' in '{}'", byte, hex))
+        })
+        .collect()
+}
+
+fn compile(rule: &TamperRule) -> Result<CompiledRule, String> {
+    if rule.name.trim().is_empty() {
+        return Err("Tamper rule name cannot be empty".to_string());
+    }
+    if rule.operations.is_empty() {
+        return Err(format!("Tamper rule '{}' has no operations", rule.name));
+    }
+    if !(0.0..=100.0).contains(&rule.percent) {
+        return Err("Tamper chance must be between 0 and 100".to_string());
+    }
+
+    let remote_addr = rule.remote_addr.as_ref()
+        .map(|addr| addr.parse::<IpAddr>().map_err(|_| format!("Invalid remote address '{}'", addr)))
+        .transpose()?;
+
+    let pattern = match &rule.pattern {
+        Some(pattern) => {
+            let bytes = parse_hex(&pattern.bytes)?;
+            if bytes.is_empty() {
+                return Err("Tamper pattern cannot be empty".to_string());
+            }
+            Some((pattern.region, pattern.offset, bytes))
+        }
+        None => None,
+    };
+
+    let operations = rule.operations.iter()
+        .map(|operation| Ok(match operation {
+            TamperOperation::Replace { offset, length, bytes } => {
+                let bytes = parse_hex(bytes)?;
+                Operation::Replace { offset: *offset, length: length.unwrap_or(bytes.len()), bytes }
+            }
+            TamperOperation::Flip { offset, length, mask } => Operation::Flip { offset: *offset, length: *length, mask: *mask },
+            TamperOperation::Randomize { offset, length } => Operation::Randomize { offset: *offset, length: *length },
+        }))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(CompiledRule {
+        rule: rule.clone(),
+        remote_addr,
+        pattern,
+        operations,
+        hits: TamperRuleHits {
+            id: rule.id.clone(),
+            matched: 0,
+            modified: 0,
+        },
+    })
+}
+
+pub fn validate(rule: &TamperRule) -> Result<(), String> {
+    compile(rule).map(|_| ())
+}
+
+// end of the IP datagram, which excludes any link-layer padding after it
+fn ip_end(layout: &PacketLayout, data: &[u8]) -> usize {
+    let at = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
+    let end = match layout.src_addr {
+        IpAddr::V4(_) => layout.ip_offset + at(layout.ip_offset + 2),
+        IpAddr::V6(_) => layout.ip_offset + 40 + at(layout.ip_offset + 4),
+    };
+    end.min(data.len())
+}
+
+// The length fields and the IPv4 header have to fit inside the datagram for
+// lengths and checksums to be rewritten. Captures from offloading NICs often
+// carry a total length of 0, and a header length only says what should follow.
+fn in_bounds(layout: &PacketLayout, data: &[u8], end: usize) -> bool {
+    let ip = layout.ip_offset;
+    let header_end = match layout.src_addr {
+        IpAddr::V4(_) if ip < end => ip + ((data[ip] & 0x0f) as usize) * 4,
+        IpAddr::V4(_) => return false,
+        IpAddr::V6(_) => ip + 40,
+    };
+    let transport_end = match layout.protocol {
+        Some(TransportProtocol::Udp) => layout.transport_offset + 8,
+        _ => layout.transport_offset,
+    };
+    header_end <= end && transport_end <= end
+}
+
+fn region_start(layout: &PacketLayout, region: PacketRegion) -> usize {
+    match region {
+        PacketRegion::Ip => layout.ip_offset,
+        PacketRegion::Transport => layout.transport_offset,
+        PacketRegion::Payload => layout.payload_offset,
+    }
+}
+
+impl CompiledRule {
+    // a capture has no owning process, so pid constraints only apply when one is known
+    fn matches(&self, pid: Option<u32>, direction: TrafficDirection, layout: &PacketLayout, data: &[u8]) -> bool {
+        let rule = &self.rule;
+        let (local_port, remote) = match direction {
+            TrafficDirection::Upload => (layout.src_port, (layout.dst_addr, layout.dst_port)),
+            TrafficDirection::Download => (layout.dst_port, (layout.src_addr, layout.src_port)),
+        };
+
+        let header_matches = rule.pid.is_none_or(|rule_pid| pid.is_none_or(|pid| pid == rule_pid))
+            && rule.direction.is_none_or(|rule_direction| rule_direction == direction)
+            && rule.protocol.is_none_or(|protocol| layout.protocol == Some(protocol))
+            && rule.local_port.is_none_or(|port| port == local_port)
+            && self.remote_addr.is_none_or(|addr| addr == remote.0)
+            && rule.remote_port.is_none_or(|port| port == remote.1);
+        if !header_matches {
+            return false;
+        }
+
+        let Some((region, offset, bytes)) = &self.pattern else {
+            return true;
+        };
+        let start = region_start(layout, *region) + offset;
+        let end = ip_end(layout, data);
+        start + bytes.len() <= end && data[start..start + bytes.len()] == bytes[..]
+    }
+
+    // false when the result no longer holds together, the caller puts the packet back
+    fn apply(&self, layout: &PacketLayout, data: &mut Vec<u8>, rng: &mut SeededRng) -> bool {
+        let base = region_start(layout, self.rule.region);
+        let mut end = ip_end(layout, data);
+
+        for operation in &self.operations {
+            match operation {
+                Operation::Replace { offset, length, bytes } => {
+                    let start = base + offset;
+                    if start > end {
+                        continue;
+                    }
+                    let stop = (start + length).min(end);
+                    data.splice(start..stop, bytes.iter().copied());
+                    end = end - (stop - start) + bytes.len();
+                }
+                Operation::Flip { offset, length, mask } => {
+                    for byte in data.iter_mut().take(end).skip(base + offset).take(*length) {
+                        *byte ^= mask;
+                    }
+                }
+                Operation::Randomize { offset, length } => {
+                    for byte in data.iter_mut().take(end).skip(base + offset).take(*length) {
+                        *byte = rng.next_u64() as u8;
+                    }
+                }
+            }
+        }
+
+        if !in_bounds(layout, data, end) {
+            return false;
+        }
+        fix_lengths(layout, data, end);
+        if self.rule.recompute_checksums {
+            recompute_checksums(layout, data, end);
+        }
+        true
+    }
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn fix_lengths(layout: &PacketLayout, data: &mut [u8], end: usize) {
+    match layout.src_addr {
+        IpAddr::V4(_) => write_u16(data, layout.ip_offset + 2, (end - layout.ip_offset) as u16),
+        IpAddr::V6(_) => write_u16(data, layout.ip_offset + 4, (end - layout.ip_offset - 40) as u16),
+    }
+    if layout.protocol == Some(TransportProtocol::Udp) {
+        write_u16(data, layout.transport_offset + 4, (end - layout.transport_offset) as u16);
+    }
+}
+
+fn checksum_add(mut sum: u32, bytes: &[u8]) -> u32 {
+    let mut chunks = bytes.chunks_exact(2);
+    for pair in &mut chunks {
+        sum += u16::from_be_bytes([pair[0], pair[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn recompute_checksums(layout: &PacketLayout, data: &mut [u8], end: usize) {
+    let ip = layout.ip_offset;
+    if layout.src_addr.is_ipv4() {
+        let header_len = ((data[ip] & 0x0f) as usize) * 4;
+        write_u16(data, ip + 10, 0);
+        let checksum = checksum_finish(checksum_add(0, &data[ip..ip + header_len]));
+        write_u16(data, ip + 10, checksum);
+
+        // the transport checksum of a fragmented datagram covers bytes we do not have
+        let more_fragments = data[ip + 6] & 0x20 != 0;
+        if more_fragments {
+            return;
+        }
+    }
+
+    let (checksum_offset, protocol_number) = match layout.protocol {
+        Some(TransportProtocol::Tcp) => (16, 6u32),
+        Some(TransportProtocol::Udp) => (6, 17u32),
+        None => return,
+    };
+    let transport = layout.transport_offset;
+    if transport + checksum_offset + 2 > end {
+        return;
+    }
+
+    // an IPv4 UDP checksum of zero means the sender did not use one
+    let is_ipv4 = layout.src_addr.is_ipv4();
+    let udp_without_checksum = protocol_number == 17 && is_ipv4 && data[transport + 6..transport + 8] == [0, 0];
+    if udp_without_checksum {
+        return;
+    }
+
+    let transport_len = (end - transport) as u32;
+    let mut sum = match (layout.src_addr, layout.dst_addr) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => checksum_add(checksum_add(0, &src.octets()), &dst.octets()),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => checksum_add(checksum_add(0, &src.octets()), &dst.octets()),
+        _ => return,
+    };
+    sum += protocol_number + (transport_len >> 16) + (transport_len & 0xffff);
+
+    write_u16(data, transport + checksum_offset, 0);
+    let mut checksum = checksum_finish(checksum_add(sum, &data[transport..end]));
+    if checksum == 0 && protocol_number == 17 {
+        checksum = 0xffff;
+    }
+    write_u16(data, transport + checksum_offset, checksum);
+}
+
+// Applies the first enabled rule that matches each packet. Randomness comes
+// from the seed so the same input is always tampered the same way.
+pub struct Tamperer {
+    rules: Vec<CompiledRule>,
+    rng: SeededRng,
+}
+
+impl Tamperer {
+    pub fn new(rules: &[TamperRule], seed: u64) -> Result<Self, String> {
+        Ok(Self {
+            rules: rules.iter()
+                .filter(|rule| rule.enabled)
+                .map(compile)
+                .collect::<Result<Vec<_>, String>>()?,
+            rng: SeededRng::new(seed),
+        })
+    }
+
+    // returns true when the packet was changed
+    pub fn apply(&mut self, pid: Option<u32>, direction: TrafficDirection, layout: &PacketLayout, data: &mut Vec<u8>) -> bool {
+        if !in_bounds(layout, data, ip_end(layout, data)) {
+            return false;
+        }
+        let Some(rule) = self.rules.iter_mut().find(|rule| rule.matches(pid, direction, layout, data)) else {
+            return false;
+        };
+
+        rule.hits.matched += 1;
+        if !self.rng.chance(rule.rule.percent / 100.0) {
+            return false;
+        }
+
+        let original = data.clone();
+        // a datagram can not grow past what its length fields can express
+        if !rule.apply(layout, data, &mut self.rng) || data.len() - layout.ip_offset > u16::MAX as usize {
+            *data = original;
+            return false;
+        }
+
+        rule.hits.modified += 1;
+        true
+    }
+
+    pub fn hits(&self) -> Vec<TamperRuleHits> {
+        self.rules.iter().map(|rule| rule.hits.clone()).collect()
+    }
+}
+
+pub fn replay(rules: &[TamperRule], input: &Path, output: Option<&Path>, local_addr: Option<IpAddr>, seed: u64) -> Result<TamperReport, String> {
+    let mut capture = pcap::read(input)?;
+    let mut tamperer = Tamperer::new(rules, seed)?;
+    let mut report = TamperReport {
+        packets_read: capture.packets.len() as u64,
+        packets_modified: 0,
+        unparsed: 0,
+        rules: Vec::new(),
+        output_path: output.map(|path| path.display().to_string()),
+    };
+
+    for captured in &mut capture.packets {
+        let Some(layout) = packet::parse(capture.link_type, &captured.data) else {
+            report.unparsed += 1;
+            continue;
+        };
+
+        let direction = direction_of(&layout, local_addr);
+        if tamperer.apply(None, direction, &layout, &mut captured.data) {
+            report.packets_modified += 1;
+            captured.original_len = captured.data.len() as u32;
+        }
+    }
+
+    report.rules = tamperer.hits();
+    if let Some(path) = output {
+        pcap::write(path, &PcapFile {
+            link_type: capture.link_type,
+            packets: capture.packets,
+        })?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::BytePattern;
+
+    const LINKTYPE_IPV4: u32 = 228;
+
+    // IPv4/UDP with a valid header checksum and `payload` after the UDP header
+    fn udp_packet(payload: &[u8]) -> Vec<u8> {
+        let total = (28 + payload.len()) as u16;
+        let mut data = vec![0x45, 0, 0, 0, 0, 1, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        data[2..4].copy_from_slice(&total.to_be_bytes());
+        data.extend_from_slice(&[0x30, 0x39, 0x00, 0x35]);
+        data.extend_from_slice(&(total - 20).to_be_bytes());
+        // anything but zero, which would mean no checksum
+        data.extend_from_slice(&[0xff, 0xff]);
+        data.extend_from_slice(payload);
+        let layout = packet::parse(LINKTYPE_IPV4, &data).unwrap();
+        let end = data.len();
+        recompute_checksums(&layout, &mut data, end);
+        data
+    }
+
+    fn rule(operations: Vec<TamperOperation>) -> TamperRule {
+        TamperRule {
+            id: "r".to_string(),
+            name: "rule".to_string(),
+            enabled: true,
+            pid: None,
+            direction: None,
+            protocol: None,
+            local_port: None,
+            remote_addr: None,
+            remote_port: None,
+            pattern: None,
+            region: PacketRegion::Payload,
+            operations,
+            percent: 100.0,
+            recompute_checksums: true,
+        }
+    }
+
+    fn tamper(rule: TamperRule, data: &mut Vec<u8>) -> bool {
+        let layout = packet::parse(LINKTYPE_IPV4, data).unwrap();
+        Tamperer::new(&[rule], 0).unwrap().apply(None, TrafficDirection::Upload, &layout, data)
+    }
+
+    fn sums_to_zero(bytes: &[u8], seed: u32) -> bool {
+        checksum_finish(checksum_add(seed, bytes)) == 0
+    }
+
+    fn udp_checksum_holds(data: &[u8]) -> bool {
+        let udp_len = (data.len() - 20) as u32;
+        let pseudo = checksum_add(checksum_add(0, &data[12..16]), &data[16..20]) + 17 + udp_len;
+        sums_to_zero(&data[20..], pseudo)
+    }
+
+    #[test]
+    fn replace_rewrites_lengths_and_checksums() {
+        let mut data = udp_packet(b"hello");
+        assert!(tamper(rule(vec![TamperOperation::Replace { offset: 0, length: None, bytes: "676f6f646279652121".to_string() }]), &mut data));
+
+        assert_eq!(&data[28..], b"goodbye!!");
+        assert_eq!(u16::from_be_bytes([data[2], data[3]]), 37);
+        assert_eq!(u16::from_be_bytes([data[24], data[25]]), 17);
+        assert!(sums_to_zero(&data[..20], 0));
+        assert!(udp_checksum_holds(&data));
+    }
+
+    #[test]
+    fn patterns_pick_the_packets() {
+        let mut matching = rule(vec![TamperOperation::Flip { offset: 0, length: 2, mask: 0xff }]);
+        matching.pattern = Some(BytePattern { region: PacketRegion::Payload, offset: 1, bytes: "656c".to_string() });
+
+        let mut other = udp_packet(b"world");
+        assert!(!tamper(matching.clone(), &mut other));
+        let mut data = udp_packet(b"hello");
+        assert!(tamper(matching, &mut data));
+        assert_eq!(&data[28..], &[!b'h', !b'e', b'l', b'l', b'o']);
+        assert!(udp_checksum_holds(&data));
+    }
+
+    #[test]
+    fn truncated_headers_are_left_alone() {
+        // the header length claims options that were never captured
+        let mut data = udp_packet(b"hello");
+        data[0] = 0x4f;
+        let original = data.clone();
+        assert!(!tamper(rule(vec![TamperOperation::Flip { offset: 0, length: 1, mask: 1 }]), &mut data));
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn zero_total_length_is_left_alone() {
+        let mut data = udp_packet(b"hello");
+        data[2..4].copy_from_slice(&[0, 0]);
+        let original = data.clone();
+        let mut tamperer = Tamperer::new(&[rule(vec![TamperOperation::Randomize { offset: 0, length: 5 }])], 0).unwrap();
+        let layout = packet::parse(LINKTYPE_IPV4, &data).unwrap();
+
+        assert!(!tamperer.apply(None, TrafficDirection::Upload, &layout, &mut data));
+        assert_eq!(data, original);
+        assert_eq!(tamperer.hits()[0].matched, 0);
+    }
+
+    #[test]
+    fn shrinking_into_the_transport_header_is_undone() {
+        let mut data = udp_packet(b"hello");
+        let original = data.clone();
+        let mut cut = rule(vec![TamperOperation::Replace { offset: 0, length: Some(100), bytes: String::new() }]);
+        cut.region = PacketRegion::Ip;
+        assert!(!tamper(cut, &mut data));
+        assert_eq!(data, original);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use crate::models::{EnforcementStatus, LimiterConfig, PriorityClass};
+use crate::modules::settings;
+use crate::modules::enforcement::{self, EnforcementBackend};
+use crate::modules::limiter::{BandwidthLimit, Limiter};
+use crate::utils::clock::SystemClock;
+use crate::log_error;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum LimitSource {
+    User,
+    Quota(String),
+    // an application limit, by executable path
+    App(String),
+    Rule(String),
+}
+
+impl LimitSource {
+    // which limit stays when two sources want the same process
+    fn precedence(&self) -> u8 {
+        match self {
+            LimitSource::User => 3,
+            LimitSource::Quota(_) => 2,
+            LimitSource::App(_) => 1,
+            LimitSource::Rule(_) => 0,
+        }
+    }
+}
+
+// limits are in KB/s, matching throttle_process; 0 leaves a direction unlimited
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProcessLimit {
+    pub download_limit: u64,
+    pub upload_limit: u64,
+    pub blocked: bool,
+    pub source: LimitSource,
+    // set when the limit reached this process through one of its ancestors
+    #[serde(default)]
+    pub inherited_from: Option<u32>,
+}
+
+impl ProcessLimit {
+    pub fn bandwidth_limit(&self) -> BandwidthLimit {
+        let bytes_per_second = |kb: u64| (kb > 0).then(|| kb * 1024);
+        BandwidthLimit {
+            upload: bytes_per_second(self.upload_limit),
+            download: bytes_per_second(self.download_limit),
+            burst: None,
+            blocked: self.blocked,
+        }
+    }
+}
+
+// every source's limit on each process, at most one per source
+static THROTTLED_PROCESSES: Lazy<RwLock<HashMap<u32, Vec<ProcessLimit>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+static LIMITER: Lazy<Limiter> = Lazy::new(|| {
+    Limiter::new(Arc::new(SystemClock::new()), LimiterConfig::default())
+});
+
+static BACKEND: Lazy<Box<dyn EnforcementBackend>> = Lazy::new(enforcement::default_backend);
+
+// verdicts for packets, consulted by whatever enforces them on this platform
+pub fn limiter() -> &'static Limiter {
+    &LIMITER
+}
+
+// pushes the configured global limit to the limiter
+pub fn apply_global_limit() {
+    let global = settings::global_limit();
+    let limit = ProcessLimit {
+        download_limit: global.download_limit,
+        upload_limit: global.upload_limit,
+        blocked: false,
+        source: LimitSource::User,
+        inherited_from: None,
+    }.bandwidth_limit();
+    LIMITER.set_global_limit((limit.upload.is_some() || limit.download.is_some()).then_some(limit));
+}
+
+// the limiter always takes the capacity; the backend may refuse it
+pub fn set_link_capacity(capacity: Option<BandwidthLimit>) -> Result<(), String> {
+    LIMITER.set_link_capacity(capacity);
+    BACKEND.set_link_capacity(capacity.as_ref())
+}
+
+pub fn set_priority(pid: u32, class: Option<PriorityClass>) -> Result<(), String> {
+    LIMITER.set_priority(pid, class);
+    BACKEND.set_priority(pid, class)
+}
+
+// A block always beats a throttle; otherwise a hand-set limit beats a quota,
+// which beats an application limit, which beats a rule.
+fn strongest(limits: &[ProcessLimit]) -> Option<&ProcessLimit> {
+    limits.iter().max_by_key(|limit| (limit.blocked, limit.source.precedence()))
+}
+
+// Puts the strongest limit on the process, or lifts it when there is none. A
+// process under its own application's limit shares the app's bucket in the
+// limiter instead of getting one of its own, and leaves it as soon as another
+// limit wins; backends only know processes.
+fn enforce(pid: u32, limit: Option<&ProcessLimit>) -> Result<(), String> {
+    let Some(limit) = limit else {
+        lift(pid);
+        return Ok(());
+    };
+
+    let bandwidth_limit = limit.bandwidth_limit();
+    if limit.blocked {
+        BACKEND.block(pid)?;
+    } else {
+        BACKEND.apply_limit(pid, &bandwidth_limit)?;
+    }
+    let app_bucket = match &limit.source {
+        LimitSource::App(path) if limit.inherited_from.is_none() => Some(path.as_str()),
+        _ => None,
+    };
+    LIMITER.set_process_limit(pid, app_bucket.is_none().then_some(bandwidth_limit));
+    LIMITER.set_app_member(pid, app_bucket);
+    Ok(())
+}
+
+// Records the limit in place of whatever its source set before and enforces
+// the strongest limit on the process. Nothing changes if the backend refuses.
+pub fn set_limit(pid: u32, limit: ProcessLimit) -> Result<(), String> {
+    let mut throttled = THROTTLED_PROCESSES.write();
+    let limits = throttled.entry(pid).or_default();
+    if limits.contains(&limit) {
+        return Ok(());
+    }
+
+    let previous = limits.clone();
+    limits.retain(|existing| existing.source != limit.source);
+    limits.push(limit);
+    let enforced = strongest(limits).cloned();
+    if strongest(&previous) != enforced.as_ref() {
+        if let Err(e) = enforce(pid, enforced.as_ref()) {
+            if previous.is_empty() {
+                throttled.remove(&pid);
+            } else {
+                throttled.insert(pid, previous);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// drops what one source set, the next strongest limit takes over
+pub fn remove_limit(pid: u32, source: &LimitSource) -> Option<ProcessLimit> {
+    let mut throttled = THROTTLED_PROCESSES.write();
+    let limits = throttled.get_mut(&pid)?;
+    let position = limits.iter().position(|limit| &limit.source == source)?;
+
+    let was_enforced = strongest(limits) == Some(&limits[position]);
+    let removed = limits.remove(position);
+    if was_enforced {
+        if let Err(e) = enforce(pid, strongest(limits)) {
+            log_error!("Failed to enforce the remaining limit on process {}: {}", pid, e);
+        }
+    }
+    if limits.is_empty() {
+        throttled.remove(&pid);
+    }
+    Some(removed)
+}
+
+// lifts everything a given source imposed, leaving other limits alone
+pub fn remove_by_source(source: &LimitSource) -> Vec<u32> {
+    let pids: Vec<u32> = THROTTLED_PROCESSES.read().iter()
+        .filter(|(_, limits)| limits.iter().any(|limit| &limit.source == source))
+        .map(|(&pid, _)| pid)
+        .collect();
+
+    for pid in &pids {
+        remove_limit(*pid, source);
+    }
+    pids
+}
+
+// drops whatever was set for processes that are gone, so a reused pid starts clean
+pub fn forget_exited(running: &HashSet<u32>) -> Vec<u32> {
+    let mut limits = THROTTLED_PROCESSES.write();
+    let pids: Vec<u32> = limits.keys()
+        .filter(|pid| !running.contains(pid))
+        .copied()
+        .collect();
+
+    for pid in &pids {
+        lift(*pid);
+        limits.remove(pid);
+    }
+
+    pids
+}
+
+fn lift(pid: u32) {
+    LIMITER.set_process_limit(pid, None);
+    LIMITER.set_app_member(pid, None);
+    if let Err(e) = BACKEND.remove_limit(pid) {
+        log_error!("Failed to remove enforcement for process {}: {}", pid, e);
+    }
+}
+
+pub fn enforcement_status() -> EnforcementStatus {
+    EnforcementStatus {
+        backend: BACKEND.name().to_string(),
+        active: BACKEND.list_active(),
+    }
+}
+
+// the limit in force on a process
+pub fn get_limit(pid: u32) -> Option<ProcessLimit> {
+    THROTTLED_PROCESSES.read().get(&pid).and_then(|limits| strongest(limits).cloned())
+}
+
+// every recorded limit, including those a stronger one overrides
+pub fn limits() -> Vec<(u32, ProcessLimit)> {
+    THROTTLED_PROCESSES.read().iter()
+        .flat_map(|(&pid, limits)| limits.iter().map(move |limit| (pid, limit.clone())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(source: LimitSource, download_limit: u64, blocked: bool) -> ProcessLimit {
+        ProcessLimit {
+            download_limit,
+            upload_limit: 0,
+            blocked,
+            source,
+            inherited_from: None,
+        }
+    }
+
+    #[test]
+    fn strongest_follows_blocks_then_precedence() {
+        assert_eq!(strongest(&[]), None);
+
+        let rule = limit(LimitSource::Rule("r".to_string()), 10, false);
+        let app = limit(LimitSource::App("C:\\game.exe".to_string()), 500, false);
+        let user = limit(LimitSource::User, 1_000, false);
+        assert_eq!(strongest(&[rule.clone(), user.clone(), app.clone()]), Some(&user));
+        assert_eq!(strongest(&[rule.clone(), app.clone()]), Some(&app));
+
+        let blocking_rule = limit(LimitSource::Rule("r".to_string()), 0, true);
+        assert_eq!(strongest(&[user, blocking_rule.clone(), app]), Some(&blocking_rule));
+    }
+
+    #[test]
+    fn a_stronger_limit_takes_the_process_out_of_its_app_bucket() {
+        let path = "/opt/bucket-test/app";
+        LIMITER.set_app_limit(path, Some(BandwidthLimit { blocked: true, ..BandwidthLimit::default() }));
+
+        set_limit(9_101, limit(LimitSource::App(path.to_string()), 100, false)).unwrap();
+        assert!(LIMITER.is_blocked(9_101));
+
+        set_limit(9_101, limit(LimitSource::User, 1_000, false)).unwrap();
+        assert!(!LIMITER.is_blocked(9_101));
+
+        // back in once the stronger limit goes
+        remove_limit(9_101, &LimitSource::User);
+        assert!(LIMITER.is_blocked(9_101));
+
+        remove_limit(9_101, &LimitSource::App(path.to_string()));
+        assert!(!LIMITER.is_blocked(9_101));
+        LIMITER.set_app_limit(path, None);
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use parking_lot::RwLock;
+use crate::models::{ByteRate, HistoryPoint, HistoryResolution, HistorySeries, HISTORY_SAMPLE_INTERVAL_MS};
+
+const RESOLUTIONS: [HistoryResolution; 3] = [
+    HistoryResolution::Second,
+    HistoryResolution::Minute,
+    HistoryResolution::Hour,
+];
+
+// a sample further apart from the last one than this, e.g. after the
+// machine slept, only counts for one interval; its rate says nothing about the gap
+const MAX_SAMPLE_GAP_MS: i64 = 5 * HISTORY_SAMPLE_INTERVAL_MS as i64;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    start: i64,
+    download_bytes: f64,
+    upload_bytes: f64,
+}
+
+// One resolution of a series. Only buckets that saw traffic are stored,
+// gaps are filled with zeros when the series is read back.
+struct Tier {
+    resolution: HistoryResolution,
+    closed: VecDeque<Bucket>,
+    open: Option<Bucket>,
+}
+
+impl Tier {
+    fn new(resolution: HistoryResolution) -> Self {
+        Self {
+            resolution,
+            closed: VecDeque::new(),
+            open: None,
+        }
+    }
+
+    fn add(&mut self, timestamp: i64, download_bytes: f64, upload_bytes: f64) {
+        let width = self.resolution.seconds() as i64;
+        let start = timestamp - timestamp.rem_euclid(width);
+
+        match self.open.as_mut() {
+            // samples older than the open bucket are folded into it
+            Some(open) if open.start >= start => {
+                open.download_bytes += download_bytes;
+                open.upload_bytes += upload_bytes;
+            }
+            _ => {
+                if let Some(finished) = self.open.take() {
+                    self.closed.push_back(finished);
+                }
+                self.open = Some(Bucket { start, download_bytes, upload_bytes });
+            }
+        }
+
+        let oldest = start - self.resolution.retention_seconds() as i64;
+        while matches!(self.closed.front(), Some(bucket) if bucket.start < oldest) {
+            self.closed.pop_front();
+        }
+    }
+
+    fn series(&self, from: i64, to: i64) -> Vec<HistoryPoint> {
+        let width = self.resolution.seconds() as i64;
+        let first = from - from.rem_euclid(width);
+        let last = to - to.rem_euclid(width);
+
+        let stored: HashMap<i64, &Bucket> = self.closed.iter()
+            .chain(self.open.iter())
+            .filter(|bucket| bucket.start >= first && bucket.start <= last)
+            .map(|bucket| (bucket.start, bucket))
+            .collect();
+
+        let mut points = Vec::with_capacity(((last - first) / width + 1) as usize);
+        let mut start = first;
+        while start <= last {
+            // the current bucket is averaged over the part that has elapsed
+            let elapsed = (to - start + 1).clamp(1, width) as f64;
+            let point = match stored.get(&start) {
+                Some(bucket) => HistoryPoint {
+                    timestamp: start * 1000,
+                    download: ByteRate(bucket.download_bytes / elapsed),
+                    upload: ByteRate(bucket.upload_bytes / elapsed),
+                },
+                None => HistoryPoint {
+                    timestamp: start * 1000,
+                    download: ByteRate::ZERO,
+                    upload: ByteRate::ZERO,
+                },
+            };
+            points.push(point);
+            start += width;
+        }
+
+        points
+    }
+}
+
+struct SeriesHistory {
+    tiers: Vec<Tier>,
+    last_active: i64,
+}
+
+impl SeriesHistory {
+    fn new() -> Self {
+        Self {
+            tiers: RESOLUTIONS.iter().map(|&resolution| Tier::new(resolution)).collect(),
+            last_active: 0,
+        }
+    }
+
+    fn add(&mut self, timestamp: i64, download_bytes: f64, upload_bytes: f64) {
+        for tier in self.tiers.iter_mut() {
+            tier.add(timestamp, download_bytes, upload_bytes);
+        }
+        self.last_active = self.last_active.max(timestamp);
+    }
+
+    fn tier(&self, resolution: HistoryResolution) -> &Tier {
+        self.tiers.iter()
+            .find(|tier| tier.resolution == resolution)
+            .expect("every resolution has a tier")
+    }
+}
+
+pub struct HistorySample {
+    pub pid: u32,
+    pub app_path: Option<String>,
+    pub download: ByteRate,
+    pub upload: ByteRate,
+}
+
+pub enum HistoryTarget {
+    Global,
+    Process(u32),
+    Application(String),
+}
+
+// In-memory ring buffers fed with rate samples and downsampled into minute
+// and hour buckets as they arrive. Each sample stands for the time since the
+// one before, so late or doubled ticks neither lose nor count traffic twice.
+pub struct TrafficHistory {
+    global: RwLock<SeriesHistory>,
+    by_pid: RwLock<HashMap<u32, SeriesHistory>>,
+    by_app: RwLock<HashMap<String, SeriesHistory>>,
+    // unix milliseconds of the last sample
+    last_sample: RwLock<Option<i64>>,
+}
+
+impl TrafficHistory {
+    pub fn new() -> Self {
+        Self {
+            global: RwLock::new(SeriesHistory::new()),
+            by_pid: RwLock::new(HashMap::new()),
+            by_app: RwLock::new(HashMap::new()),
+            last_sample: RwLock::new(None),
+        }
+    }
+
+    // `timestamp_ms` is unix milliseconds; the samples are rates over the time
+    // since the previous call
+    pub fn record(&self, timestamp_ms: i64, samples: &[HistorySample]) {
+        let covered_ms = {
+            let mut last_sample = self.last_sample.write();
+            let covered_ms = match *last_sample {
+                Some(last) if timestamp_ms - last <= MAX_SAMPLE_GAP_MS => (timestamp_ms - last).max(0),
+                _ => HISTORY_SAMPLE_INTERVAL_MS as i64,
+            };
+            *last_sample = Some(last_sample.map_or(timestamp_ms, |last| last.max(timestamp_ms)));
+            covered_ms
+        };
+        let seconds = covered_ms as f64 / 1000.0;
+        let timestamp = timestamp_ms.div_euclid(1000);
+
+        let mut total_download = 0.0;
+        let mut total_upload = 0.0;
+        let mut apps: HashMap<&str, (f64, f64)> = HashMap::new();
+
+        {
+            let mut by_pid = self.by_pid.write();
+            for sample in samples {
+                let download = sample.download.bytes_per_second() * seconds;
+                let upload = sample.upload.bytes_per_second() * seconds;
+                if download <= 0.0 && upload <= 0.0 {
+                    continue;
+                }
+
+                total_download += download;
+                total_upload += upload;
+                by_pid.entry(sample.pid)
+                    .or_insert_with(SeriesHistory::new)
+                    .add(timestamp, download, upload);
+
+                if let Some(path) = sample.app_path.as_deref() {
+                    let app = apps.entry(path).or_insert((0.0, 0.0));
+                    app.0 += download;
+                    app.1 += upload;
+                }
+            }
+        }
+
+        {
+            let mut by_app = self.by_app.write();
+            for (path, (download, upload)) in apps {
+                by_app.entry(path.to_string())
+                    .or_insert_with(SeriesHistory::new)
+                    .add(timestamp, download, upload);
+            }
+
+            let retention = HistoryResolution::Hour.retention_seconds() as i64;
+            by_app.retain(|_, series| timestamp - series.last_active <= retention);
+        }
+
+        if total_download > 0.0 || total_upload > 0.0 {
+            self.global.write().add(timestamp, total_download, total_upload);
+        }
+    }
+
+    // a pid only means something while its process runs; a quiet one keeps its series
+    pub fn forget_exited(&self, running: &HashSet<u32>) {
+        self.by_pid.write().retain(|pid, _| running.contains(pid));
+    }
+
+    pub fn series(&self, target: &HistoryTarget, now: i64, range_secs: u64, resolution: Option<HistoryResolution>) -> HistorySeries {
+        let resolution = resolution.unwrap_or_else(|| pick_resolution(range_secs));
+        let range_secs = range_secs.min(resolution.retention_seconds()) as i64;
+        let from = now - range_secs;
+
+        let points = match target {
+            HistoryTarget::Global => self.global.read().tier(resolution).series(from, now),
+            HistoryTarget::Process(pid) => match self.by_pid.read().get(pid) {
+                Some(series) => series.tier(resolution).series(from, now),
+                None => Tier::new(resolution).series(from, now),
+            },
+            HistoryTarget::Application(path) => match self.by_app.read().get(path) {
+                Some(series) => series.tier(resolution).series(from, now),
+                None => Tier::new(resolution).series(from, now),
+            },
+        };
+
+        HistorySeries { resolution, points }
+    }
+}
+
+impl Default for TrafficHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// finest resolution that still covers the whole range
+fn pick_resolution(range_secs: u64) -> HistoryResolution {
+    RESOLUTIONS.iter()
+        .copied()
+        .find(|resolution| resolution.retention_seconds() >= range_secs)
+        .unwrap_or(HistoryResolution::Hour)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an hour boundary, so every tier's buckets line up with it
+    const T0: i64 = 1_700_002_800;
+
+    fn sample(pid: u32, download: f64) -> HistorySample {
+        HistorySample { pid, app_path: Some("/usr/bin/app".to_string()), download: ByteRate(download), upload: ByteRate::ZERO }
+    }
+
+    fn downloads(series: &HistorySeries) -> Vec<f64> {
+        series.points.iter().map(|point| point.download.bytes_per_second()).collect()
+    }
+
+    #[test]
+    fn minutes_and_hours_average_the_seconds() {
+        let history = TrafficHistory::new();
+        // 30 s at 100 B/s, then 30 s of silence, then 60 s at 40 B/s
+        for second in 0..120 {
+            let rate = match second {
+                0..=29 => 100.0,
+                30..=59 => 0.0,
+                _ => 40.0,
+            };
+            history.record((T0 + second) * 1000, &[sample(1, rate)]);
+        }
+
+        let now = T0 + 119;
+        let minutes = history.series(&HistoryTarget::Global, now, 60, Some(HistoryResolution::Minute));
+        assert_eq!(downloads(&minutes), vec![50.0, 40.0]);
+        // the open hour is averaged over the two minutes that have passed of it
+        let hours = history.series(&HistoryTarget::Global, now, 119, Some(HistoryResolution::Hour));
+        assert_eq!(downloads(&hours), vec![(3000.0 + 2400.0) / 120.0]);
+    }
+
+    #[test]
+    fn gaps_read_back_as_zeros() {
+        let history = TrafficHistory::new();
+        history.record(T0 * 1000, &[sample(1, 10.0)]);
+        history.record((T0 + 3) * 1000, &[sample(1, 10.0)]);
+
+        let seconds = history.series(&HistoryTarget::Process(1), T0 + 4, 5, None);
+        assert_eq!(seconds.resolution, HistoryResolution::Second);
+        // the second sample stands for the three seconds since the first
+        assert_eq!(downloads(&seconds), vec![0.0, 10.0, 0.0, 0.0, 30.0, 0.0]);
+        let unknown = history.series(&HistoryTarget::Application("/usr/bin/other".to_string()), T0 + 4, 5, None);
+        assert_eq!(downloads(&unknown), vec![0.0; 6]);
+    }
+
+    #[test]
+    fn samples_count_for_the_time_they_cover() {
+        let history = TrafficHistory::new();
+        // a late tick and the one that follows it closely land in one second
+        history.record(T0 * 1000, &[sample(1, 100.0)]);
+        history.record(T0 * 1000 + 1400, &[sample(1, 100.0)]);
+        history.record(T0 * 1000 + 1900, &[sample(1, 100.0)]);
+        // after the machine slept, the rate only stands for one interval
+        history.record((T0 + 3600) * 1000, &[sample(1, 100.0)]);
+
+        let hour = history.series(&HistoryTarget::Global, T0 + 3599, 3599, Some(HistoryResolution::Hour));
+        assert_eq!(downloads(&hour), vec![(100.0 + 140.0 + 50.0) / 3600.0]);
+        let next_hour = history.series(&HistoryTarget::Global, T0 + 3600, 0, Some(HistoryResolution::Hour));
+        assert_eq!(downloads(&next_hour), vec![100.0]);
+    }
+
+    #[test]
+    fn quiet_processes_keep_their_series_until_they_exit() {
+        let history = TrafficHistory::new();
+        history.record(T0 * 1000, &[sample(1, 10.0), sample(2, 10.0)]);
+        // an hour later pid 1 is quiet but running, pid 2 is gone
+        history.record((T0 + 3600) * 1000, &[sample(1, 0.0)]);
+        history.forget_exited(&HashSet::from([1]));
+
+        let bytes = |target: HistoryTarget| -> f64 {
+            downloads(&history.series(&target, T0 + 3599, 3599, Some(HistoryResolution::Hour))).iter().sum::<f64>() * 3600.0
+        };
+        assert_eq!(bytes(HistoryTarget::Process(1)), 10.0);
+        assert_eq!(bytes(HistoryTarget::Process(2)), 0.0);
+        // the app keeps what its processes did
+        assert_eq!(bytes(HistoryTarget::Application("/usr/bin/app".to_string())), 20.0);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::models::HistogramBucket;
+
+// log-scale buckets: [0, 64), [64, 128), [128, 256) ... [32768, inf)
+const HISTOGRAM_FIRST_SHIFT: u32 = 6;
+pub const HISTOGRAM_BUCKETS: usize = 11;
+
+#[derive(Debug, Clone, Default)]
+pub struct PacketSizeHistogram {
+    counts: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl PacketSizeHistogram {
+    pub fn record(&mut self, size: u64) {
+        self.counts[bucket_index(size)] += 1;
+    }
+
+    pub fn buckets(&self) -> Vec<HistogramBucket> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(index, &count)| {
+                let (min_size, max_size) = bucket_bounds(index);
+                HistogramBucket { min_size, max_size, count }
+            })
+            .collect()
+    }
+}
+
+fn bucket_index(size: u64) -> usize {
+    if size < (1 << HISTOGRAM_FIRST_SHIFT) {
+        return 0;
+    }
+
+    let log2 = 63 - size.leading_zeros();
+    ((log2 - HISTOGRAM_FIRST_SHIFT + 1) as usize).min(HISTOGRAM_BUCKETS - 1)
+}
+
+// max_size is exclusive, None for the open-ended last bucket
+fn bucket_bounds(index: usize) -> (u64, Option<u64>) {
+    let min_size = if index == 0 {
+        0
+    } else {
+        1 << (HISTOGRAM_FIRST_SHIFT + index as u32 - 1)
+    };
+
+    let max_size = if index == HISTOGRAM_BUCKETS - 1 {
+        None
+    } else {
+        Some(1 << (HISTOGRAM_FIRST_SHIFT + index as u32))
+    };
+
+    (min_size, max_size)
+}
+
+pub fn average_packet_size(bytes: u64, packets: u64) -> f64 {
+    if packets == 0 {
+        0.0
+    } else {
+        bytes as f64 / packets as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_land_in_log_scale_buckets() {
+        let mut histogram = PacketSizeHistogram::default();
+        for size in [0, 63, 64, 127, 128, 1500, 32_767, 32_768, u64::MAX] {
+            histogram.record(size);
+        }
+
+        let counts: Vec<u64> = histogram.buckets().iter().map(|bucket| bucket.count).collect();
+        assert_eq!(counts, vec![2, 2, 1, 0, 0, 1, 0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn bucket_bounds_are_contiguous() {
+        let buckets = PacketSizeHistogram::default().buckets();
+        assert_eq!((buckets[0].min_size, buckets[0].max_size), (0, Some(64)));
+        for pair in buckets.windows(2) {
+            assert_eq!(pair[0].max_size, Some(pair[1].min_size));
+        }
+        assert_eq!(buckets[HISTOGRAM_BUCKETS - 1].max_size, None);
+    }
+
+    #[test]
+    fn average_of_nothing_is_zero() {
+        assert_eq!(average_packet_size(0, 0), 0.0);
+        assert_eq!(average_packet_size(3000, 2), 1500.0);
+    }
+}
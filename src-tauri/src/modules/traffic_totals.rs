@@ -0,0 +1,248 @@
+use std::collections::{HashMap, HashSet};
+use chrono::Utc;
+use parking_lot::RwLock;
+use crate::models::{ByteTotals, ProcessTotals, TotalsReport, TrafficTotals, UNATTRIBUTED_PID, UNATTRIBUTED_NAME};
+
+struct TotalsEntry {
+    pid: u32,
+    name: String,
+    path: String,
+    totals: TrafficTotals,
+}
+
+impl TotalsEntry {
+    fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            name: if pid == UNATTRIBUTED_PID { UNATTRIBUTED_NAME.to_string() } else { String::new() },
+            path: String::new(),
+            totals: TrafficTotals::default(),
+        }
+    }
+
+    fn to_process_totals(&self, running: bool) -> ProcessTotals {
+        ProcessTotals {
+            pid: self.pid,
+            name: self.name.clone(),
+            path: self.path.clone(),
+            running,
+            totals: self.totals,
+        }
+    }
+}
+
+struct TotalsState {
+    live: HashMap<u32, TotalsEntry>,
+    // processes that exited or whose pid was reused keep their totals here,
+    // one entry per app so that short-lived processes don't pile up
+    exited: Vec<TotalsEntry>,
+    totals: TrafficTotals,
+    reset_at: i64,
+}
+
+impl TotalsState {
+    // folds into the app's earlier runs, or into one entry for everything
+    // that exited before it was identified
+    fn archive(&mut self, entry: TotalsEntry) {
+        let earlier = self.exited.iter_mut().find(|earlier| earlier.path.eq_ignore_ascii_case(&entry.path));
+        let Some(earlier) = earlier else {
+            self.exited.push(entry);
+            return;
+        };
+
+        for (into, from) in [(&mut earlier.totals.session, entry.totals.session), (&mut earlier.totals.since_reset, entry.totals.since_reset)] {
+            into.add(from.bytes_sent, from.bytes_received);
+        }
+        earlier.pid = entry.pid;
+        if !entry.name.is_empty() {
+            earlier.name = entry.name;
+        }
+    }
+}
+
+// Lifetime byte counters for the current session. Unlike the network cache
+// these are never dropped when a process goes idle or exits.
+pub struct TotalsStore {
+    session_started_at: i64,
+    state: RwLock<TotalsState>,
+}
+
+impl TotalsStore {
+    pub fn new() -> Self {
+        let now = Utc::now().timestamp_millis();
+        Self {
+            session_started_at: now,
+            state: RwLock::new(TotalsState {
+                live: HashMap::new(),
+                exited: Vec::new(),
+                totals: TrafficTotals::default(),
+                reset_at: now,
+            }),
+        }
+    }
+
+    pub fn record(&self, pid: u32, sent: u64, received: u64) {
+        let mut state = self.state.write();
+        state.totals.session.add(sent, received);
+        state.totals.since_reset.add(sent, received);
+
+        let entry = state.live.entry(pid).or_insert_with(|| TotalsEntry::new(pid));
+        entry.totals.session.add(sent, received);
+        entry.totals.since_reset.add(sent, received);
+    }
+
+    // attach a name and path to a pid, archiving the old entry if the pid was reused
+    pub fn identify(&self, pid: u32, name: &str, path: &str) {
+        if path.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.write();
+        let reused = match state.live.get(&pid) {
+            Some(entry) => !entry.path.is_empty() && entry.path != path,
+            None => return,
+        };
+
+        if reused {
+            if let Some(old) = state.live.remove(&pid) {
+                state.archive(old);
+            }
+            state.live.insert(pid, TotalsEntry {
+                name: name.to_string(),
+                path: path.to_string(),
+                ..TotalsEntry::new(pid)
+            });
+            return;
+        }
+
+        if let Some(entry) = state.live.get_mut(&pid) {
+            if entry.path.is_empty() {
+                entry.name = name.to_string();
+                entry.path = path.to_string();
+            }
+        }
+    }
+
+    pub fn mark_exited(&self, running_pids: &HashSet<u32>) {
+        let mut state = self.state.write();
+        let exited: Vec<u32> = state.live.keys()
+            // the unattributed bucket has no process behind it and never exits
+            .filter(|&&pid| pid != UNATTRIBUTED_PID && !running_pids.contains(&pid))
+            .copied()
+            .collect();
+
+        for pid in exited {
+            if let Some(entry) = state.live.remove(&pid) {
+                state.archive(entry);
+            }
+        }
+    }
+
+    pub fn get(&self, pid: u32) -> TrafficTotals {
+        self.state.read().live.get(&pid)
+            .map(|entry| entry.totals)
+            .unwrap_or_default()
+    }
+
+    pub fn reset(&self) {
+        let mut guard = self.state.write();
+        let state = &mut *guard;
+        state.totals.since_reset = ByteTotals::default();
+        state.reset_at = Utc::now().timestamp_millis();
+
+        for entry in state.live.values_mut().chain(state.exited.iter_mut()) {
+            entry.totals.since_reset = ByteTotals::default();
+        }
+    }
+
+    pub fn report(&self) -> TotalsReport {
+        let state = self.state.read();
+        let mut processes: Vec<ProcessTotals> = state.live.values()
+            .map(|entry| entry.to_process_totals(true))
+            .chain(state.exited.iter().map(|entry| entry.to_process_totals(false)))
+            .collect();
+
+        processes.sort_by(|a, b| {
+            let a_total = a.totals.session.bytes_sent + a.totals.session.bytes_received;
+            let b_total = b.totals.session.bytes_sent + b.totals.session.bytes_received;
+            b_total.cmp(&a_total)
+        });
+
+        TotalsReport {
+            session_started_at: self.session_started_at,
+            reset_at: state.reset_at,
+            totals: state.totals,
+            processes,
+        }
+    }
+}
+
+impl Default for TotalsStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running(pids: &[u32]) -> HashSet<u32> {
+        pids.iter().copied().collect()
+    }
+
+    #[test]
+    fn exited_runs_of_one_app_merge() {
+        let store = TotalsStore::new();
+        for pid in 100..150 {
+            store.record(pid, 10, 20);
+            store.identify(pid, "curl.exe", "C:\\Tools\\curl.exe");
+            store.mark_exited(&running(&[]));
+        }
+        store.record(200, 1, 1);
+        store.mark_exited(&running(&[]));
+        store.record(201, 1, 1);
+        store.mark_exited(&running(&[]));
+
+        let report = store.report();
+        assert_eq!(report.processes.len(), 2);
+        let curl = &report.processes[0];
+        assert_eq!(curl.pid, 149);
+        assert!(!curl.running);
+        assert_eq!(curl.totals.session.bytes_sent, 500);
+        assert_eq!(curl.totals.since_reset.bytes_received, 1000);
+        assert_eq!(report.processes[1].totals.session.bytes_sent, 2);
+    }
+
+    #[test]
+    fn reused_pid_starts_over_with_the_new_identity() {
+        let store = TotalsStore::new();
+        store.record(42, 100, 0);
+        store.identify(42, "old.exe", "C:\\old.exe");
+        store.identify(42, "new.exe", "C:\\new.exe");
+        store.record(42, 5, 0);
+
+        let report = store.report();
+        let live = report.processes.iter().find(|process| process.running).unwrap();
+        assert_eq!((live.name.as_str(), live.path.as_str()), ("new.exe", "C:\\new.exe"));
+        assert_eq!(live.totals.session.bytes_sent, 5);
+        let old = report.processes.iter().find(|process| !process.running).unwrap();
+        assert_eq!((old.name.as_str(), old.totals.session.bytes_sent), ("old.exe", 100));
+        assert_eq!(report.totals.session.bytes_sent, 105);
+    }
+
+    #[test]
+    fn reset_clears_exited_entries_too() {
+        let store = TotalsStore::new();
+        store.record(7, 10, 10);
+        store.mark_exited(&running(&[]));
+        store.record(UNATTRIBUTED_PID, 3, 3);
+        store.mark_exited(&running(&[]));
+        store.reset();
+
+        let report = store.report();
+        assert_eq!(report.processes.len(), 2);
+        assert!(report.processes.iter().all(|process| process.totals.since_reset == ByteTotals::default()));
+        assert_eq!(store.get(UNATTRIBUTED_PID).session.bytes_sent, 3);
+    }
+}
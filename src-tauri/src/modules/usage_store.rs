@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDate};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use crate::models::{AppUsageSummary, ByteTotals, DailyUsage};
+use crate::utils::paths::data_dir;
+use crate::{log_info, log_error};
+
+const USAGE_FILE: &str = "usage.json";
+const USAGE_FILE_VERSION: u32 = 1;
+
+const HOURLY_RETENTION_DAYS: i64 = 7;
+const DAILY_RETENTION_DAYS: i64 = 400;
+
+// keys are local time and sort chronologically as strings
+const HOUR_KEY: &str = "%Y-%m-%dT%H";
+const DAY_KEY: &str = "%Y-%m-%d";
+const MONTH_KEY: &str = "%Y-%m";
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct AppUsage {
+    name: String,
+    category: String,
+    hourly: BTreeMap<String, ByteTotals>,
+    daily: BTreeMap<String, ByteTotals>,
+    monthly: BTreeMap<String, ByteTotals>,
+}
+
+impl AppUsage {
+    fn add(&mut self, at: &DateTime<Local>, sent: u64, received: u64) {
+        let hour = at.format(HOUR_KEY).to_string();
+        if !self.hourly.contains_key(&hour) {
+            let oldest = (*at - ChronoDuration::days(HOURLY_RETENTION_DAYS)).format(HOUR_KEY).to_string();
+            self.hourly = self.hourly.split_off(&oldest);
+        }
+        self.hourly.entry(hour).or_default().add(sent, received);
+
+        let day = at.format(DAY_KEY).to_string();
+        if !self.daily.contains_key(&day) {
+            let oldest = (*at - ChronoDuration::days(DAILY_RETENTION_DAYS)).format(DAY_KEY).to_string();
+            self.daily = self.daily.split_off(&oldest);
+        }
+        self.daily.entry(day).or_default().add(sent, received);
+
+        self.monthly.entry(at.format(MONTH_KEY).to_string()).or_default().add(sent, received);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UsageData {
+    version: u32,
+    // keyed by executable path
+    apps: HashMap<String, AppUsage>,
+}
+
+// read before the rest so a file from a newer version is never parsed as ours
+#[derive(Deserialize)]
+struct UsageHeader {
+    version: u32,
+}
+
+impl Default for UsageData {
+    fn default() -> Self {
+        Self {
+            version: USAGE_FILE_VERSION,
+            apps: HashMap::new(),
+        }
+    }
+}
+
+// Per-application upload/download totals rolled up per hour, day and month,
+// kept in memory and flushed to the app data directory periodically.
+pub struct UsageStore {
+    path: Option<PathBuf>,
+    data: RwLock<UsageData>,
+    // bumped on every record, flushed is the change count last written out
+    changes: AtomicU64,
+    flushed: AtomicU64,
+}
+
+fn load(path: &Path) -> UsageData {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return UsageData::default();
+    };
+
+    match serde_json::from_str::<UsageHeader>(&contents) {
+        Ok(header) if header.version != USAGE_FILE_VERSION => {
+            // moved aside rather than overwritten, a newer build can still read it
+            let aside = path.with_extension(format!("v{}.json", header.version));
+            log_error!("Usage data at {} has unsupported version {}, moving it to {}", path.display(), header.version, aside.display());
+            move_aside(path, &aside);
+            return UsageData::default();
+        }
+        _ => {}
+    }
+
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        // kept for recovery by hand, the next flush would overwrite it
+        let aside = path.with_extension("corrupt.json");
+        log_error!("Failed to parse usage data at {}: {}, moving it to {}", path.display(), e, aside.display());
+        move_aside(path, &aside);
+        UsageData::default()
+    })
+}
+
+fn move_aside(path: &Path, aside: &Path) {
+    if let Err(e) = fs::rename(path, aside) {
+        log_error!("Failed to move usage data aside: {}", e);
+    }
+}
+
+impl UsageStore {
+    pub fn open() -> Self {
+        Self::open_at(data_dir().map(|dir| dir.join(USAGE_FILE)))
+    }
+
+    fn open_at(path: Option<PathBuf>) -> Self {
+        let data = path.as_deref().map(load).unwrap_or_default();
+        log_info!("Loaded usage data for {} applications", data.apps.len());
+
+        Self {
+            path,
+            data: RwLock::new(data),
+            changes: AtomicU64::new(0),
+            flushed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, app_path: &str, name: &str, category: &str, sent: u64, received: u64) {
+        if app_path.is_empty() || (sent == 0 && received == 0) {
+            return;
+        }
+
+        let now = Local::now();
+        let mut data = self.data.write();
+        let app = data.apps.entry(app_path.to_string()).or_default();
+        app.name = name.to_string();
+        app.category = category.to_string();
+        app.add(&now, sent, received);
+        self.changes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // written to a temp file first so a crash mid-write keeps the old data
+    // and only counted as flushed once the rename went through
+    pub fn flush(&self) -> Result<(), String> {
+        if self.changes.load(Ordering::SeqCst) == self.flushed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let path = self.path.as_ref().ok_or("Could not determine data directory")?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create data directory: {}", e))?;
+        }
+
+        // records take the write lock, so the count matches what is serialized
+        let (contents, changes) = {
+            let data = self.data.read();
+            let contents = serde_json::to_string(&*data)
+                .map_err(|e| format!("Failed to serialize usage data: {}", e))?;
+            (contents, self.changes.load(Ordering::SeqCst))
+        };
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, contents)
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|e| format!("Failed to write usage data: {}", e))?;
+
+        self.flushed.store(changes, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // month is YYYY-MM in local time
+    pub fn top_apps(&self, month: &str, limit: usize) -> Vec<AppUsageSummary> {
+        let data = self.data.read();
+        let mut apps: Vec<AppUsageSummary> = data.apps.iter()
+            .filter_map(|(path, app)| {
+                app.monthly.get(month).map(|totals| AppUsageSummary {
+                    path: path.clone(),
+                    name: app.name.clone(),
+                    category: app.category.clone(),
+                    totals: *totals,
+                })
+            })
+            .collect();
+
+        apps.sort_by_key(|app| std::cmp::Reverse(app.totals.bytes_sent + app.totals.bytes_received));
+        apps.truncate(limit);
+        apps
+    }
+
+    // one entry per day ending today, days without traffic are zero
+    pub fn daily_usage(&self, app_path: &str, days: u32) -> Vec<DailyUsage> {
+        let data = self.data.read();
+        let app = data.apps.get(app_path);
+        let today = Local::now();
+
+        (0..days as i64).rev()
+            .map(|offset| {
+                let date = (today - ChronoDuration::days(offset)).format(DAY_KEY).to_string();
+                let totals = app
+                    .and_then(|app| app.daily.get(&date))
+                    .copied()
+                    .unwrap_or_default();
+                DailyUsage { date, totals }
+            })
+            .collect()
+    }
+
+    // summed daily totals from `start` (local date, inclusive) to now for the
+    // apps the filter accepts; the filter gets (path, category)
+    pub fn totals_since<F>(&self, start: NaiveDate, filter: F) -> ByteTotals
+    where
+        F: Fn(&str, &str) -> bool
+    {
+        let start = start.format(DAY_KEY).to_string();
+        let data = self.data.read();
+        let mut totals = ByteTotals::default();
+
+        for (path, app) in data.apps.iter() {
+            if !filter(path, &app.category) {
+                continue;
+            }
+            for (_, day) in app.daily.range(start.clone()..) {
+                totals.add(day.bytes_sent, day.bytes_received);
+            }
+        }
+
+        totals
+    }
+
+    pub fn current_month() -> String {
+        Local::now().format(MONTH_KEY).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("meridian-usage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn failed_flush_is_retried() {
+        let dir = temp_dir("retry");
+        // a file where the data directory should be makes the write fail
+        let blocker = dir.join("blocked");
+        fs::write(&blocker, "").unwrap();
+        let store = UsageStore::open_at(Some(blocker.join(USAGE_FILE)));
+        store.record("C:\\app.exe", "app.exe", "Other", 10, 20);
+        assert!(store.flush().is_err());
+        assert!(store.flush().is_err());
+
+        fs::remove_file(&blocker).unwrap();
+        assert!(store.flush().is_ok());
+        assert!(blocker.join(USAGE_FILE).exists());
+
+        fs::remove_file(blocker.join(USAGE_FILE)).unwrap();
+        assert!(store.flush().is_ok());
+        assert!(!blocker.join(USAGE_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flushed_data_loads_back() {
+        let dir = temp_dir("roundtrip");
+        let path = dir.join(USAGE_FILE);
+        let store = UsageStore::open_at(Some(path.clone()));
+        store.record("C:\\app.exe", "app.exe", "Browsers", 10, 20);
+        store.flush().unwrap();
+
+        let reopened = UsageStore::open_at(Some(path));
+        let top = reopened.top_apps(&UsageStore::current_month(), 5);
+        assert_eq!(top.len(), 1);
+        assert_eq!((top[0].category.as_str(), top[0].totals.bytes_received), ("Browsers", 20));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_version_is_moved_aside() {
+        let dir = temp_dir("version");
+        let path = dir.join(USAGE_FILE);
+        fs::write(&path, r#"{"version":2,"apps":{},"extra":[1,2]}"#).unwrap();
+
+        let store = UsageStore::open_at(Some(path.clone()));
+        assert!(store.top_apps(&UsageStore::current_month(), 5).is_empty());
+        assert!(!path.exists());
+        assert!(fs::read_to_string(dir.join("usage.v2.json")).unwrap().contains("extra"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_data_survives_the_next_flush() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join(USAGE_FILE);
+        fs::write(&path, r#"{"version":1,"apps":{"/usr/bin/app":"#).unwrap();
+
+        let store = UsageStore::open_at(Some(path.clone()));
+        store.record("C:\\other.exe", "other.exe", "Other", 1, 2);
+        store.flush().unwrap();
+        assert!(fs::read_to_string(dir.join("usage.corrupt.json")).unwrap().contains("/usr/bin/app"));
+        assert!(fs::read_to_string(&path).unwrap().contains("other.exe"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
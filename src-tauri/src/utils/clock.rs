@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// monotonic time source, expressed as an offset from an arbitrary origin so
+// that live capture, pcap replays and tests can all drive the same code
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+// only moves when told to, e.g. to packet timestamps during a replay
+pub struct ManualClock {
+    nanos: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(start: Duration) -> Self {
+        Self {
+            nanos: AtomicU64::new(start.as_nanos() as u64),
+        }
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.nanos.store(now.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, delta: Duration) {
+        self.nanos.fetch_add(delta.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,21 @@
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+use crate::log_error;
+
+// set once the tauri app is up; events raised before that are dropped
+static APP_HANDLE: OnceCell<AppHandle> = OnceCell::new();
+
+pub fn init(handle: AppHandle) {
+    let _ = APP_HANDLE.set(handle);
+}
+
+pub fn emit<S: Serialize + Clone>(event: &str, payload: S) {
+    let Some(handle) = APP_HANDLE.get() else {
+        return;
+    };
+
+    if let Err(e) = handle.emit(event, payload) {
+        log_error!("Failed to emit {}: {}", event, e);
+    }
+}
@@ -1 +1,7 @@
-pub mod logger;
\ No newline at end of file
+pub mod logger;
+pub mod clock;
+pub mod paths;
+pub mod units;
+pub mod events;
+pub mod rng;
+pub mod pcap;
\ No newline at end of file
@@ -0,0 +1,174 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+// classic libpcap files, written with microsecond timestamps
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+const DEFAULT_SNAPLEN: u32 = 65535;
+
+#[derive(Debug, Clone)]
+pub struct PcapPacket {
+    // since the unix epoch
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+    // length on the wire, data may be truncated to the snaplen
+    pub original_len: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PcapFile {
+    pub link_type: u32,
+    pub packets: Vec<PcapPacket>,
+}
+
+pub fn read(path: &Path) -> Result<PcapFile, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn parse(bytes: &[u8]) -> Result<PcapFile, String> {
+    if bytes.len() < GLOBAL_HEADER_LEN {
+        return Err("file is too short for a pcap header".to_string());
+    }
+
+    let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let (little_endian, nanos) = match magic {
+        MAGIC_MICROS => (true, false),
+        MAGIC_NANOS => (true, true),
+        _ if magic.swap_bytes() == MAGIC_MICROS => (false, false),
+        _ if magic.swap_bytes() == MAGIC_NANOS => (false, true),
+        _ => return Err("not a pcap file (pcapng is not supported)".to_string()),
+    };
+
+    let read_u32 = |offset: usize| {
+        let field = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+        if little_endian { u32::from_le_bytes(field) } else { u32::from_be_bytes(field) }
+    };
+
+    let link_type = read_u32(20);
+    let mut packets = Vec::new();
+    let mut offset = GLOBAL_HEADER_LEN;
+
+    while offset + RECORD_HEADER_LEN <= bytes.len() {
+        let seconds = read_u32(offset) as u64;
+        let fraction = read_u32(offset + 4) as u64;
+        let captured_len = read_u32(offset + 8) as usize;
+        let original_len = read_u32(offset + 12);
+        offset += RECORD_HEADER_LEN;
+
+        if offset + captured_len > bytes.len() {
+            return Err(format!("truncated record after {} packets", packets.len()));
+        }
+
+        let subsec_nanos = if nanos { fraction } else { fraction * 1000 };
+        packets.push(PcapPacket {
+            timestamp: Duration::from_secs(seconds) + Duration::from_nanos(subsec_nanos),
+            data: bytes[offset..offset + captured_len].to_vec(),
+            original_len,
+        });
+        offset += captured_len;
+    }
+
+    Ok(PcapFile { link_type, packets })
+}
+
+pub fn write(path: &Path, file: &PcapFile) -> Result<(), String> {
+    fs::write(path, serialize(file)).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn serialize(file: &PcapFile) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(GLOBAL_HEADER_LEN + file.packets.iter()
+        .map(|packet| RECORD_HEADER_LEN + packet.data.len())
+        .sum::<usize>());
+
+    bytes.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+    bytes.extend_from_slice(&2u16.to_le_bytes());
+    bytes.extend_from_slice(&4u16.to_le_bytes());
+    bytes.extend_from_slice(&0i32.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&DEFAULT_SNAPLEN.to_le_bytes());
+    bytes.extend_from_slice(&file.link_type.to_le_bytes());
+
+    for packet in &file.packets {
+        bytes.extend_from_slice(&(packet.timestamp.as_secs() as u32).to_le_bytes());
+        bytes.extend_from_slice(&packet.timestamp.subsec_micros().to_le_bytes());
+        bytes.extend_from_slice(&(packet.data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&packet.original_len.max(packet.data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&packet.data);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seconds: u64, micros: u64, data: &[u8], original_len: u32) -> PcapPacket {
+        PcapPacket {
+            timestamp: Duration::from_secs(seconds) + Duration::from_micros(micros),
+            data: data.to_vec(),
+            original_len,
+        }
+    }
+
+    #[test]
+    fn serialized_files_parse_back() {
+        let file = PcapFile {
+            link_type: 1,
+            packets: vec![
+                packet(1_700_000_000, 123_456, &[1, 2, 3], 3),
+                // cut short by the snaplen
+                packet(1_700_000_001, 0, &[4; 64], 1500),
+                packet(1_700_000_001, 999_999, &[], 0),
+            ],
+        };
+
+        let parsed = parse(&serialize(&file)).unwrap();
+        assert_eq!(parsed.link_type, 1);
+        assert_eq!(parsed.packets.len(), 3);
+        for (parsed, original) in parsed.packets.iter().zip(&file.packets) {
+            assert_eq!(parsed.timestamp, original.timestamp);
+            assert_eq!(parsed.data, original.data);
+            assert_eq!(parsed.original_len, original.original_len);
+        }
+    }
+
+    #[test]
+    fn original_length_is_never_below_the_captured_one() {
+        let file = PcapFile { link_type: 1, packets: vec![packet(1, 0, &[0; 10], 4)] };
+        assert_eq!(parse(&serialize(&file)).unwrap().packets[0].original_len, 10);
+    }
+
+    #[test]
+    fn big_endian_nanosecond_files_are_read() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC_NANOS.to_be_bytes());
+        bytes.extend_from_slice(&[0, 2, 0, 4]);
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&DEFAULT_SNAPLEN.to_be_bytes());
+        bytes.extend_from_slice(&101u32.to_be_bytes());
+        for field in [5u32, 250, 2, 60] {
+            bytes.extend_from_slice(&field.to_be_bytes());
+        }
+        bytes.extend_from_slice(&[0x45, 0]);
+
+        let file = parse(&bytes).unwrap();
+        assert_eq!(file.link_type, 101);
+        assert_eq!(file.packets[0].timestamp, Duration::new(5, 250));
+        assert_eq!(file.packets[0].original_len, 60);
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        assert!(parse(&[0xd4, 0xc3, 0xb2]).is_err());
+        assert!(parse(&[0x0a, 0x0d, 0x0d, 0x0a].repeat(6)).unwrap_err().contains("pcapng"));
+
+        let mut bytes = serialize(&PcapFile { link_type: 1, packets: vec![packet(1, 0, &[0; 32], 32)] });
+        bytes.truncate(bytes.len() - 1);
+        assert!(parse(&bytes).unwrap_err().contains("truncated"));
+    }
+}
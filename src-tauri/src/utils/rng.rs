@@ -0,0 +1,78 @@
+// SplitMix64: tiny, fast and fully determined by its seed, which is all the
+// emulator needs to make a run reproducible. Not for anything security related.
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    // independent stream for the n-th consumer of one seed
+    pub fn derive(seed: u64, stream: u64) -> Self {
+        let mut rng = Self::new(seed ^ stream.wrapping_mul(0xd1b5_4a32_d192_ed03));
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // uniform in [min, max)
+    pub fn range_f64(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min) * self.next_f64()
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draws(mut rng: SeededRng) -> Vec<u64> {
+        (0..32).map(|_| rng.next_u64()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        assert_eq!(draws(SeededRng::new(42)), draws(SeededRng::new(42)));
+        assert_ne!(draws(SeededRng::new(42)), draws(SeededRng::new(43)));
+        // pinned so a change to the generator shows up as changed replays
+        assert_eq!(SeededRng::new(0).next_u64(), 0xe220_a839_7b1d_cdaf);
+    }
+
+    #[test]
+    fn derived_streams_are_independent_and_reproducible() {
+        assert_eq!(draws(SeededRng::derive(7, 1)), draws(SeededRng::derive(7, 1)));
+        assert_ne!(draws(SeededRng::derive(7, 1)), draws(SeededRng::derive(7, 2)));
+        assert_ne!(draws(SeededRng::derive(7, 0)), draws(SeededRng::new(7)));
+    }
+
+    #[test]
+    fn floats_stay_in_range() {
+        let mut rng = SeededRng::new(1);
+        for _ in 0..10_000 {
+            let unit = rng.next_f64();
+            assert!((0.0..1.0).contains(&unit));
+            let ranged = rng.range_f64(-5.0, 5.0);
+            assert!((-5.0..5.0).contains(&ranged));
+        }
+        assert!(!rng.chance(0.0));
+        assert!(rng.chance(1.0));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::models::{ByteRate, RateUnit, UnitPrefix, UnitPreferences};
+
+const SI_PREFIXES: [&str; 5] = ["", "k", "M", "G", "T"];
+const IEC_PREFIXES: [&str; 5] = ["", "Ki", "Mi", "Gi", "Ti"];
+const JEDEC_PREFIXES: [&str; 5] = ["", "K", "M", "G", "T"];
+
+// the compatibility view never goes below kilo, matching the old KB/s output
+const MIN_RATE_EXPONENT: usize = 1;
+
+pub fn format_rate(rate: ByteRate, preferences: &UnitPreferences) -> (f64, String) {
+    let (amount, suffix) = match preferences.unit {
+        RateUnit::Bytes => (rate.bytes_per_second(), "B/s"),
+        RateUnit::Bits => (rate.bytes_per_second() * 8.0, "bit/s"),
+    };
+
+    scale(amount, suffix, preferences.prefix, MIN_RATE_EXPONENT)
+}
+
+fn scale(amount: f64, suffix: &str, prefix: UnitPrefix, min_exponent: usize) -> (f64, String) {
+    let (base, prefixes) = match prefix {
+        UnitPrefix::Si => (1000.0, &SI_PREFIXES),
+        UnitPrefix::Iec => (1024.0, &IEC_PREFIXES),
+        UnitPrefix::Jedec => (1024.0, &JEDEC_PREFIXES),
+    };
+
+    let mut exponent = min_exponent;
+    let mut value = amount / f64::powi(base, exponent as i32);
+    while value >= base && exponent < prefixes.len() - 1 {
+        value /= base;
+        exponent += 1;
+    }
+
+    (value, format!("{}{}", prefixes[exponent], suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formatted(bytes_per_second: f64, unit: RateUnit, prefix: UnitPrefix) -> (f64, String) {
+        format_rate(ByteRate(bytes_per_second), &UnitPreferences { unit, prefix })
+    }
+
+    #[test]
+    fn prefixes_follow_their_base_and_labels() {
+        assert_eq!(formatted(2048.0, RateUnit::Bytes, UnitPrefix::Jedec), (2.0, "KB/s".to_string()));
+        assert_eq!(formatted(2048.0, RateUnit::Bytes, UnitPrefix::Iec), (2.0, "KiB/s".to_string()));
+        assert_eq!(formatted(2000.0, RateUnit::Bytes, UnitPrefix::Si), (2.0, "kB/s".to_string()));
+        assert_eq!(formatted(3.0 * 1024.0 * 1024.0, RateUnit::Bytes, UnitPrefix::Iec), (3.0, "MiB/s".to_string()));
+        assert_eq!(formatted(1_500_000.0, RateUnit::Bytes, UnitPrefix::Si), (1.5, "MB/s".to_string()));
+    }
+
+    #[test]
+    fn bits_are_eight_times_the_bytes() {
+        assert_eq!(formatted(125.0, RateUnit::Bits, UnitPrefix::Si), (1.0, "kbit/s".to_string()));
+        assert_eq!(formatted(125_000.0, RateUnit::Bits, UnitPrefix::Si), (1.0, "Mbit/s".to_string()));
+        assert_eq!(formatted(128.0, RateUnit::Bits, UnitPrefix::Iec), (1.0, "Kibit/s".to_string()));
+        assert_eq!(formatted(128.0 * 1024.0, RateUnit::Bits, UnitPrefix::Jedec), (1.0, "Mbit/s".to_string()));
+    }
+
+    #[test]
+    fn rates_stay_between_kilo_and_the_largest_prefix() {
+        assert_eq!(formatted(0.0, RateUnit::Bytes, UnitPrefix::Jedec), (0.0, "KB/s".to_string()));
+        assert_eq!(formatted(512.0, RateUnit::Bytes, UnitPrefix::Jedec), (0.5, "KB/s".to_string()));
+        // just below the next prefix does not round up into it
+        assert_eq!(formatted(999_999.0, RateUnit::Bytes, UnitPrefix::Si).1, "kB/s");
+        assert_eq!(formatted(5e15, RateUnit::Bytes, UnitPrefix::Si), (5000.0, "TB/s".to_string()));
+    }
+
+    #[test]
+    fn scale_can_start_below_kilo() {
+        assert_eq!(scale(999.0, "B", UnitPrefix::Si, 0), (999.0, "B".to_string()));
+        assert_eq!(scale(1024.0, "B", UnitPrefix::Iec, 0), (1.0, "KiB".to_string()));
+    }
+}
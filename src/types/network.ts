@@ -4,15 +4,626 @@ export enum ProcessStatus {
 }
 
 export interface NetworkUsage {
+    // raw rate; value/unit are a pre-formatted view of it
+    bytes_per_second?: number;
     value: number;
     unit: string;
 }
 
+export type RateUnit = "Bytes" | "Bits";
+export type UnitPrefix = "Jedec" | "Si" | "Iec";
+
+export interface UnitPreferences {
+    unit: RateUnit;
+    prefix: UnitPrefix;
+}
+
+export interface RateConfig {
+    window_ms: number;
+    bucket_ms: number;
+    // 0 averages the window evenly, otherwise older buckets fade with this half-life
+    smoothing_half_life_ms: number;
+}
+
 export interface ProcessNetworkUsage {
     download: NetworkUsage;
     upload: NetworkUsage;
 }
 
+export interface HistogramBucket {
+    min_size: number;
+    max_size: number | null;
+    count: number;
+}
+
+export interface PacketStats {
+    download_pps: number;
+    upload_pps: number;
+    avg_download_packet_size: number;
+    avg_upload_packet_size: number;
+    size_histogram: HistogramBucket[];
+}
+
+export interface FlowStats {
+    protocol: TransportProtocol;
+    local_addr: string;
+    local_port: number;
+    remote_addr: string;
+    remote_port: number;
+    // from a DNS answer or TLS server name, when one was seen
+    hostname?: string | null;
+    bytes_sent: number;
+    bytes_received: number;
+    packets_sent: number;
+    packets_received: number;
+    packet_stats: PacketStats;
+}
+
+export interface ByteTotals {
+    bytes_sent: number;
+    bytes_received: number;
+}
+
+export interface TrafficTotals {
+    session: ByteTotals;
+    since_reset: ByteTotals;
+}
+
+export interface ProcessTotals {
+    pid: number;
+    name: string;
+    path: string;
+    running: boolean;
+    totals: TrafficTotals;
+}
+
+export interface TotalsReport {
+    session_started_at: number;
+    reset_at: number;
+    totals: TrafficTotals;
+    processes: ProcessTotals[];
+}
+
+export type HistoryResolution = "Second" | "Minute" | "Hour";
+
+export interface HistoryPoint {
+    timestamp: number;
+    download: number;
+    upload: number;
+}
+
+export interface HistorySeries {
+    resolution: HistoryResolution;
+    points: HistoryPoint[];
+}
+
+// pid of the pseudo-process holding traffic no process could be matched to
+export const UNATTRIBUTED_PID = 4294967295;
+
+export type TransportProtocol = "Tcp" | "Udp";
+export type UnattributedReason = "Ipv6" | "Udp" | "NoMatchingConnection" | "LookupFailed";
+
+export interface ReasonTotals {
+    reason: UnattributedReason;
+    bytes: number;
+    packets: number;
+}
+
+export interface EndpointTotals {
+    remote_addr: string;
+    remote_port: number;
+    protocol: TransportProtocol;
+    bytes_sent: number;
+    bytes_received: number;
+    packets: number;
+}
+
+export interface AttributionReport {
+    interface: ByteTotals;
+    attributed: ByteTotals;
+    unattributed: ByteTotals;
+    // payload relayed by the proxy, not part of the interface bytes or coverage
+    proxied: ByteTotals;
+    coverage_percent: number;
+    reasons: ReasonTotals[];
+    top_endpoints: EndpointTotals[];
+}
+
+export type EnforcementAction =
+    | { type: "Limit"; upload: number | null; download: number | null }
+    | { type: "Block" };
+
+export interface ActiveEnforcement {
+    pid: number;
+    action: EnforcementAction;
+}
+
+// KB/s shared by every process, split evenly between active apps; 0 leaves that direction unlimited
+export interface GlobalLimit {
+    download_limit: number;
+    upload_limit: number;
+}
+
+export interface EnforcementStatus {
+    backend: string;
+    active: ActiveEnforcement[];
+}
+
+export interface ProxySettings {
+    enabled: boolean;
+    port: number;
+}
+
+export interface ProxyStatus {
+    settings: ProxySettings;
+    running: boolean;
+    active_connections: number;
+    bytes_relayed: ByteTotals;
+}
+
+export type EmulationStageKind =
+    | { type: "Delay"; ms: number }
+    | { type: "Allow" }
+    | { type: "RandomDelay"; min_ms: number; max_ms: number }
+    | { type: "Jitter"; ms: number }
+    | { type: "Drop"; percent: number }
+    | {
+          type: "BurstLoss";
+          enter_bad_percent: number;
+          exit_bad_percent: number;
+          good_loss_percent: number;
+          bad_loss_percent: number;
+      }
+    | { type: "Duplicate"; percent: number; copies: number }
+    | { type: "Reorder"; percent: number; delay_ms: number }
+    | { type: "Bandwidth"; bytes_per_second: number; queue_ms: number };
+
+export type EmulationStage = EmulationStageKind & {
+    upload: boolean;
+    download: boolean;
+};
+
+export type EmulationTarget =
+    | { type: "Process"; pid: number }
+    | { type: "Application"; path: string }
+    | {
+          type: "Filter";
+          protocol: TransportProtocol | null;
+          remote_addr: string | null;
+          remote_port: number | null;
+          local_port: number | null;
+      };
+
+export interface EmulationProfile {
+    id: string;
+    name: string;
+    target: EmulationTarget;
+    stages: EmulationStage[];
+    seed: number;
+    enabled: boolean;
+}
+
+export type PacketRegion = "Ip" | "Transport" | "Payload";
+
+export interface BytePattern {
+    region: PacketRegion;
+    offset: number;
+    bytes: string;
+}
+
+export type TamperOperation =
+    | { type: "Replace"; offset: number; length: number | null; bytes: string }
+    | { type: "Flip"; offset: number; length: number; mask: number }
+    | { type: "Randomize"; offset: number; length: number };
+
+export interface TamperRule {
+    id: string;
+    name: string;
+    enabled: boolean;
+    pid: number | null;
+    direction: "Upload" | "Download" | null;
+    protocol: TransportProtocol | null;
+    local_port: number | null;
+    remote_addr: string | null;
+    remote_port: number | null;
+    pattern: BytePattern | null;
+    region: PacketRegion;
+    operations: TamperOperation[];
+    percent: number;
+    recompute_checksums: boolean;
+}
+
+export interface TamperRuleHits {
+    id: string;
+    matched: number;
+    modified: number;
+}
+
+export interface TamperReport {
+    packets_read: number;
+    packets_modified: number;
+    unparsed: number;
+    rules: TamperRuleHits[];
+    output_path: string | null;
+}
+
+export type HoldMode = "Buffer" | "Drop";
+export type HoldRelease = "Flush" | "Discard";
+
+export interface HoldSettings {
+    upload: boolean;
+    download: boolean;
+    mode: HoldMode;
+    release: HoldRelease;
+    timeout_ms: number;
+    max_buffer_bytes: number;
+}
+
+export interface HoldStatus {
+    pid: number;
+    settings: HoldSettings;
+    started_at: number;
+    expires_at: number | null;
+    held_packets: number;
+    held_bytes: number;
+    dropped_packets: number;
+    dropped_bytes: number;
+}
+
+export interface HoldWindow {
+    start_ms: number;
+    settings: HoldSettings;
+}
+
+export interface EmulationStats {
+    packets_in: number;
+    packets_out: number;
+    dropped: number;
+    held: number;
+    duplicated: number;
+    reordered: number;
+    average_delay_ms: number;
+    max_delay_ms: number;
+}
+
+export interface ReplayReport {
+    packets_read: number;
+    packets_matched: number;
+    unparsed: number;
+    stats: EmulationStats;
+    output_path: string | null;
+}
+
+export interface PortRange {
+    start: number;
+    end: number;
+}
+
+export interface RuleConditions {
+    path: string | null;
+    process_name: string | null;
+    pid: number | null;
+    parent_pid: number | null;
+    protocol: TransportProtocol | null;
+    local_ports: PortRange[];
+    remote_ports: PortRange[];
+    remote_cidrs: string[];
+    remote_hosts: string[];
+    direction: "Upload" | "Download" | null;
+}
+
+export type RuleAction =
+    | { type: "Limit"; download_limit: number; upload_limit: number }
+    | { type: "Block" }
+    // Delay and Mark only apply to connections through the proxy
+    | { type: "Delay"; ms: number }
+    | { type: "Allow" }
+    | { type: "Mark"; mark: number }
+    | { type: "Log" };
+
+export interface Rule {
+    id: string;
+    name: string;
+    enabled: boolean;
+    conditions: RuleConditions;
+    action: RuleAction;
+    schedule?: Schedule | null;
+    apply_to_descendants?: boolean;
+}
+
+export type QuotaScope =
+    | { type: "Global" }
+    | { type: "Application"; path: string }
+    | { type: "Category"; category: string };
+
+export type QuotaDirection = "Both" | "Download" | "Upload";
+export type QuotaPeriod = "Daily" | "Weekly" | "Monthly";
+
+export type QuotaAction =
+    | { type: "Notify" }
+    | { type: "Throttle"; download_limit: number; upload_limit: number }
+    | { type: "Block" };
+
+export interface QuotaThreshold {
+    percent: number;
+    action: QuotaAction;
+}
+
+export interface Quota {
+    id: string;
+    name: string;
+    scope: QuotaScope;
+    limit_bytes: number;
+    direction: QuotaDirection;
+    period: QuotaPeriod;
+    reset_day: number;
+    thresholds: QuotaThreshold[];
+    enabled: boolean;
+}
+
+export interface QuotaStatus {
+    quota: Quota;
+    period_start: string;
+    period_end: string;
+    used_bytes: number;
+    percent: number;
+    triggered: number[];
+}
+
+// payload of the "quota-threshold" event
+export interface QuotaEvent {
+    quota_id: string;
+    name: string;
+    percent: number;
+    action: QuotaAction;
+    used_bytes: number;
+    limit_bytes: number;
+}
+
+// applies to every process of the executable; sha256 pins one exact build
+export interface AppLimit {
+    path: string;
+    sha256?: string | null;
+    download_limit: number;
+    upload_limit: number;
+    schedule?: Schedule | null;
+    apply_to_descendants?: boolean;
+    // blocks every connection of the app; the rates are ignored
+    blocked?: boolean;
+}
+
+export type PriorityClass = "Critical" | "High" | "Normal" | "Low" | "Background";
+
+// KB/s; 0 leaves that direction unshaped
+export interface LinkCapacity {
+    download_limit: number;
+    upload_limit: number;
+}
+
+export interface AppPriority {
+    path: string;
+    class: PriorityClass;
+}
+
+export interface ProcessPriority {
+    pid: number;
+    class: PriorityClass;
+    manual: boolean;
+}
+
+// times are "HH:MM"; an end at or before the start runs past midnight
+export interface TimeWindow {
+    weekdays: number[];
+    start: string;
+    end: string;
+}
+
+// timezone is an IANA name, null follows the system timezone
+export interface Schedule {
+    windows: TimeWindow[];
+    timezone?: string | null;
+}
+
+export interface ScheduleStatus {
+    rule_id: string;
+    name: string;
+    active: boolean;
+    // unix milliseconds
+    next_transition: number | null;
+}
+
+// what the flows of a destination-scoped rule measure, across all processes
+export interface ScopeUsage {
+    rule_id: string;
+    name: string;
+    action: RuleAction;
+    usage: ProcessNetworkUsage;
+    flows: number;
+    // false while the proxy is off: measured, but not limited or blocked
+    enforced: boolean;
+}
+
+export interface LearningStatus {
+    active: boolean;
+    // unix milliseconds, of the current or last session
+    started_at: number | null;
+    ends_at: number | null;
+    apps: number;
+    flows: number;
+}
+
+export interface LearnedFlow {
+    app_path: string;
+    app_name: string;
+    protocol: TransportProtocol;
+    remote_addr: string;
+    remote_port: number;
+    hostname: string | null;
+    // unix milliseconds
+    first_seen: number;
+    last_seen: number;
+}
+
+export type ProposalChange = "Added" | "Covered" | "Conflicting";
+
+export interface ProposedRule {
+    rule: Rule;
+    change: ProposalChange;
+    flows: number;
+    existing_rule_ids: string[];
+}
+
+export interface RuleProposal {
+    rules: ProposedRule[];
+    unused_rule_ids: string[];
+}
+
+// one connection of a recorded flow log; only the protocol and remote end are required
+export interface FlowRecord {
+    pid?: number;
+    app_name?: string;
+    app_path?: string;
+    protocol: TransportProtocol;
+    local_port?: number;
+    remote_addr: string;
+    remote_port: number;
+    hostname?: string | null;
+    bytes_sent?: number;
+    bytes_received?: number;
+    // unix milliseconds the flow started, which places it in rule schedules
+    timestamp?: number | null;
+}
+
+export type SimulationSource =
+    | { type: "Pcap"; path: string; local_addr?: string | null; app_path?: string | null }
+    | { type: "FlowLog"; path: string };
+
+export interface SimulatedFlow {
+    app_path: string;
+    protocol: TransportProtocol;
+    local_port: number;
+    remote_addr: string;
+    remote_port: number;
+    hostname: string | null;
+    bytes_sent: number;
+    bytes_received: number;
+    upload_rule_id: string | null;
+    download_rule_id: string | null;
+    tagged_by: string[];
+}
+
+export interface SimulatedRule {
+    rule_id: string;
+    name: string;
+    action: RuleAction;
+    flows: number;
+    bytes: number;
+}
+
+export interface SimulationReport {
+    flows: SimulatedFlow[];
+    rules: SimulatedRule[];
+    unmatched_rule_ids: string[];
+    bytes_blocked: number;
+    // bytes that would have been subject to a limit, not what it would have cut
+    bytes_limited: number;
+    bytes_delayed: number;
+    bytes_allowed: number;
+    bytes_undecided: number;
+    scheduled_rule_ids: string[];
+    packets_read: number;
+    unparsed: number;
+    // ICMP and other protocols besides TCP and UDP, which no rule sees
+    other_protocols: number;
+}
+
+export interface FirewallSettings {
+    ask_mode: boolean;
+    decision_timeout_ms: number;
+    allow_on_timeout: boolean;
+}
+
+// payload of the "firewall-pending" event
+export interface PendingDecision {
+    id: number;
+    pid: number;
+    app_name: string;
+    app_path: string;
+    remote_addr: string;
+    remote_port: number;
+    hostname: string | null;
+    protocol: TransportProtocol;
+    // unix milliseconds
+    requested_at: number;
+    expires_at: number;
+}
+
+export type DecisionScope = "App" | "Connection";
+
+export interface DecisionAnswer {
+    allow: boolean;
+    scope?: DecisionScope;
+    remember?: boolean;
+}
+
+// payload of the "firewall-resolved" event
+export interface DecisionResolved {
+    id: number;
+    allowed: boolean;
+    timed_out: boolean;
+    rule_id: string | null;
+}
+
+export type ProfileTrigger =
+    | ({ type: "Schedule" } & Schedule)
+    | { type: "Interface"; name: string };
+
+export interface Profile {
+    id: string;
+    name: string;
+    rules: Rule[];
+    limits: AppLimit[];
+    triggers: ProfileTrigger[];
+}
+
+export type ProfileActivation = "Manual" | "Schedule" | "Interface";
+
+// payload of the "profile-changed" event
+export interface ProfileChange {
+    previous: string | null;
+    active: string | null;
+    reason: ProfileActivation;
+}
+
+export interface ConfigDocument {
+    schema_version: number;
+    rules: Rule[];
+    limits: AppLimit[];
+    quotas: Quota[];
+    profiles: Profile[];
+}
+
+export type ConfigFormat = "Toml" | "Json";
+
+export type ImportMode = "Merge" | "Replace";
+
+export interface ConfigIssue {
+    line: number | null;
+    column: number | null;
+    location: string | null;
+    message: string;
+}
+
+export interface ConfigValidation {
+    schema_version: number;
+    migrated_from: number | null;
+    rules: number;
+    limits: number;
+    quotas: number;
+    profiles: number;
+    issues: ConfigIssue[];
+}
+
 export interface ApplicationProcess {
     id: number;
     name: string;
@@ -20,12 +631,16 @@ export interface ApplicationProcess {
     path: string;
     icon: string | null;
     network_usage: ProcessNetworkUsage;
+    packet_stats?: PacketStats;
+    totals?: TrafficTotals;
     pid: number;
     parent_pid?: number;
     children: number[];
     status: ProcessStatus;
     is_system: boolean;
     category: string;
+    // pid of the ancestor whose limit this process inherited
+    inherited_from?: number | null;
     firstSeen?: number;
 }
 